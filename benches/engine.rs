@@ -0,0 +1,165 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use kvs::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+fn pairs(n: usize) -> Vec<(String, String)> {
+    (0..n)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect()
+}
+
+// `set` in a loop against one `set_batch` call
+fn set_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_batch");
+    group.bench_function("set_loop", |b| {
+        b.iter_batched(
+            || (TempDir::new().unwrap(), pairs(1000)),
+            |(temp_dir, pairs)| {
+                let mut store = KvStore::open(temp_dir.path()).unwrap();
+                for (key, value) in pairs {
+                    store.set(key, value).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("set_batch", |b| {
+        b.iter_batched(
+            || (TempDir::new().unwrap(), pairs(1000)),
+            |(temp_dir, pairs)| {
+                let mut store = KvStore::open(temp_dir.path()).unwrap();
+                store.set_batch(pairs).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// `get` in a loop against one `get_many` call, on keys spread over the log
+fn get_many(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set_batch(pairs(100_000)).unwrap();
+    // a fixed pseudo random order
+    let keys: Vec<String> = (0..1000u64)
+        .map(|i| format!("key{}", i.wrapping_mul(7919) % 100_000))
+        .collect();
+
+    let mut group = c.benchmark_group("get_many");
+    group.bench_function("get_loop", |b| {
+        b.iter(|| {
+            for key in keys.iter() {
+                store.get(key.clone()).unwrap();
+            }
+        })
+    });
+    group.bench_function("get_many", |b| b.iter(|| store.get_many(&keys).unwrap()));
+    group.finish();
+}
+
+// `set` of the same key with values of varying lengths, linear in the
+// number of writes since every overwrite is appended
+fn overwrite(c: &mut Criterion) {
+    let mut group = c.benchmark_group("overwrite");
+    for &n in [1000, 10_000].iter() {
+        group.bench_function(format!("set_same_key_{}", n), |b| {
+            b.iter_batched(
+                || TempDir::new().unwrap(),
+                |temp_dir| {
+                    let mut store = KvStore::open(temp_dir.path()).unwrap();
+                    for i in 0..n {
+                        store.set("key".to_owned(), "v".repeat(i % 64)).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// `get` of small values read from the log against kept inline in the index
+fn inline_values(c: &mut Criterion) {
+    let keys: Vec<String> = (0..1000u64)
+        .map(|i| format!("key{}", i.wrapping_mul(7919) % 100_000))
+        .collect();
+    let mut group = c.benchmark_group("inline_values");
+    for &max_len in [0, 64].iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStoreOptions::new()
+            .inline_values(max_len)
+            .open(temp_dir.path())
+            .unwrap();
+        store.set_batch(pairs(100_000)).unwrap();
+        group.bench_function(format!("get_inline_{}", max_len), |b| {
+            b.iter(|| {
+                for key in keys.iter() {
+                    store.get(key.clone()).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// `set` of 1000 values of 1 KB each, the keys and values moved in
+fn set_1k_values(c: &mut Criterion) {
+    let values: Vec<(String, String)> = (0..1000)
+        .map(|id| (format!("key{}", id), format!("{:01024}", id)))
+        .collect();
+
+    let mut group = c.benchmark_group("set_1k_values");
+    group.throughput(Throughput::Bytes(1000 * 1024));
+    group.bench_function("set_loop", |b| {
+        b.iter_batched(
+            || (TempDir::new().unwrap(), values.clone()),
+            |(temp_dir, values)| {
+                let mut store = KvStore::open(temp_dir.path()).unwrap();
+                for (key, value) in values {
+                    store.set(key, value).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// `open` of a log of 1M records, replayed whole with no hint file
+fn open(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .open(temp_dir.path())
+        .unwrap();
+    for chunk in pairs(1_000_000).chunks(10_000) {
+        store.set_batch(chunk.to_vec()).unwrap();
+    }
+    drop(store);
+    let hint = temp_dir.path().join("kvs.log.hint");
+
+    let mut group = c.benchmark_group("open");
+    group.sample_size(10);
+    group.bench_function("replay_1m_records", |b| {
+        b.iter_batched(
+            || {
+                let _ = std::fs::remove_file(&hint);
+            },
+            |_| KvStore::open(temp_dir.path()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    set_batch,
+    get_many,
+    overwrite,
+    inline_values,
+    set_1k_values,
+    open
+);
+criterion_main!(benches);
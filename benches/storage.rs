@@ -0,0 +1,147 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use kvs::workload::Workload;
+use kvs::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const KEYS: usize = 100_000;
+// the lengths of the values every benchmark runs with
+const VALUE_LENS: [usize; 2] = [16, 1024];
+const SEED: u64 = 42;
+
+// a KvStore in a new directory holding every pair of workload
+fn filled(workload: &Workload, options: &KvStoreOptions) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = options.open(temp_dir.path()).unwrap();
+    for chunk in workload.pairs().chunks(10_000) {
+        store.set_batch(chunk.to_vec()).unwrap();
+    }
+    (temp_dir, store)
+}
+
+// `set` of 100k unique keys in key order
+fn sequential_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sequential_set");
+    group.sample_size(10);
+    for &value_len in VALUE_LENS.iter() {
+        let workload = Workload::new(SEED, KEYS, value_len);
+        group.throughput(Throughput::Elements(KEYS as u64));
+        group.bench_function(format!("value_{}", value_len), |b| {
+            b.iter_batched(
+                || (TempDir::new().unwrap(), workload.pairs()),
+                |(temp_dir, pairs)| {
+                    let mut store = KvStore::open(temp_dir.path()).unwrap();
+                    for (key, value) in pairs {
+                        store.set(key, value).unwrap();
+                    }
+                    (temp_dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// `set` of one key 100k times, with the compactions it triggers
+fn overwrite(c: &mut Criterion) {
+    let mut group = c.benchmark_group("overwrite");
+    group.sample_size(10);
+    for &value_len in VALUE_LENS.iter() {
+        let workload = Workload::new(SEED, KEYS, value_len);
+        group.throughput(Throughput::Elements(KEYS as u64));
+        group.bench_function(format!("value_{}", value_len), |b| {
+            b.iter_batched(
+                || {
+                    let values: Vec<String> = (0..KEYS).map(|i| workload.value(i)).collect();
+                    (TempDir::new().unwrap(), values)
+                },
+                |(temp_dir, values)| {
+                    let mut store = KvStore::open(temp_dir.path()).unwrap();
+                    for value in values {
+                        store.set(workload.key(0), value).unwrap();
+                    }
+                    (temp_dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// `get` of 1000 keys picked at random out of 100k
+fn random_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_get");
+    for &value_len in VALUE_LENS.iter() {
+        let workload = Workload::new(SEED, KEYS, value_len);
+        let (_temp_dir, store) = filled(&workload, &KvStoreOptions::new());
+        let keys = workload.random_keys(1000);
+        group.throughput(Throughput::Elements(keys.len() as u64));
+        group.bench_function(format!("value_{}", value_len), |b| {
+            b.iter(|| {
+                for key in keys.iter() {
+                    store.get(key.clone()).unwrap().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// `open` of a log of 100k keys, replayed whole with no hint file
+fn open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    group.sample_size(10);
+    for &value_len in VALUE_LENS.iter() {
+        let workload = Workload::new(SEED, KEYS, value_len);
+        let (temp_dir, store) = filled(&workload, &KvStoreOptions::new());
+        drop(store);
+        let hint = temp_dir.path().join("kvs.log.hint");
+        group.bench_function(format!("value_{}", value_len), |b| {
+            b.iter_batched(
+                || {
+                    let _ = std::fs::remove_file(&hint);
+                },
+                |_| KvStore::open(temp_dir.path()).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// `compact` of a log of 100k keys each set twice, in a random order
+fn compaction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction");
+    group.sample_size(10);
+    for &value_len in VALUE_LENS.iter() {
+        let workload = Workload::new(SEED, KEYS, value_len);
+        let mut options = KvStoreOptions::new();
+        options.compaction_threshold(None);
+        group.bench_function(format!("value_{}", value_len), |b| {
+            b.iter_batched(
+                || {
+                    let (temp_dir, mut store) = filled(&workload, &options);
+                    store.set_batch(workload.shuffled_pairs()).unwrap();
+                    (temp_dir, store)
+                },
+                |(temp_dir, mut store)| {
+                    store.compact().unwrap();
+                    (temp_dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    sequential_set,
+    overwrite,
+    random_get,
+    open,
+    compaction
+);
+criterion_main!(benches);
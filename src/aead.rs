@@ -0,0 +1,33 @@
+//! The ChaCha20-Poly1305 the logs are encrypted with, see
+//! `KvStoreOptions::encryption_key`, for its known-answer tests; needs the
+//! `test-internals` feature.
+
+use crate::crypt::{self, key_words, Cipher, Poly1305};
+
+/// The ChaCha20 block of RFC 8439 for `key`, `counter` and `nonce`.
+pub fn chacha20_block(key: [u8; 32], counter: u32, nonce: [u8; 12]) -> [u8; 64] {
+    crypt::chacha20_block(&key_words(key), counter, &nonce)
+}
+
+/// The ChaCha20 encryption of `plaintext` with `key` and `nonce`, from the
+/// block counter 1 as the AEAD of RFC 8439 encrypts.
+pub fn chacha20(key: [u8; 32], nonce: [u8; 12], plaintext: &[u8]) -> Vec<u8> {
+    let mut encrypted = plaintext.to_vec();
+    Cipher::new(key).apply_keystream(&nonce, &mut encrypted);
+    encrypted
+}
+
+/// The Poly1305 tag of `message` with the one-time `key`.
+pub fn poly1305(key: [u8; 32], message: &[u8]) -> [u8; 16] {
+    let mut poly = Poly1305::new(&key);
+    poly.message(message);
+    poly.finish()
+}
+
+/// The AEAD_CHACHA20_POLY1305 encryption of `plaintext` with `key`, `nonce`
+/// and the additional data `aad`: the ciphertext and its tag.
+pub fn seal(key: [u8; 32], nonce: [u8; 12], aad: &[u8], plaintext: &[u8]) -> (Vec<u8>, [u8; 16]) {
+    let encrypted = chacha20(key, nonce, plaintext);
+    let tag = Cipher::new(key).tag(&nonce, aad, &encrypted);
+    (encrypted, tag)
+}
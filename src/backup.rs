@@ -0,0 +1,273 @@
+use crate::crypt::open_record;
+use crate::header::{record_format, records_start};
+use crate::index::Key;
+use crate::record::{decode, encode, read_next};
+use crate::{
+    decode_key, now_millis, read_record, replay_record, KvStore, KvsError, LogFormat, OptData,
+    Result,
+};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+
+// the start of every backup stream, followed by the version byte
+const BACKUP_MAGIC: &[u8; 7] = b"KVSBACK";
+const BACKUP_VERSION: u8 = 1;
+// the number of records applied with a single log write
+const APPLY_BATCH: usize = 1024;
+
+/// What `KvStore::backup_since` wrote.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BackupStats {
+    /// the number of sets and removals streamed
+    pub records: usize,
+    /// the length of the stream in bytes
+    pub bytes: u64,
+    /// the last sequence number of the KvStore, which a later backup goes on
+    /// from
+    pub last_seq: u64,
+}
+
+impl KvStore {
+    /// Writes every set and removal logged with a sequence number greater
+    /// than `seq` to `w`, in log order, for `KvStore::apply_backup`.
+    ///
+    /// The stream starts with the magic bytes `KVSBACK`, a version byte and
+    /// `seq` as a u64 LE, then holds the records as binary log records and
+    /// ends with a record of the last sequence number. A compaction drops
+    /// removals and overwritten values, so once a record after `seq` is gone
+    /// nothing is written and a `KvsError::BackupGap` error is returned:
+    /// `backup_since(0, ..)` then streams what the log holds, which seeds an
+    /// empty KvStore. So does a `clear` since `seq`.
+    pub fn backup_since(&mut self, seq: u64, w: impl Write) -> Result<BackupStats> {
+        let last_seq = self.last_sequence();
+        let files = self.segment_files();
+        // the records to stream, found before anything is written
+        let mut records = Vec::new();
+        for (file_id, (segment, log, header, end)) in files.iter().enumerate() {
+            let format = record_format(*header);
+            let required = header.is_some_and(|header| header.checksums());
+            let mut offset = records_start(*header);
+            let mut log = log.try_clone()?;
+            log.seek(SeekFrom::Start(offset))?;
+            let mut reader = BufReader::new(log.take(end - offset));
+            let mut record = Vec::new();
+            loop {
+                let len = read_next(format, &mut reader, &mut record)?;
+                if len == 0 {
+                    break;
+                }
+                let record = open_record(
+                    self.segments.cipher.as_ref(),
+                    &record,
+                    (*header, *segment, offset),
+                )?;
+                match decode(format, &record, offset, required)? {
+                    OptData::SetData {
+                        seq: record_seq, ..
+                    }
+                    | OptData::RmData {
+                        seq: record_seq, ..
+                    } if record_seq > seq => {
+                        records.push((file_id, offset, len));
+                    }
+                    _ => {}
+                }
+                offset += len as u64;
+            }
+        }
+        if seq > 0 && records.len() as u64 != last_seq.saturating_sub(seq) {
+            return Err(KvsError::BackupGap { seq }.into());
+        }
+
+        let mut w = BufWriter::new(w);
+        w.write_all(BACKUP_MAGIC)?;
+        w.write_all(&[BACKUP_VERSION])?;
+        w.write_all(&seq.to_le_bytes())?;
+        let mut stats = BackupStats {
+            records: records.len(),
+            bytes: (BACKUP_MAGIC.len() + 1 + 8) as u64,
+            last_seq,
+        };
+        let mut buf = Vec::new();
+        for (file_id, offset, len) in records {
+            let (segment, log, header, _) = files[file_id];
+            buf.resize(len, 0);
+            log.read_exact_at(&mut buf, offset)?;
+            let cipher = self.segments.cipher.as_ref();
+            let record = open_record(cipher, &buf, (header, segment, offset))?;
+            let data = decode(record_format(header), &record, offset, false)?;
+            let record = encode(LogFormat::Binary, &data)?;
+            w.write_all(&record)?;
+            stats.bytes += record.len() as u64;
+        }
+        let record = encode(LogFormat::Binary, &OptData::SeqData { seq: last_seq })?;
+        w.write_all(&record)?;
+        w.flush()?;
+        stats.bytes += record.len() as u64;
+        Ok(stats)
+    }
+
+    /// Applies a stream written by `KvStore::backup_since` to the KvStore.
+    /// Returns the last sequence number of the KvStore, the one the stream
+    /// ends with.
+    ///
+    /// The records are logged with their own sequence numbers and metadata,
+    /// so the KvStore follows the one backed up and should not be written
+    /// to otherwise. Records with a sequence number the KvStore already has
+    /// are skipped, which makes applying a stream again a no-op. A stream
+    /// since a sequence number after `last_sequence` is refused with a
+    /// `KvsError::BackupGap` error, one which is not a backup or got cut
+    /// short with a `KvsError::InvalidBackup` error once the records before
+    /// the fault are applied.
+    pub fn apply_backup(&mut self, r: impl Read) -> Result<u64> {
+        self.check_writable()?;
+        let mut r = BufReader::new(r);
+        let mut header = [0; 8];
+        r.read_exact(&mut header).map_err(invalid_backup)?;
+        if &header[..7] != BACKUP_MAGIC {
+            return Err(KvsError::InvalidBackup {
+                reason: String::from("bad magic bytes"),
+            }
+            .into());
+        }
+        if header[7] != BACKUP_VERSION {
+            return Err(KvsError::InvalidBackup {
+                reason: format!("unsupported version {}", header[7]),
+            }
+            .into());
+        }
+        let mut since = [0; 8];
+        r.read_exact(&mut since).map_err(invalid_backup)?;
+        if u64::from_le_bytes(since) > self.last_sequence() {
+            return Err(KvsError::BackupGap {
+                seq: self.last_sequence(),
+            }
+            .into());
+        }
+
+        let mut offset = (header.len() + since.len()) as u64;
+        let mut record = Vec::new();
+        let mut batch = Vec::with_capacity(APPLY_BATCH);
+        // the last sequence number applied or batched, and whether the keys
+        // the batch writes exist after it
+        let mut applied = self.last_sequence();
+        let mut pending: HashMap<Key, bool> = HashMap::new();
+        loop {
+            let len = read_next(LogFormat::Binary, &mut r, &mut record)?;
+            if len == 0 {
+                self.apply_batch(&mut batch)?;
+                return Err(invalid_backup(io::ErrorKind::UnexpectedEof.into()).into());
+            }
+            let data = match decode(LogFormat::Binary, &record, offset, true) {
+                Ok(data) => data,
+                Err(err) => {
+                    self.apply_batch(&mut batch)?;
+                    return Err(KvsError::InvalidBackup {
+                        reason: err.to_string(),
+                    }
+                    .into());
+                }
+            };
+            offset += len as u64;
+            let seq = match &data {
+                OptData::SetData { seq, .. } | OptData::RmData { seq, .. } => *seq,
+                // the end of the stream
+                OptData::SeqData { seq } => {
+                    let seq = *seq;
+                    self.apply_batch(&mut batch)?;
+                    // logged for the removals skipped at the end
+                    if seq > self.last_sequence() {
+                        let record = self.encode_record(&data, 0)?;
+                        self.append_log(&record)?;
+                        self.next_seq = seq + 1;
+                    }
+                    self.maybe_compact()?;
+                    return Ok(self.last_sequence());
+                }
+                _ => continue,
+            };
+            if seq <= applied {
+                continue;
+            }
+            applied = seq;
+            let (key, set) = match &data {
+                OptData::SetData {
+                    key, key_base64, ..
+                } => (decode_key(key.clone(), *key_base64)?, true),
+                OptData::RmData {
+                    key, key_base64, ..
+                } => (decode_key(key.clone(), *key_base64)?, false),
+                _ => continue,
+            };
+            let exists = match pending.get(&key) {
+                Some(exists) => *exists,
+                None => self.kvs.get(key.as_bytes())?.is_some(),
+            };
+            // the removal of a key the KvStore does not have logs nothing
+            if !set && !exists {
+                continue;
+            }
+            pending.insert(key, set);
+            batch.push((seq, data));
+            if batch.len() == APPLY_BATCH {
+                self.apply_batch(&mut batch)?;
+                pending.clear();
+            }
+        }
+    }
+
+    // log the records of batch with a single write and apply them to the
+    // index like open would
+    fn apply_batch(&mut self, batch: &mut Vec<(u64, OptData)>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        let mut lens = Vec::with_capacity(batch.len());
+        for (_, data) in batch.iter() {
+            lens.push(self.push_record(&mut buf, data)?);
+        }
+        let mut offset = self.append_log(&buf)?;
+        let now = now_millis();
+        for ((seq, data), len) in batch.drain(..).zip(lens) {
+            let key = match &data {
+                OptData::SetData {
+                    key, key_base64, ..
+                }
+                | OptData::RmData {
+                    key, key_base64, ..
+                } => decode_key(key.clone(), *key_base64)?,
+                _ => continue,
+            };
+            let mut last_seq = 0;
+            // an expired value is logged but not indexed
+            replay_record(
+                &mut self.kvs,
+                data,
+                (self.segment, offset),
+                len,
+                u64::MAX,
+                now,
+                &mut last_seq,
+            )?;
+            self.next_seq = self.next_seq.max(seq + 1);
+            offset += len as u64;
+            if !self.subscribers.is_empty() {
+                let value = match self.kvs.get(key.as_bytes())? {
+                    Some(off2len) => read_record(&self.segments, &off2len)?,
+                    None => None,
+                };
+                let bytes = value.as_ref().map(|value| value.as_bytes());
+                self.subscribers.notify(seq, key.as_bytes(), bytes);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_backup(err: io::Error) -> KvsError {
+    KvsError::InvalidBackup {
+        reason: err.to_string(),
+    }
+}
@@ -0,0 +1,82 @@
+extern crate structopt;
+
+use kvs::{KvsClient, KvsError};
+use std::net::SocketAddr;
+use std::process;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "kvs-client", about = "a client of kvs-server")]
+struct Opt {
+    #[structopt(name = "version", long = "version", short = "V")]
+    version: bool,
+    /// the address of the server, IP:PORT
+    #[structopt(
+        name = "addr",
+        long = "addr",
+        default_value = "127.0.0.1:4000",
+        global = true
+    )]
+    addr: SocketAddr,
+    /// the token the server was started with, if any
+    #[structopt(
+        name = "auth-token",
+        long = "auth-token",
+        env = "KVS_AUTH_TOKEN",
+        hide_env_values = true,
+        global = true
+    )]
+    auth_token: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(name = "get")]
+    Get { key: String },
+    #[structopt(name = "rm")]
+    Rm { key: String },
+    #[structopt(name = "set")]
+    Set { key: String, value: String },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if opt.version {
+        println!(env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    let cmd = match opt.cmd {
+        Some(cmd) => cmd,
+        None => {
+            eprintln!("a subcommand is required: get, set or rm");
+            process::exit(1);
+        }
+    };
+    if let Err(err) = run(opt.addr, opt.auth_token, cmd) {
+        match err.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => eprintln!("Key not found"),
+            _ => eprintln!("{}", err),
+        }
+        process::exit(1);
+    }
+}
+
+fn run(addr: SocketAddr, auth_token: Option<String>, cmd: Command) -> kvs::Result<()> {
+    let mut client = match auth_token {
+        Some(token) => KvsClient::connect_with_token(addr, token)?,
+        None => KvsClient::connect(addr)?,
+    };
+    match cmd {
+        Command::Get { key } => match client.get(key)? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        Command::Rm { key } => client.remove(key)?,
+        Command::Set { key, value } => client.set(key, value)?,
+    }
+    Ok(())
+}
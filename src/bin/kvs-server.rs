@@ -0,0 +1,207 @@
+extern crate structopt;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{engine_marker, KvsError, KvsServer, ServerProtocol, SharedEngine, SharedKvStore};
+use std::env;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+// set by the handler of SIGINT and SIGTERM
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+// how often the signal flag is looked at
+const SIGNAL_POLL: Duration = Duration::from_millis(50);
+
+#[derive(StructOpt)]
+#[structopt(name = "kvs-server", about = "serves a key/value store over TCP")]
+struct Opt {
+    #[structopt(name = "version", long = "version", short = "V")]
+    version: bool,
+    /// the address to listen on, IP:PORT
+    #[structopt(name = "addr", long = "addr", default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    /// the data directory, the current directory by default
+    #[structopt(name = "dir", long = "dir", parse(from_os_str))]
+    dir: Option<PathBuf>,
+    /// the engine, kvs, the only one built in; a data directory which
+    /// belongs to another engine is refused
+    #[structopt(name = "engine", long = "engine")]
+    engine: Option<Engine>,
+    /// the number of threads serving connections, the number of CPUs by
+    /// default
+    #[structopt(name = "threads", long = "threads")]
+    threads: Option<usize>,
+    /// the protocol to speak, kvs or resp, the one of Redis
+    #[structopt(name = "protocol", long = "protocol", default_value = "kvs")]
+    protocol: Protocol,
+    /// the token every connection has to authenticate with
+    #[structopt(
+        name = "auth-token",
+        long = "auth-token",
+        env = "KVS_AUTH_TOKEN",
+        hide_env_values = true
+    )]
+    auth_token: Option<String>,
+    /// the most connections served at once, the others are turned away
+    #[structopt(name = "max-connections", long = "max-connections")]
+    max_connections: Option<usize>,
+    /// how long a client may take to send a request, or wait to read its
+    /// response, in seconds, before its connection is dropped
+    #[structopt(name = "request-timeout", long = "request-timeout")]
+    request_timeout: Option<u64>,
+    /// how long a shutdown on SIGINT or SIGTERM waits for the requests in
+    /// flight, in seconds
+    #[structopt(name = "drain-timeout", long = "drain-timeout", default_value = "10")]
+    drain_timeout: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Engine {
+    Kvs,
+}
+
+impl Engine {
+    // the name of the engine, the one its marker holds
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Engine, String> {
+        match name {
+            "kvs" => Ok(Engine::Kvs),
+            _ => Err(format!("unknown engine: {}", name)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Kvs,
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Protocol, String> {
+        match name {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(format!("unknown protocol: {}", name)),
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if opt.version {
+        println!(env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    if let Err(err) = run(opt) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> kvs::Result<()> {
+    let flag = opt.engine;
+    let dir = match opt.dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+    // the engine which owns the directory, checked before anything opens it
+    let found = match engine_marker(&dir)? {
+        Some(name) => Some(name.parse::<Engine>().map_err(|_| KvsError::WrongEngine {
+            expected: flag.unwrap_or(Engine::Kvs).name().to_owned(),
+            found: name,
+        })?),
+        None => None,
+    };
+    let engine = match (flag, found) {
+        (Some(engine), Some(found)) if engine != found => {
+            return Err(KvsError::WrongEngine {
+                expected: engine.name().to_owned(),
+                found: found.name().to_owned(),
+            }
+            .into());
+        }
+        (Some(engine), _) | (None, Some(engine)) => engine,
+        (None, None) => Engine::Kvs,
+    };
+    let threads = match opt.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism().map_or(1, |threads| threads.get()),
+    };
+    let pool = SharedQueueThreadPool::new(threads)?;
+    let drain_timeout = Duration::from_secs(opt.drain_timeout);
+    let protocol = match opt.protocol {
+        Protocol::Kvs => ServerProtocol::Kvs,
+        Protocol::Resp => ServerProtocol::Resp,
+    };
+    match engine {
+        Engine::Kvs => {
+            let mut server = KvsServer::new(SharedKvStore::open(dir)?, pool);
+            server
+                .protocol(protocol)
+                .auth_token(opt.auth_token)
+                .max_connections(opt.max_connections)
+                .request_timeout(opt.request_timeout.map(Duration::from_secs))
+                .drain_timeout(drain_timeout);
+            serve(server, opt.addr, engine)
+        }
+    }
+}
+
+// serve the connections to addr with server, after logging how, until a
+// SIGINT or a SIGTERM
+fn serve<E: SharedEngine, P: ThreadPool>(
+    server: KvsServer<E, P>,
+    addr: SocketAddr,
+    engine: Engine,
+) -> kvs::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let handle = server.shutdown_handle();
+    on_signal(&[libc::SIGINT, libc::SIGTERM]);
+    thread::spawn(move || {
+        while !SIGNALED.load(Ordering::SeqCst) {
+            thread::sleep(SIGNAL_POLL);
+        }
+        eprintln!("kvs-server shutting down");
+        handle.shutdown();
+    });
+    // the address bound, which names the port given as 0
+    eprintln!(
+        "kvs-server {}, engine {}, listening on {}",
+        env!("CARGO_PKG_VERSION"),
+        engine.name(),
+        listener.local_addr()?
+    );
+    server.run(&listener)
+}
+
+// set SIGNALED on the signals, instead of exiting
+fn on_signal(signals: &[libc::c_int]) {
+    extern "C" fn handler(_: libc::c_int) {
+        SIGNALED.store(true, Ordering::SeqCst);
+    }
+    for signal in signals {
+        // the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(*signal, handler as *const () as libc::sighandler_t);
+        }
+    }
+}
@@ -1,8 +1,12 @@
 extern crate structopt;
 
-use kvs::KvStore;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, SyncPolicy};
 use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -10,6 +14,10 @@ use structopt::StructOpt;
 struct Opt {
     #[structopt(name = "version", long = "version", short = "V")]
     version: bool,
+    /// when writes are synced to disk: always, never, every=N writes or
+    /// interval=MS milliseconds
+    #[structopt(name = "sync", long = "sync", parse(try_from_str = parse_sync))]
+    sync: Option<SyncPolicy>,
     #[structopt(subcommand)]
     cmd: Option<Command>,
 }
@@ -21,7 +29,26 @@ enum Command {
     #[structopt(name = "rm")]
     Rm { key: String },
     #[structopt(name = "set")]
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        #[structopt(required_unless = "from-file")]
+        value: Option<String>,
+        #[structopt(
+            name = "from-file",
+            long = "from-file",
+            parse(from_os_str),
+            conflicts_with = "value"
+        )]
+        from_file: Option<PathBuf>,
+    },
+    #[structopt(name = "incr", setting = AppSettings::AllowNegativeNumbers)]
+    Incr { key: String, delta: Option<i64> },
+    #[structopt(name = "persist")]
+    Persist { key: String },
+    #[structopt(name = "rename")]
+    Rename { from: String, to: String },
+    #[structopt(name = "migrate")]
+    Migrate,
 }
 
 fn main() {
@@ -33,28 +60,107 @@ fn main() {
     }
 
     let cwd = env::current_dir().unwrap();
-    let mut kv = KvStore::open(cwd).unwrap();
+    // the log is rewritten while no KvStore has it open
+    if let Some(Command::Migrate) = opt.cmd {
+        match KvStore::migrate(cwd) {
+            Ok(report) => println!(
+                "{} records, {} bytes -> {} bytes",
+                report.records_written, report.bytes_before, report.bytes_after
+            ),
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let mut options = KvStoreOptions::new();
+    if let Some(policy) = opt.sync {
+        options.sync_policy(policy);
+    }
+    let mut kv = match options.open(cwd) {
+        Ok(kv) => kv,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    };
     match opt.cmd {
-        Some(Command::Get { key }) => match kv.get(key) {
-            Ok(value) => match value {
-                Some(v) => println!("{}", v),
-                None => println!("Key not found"),
-            },
-            Err(_) => {}
-        },
-        Some(Command::Rm { key }) => match kv.remove(key) {
-            Ok(_) => {}
+        Some(Command::Get { key }) => get(&mut kv, key),
+        Some(Command::Rm { key }) => exit_on_error(KvsEngine::remove(&mut kv, key)),
+        Some(Command::Set {
+            key,
+            value,
+            from_file,
+        }) => exit_on_error(match (value, from_file) {
+            (Some(value), _) => KvsEngine::set(&mut kv, key, value),
+            (None, Some(path)) => set_from_file(&mut kv, key, &path),
+            (None, None) => unreachable!(),
+        }),
+        Some(Command::Incr { key, delta }) => match kv.increment(key, delta.unwrap_or(1)) {
+            Ok(value) => println!("{}", value),
             Err(err) => {
                 println!("{}", err);
                 process::exit(1);
             }
         },
-        Some(Command::Set { key, value }) => match kv.set(key, value) {
-            Err(err) => panic!("set fail for {}", err),
-            _ => (),
+        Some(Command::Persist { key }) => match kv.persist(&key) {
+            Ok(cleared) => println!("{}", cleared),
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
         },
+        Some(Command::Rename { from, to }) => {
+            if let Err(err) = kv.rename(from, to) {
+                println!("{}", err);
+                process::exit(1);
+            }
+        }
+        Some(Command::Migrate) => unreachable!(),
         None => {
             panic!("unimplemented");
         }
     }
 }
+
+// print the value of key, or that it is not found
+fn get<E: KvsEngine>(engine: &mut E, key: String) {
+    if let Ok(value) = engine.get(key) {
+        match value {
+            Some(v) => println!("{}", v),
+            None => println!("Key not found"),
+        }
+    }
+}
+
+// print the error of result, if any, and exit with 1
+fn exit_on_error(result: kvs::Result<()>) {
+    if let Err(err) = result {
+        println!("{}", err);
+        process::exit(1);
+    }
+}
+
+// stream the content of the file at path as the value of key
+fn set_from_file(kv: &mut KvStore, key: String, path: &Path) -> kvs::Result<()> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    kv.set_from_reader(key, len, file)
+}
+
+// the sync policy named by arg
+fn parse_sync(arg: &str) -> Result<SyncPolicy, String> {
+    let number = |value: &str| {
+        value
+            .parse::<u64>()
+            .map_err(|_| format!("invalid number in sync policy: {}", arg))
+    };
+    match arg.split_once('=') {
+        None if arg == "always" => Ok(SyncPolicy::Always),
+        None if arg == "never" => Ok(SyncPolicy::Never),
+        Some(("every", n)) => Ok(SyncPolicy::EveryN(number(n)? as usize)),
+        Some(("interval", ms)) => Ok(SyncPolicy::Interval(Duration::from_millis(number(ms)?))),
+        _ => Err(format!("unknown sync policy: {}", arg)),
+    }
+}
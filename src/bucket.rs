@@ -0,0 +1,125 @@
+use crate::index::Key;
+use crate::{now_millis, KvStore, KvsError, Result, Value};
+use std::collections::BTreeSet;
+
+// the byte starting and ending the bucket name in the keys of a bucket,
+// which never appears in UTF-8 so no bucket key is a String key and the
+// name ends at its first occurrence
+const BUCKET_MARK: u8 = 0xff;
+
+/// A namespace of keys inside a KvStore, see `KvStore::bucket`.
+pub struct Bucket<'a> {
+    store: &'a mut KvStore,
+    prefix: Vec<u8>,
+}
+
+impl KvStore {
+    /// Returns a handle on the bucket named `name`, whose keys are apart
+    /// from the keys of the KvStore and of the other buckets.
+    ///
+    /// The keys of a bucket are logged as raw keys made of the byte 0xff,
+    /// the name, 0xff again and the key, so any key is allowed, but the raw
+    /// keys starting with 0xff are reserved for buckets. A bucket exists as
+    /// long as it holds a key.
+    pub fn bucket(&mut self, name: &str) -> Bucket<'_> {
+        Bucket {
+            store: self,
+            prefix: bucket_prefix(name),
+        }
+    }
+
+    /// Returns the names of the buckets holding live keys, sorted.
+    pub fn list_buckets(&self) -> Vec<String> {
+        let names: BTreeSet<_> = self
+            .kvs
+            .lookup_live(now_millis())
+            .filter_map(|kv| bucket_name(kv.0.as_bytes()))
+            .collect();
+        names.into_iter().map(String::from).collect()
+    }
+
+    /// Removes every key of the bucket named `name` with a single log write.
+    /// Returns the number of removed keys.
+    pub fn drop_bucket(&mut self, name: &str) -> Result<usize> {
+        let prefix = bucket_prefix(name);
+        let doomed: Vec<Vec<u8>> = self
+            .kvs
+            .live(now_millis())?
+            .filter(|kv| kv.0.as_bytes().starts_with(&prefix))
+            .map(|kv| kv.0.as_bytes().to_vec())
+            .collect();
+        self.remove_keys(&doomed)?;
+        Ok(doomed.len())
+    }
+}
+
+impl Bucket<'_> {
+    /// Inserts a key-value pair into the bucket, like `KvStore::set`.
+    pub fn set(&mut self, key: String, value: String) -> Result<bool> {
+        let key = Key::from_bytes(self.full_key(&key));
+        self.store.set_value(key, Value::Text(value))
+    }
+
+    /// Returns a copy of the value of a key of the bucket.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let key = self.full_key(key);
+        self.store
+            .get_value(&key)?
+            .map(Value::into_string)
+            .transpose()
+    }
+
+    /// Removes a key from the bucket and returns its value.
+    ///
+    /// Returns a `KvsError::KeyNotFound` error if the key does not exist.
+    pub fn remove(&mut self, key: &str) -> Result<String> {
+        self.store.check_writable()?;
+        let value = match self.get(key)? {
+            Some(value) => value,
+            None => return Err(KvsError::KeyNotFound.into()),
+        };
+        let key = self.full_key(key);
+        self.store.remove_key(&key)?;
+        Ok(value)
+    }
+
+    /// Returns true if the bucket contains the key.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let now = now_millis();
+        self.store
+            .kvs
+            .lookup(&self.full_key(key))
+            .is_some_and(|v| !v.is_expired(now))
+    }
+
+    /// Returns the live keys of the bucket, in arbitrary order.
+    pub fn keys(&self) -> Vec<String> {
+        self.store
+            .kvs
+            .lookup_live(now_millis())
+            .filter_map(|kv| kv.0.as_bytes().strip_prefix(&self.prefix[..]))
+            .filter_map(|key| String::from_utf8(key.to_vec()).ok())
+            .collect()
+    }
+
+    fn full_key(&self, key: &str) -> Vec<u8> {
+        let mut full = self.prefix.clone();
+        full.extend_from_slice(key.as_bytes());
+        full
+    }
+}
+
+fn bucket_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(name.len() + 2);
+    prefix.push(BUCKET_MARK);
+    prefix.extend_from_slice(name.as_bytes());
+    prefix.push(BUCKET_MARK);
+    prefix
+}
+
+// the name of the bucket a raw key belongs to, if any
+fn bucket_name(key: &[u8]) -> Option<&str> {
+    let rest = key.strip_prefix(&[BUCKET_MARK])?;
+    let end = rest.iter().position(|b| *b == BUCKET_MARK)?;
+    std::str::from_utf8(&rest[..end]).ok()
+}
@@ -0,0 +1,186 @@
+use crate::index::{Index, Key, OffsetLen};
+use crate::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// the bytes an entry of the cache takes on top of its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+// the values read last, up to capacity bytes, see
+// `KvStoreOptions::cache_capacity`
+//
+// An entry holds the position of the record its value was read from and is
+// only served for the index entry at that position, so a value written
+// since is never served even if the entry was not dropped.
+pub(crate) struct ValueCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Key, Cached>,
+    order: BTreeMap<u64, Key>, // the keys by the tick of their last read
+    tick: u64,
+    bytes: usize,
+}
+
+struct Cached {
+    position: (u32, u64),
+    value: Value,
+    tick: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // the value read from the record of off2len, if it is cached; a value
+    // the index keeps inline is neither a hit nor a miss
+    pub(crate) fn get(&self, key: &[u8], off2len: &OffsetLen) -> Option<Value> {
+        if !self.is_enabled() || off2len.inline.is_some() {
+            return None;
+        }
+        let mut lru = self.lock();
+        let lru = &mut *lru;
+        let found = match lru.entries.get_mut(key) {
+            Some(cached) if cached.position == off2len.position() => {
+                lru.tick += 1;
+                if let Some(key) = lru.order.remove(&cached.tick) {
+                    lru.order.insert(lru.tick, key);
+                }
+                cached.tick = lru.tick;
+                Some(cached.value.clone())
+            }
+            _ => None,
+        };
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    // cache value, read from the record of off2len, then drop the values
+    // read least recently until the cache fits its capacity
+    pub(crate) fn insert(&self, key: &[u8], off2len: &OffsetLen, value: &Value) {
+        let size = entry_size(key, value);
+        if size > self.capacity || off2len.inline.is_some() {
+            return;
+        }
+        let mut lru = self.lock();
+        lru.remove(key);
+        lru.tick += 1;
+        let tick = lru.tick;
+        let key = Key::from_bytes(key.to_vec());
+        lru.order.insert(tick, key.clone());
+        let cached = Cached {
+            position: off2len.position(),
+            value: value.clone(),
+            tick,
+        };
+        lru.entries.insert(key, cached);
+        lru.bytes += size;
+        while lru.bytes > self.capacity {
+            let oldest = match lru.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            lru.remove(oldest.as_bytes());
+        }
+    }
+
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        if self.is_enabled() {
+            self.lock().remove(key);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        if self.is_enabled() {
+            *self.lock() = Lru::default();
+        }
+    }
+
+    // drop the values whose key the index no longer points at their record,
+    // before a compaction moves the records
+    pub(crate) fn retain_current(&self, kvs: &Index) {
+        self.retain(|key, cached| {
+            let current = kvs.lookup(key.as_bytes());
+            current.is_some_and(|off2len| off2len.position() == cached.position)
+        });
+    }
+
+    // follow the records a compaction moved, which hold the same values
+    pub(crate) fn follow(&self, kvs: &Index) {
+        self.retain(|key, cached| match kvs.lookup(key.as_bytes()) {
+            Some(off2len) => {
+                cached.position = off2len.position();
+                true
+            }
+            None => false,
+        });
+    }
+
+    fn retain<F: FnMut(&Key, &mut Cached) -> bool>(&self, mut f: F) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut lru = self.lock();
+        let doomed: Vec<Key> = lru
+            .entries
+            .iter_mut()
+            .filter_map(|(key, cached)| {
+                if f(key, cached) {
+                    None
+                } else {
+                    Some(key.clone())
+                }
+            })
+            .collect();
+        for key in doomed {
+            lru.remove(key.as_bytes());
+        }
+    }
+
+    // the number of reads it served and of those it did not
+    pub(crate) fn counters(&self) -> (u64, u64) {
+        let hits = self.hits.load(Ordering::Relaxed);
+        (hits, self.misses.load(Ordering::Relaxed))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        // the entries are whole whatever panicked while it was held
+        self.lru
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Lru {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(cached) = self.entries.remove(key) {
+            self.order.remove(&cached.tick);
+            self.bytes -= entry_size(key, &cached.value);
+        }
+    }
+}
+
+fn entry_size(key: &[u8], value: &Value) -> usize {
+    key.len() + value.as_bytes().len() + ENTRY_OVERHEAD
+}
@@ -0,0 +1,151 @@
+use crate::crypt::move_record;
+use crate::header::{records_start, Header};
+use crate::hint::hint_name;
+use crate::index::Index;
+use crate::lock::lock_dir;
+use crate::segment::{missing_segment, remove_swap_files, segment_ids, segment_name, Segments};
+use crate::writer::LogWriter;
+use crate::{now_millis, KvStore, KvStoreOptions, KvsError, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// the name of the log while restore_from writes it
+const RESTORE_LOG: &str = "kvs.log.restore";
+
+/// What `KvStore::checkpoint` wrote.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CheckpointInfo {
+    /// the number of live keys copied
+    pub entries: usize,
+    /// the length of the new log in bytes
+    pub bytes: u64,
+}
+
+impl KvStore {
+    /// Writes a compacted copy of the live key-value pairs to the directory
+    /// `dest_dir`, which `KvStore::open` can open.
+    ///
+    /// The directory is created if needed and must not hold a log yet. Only
+    /// the latest record of every live key is copied, as it is, so versions,
+    /// expirations and sequence numbers are kept. The new log and the
+    /// directory are synced to disk before returning. The KvStore is busy
+    /// for the whole copy, use `Snapshot::checkpoint` to copy while it keeps
+    /// serving writes.
+    pub fn checkpoint(&mut self, dest_dir: impl Into<PathBuf>) -> Result<CheckpointInfo> {
+        self.snapshot()?.checkpoint(dest_dir)
+    }
+
+    /// Creates a KvStore in `target_dir` from a compacted copy of the one in
+    /// `backup_dir`, such as a checkpoint. Return the KvStore.
+    ///
+    /// A target which already holds a log is refused with a
+    /// `KvsError::LogExists` error, unless `force` is set and the log is
+    /// replaced. A target a KvStore has open is refused with a
+    /// `KvsError::AlreadyLocked` error, forced or not. The copy is written
+    /// under a temporary name and renamed to `kvs.log` once synced, so an
+    /// interrupted restore leaves the target as it was; a forced one first
+    /// deletes the other segments, the hint file and the temporary files of
+    /// the old log, so it may leave only part of the old data, never a mix
+    /// of it and the restored one.
+    pub fn restore_from(
+        backup_dir: impl Into<PathBuf>,
+        target_dir: impl Into<PathBuf>,
+        force: bool,
+    ) -> Result<KvStore> {
+        let target_dir = target_dir.into();
+        let log_file_name = KvStoreOptions::default().log_file_name;
+        let log_name = target_dir.join(&log_file_name);
+        fs::create_dir_all(&target_dir)?;
+        // held until the restored log is in place
+        let lock = lock_dir(&target_dir, &log_file_name, false)?;
+        if !force && log_name.exists() {
+            return Err(KvsError::LogExists {
+                path: log_name.display().to_string(),
+            }
+            .into());
+        }
+        let backup = KvStore::open_read_only(backup_dir)?;
+        // the leftover of an interrupted restore
+        let temp_name = target_dir.join(RESTORE_LOG);
+        remove_file(&temp_name)?;
+        write_checkpoint(
+            &backup.kvs,
+            &backup.segments,
+            target_dir.clone(),
+            RESTORE_LOG,
+        )?;
+        if force {
+            remove_file(&target_dir.join(hint_name(&log_file_name)))?;
+            for id in segment_ids(&target_dir, &log_file_name)? {
+                if id > 0 {
+                    fs::remove_file(target_dir.join(segment_name(&log_file_name, id)))?;
+                }
+            }
+            remove_swap_files(&target_dir, &log_file_name)?;
+        }
+        fs::rename(&temp_name, &log_name)?;
+        File::open(&target_dir)?.sync_all()?;
+        // the KvStore takes the lock again, a writer taking it first gets the
+        // restored log all the same
+        drop(lock);
+        KvStore::open(target_dir)
+    }
+}
+
+// copy the records kvs points into the segments to a new log named
+// log_file_name in dest_dir
+pub(crate) fn write_checkpoint(
+    kvs: &Index,
+    segments: &Segments,
+    dest_dir: PathBuf,
+    log_file_name: &str,
+) -> Result<CheckpointInfo> {
+    fs::create_dir_all(&dest_dir)?;
+    let file = OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(dest_dir.join(log_file_name))?;
+    let mut sorted: Vec<_> = kvs.live(now_millis())?.collect();
+    sorted.sort_by_key(|kv| kv.1.position());
+
+    let header = Header::copied(segments.iter().map(|(_, segment)| segment.header));
+    header.write(&file)?;
+    let mut info = CheckpointInfo {
+        entries: 0,
+        bytes: records_start(Some(header)),
+    };
+    let mut writer = LogWriter::buffered(&file, info.bytes)?;
+    let mut buf = Vec::new();
+    for (_, off2len) in sorted {
+        let log = segments.file(off2len.segment).ok_or_else(missing_segment)?;
+        buf.resize(off2len.len, 0);
+        log.read_exact_at(&mut buf, off2len.offset)?;
+        // the new log is the segment 0 of its directory
+        let from = (
+            segments.header(off2len.segment),
+            off2len.segment,
+            off2len.offset,
+        );
+        let to = (Some(header), 0, info.bytes);
+        writer.write_all(&move_record(segments.cipher.as_ref(), &buf, from, to)?)?;
+        info.entries += 1;
+        info.bytes += buf.len() as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+    // the directory entry of the new log
+    File::open(&dest_dir)?.sync_all()?;
+    Ok(info)
+}
+
+// delete the file at path if there is one
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
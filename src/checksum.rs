@@ -0,0 +1,59 @@
+use crate::{KvsError, Result};
+use crc32fast::Hasher;
+use serde::Serialize;
+
+// the field holding the CRC32 of a record, added last to its inner object:
+// the checksum is the one of the record without it
+const CRC_FIELD: &[u8] = b",\"crc\":";
+
+// the line logged for a record: its JSON with the checksum field, then a
+// newline
+pub(crate) fn record_line(data: &impl Serialize) -> Result<String> {
+    let mut line = serde_json::to_string(data)?;
+    let crc = crc32fast::hash(line.as_bytes());
+    // every record is an object holding one object
+    line.truncate(line.len() - 2);
+    line.push_str(&crc_suffix(crc));
+    Ok(line)
+}
+
+// what replaces the closing "}}" of the JSON of a record whose checksum is
+// crc
+pub(crate) fn crc_suffix(crc: u32) -> String {
+    format!(",\"crc\":{}}}}}\n", crc)
+}
+
+// check the line of the record at offset in the log against its checksum,
+// records logged before checksums have none and pass unless required
+pub(crate) fn check_record(line: &[u8], offset: u64, required: bool) -> Result<()> {
+    let missing = if required {
+        Err(KvsError::Corruption { offset }.into())
+    } else {
+        Ok(())
+    };
+    let body = line.strip_suffix(b"\n").unwrap_or(line);
+    let inner = match body.strip_suffix(b"}}") {
+        Some(inner) => inner,
+        None => return missing,
+    };
+    let digits = inner.len()
+        - inner
+            .iter()
+            .rev()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+    let head = match inner[..digits].strip_suffix(CRC_FIELD) {
+        Some(head) => head,
+        None => return missing,
+    };
+    let logged = std::str::from_utf8(&inner[digits..])
+        .ok()
+        .and_then(|digits| digits.parse::<u32>().ok());
+    let mut hasher = Hasher::new();
+    hasher.update(head);
+    hasher.update(b"}}");
+    if logged != Some(hasher.finalize()) {
+        return Err(KvsError::Corruption { offset }.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,425 @@
+use crate::protocol::{push_frame, read_frame, write_frame, Request, Response, ServerStats};
+use crate::{KvsError, Result};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// A client of `kvs-server`, over one connection.
+///
+/// A client whose connection failed connects again for the next request.
+/// With the retries of `KvsClientBuilder::retries`, the requests which can
+/// be sent twice, the gets and the sets, are sent again on the new
+/// connection when the connection is reset, closed or times out.
+pub struct KvsClient {
+    conn: Option<Connection>,
+    options: ConnectOptions,
+}
+
+/// Builds a `KvsClient` which retries its gets and its sets.
+///
+/// ```no_run
+/// # use kvs::{KvsClientBuilder, Result};
+/// # use std::time::Duration;
+/// # fn main() -> Result<()> {
+/// let mut client = KvsClientBuilder::new("127.0.0.1:4000")
+///     .retries(3)
+///     .backoff(Duration::from_millis(50))
+///     .connect()?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClientBuilder<A> {
+    addr: A,
+    token: Option<String>,
+    retries: u32,
+    backoff: Duration,
+    timeout: Option<Duration>,
+}
+
+/// Requests queued to be sent together, see `KvsClient::pipeline`.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+// one connection to the server
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+// how a client connects, again after its connection failed
+struct ConnectOptions {
+    addrs: Vec<SocketAddr>,
+    token: Option<String>,
+    retries: u32,
+    backoff: Duration,
+    timeout: Option<Duration>,
+}
+
+impl<A: ToSocketAddrs> KvsClientBuilder<A> {
+    /// A builder of a client of the server at `addr`, which retries no
+    /// request and waits for the server as long as it takes.
+    pub fn new(addr: A) -> KvsClientBuilder<A> {
+        KvsClientBuilder {
+            addr,
+            token: None,
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            timeout: None,
+        }
+    }
+
+    /// Authenticates every connection with `token`, see
+    /// `KvsClient::connect_with_token`.
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Sends a get or a set up to `retries` more times when its connection
+    /// fails, connecting again before every one.
+    ///
+    /// The removals are never sent again: a removal the server did before
+    /// the connection failed would fail with `KvsError::KeyNotFound` the
+    /// second time. Nor are the removals of `KvsClient::remove_many` and the
+    /// requests of a pipeline. A set sent again can land after the set of
+    /// the same key by another client.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Waits `backoff` before the first retry of a request, twice as long
+    /// before every next one; 100 milliseconds by default.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Fails a connection, to be retried, on which the server has not
+    /// answered for `timeout`; `None`, the default, waits forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout.filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// Connects to the server, retrying like a request.
+    pub fn connect(self) -> Result<KvsClient> {
+        let mut client = KvsClient {
+            conn: None,
+            options: ConnectOptions {
+                addrs: self.addr.to_socket_addrs()?.collect(),
+                token: self.token,
+                retries: self.retries,
+                backoff: self.backoff,
+                timeout: self.timeout,
+            },
+        };
+        let retries = client.options.retries;
+        client.retrying(retries, |client| client.connection().map(drop))?;
+        Ok(client)
+    }
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClientBuilder::new(addr).connect()
+    }
+
+    /// Connects to the server at `addr` and authenticates with `token`, the
+    /// one the server was started with: a `KvsError::AuthFailed` error if it
+    /// is not. The connection is then used like the one of `connect`.
+    pub fn connect_with_token(addr: impl ToSocketAddrs, token: String) -> Result<KvsClient> {
+        KvsClientBuilder::new(addr).auth_token(token).connect()
+    }
+
+    /// Returns the value of a key, `None` if it does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(Request::Get { key })
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(Request::Set { key, value })?;
+        Ok(())
+    }
+
+    /// Removes a key, a `KvsError::KeyNotFound` error if it does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(Request::Remove { key })?;
+        Ok(())
+    }
+
+    /// Returns the values of all the keys, in the order of `keys`, with one
+    /// request.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let len = keys.len();
+        match self.exchange(&Request::MultiGet { keys })? {
+            Response::Values(values) if values.len() == len => Ok(values),
+            response => Err(refused(response)),
+        }
+    }
+
+    /// Sets the values of all the keys with one request, a key given twice
+    /// takes its last value.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.exchange(&Request::MultiSet { pairs })? {
+            Response::Done => Ok(()),
+            response => Err(refused(response)),
+        }
+    }
+
+    /// Removes all the keys with one request. Returns for every one of
+    /// `keys` whether it existed, a missing key is no error.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let len = keys.len();
+        match self.exchange(&Request::MultiRemove { keys })? {
+            Response::Removed(removed) if removed.len() == len => Ok(removed),
+            response => Err(refused(response)),
+        }
+    }
+
+    /// Returns the connections of the server and its limits. A server
+    /// which turns the connection away fails it with a `KvsError::Busy`
+    /// error, as it does any request.
+    pub fn server_stats(&mut self) -> Result<ServerStats> {
+        match self.exchange(&Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            response => Err(refused(response)),
+        }
+    }
+
+    /// Returns an empty pipeline: the requests queued in it are sent
+    /// back to back by `Pipeline::execute`, without waiting for the
+    /// response to one before sending the next.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    // what the response of the server to request tells
+    fn request(&mut self, request: Request) -> Result<Option<String>> {
+        let response = self.exchange(&request)?;
+        outcome(&request, response)
+    }
+
+    // the response of the server to request, sent again on a new connection
+    // if it can be
+    fn exchange(&mut self, request: &Request) -> Result<Response> {
+        let retries = match request {
+            Request::Get { .. }
+            | Request::Set { .. }
+            | Request::MultiGet { .. }
+            | Request::MultiSet { .. }
+            | Request::Stats => self.options.retries,
+            _ => 0,
+        };
+        self.retrying(retries, |client| {
+            let exchanged = client.connection()?.exchange(request);
+            if exchanged.is_err() {
+                // what the server read of the connection is unknown
+                client.conn = None;
+            }
+            exchanged
+        })
+    }
+
+    // the connection, a new one if the last one failed
+    fn connection(&mut self) -> Result<&mut Connection> {
+        match self.conn {
+            Some(ref mut conn) => Ok(conn),
+            None => Ok(self.conn.insert(self.options.connect()?)),
+        }
+    }
+
+    // the result of f, called again up to retries times while it fails with
+    // an error of the connection
+    fn retrying<T, F>(&mut self, retries: u32, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        let mut backoff = self.options.backoff;
+        for _ in 0..retries {
+            match f(self) {
+                Err(err) if is_transient(&err) => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+        f(self)
+    }
+}
+
+impl ConnectOptions {
+    // a new connection, authenticated by the token
+    fn connect(&self) -> Result<Connection> {
+        let stream = TcpStream::connect(&self.addrs[..])?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        };
+        if let Some(token) = &self.token {
+            let token = token.clone();
+            match conn.exchange(&Request::Auth { token })? {
+                Response::Done => {}
+                response => return Err(refused(response)),
+            }
+        }
+        Ok(conn)
+    }
+}
+
+impl Connection {
+    // the response of the server to request
+    fn exchange(&mut self, request: &Request) -> Result<Response> {
+        write_frame(&mut self.writer, request)?;
+        read_response(&mut self.reader)
+    }
+}
+
+impl Pipeline<'_> {
+    /// Queues the get of a key.
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Queues the set of a key.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    /// Queues the removal of a key.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Returns the number of requests queued.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns true if no request is queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends the requests queued and returns the result of every one, in
+    /// order: the value of a get, `None` for a set or a removal.
+    ///
+    /// A request which fails, like the removal of a missing key, only fails
+    /// its own result; the `Err` of the whole pipeline is the connection
+    /// failing, after which no request is sent again. The requests are
+    /// written while the responses are read, so a long pipeline does not
+    /// stall on a full socket buffer.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        // a request which cannot be sent fails before any is
+        let mut frames = Vec::new();
+        for request in &self.requests {
+            push_frame(&mut frames, request)?;
+        }
+        let requests = self.requests;
+        let client = self.client;
+        let executed = execute(client.connection()?, &requests, &frames);
+        if executed.is_err() {
+            client.conn = None;
+        }
+        executed
+    }
+}
+
+// send the frames of requests on conn and read the responses to them
+fn execute(
+    conn: &mut Connection,
+    requests: &[Request],
+    frames: &[u8],
+) -> Result<Vec<Result<Option<String>>>> {
+    let Connection { reader, writer } = conn;
+    thread::scope(|scope| {
+        let written = scope.spawn(move || -> Result<()> {
+            writer.write_all(frames)?;
+            writer.flush()?;
+            Ok(())
+        });
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            results.push(outcome(request, read_response(&mut *reader)?));
+        }
+        written.join().expect("pipeline writer panicked")?;
+        Ok(results)
+    })
+}
+
+// the next response of the server, an error once it closed the connection
+fn read_response(reader: &mut BufReader<TcpStream>) -> Result<Response> {
+    read_frame(reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed by the server",
+        )
+        .into()
+    })
+}
+
+// whether err is one of the connection, which a new one can get past
+fn is_transient(err: &failure::Error) -> bool {
+    match err.downcast_ref::<io::Error>() {
+        Some(err) => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+        ),
+        None => false,
+    }
+}
+
+// the result of request told by its response
+fn outcome(request: &Request, response: Response) -> Result<Option<String>> {
+    match (request, response) {
+        (Request::Get { .. }, Response::Value(value)) => Ok(value),
+        (Request::Set { .. }, Response::Done) | (Request::Remove { .. }, Response::Done) => {
+            Ok(None)
+        }
+        (Request::Remove { .. }, Response::KeyNotFound) => Err(KvsError::KeyNotFound.into()),
+        (_, response) => Err(refused(response)),
+    }
+}
+
+// the error of a response which does not answer its request: the error the
+// server answered with becomes a `KvsError::Server` error
+fn refused(response: Response) -> failure::Error {
+    match response {
+        Response::Error(message) => KvsError::Server { message }.into(),
+        Response::AuthRequired => KvsError::AuthRequired.into(),
+        Response::AuthFailed => KvsError::AuthFailed.into(),
+        Response::Busy => KvsError::Busy.into(),
+        response => unexpected(response),
+    }
+}
+
+// the error of a response which does not answer the request
+fn unexpected(response: Response) -> failure::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response: {:?}", response),
+    )
+    .into()
+}
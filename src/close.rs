@@ -0,0 +1,72 @@
+use crate::header::{Header, FLAG_CLEAN};
+use crate::{KvStore, Result};
+use std::io::Write;
+
+impl KvStore {
+    /// Closes the KvStore cleanly: flushes and syncs the log, writes a hint
+    /// file of the index, see `KvStore::write_hint`, and marks the log as
+    /// closed cleanly in its header.
+    ///
+    /// The next `open` of a log closed cleanly loads the hint and replays
+    /// nothing it covers, a log without the mark is replayed whole and every
+    /// record checked. Dropping the KvStore does the same but ignores
+    /// errors, `close` returns them. A read-only KvStore only drops its log.
+    pub fn close(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    // what close does, which leaves the KvStore without a log so it is done
+    // once
+    fn shutdown(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        self.options.file_sync.sync_all(log)?;
+        self.write_hint()?;
+        // the hint is in place before the mark which makes open load it
+        if let Some(mut header) = self.header {
+            header.flags |= FLAG_CLEAN;
+            self.set_header(header)?;
+        }
+        self.writer = None;
+        self.log = None;
+        Ok(())
+    }
+
+    // clear the mark of a log closed cleanly, which holds no more once it is
+    // written to
+    pub(crate) fn mark_open(&mut self) -> Result<()> {
+        match self.header {
+            Some(mut header) if header.clean() => {
+                header.flags &= !FLAG_CLEAN;
+                self.set_header(header)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // write header over the one of the active segment and sync it
+    fn set_header(&mut self, header: Header) -> Result<()> {
+        if let Some(log) = &self.log {
+            header.write(log)?;
+            self.options.file_sync.sync_data(log)?;
+            self.segments.insert(self.segment, log, Some(header))?;
+        }
+        self.header = Some(header);
+        Ok(())
+    }
+}
+
+impl Drop for KvStore {
+    // a best effort at close
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
@@ -0,0 +1,263 @@
+use crate::crypt::move_record;
+use crate::fsync::{replace_file, FileSync};
+use crate::header::{records_start, Header, FLAG_MERGED, FLAG_SORTED};
+use crate::index::OffsetLen;
+use crate::run::SortedWriter;
+use crate::segment::{missing_segment, segment_name, Segments};
+use crate::writer::LogWriter;
+use crate::{now_millis, KvStore, OptData, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What `KvStore::compact` did to the log.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// the length of the log before the compaction
+    pub bytes_before: u64,
+    /// the length of the log after the compaction
+    pub bytes_after: u64,
+    /// the bytes dropped by the compaction, 0 for a log written before
+    /// headers which grew by getting one
+    pub bytes_reclaimed: u64,
+}
+
+impl KvStore {
+    /// Rewrites the log with only the latest record of every live key, then
+    /// syncs it to disk.
+    ///
+    /// The new log is written under a temporary name and renamed over the
+    /// old one, so the KvStore can be compacted at any time. Snapshots keep
+    /// reading the old log.
+    ///
+    /// A log split in segments, see `KvStoreOptions::max_segment_size`, has
+    /// its sealed segments merged instead and the active one left alone:
+    /// their live records are copied into one segment which replaces the
+    /// last of them, the others are deleted. A hint file is written for the
+    /// compacted log, see `KvStore::write_hint`.
+    ///
+    /// With a sampled index, see `KvStoreOptions::index_sampling`, the active
+    /// segment is sealed first and every live record is merged, in key
+    /// order.
+    pub fn compact(&mut self) -> Result<CompactionStats> {
+        self.check_writable()?;
+        let bytes_before = self.log_bytes();
+        self.cache.retain_current(&self.kvs);
+        if self.options.index_sampling > 1 {
+            self.seal_active()?;
+            self.merge_sealed()?;
+        } else if self.segments.sealed(self.segment).next().is_some() {
+            self.merge_sealed()?;
+        } else {
+            self.rebuild_log()?;
+        }
+        self.cache.follow(&self.kvs);
+        self.write_hint()?;
+        let bytes_after = self.log_bytes();
+        Ok(CompactionStats {
+            bytes_before,
+            bytes_after,
+            bytes_reclaimed: bytes_before.saturating_sub(bytes_after),
+        })
+    }
+
+    // compact the log once its garbage passes the threshold of the options
+    pub(crate) fn maybe_compact(&mut self) -> Result<()> {
+        if self.compaction_due(self.options.compaction_threshold) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // whether the garbage of the log passes threshold, only the garbage of
+    // the sealed segments counts once there are some
+    pub(crate) fn compaction_due(&self, threshold: Option<u64>) -> bool {
+        let garbage = if self.segments.sealed(self.segment).next().is_some() {
+            let live = self.kvs.bytes() - self.kvs.segment_bytes(self.segment);
+            self.segments
+                .sealed_records(self.segment)
+                .saturating_sub(live)
+        } else {
+            let records = self.log_off - records_start(self.header);
+            records.saturating_sub(self.kvs.bytes())
+        };
+        threshold.is_some_and(|threshold| garbage > threshold)
+    }
+
+    // start a new active segment unless the active one is empty, then log
+    // the last sequence number in it, which the merged segment may not hold
+    fn seal_active(&mut self) -> Result<()> {
+        if self.log.is_none() || self.log_off == records_start(self.header) {
+            return Ok(());
+        }
+        self.rotate()?;
+        if self.next_seq > 1 {
+            let seq = self.next_seq - 1;
+            let record = self.encode_record(&OptData::SeqData { seq }, 0)?;
+            self.append_log(&record)?;
+        }
+        Ok(())
+    }
+
+    // copy the live records of the sealed segments into a merged segment
+    // which replaces the last of them, then delete the others, or all of them
+    // if they hold no live record
+    //
+    // The index only points into the merged segment once it was synced and
+    // renamed into place. A crash before the others are deleted leaves them behind
+    // the merged segment, `open` deletes them.
+    fn merge_sealed(&mut self) -> Result<()> {
+        let active = self.segment;
+        let ids: Vec<u32> = self.segments.sealed(active).map(|(id, _)| id).collect();
+        let last = match ids.last() {
+            Some(last) => *last,
+            None => return Ok(()),
+        };
+        // expired keys are not copied
+        let now = now_millis();
+        self.kvs.retain(|_, v| !v.is_expired(now));
+        if self.kvs.segment_bytes(active) == self.kvs.bytes() {
+            // nothing live is left in them, the oldest go first so a crash
+            // leaves no record whose removal is gone
+            for id in ids {
+                let name = segment_name(&self.options.log_file_name, id);
+                fs::remove_file(self.log_name.with_file_name(name))?;
+                self.segments.remove(id);
+            }
+            self.kvs.drop_run();
+            self.sync_dir()?;
+            self.compactions += 1;
+            return Ok(());
+        }
+        let headers = self.segments.sealed(active).map(|(_, s)| s.header);
+        let copied = Header::copied(headers);
+        let header = Header {
+            flags: copied.flags | FLAG_MERGED,
+            ..copied
+        };
+        let (temp_path, temp) = self.create_swap_file()?;
+        let file_sync = Arc::clone(&self.options.file_sync);
+        let log_file_name = &self.options.log_file_name;
+        let target = self
+            .log_name
+            .with_file_name(segment_name(log_file_name, last));
+        let (file, header, end) = if self.options.index_sampling > 1 {
+            // the run of the index and the resident entries it does not
+            // hold, merged by key
+            let header = Header {
+                flags: header.flags | FLAG_SORTED,
+                ..header
+            };
+            let mut entries: Vec<_> = self
+                .kvs
+                .resident_live(now)
+                .filter(|kv| kv.1.segment != active)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            entries.sort_by(|l, r| l.0.cmp(&r.0));
+            let (segments, run) = (&self.segments, self.kvs.run());
+            let sampling = self.options.index_sampling;
+            let (file, (builder, end)) =
+                install_copy(&*file_sync, (&temp_path, temp), &target, |temp| {
+                    SortedWriter::new(segments, (last, header), temp, sampling)?.write(run, entries)
+                })?;
+            let cipher = self.segments.cipher.as_ref();
+            let (run, kept) = builder.finish((last, &file, header), cipher, end)?;
+            self.kvs.retain(|_, v| v.segment == active);
+            self.kvs.set_run(run, kept)?;
+            (file, header, end)
+        } else {
+            let mut sorted: Vec<_> = self
+                .kvs
+                .iter_mut()
+                .filter(|kv| kv.1.segment != active)
+                .collect();
+            sorted.sort_by_key(|kv| kv.1.position());
+            let records = sorted.iter().map(|kv| &*kv.1);
+            let segments = &self.segments;
+            let (file, (offsets, end)) =
+                install_copy(&*file_sync, (&temp_path, temp), &target, |temp| {
+                    copy_records(segments, (records, &[]), (last, header), temp)
+                })?;
+            for ((_, value), offset) in sorted.iter_mut().zip(offsets) {
+                value.segment = last;
+                value.offset = offset;
+            }
+            self.kvs.recount_bytes();
+            (file, header, end)
+        };
+        self.segments.insert(last, &file, Some(header))?;
+        self.segments.seal(last, end);
+        self.sync_dir()?;
+        for id in ids.iter().filter(|id| **id != last) {
+            let name = segment_name(&self.options.log_file_name, *id);
+            fs::remove_file(self.log_name.with_file_name(name))?;
+            self.segments.remove(*id);
+        }
+        self.compactions += 1;
+        Ok(())
+    }
+
+    // sync the directory of the log, which makes renames and new files in it
+    // durable
+    pub(crate) fn sync_dir(&self) -> Result<()> {
+        if let Some(dir) = self.log_name.parent() {
+            self.options.file_sync.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+// write temp with write, sync it and move it over target; return the handle
+// of target and what write returned
+//
+// On error temp is deleted and target is left as it was, the index must
+// only be pointed at the copies once this returned.
+pub(crate) fn install_copy<T>(
+    file_sync: &dyn FileSync,
+    (temp_path, temp): (&Path, File),
+    target: &Path,
+    write: impl FnOnce(&File) -> Result<T>,
+) -> Result<(File, T)> {
+    let copied = write(&temp).and_then(|copied| Ok(file_sync.sync_all(&temp).map(|_| copied)?));
+    let installed = copied.and_then(|copied| {
+        let file = replace_file(file_sync, temp_path, temp, target)?;
+        Ok((file, copied))
+    });
+    if installed.is_err() {
+        // what is left of the copy, if anything
+        let _ = fs::remove_file(temp_path);
+    }
+    installed
+}
+
+// write header, the records from the segments back to back and trailer to
+// temp, as the segment with the id; return the new offsets of the records
+// and where the trailer ends
+pub(crate) fn copy_records<'a>(
+    segments: &Segments,
+    (records, trailer): (impl Iterator<Item = &'a OffsetLen>, &[u8]),
+    (segment, header): (u32, Header),
+    temp: &File,
+) -> Result<(Vec<u64>, u64)> {
+    header.write(temp)?;
+    let mut offsets = Vec::new();
+    let mut offset = records_start(Some(header));
+    let mut writer = LogWriter::buffered(temp, offset)?;
+    let mut buf = Vec::new();
+    for value in records {
+        let log = segments.file(value.segment).ok_or_else(missing_segment)?;
+        buf.resize(value.len, 0);
+        log.read_exact_at(&mut buf, value.offset)?;
+        let from = (segments.header(value.segment), value.segment, value.offset);
+        let to = (Some(header), segment, offset);
+        writer.write_all(&move_record(segments.cipher.as_ref(), &buf, from, to)?)?;
+        offsets.push(offset);
+        offset += value.len as u64;
+    }
+    writer.write_all(trailer)?;
+    writer.flush()?;
+    Ok((offsets, offset + trailer.len() as u64))
+}
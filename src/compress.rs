@@ -0,0 +1,105 @@
+use crate::{KvStore, KvsError, OptData, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::convert::TryFrom;
+
+/// How the values of large records are compressed, see
+/// `KvStoreOptions::compression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// DEFLATE, the compression of gzip, at its default level; needs the
+    /// `compression` feature
+    #[cfg(feature = "compression")]
+    Deflate,
+}
+
+impl KvStore {
+    // data with its value compressed, if the options ask for it and the
+    // value is large enough and shrinks
+    pub(crate) fn compress(&self, mut data: OptData) -> Result<OptData> {
+        if self.options.compression.is_none() {
+            return Ok(data);
+        }
+        if let OptData::SetData {
+            value,
+            base64,
+            compressed: compressed @ None,
+            ..
+        } = &mut data
+        {
+            let deflated = if *base64 {
+                self.deflate_value(&BASE64.decode(&*value)?)
+            } else {
+                self.deflate_value(value.as_bytes())
+            };
+            if let Some((len, deflated)) = deflated {
+                *compressed = Some(len);
+                *value = BASE64.encode(deflated);
+                *base64 = true;
+            }
+        }
+        Ok(data)
+    }
+
+    // the length of raw, a value, and its compression, if the options ask
+    // for it and the value is large enough and shrinks
+    pub(crate) fn deflate_value(&self, raw: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let compression = self.options.compression?;
+        if raw.len() < self.options.compress_min_size {
+            return None;
+        }
+        let deflated = deflate(compression, raw);
+        // a value which does not shrink is logged as it is
+        if deflated.len() < raw.len() {
+            Some((raw.len() as u64, deflated))
+        } else {
+            None
+        }
+    }
+}
+
+// the bytes compression saved on the value of data, 0 unless it is a
+// SetData whose value is compressed
+pub(crate) fn saved_bytes(data: &OptData) -> u32 {
+    match data {
+        OptData::SetData {
+            value,
+            compressed: Some(len),
+            ..
+        } => {
+            // the length of the compressed value, which value is the base64 of
+            let padding = value.bytes().rev().take_while(|b| *b == b'=').count();
+            let stored = (value.len() / 4 * 3).saturating_sub(padding) as u64;
+            u32::try_from(len.saturating_sub(stored)).unwrap_or(u32::MAX)
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(feature = "compression")]
+fn deflate(compression: Compression, bytes: &[u8]) -> Vec<u8> {
+    match compression {
+        Compression::Deflate => miniz_oxide::deflate::compress_to_vec(bytes, 6),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn deflate(compression: Compression, _: &[u8]) -> Vec<u8> {
+    match compression {}
+}
+
+// the len bytes of the value of the record at offset, which bytes are the
+// compression of
+#[cfg(feature = "compression")]
+pub(crate) fn inflate(bytes: &[u8], len: u64, offset: u64) -> Result<Vec<u8>> {
+    let limit = usize::try_from(len).map_err(|_| KvsError::Corruption { offset })?;
+    match miniz_oxide::inflate::decompress_to_vec_with_limit(bytes, limit) {
+        Ok(value) if value.len() == limit => Ok(value),
+        _ => Err(KvsError::Corruption { offset }.into()),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn inflate(_: &[u8], _: u64, offset: u64) -> Result<Vec<u8>> {
+    Err(KvsError::CompressionUnsupported { offset }.into())
+}
@@ -0,0 +1,492 @@
+use crate::header::Header;
+#[cfg(feature = "encryption")]
+use crate::record::frame;
+use crate::record::FRAME_LEN;
+use crate::{KvsError, Result};
+use std::borrow::Cow;
+use std::convert::TryInto;
+#[cfg(feature = "encryption")]
+use std::fmt;
+
+// an encrypted binary record keeps the type of its frame, its payload is
+// the nonce, the ChaCha20 encryption of the payload of the record and the
+// Poly1305 tag of the type, the place of the record and the encrypted
+// payload
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// the header of an encrypted log ends with a key check: a nonce and the tag
+// of KEY_CHECK_DATA, which only the key of the log gives back
+pub(crate) const KEY_CHECK_LEN: usize = NONCE_LEN + TAG_LEN;
+#[cfg(feature = "encryption")]
+const KEY_CHECK_DATA: &[u8] = b"KVS-KEY";
+
+// where a record is: the header of its segment, the id of the segment and
+// its offset
+pub(crate) type Place = (Option<Header>, u32, u64);
+
+// the cipher records are encrypted with, see
+// `KvStoreOptions::encryption_key`
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    key: [u32; 8],
+}
+
+// no cipher can be made without the encryption feature
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone, Debug)]
+pub(crate) enum Cipher {}
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for Cipher {
+    // the key is never printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher")
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    pub(crate) fn new(key: [u8; 32]) -> Cipher {
+        Cipher {
+            key: key_words(key),
+        }
+    }
+
+    // a new key check for the header of an encrypted log
+    pub(crate) fn key_check(&self) -> [u8; KEY_CHECK_LEN] {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut check = [0; KEY_CHECK_LEN];
+        check[..NONCE_LEN].copy_from_slice(&nonce);
+        check[NONCE_LEN..].copy_from_slice(&self.tag(&nonce, KEY_CHECK_DATA, &[]));
+        check
+    }
+
+    // true if check was made with the key of the cipher
+    fn checks(&self, check: &[u8; KEY_CHECK_LEN]) -> bool {
+        let (nonce, tag) = check.split_at(NONCE_LEN);
+        let nonce = nonce.try_into().unwrap_or_default();
+        same(&self.tag(&nonce, KEY_CHECK_DATA, &[]), tag)
+    }
+
+    // the encryption of a binary record with a new nonce, sealed at place
+    pub(crate) fn seal(&self, record: &[u8], place: Place) -> Result<Vec<u8>> {
+        let (kind, payload) = (record[0], &record[FRAME_LEN..]);
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut sealed = Vec::with_capacity(record.len() + KEY_CHECK_LEN);
+        // the frame is written once the record is placed
+        sealed.extend_from_slice(&[kind; FRAME_LEN]);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(payload);
+        self.apply_keystream(&nonce, &mut sealed[FRAME_LEN + NONCE_LEN..]);
+        sealed.extend_from_slice(&[0; TAG_LEN]);
+        self.place(&mut sealed, place)?;
+        Ok(sealed)
+    }
+
+    // the binary record an encrypted record sealed at place decrypts to,
+    // which fails unless its tag matches
+    pub(crate) fn open(&self, record: &[u8], place: Place) -> Result<Vec<u8>> {
+        self.check(record, place)?;
+        let mut payload = record[FRAME_LEN + NONCE_LEN..record.len() - TAG_LEN].to_vec();
+        self.apply_keystream(&nonce_of(record), &mut payload);
+        let mut opened =
+            frame(record[0], payload.len() as u64, crc32fast::hash(&payload))?.to_vec();
+        opened.extend_from_slice(&payload);
+        Ok(opened)
+    }
+
+    // the encrypted record sealed at from, sealed at to instead, which
+    // fails unless its tag matches
+    pub(crate) fn moved(&self, record: &[u8], from: Place, to: Place) -> Result<Vec<u8>> {
+        self.check(record, from)?;
+        let mut moved = record.to_vec();
+        self.place(&mut moved, to)?;
+        Ok(moved)
+    }
+
+    // fail unless the encrypted record was sealed at place
+    fn check(&self, record: &[u8], place: Place) -> Result<()> {
+        let offset = place.2;
+        if record.len() < FRAME_LEN + KEY_CHECK_LEN {
+            return Err(KvsError::Corruption { offset }.into());
+        }
+        let (encrypted, tag) =
+            record[FRAME_LEN + NONCE_LEN..].split_at(record.len() - FRAME_LEN - KEY_CHECK_LEN);
+        if !same(
+            &self.tag(&nonce_of(record), &aad(record[0], place), encrypted),
+            tag,
+        ) {
+            return Err(KvsError::Corruption { offset }.into());
+        }
+        Ok(())
+    }
+
+    // write the tag of the encrypted record for place and its frame
+    fn place(&self, record: &mut [u8], place: Place) -> Result<()> {
+        let tag_at = record.len() - TAG_LEN;
+        let tag = self.tag(
+            &nonce_of(record),
+            &aad(record[0], place),
+            &record[FRAME_LEN + NONCE_LEN..tag_at],
+        );
+        record[tag_at..].copy_from_slice(&tag);
+        let stored = &record[FRAME_LEN..];
+        let frame = frame(record[0], stored.len() as u64, crc32fast::hash(stored))?;
+        record[..FRAME_LEN].copy_from_slice(&frame);
+        Ok(())
+    }
+
+    // xor data with the ChaCha20 keystream of the nonce from block 1, as
+    // RFC 8439 encrypts
+    pub(crate) fn apply_keystream(&self, nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+        for (counter, chunk) in data.chunks_mut(64).enumerate() {
+            let block = chacha20_block(&self.key, counter as u32 + 1, nonce);
+            for (byte, key) in chunk.iter_mut().zip(block.iter()) {
+                *byte ^= key;
+            }
+        }
+    }
+
+    // the Poly1305 tag of aad and encrypted, keyed by block 0 of the nonce
+    pub(crate) fn tag(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        encrypted: &[u8],
+    ) -> [u8; TAG_LEN] {
+        let block = chacha20_block(&self.key, 0, nonce);
+        let mut poly = Poly1305::new(&block[..32]);
+        poly.padded(aad);
+        poly.padded(encrypted);
+        let mut lens = [0; 16];
+        lens[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+        lens[8..].copy_from_slice(&(encrypted.len() as u64).to_le_bytes());
+        poly.padded(&lens);
+        poly.finish()
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub(crate) fn key_check(&self) -> [u8; KEY_CHECK_LEN] {
+        match *self {}
+    }
+
+    fn checks(&self, _: &[u8; KEY_CHECK_LEN]) -> bool {
+        match *self {}
+    }
+
+    pub(crate) fn seal(&self, _: &[u8], _: Place) -> Result<Vec<u8>> {
+        match *self {}
+    }
+
+    pub(crate) fn open(&self, _: &[u8], _: Place) -> Result<Vec<u8>> {
+        match *self {}
+    }
+
+    pub(crate) fn moved(&self, _: &[u8], _: Place, _: Place) -> Result<Vec<u8>> {
+        match *self {}
+    }
+}
+
+// record, a binary one, encrypted if there is a cipher and sealed at
+// place
+pub(crate) fn seal_record(
+    cipher: Option<&Cipher>,
+    record: Vec<u8>,
+    place: Place,
+) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.seal(&record, place),
+        None => Ok(record),
+    }
+}
+
+// fail unless the segment with header can be read with cipher: it is
+// encrypted with its key, or neither
+pub(crate) fn check_key(cipher: Option<&Cipher>, header: Option<Header>) -> Result<()> {
+    match (cipher, header.and_then(|header| header.key_check)) {
+        (None, None) => Ok(()),
+        (Some(cipher), Some(check)) if cipher.checks(&check) => Ok(()),
+        _ => Err(KvsError::BadEncryptionKey.into()),
+    }
+}
+
+// the record at place, decrypted if its segment is encrypted
+pub(crate) fn open_record<'a>(
+    cipher: Option<&Cipher>,
+    record: &'a [u8],
+    place: Place,
+) -> Result<Cow<'a, [u8]>> {
+    match (cipher, encrypted(place)) {
+        (_, false) => Ok(Cow::Borrowed(record)),
+        (Some(cipher), true) => Ok(Cow::Owned(cipher.open(record, place)?)),
+        (None, true) => Err(KvsError::BadEncryptionKey.into()),
+    }
+}
+
+// record, the one at from, for the place to: sealed there if its segment
+// is encrypted, which fails unless it was sealed at from
+pub(crate) fn move_record<'a>(
+    cipher: Option<&Cipher>,
+    record: &'a [u8],
+    from: Place,
+    to: Place,
+) -> Result<Cow<'a, [u8]>> {
+    match (cipher, encrypted(from)) {
+        (_, false) => Ok(Cow::Borrowed(record)),
+        (Some(_), true) if from == to => Ok(Cow::Borrowed(record)),
+        (Some(cipher), true) => Ok(Cow::Owned(cipher.moved(record, from, to)?)),
+        (None, true) => Err(KvsError::BadEncryptionKey.into()),
+    }
+}
+
+// move_record for every record of records, binary ones back to back from
+// the place from on, to the place to on
+pub(crate) fn move_records<'a>(
+    cipher: Option<&Cipher>,
+    records: &'a [u8],
+    (from_header, from_segment, from): Place,
+    (to_header, to_segment, to): Place,
+) -> Result<Cow<'a, [u8]>> {
+    if !encrypted((from_header, from_segment, from)) || (from_segment, from) == (to_segment, to) {
+        return Ok(Cow::Borrowed(records));
+    }
+    let mut moved = Vec::with_capacity(records.len());
+    while moved.len() < records.len() {
+        let at = moved.len();
+        let offset = from + at as u64;
+        let len = match records.get(at + 1..at + FRAME_LEN) {
+            Some(frame) => {
+                FRAME_LEN + u32::from_le_bytes(frame[..4].try_into().unwrap_or_default()) as usize
+            }
+            None => return Err(KvsError::Corruption { offset }.into()),
+        };
+        let record = records
+            .get(at..at + len)
+            .ok_or(KvsError::Corruption { offset })?;
+        let record = move_record(
+            cipher,
+            record,
+            (from_header, from_segment, offset),
+            (to_header, to_segment, to + at as u64),
+        )?;
+        moved.extend_from_slice(&record);
+    }
+    Ok(Cow::Owned(moved))
+}
+
+fn encrypted((header, _, _): Place) -> bool {
+    header.is_some_and(|header| header.encrypted())
+}
+
+// the additional data of an encrypted record of type kind at place: the
+// type, then the id of the segment and the offset as u32 and u64 LE unless
+// the segment was written before records were sealed with their place
+#[cfg(feature = "encryption")]
+fn aad(kind: u8, (header, segment, offset): Place) -> Vec<u8> {
+    let mut aad = vec![kind];
+    if header.is_some_and(|header| header.placed()) {
+        aad.extend_from_slice(&segment.to_le_bytes());
+        aad.extend_from_slice(&offset.to_le_bytes());
+    }
+    aad
+}
+
+// the nonce of an encrypted record
+#[cfg(feature = "encryption")]
+fn nonce_of(record: &[u8]) -> [u8; NONCE_LEN] {
+    record[FRAME_LEN..FRAME_LEN + NONCE_LEN]
+        .try_into()
+        .unwrap_or_default()
+}
+
+// true if a and b are the same bytes, compared in a time which does not
+// depend on where they differ
+#[cfg(feature = "encryption")]
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// the key of ChaCha20 as the words of its state
+#[cfg(feature = "encryption")]
+pub(crate) fn key_words(key: [u8; 32]) -> [u32; 8] {
+    let mut words = [0; 8];
+    for (word, bytes) in words.iter_mut().zip(key.chunks(4)) {
+        *word = le32(bytes);
+    }
+    words
+}
+
+#[cfg(feature = "encryption")]
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap_or_default())
+}
+
+// the ChaCha20 block of RFC 8439 for key, counter and nonce
+#[cfg(feature = "encryption")]
+pub(crate) fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u8; NONCE_LEN]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter;
+    for (word, bytes) in state[13..].iter_mut().zip(nonce.chunks(4)) {
+        *word = le32(bytes);
+    }
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut block = [0; 64];
+    for (i, bytes) in block.chunks_mut(4).enumerate() {
+        bytes.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+#[cfg(feature = "encryption")]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+// the Poly1305 MAC of RFC 8439, with the accumulator and r as five 26-bit
+// limbs
+#[cfg(feature = "encryption")]
+pub(crate) struct Poly1305 {
+    r: [u32; 5],
+    s: [u32; 4],
+    h: [u32; 5],
+}
+
+#[cfg(feature = "encryption")]
+impl Poly1305 {
+    pub(crate) fn new(key: &[u8]) -> Poly1305 {
+        Poly1305 {
+            r: [
+                le32(&key[0..]) & 0x3ff_ffff,
+                (le32(&key[3..]) >> 2) & 0x3ff_ff03,
+                (le32(&key[6..]) >> 4) & 0x3ff_c0ff,
+                (le32(&key[9..]) >> 6) & 0x3f0_3fff,
+                (le32(&key[12..]) >> 8) & 0x00f_ffff,
+            ],
+            s: [
+                le32(&key[16..]),
+                le32(&key[20..]),
+                le32(&key[24..]),
+                le32(&key[28..]),
+            ],
+            h: [0; 5],
+        }
+    }
+
+    // add data, zero-padded to whole blocks, as the AEAD does
+    fn padded(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut block = [0; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.block(&block, 1 << 24);
+        }
+    }
+
+    // add data as the whole message, its last block is not padded but ended
+    // by a 1 byte; only the known-answer tests of kvs::aead take a bare tag
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn message(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut block = [0; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            if chunk.len() == 16 {
+                self.block(&block, 1 << 24);
+            } else {
+                block[chunk.len()] = 1;
+                self.block(&block, 0);
+            }
+        }
+    }
+
+    // add the block m, with hibit the bit 2^128 of a whole one
+    fn block(&mut self, m: &[u8; 16], hibit: u32) {
+        let [r0, r1, r2, r3, r4] = self.r.map(u64::from);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+        h[0] += le32(&m[0..]) & 0x3ff_ffff;
+        h[1] += (le32(&m[3..]) >> 2) & 0x3ff_ffff;
+        h[2] += (le32(&m[6..]) >> 4) & 0x3ff_ffff;
+        h[3] += (le32(&m[9..]) >> 6) & 0x3ff_ffff;
+        h[4] += (le32(&m[12..]) >> 8) | hibit;
+        let [h0, h1, h2, h3, h4] = h.map(u64::from);
+        let d = [
+            h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1,
+            h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2,
+            h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3,
+            h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4,
+            h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0,
+        ];
+        let mut carry = 0;
+        for (h, d) in h.iter_mut().zip(d.iter()) {
+            let d = d + carry;
+            *h = (d & 0x3ff_ffff) as u32;
+            carry = d >> 26;
+        }
+        h[0] += (carry * 5) as u32;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x3ff_ffff;
+    }
+
+    pub(crate) fn finish(self) -> [u8; TAG_LEN] {
+        let mut h = self.h;
+        // carry fully, then take h - p if h >= p = 2^130 - 5
+        let mut carry = 0;
+        for limb in h[1..].iter_mut() {
+            *limb += carry;
+            carry = *limb >> 26;
+            *limb &= 0x3ff_ffff;
+        }
+        h[0] += carry * 5;
+        carry = h[0] >> 26;
+        h[0] &= 0x3ff_ffff;
+        h[1] += carry;
+        let mut g = [0u32; 5];
+        carry = 5;
+        for (g, h) in g.iter_mut().zip(h.iter()) {
+            *g = h + carry;
+            carry = *g >> 26;
+            *g &= 0x3ff_ffff;
+        }
+        g[4] = g[4].wrapping_add(carry << 26).wrapping_sub(1 << 26);
+        // all ones if g did not underflow, that is h >= p
+        let mask = (g[4] >> 31).wrapping_sub(1);
+        for (h, g) in h.iter_mut().zip(g.iter()) {
+            *h = (*h & !mask) | (g & mask);
+        }
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0; TAG_LEN];
+        let mut carry = 0;
+        for (i, (word, s)) in words.iter().zip(self.s.iter()).enumerate() {
+            let sum = u64::from(*word) + u64::from(*s) + carry;
+            tag[i * 4..i * 4 + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
+        }
+        tag
+    }
+}
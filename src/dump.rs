@@ -0,0 +1,195 @@
+use crate::cache::ValueCache;
+use crate::engine::{check_engine, KVS_ENGINE};
+use crate::header::{records_start, Header};
+use crate::index::{Index, Key, Meta, OffsetLen};
+use crate::lock::lock_dir;
+use crate::record::encode;
+use crate::segment::Segments;
+use crate::subscribe::Subscribers;
+use crate::sync::SyncState;
+use crate::writer::LogWriter;
+use crate::{
+    now_millis, read_record, set_data, KvStore, KvStoreOptions, KvsError, LogFormat, Result, Value,
+};
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// the start of every dump, followed by the version byte
+const DUMP_MAGIC: &[u8; 7] = b"KVSDUMP";
+const DUMP_VERSION: u8 = 1;
+
+impl KvStore {
+    /// Writes every live key-value pair to `w` in a compact binary form,
+    /// which `KvStore::restore` reads back.
+    ///
+    /// The dump starts with the magic bytes `KVSDUMP` and a version byte,
+    /// then every pair follows in log order: the key length, the key, the
+    /// value length and the value, then the expiration time, 0 for none.
+    /// Lengths are u32 and the time a u64 in ms since the unix epoch, all
+    /// little-endian.
+    pub fn dump(&mut self, w: impl Write) -> Result<()> {
+        let mut w = BufWriter::new(w);
+        w.write_all(DUMP_MAGIC)?;
+        w.write_all(&[DUMP_VERSION])?;
+        let now = now_millis();
+        let mut sorted: Vec<_> = self.kvs.live(now)?.collect();
+        sorted.sort_by_key(|kv| kv.1.position());
+        for (key, off2len) in sorted {
+            let value = match read_record(&self.segments, off2len)? {
+                Some(value) => value,
+                None => continue,
+            };
+            write_bytes(&mut w, key.as_bytes())?;
+            write_bytes(&mut w, value.as_bytes())?;
+            w.write_all(&off2len.expires_at.unwrap_or(0).to_le_bytes())?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Creates a KvStore at a given path from a dump written by
+    /// `KvStore::dump`. Return the KvStore.
+    ///
+    /// The log is written as the dump is read and must not exist yet. The
+    /// pairs which expired since the dump are skipped, the others are created
+    /// anew with version 1. A dump which is not
+    /// one or got cut short is reported as a `KvsError::InvalidDump` error.
+    pub fn restore(path: impl Into<PathBuf>, r: impl Read) -> Result<KvStore> {
+        let mut r = io::BufReader::new(r);
+        let mut header = [0; 8];
+        r.read_exact(&mut header).map_err(invalid_dump)?;
+        if &header[..7] != DUMP_MAGIC {
+            return Err(KvsError::InvalidDump {
+                reason: String::from("bad magic bytes"),
+            }
+            .into());
+        }
+        if header[7] != DUMP_VERSION {
+            return Err(KvsError::InvalidDump {
+                reason: format!("unsupported version {}", header[7]),
+            }
+            .into());
+        }
+
+        let mut pathbuf = path.into();
+        let lock = lock_dir(&pathbuf, "kvs.log", false)?;
+        check_engine(&pathbuf, KVS_ENGINE, true)?;
+        pathbuf.push("kvs.log");
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&pathbuf)?;
+        let header = Header::new(LogFormat::default());
+        header.write(&file)?;
+        let mut kvs = Index::new(false);
+        let mut offset = records_start(Some(header));
+        let mut seq = 0;
+        let now = now_millis();
+        let mut writer = LogWriter::buffered(&file, offset)?;
+        {
+            while let Some(key) = read_bytes(&mut r, true)? {
+                let key = Key::from_bytes(key);
+                let value = Value::from_bytes(read_bytes(&mut r, false)?.unwrap_or_default());
+                let mut time = [0; 8];
+                r.read_exact(&mut time).map_err(invalid_dump)?;
+                let expires_at = match u64::from_le_bytes(time) {
+                    0 => None,
+                    time => Some(time),
+                };
+                if expires_at.is_some_and(|time| time <= now) {
+                    continue;
+                }
+                let meta = Meta::next(None, now);
+                seq += 1;
+                let data = set_data(key.as_bytes(), value, expires_at, meta, seq);
+                let record = encode(header.format(), &data)?;
+                let len = record.len();
+                writer.write_all(&record)?;
+                kvs.insert(
+                    key,
+                    OffsetLen {
+                        segment: 0,
+                        saved: 0,
+                        offset,
+                        len,
+                        expires_at,
+                        meta,
+                        inline: None,
+                    },
+                )?;
+                offset += len as u64;
+            }
+            writer.flush()?;
+        }
+        let mut segments = Segments::default();
+        segments.insert(0, &file, Some(header))?;
+        Ok(KvStore {
+            kvs,
+            log: Some(file),
+            segment: 0,
+            segments,
+            writer: Some(writer),
+            log_off: offset,
+            header: Some(header),
+            log_name: pathbuf,
+            snapshots: Arc::new(()),
+            subscribers: Subscribers::default(),
+            cache: ValueCache::new(0),
+            next_seq: seq + 1,
+            compactions: 0,
+            truncated_bytes: 0,
+            sync: SyncState::new(),
+            read_only: false,
+            _lock: Some(lock),
+            options: KvStoreOptions::default(),
+        })
+    }
+}
+
+// write the length of bytes then bytes
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "dumped string too long"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(bytes)
+}
+
+// read bytes written by write_bytes, None at the end of the dump if the
+// end is allowed there
+fn read_bytes(r: &mut impl Read, may_end: bool) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    if read == 0 && may_end {
+        return Ok(None);
+    }
+    if read < len.len() {
+        return Err(invalid_dump(io::ErrorKind::UnexpectedEof.into()));
+    }
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    r.read_exact(&mut bytes).map_err(invalid_dump)?;
+    Ok(Some(bytes))
+}
+
+// a dump cut short is invalid, any other io error is kept
+fn invalid_dump(err: io::Error) -> failure::Error {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        KvsError::InvalidDump {
+            reason: String::from("unexpected end of dump"),
+        }
+        .into()
+    } else {
+        err.into()
+    }
+}
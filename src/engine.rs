@@ -0,0 +1,193 @@
+use crate::{KvStore, KvsError, Result, SharedKvStore};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// the file of a data directory naming the engine which owns it
+const ENGINE_FILE: &str = "engine";
+// the name KvStore writes in it
+pub(crate) const KVS_ENGINE: &str = "kvs";
+
+/// A key-value store engine, which the `kvs` command line and servers are
+/// written against so that backends can be swapped.
+///
+/// Every engine follows the semantics of `KvStore`: `get` of a missing key
+/// returns `Ok(None)`, `remove` of a missing key fails with
+/// `KvsError::KeyNotFound` and a `set` replaces the value of a key.
+pub trait KvsEngine {
+    /// Sets the value of a key, replacing the value it had.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Returns the value of a key, `None` if it does not exist.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Removes a key, a `KvsError::KeyNotFound` error if it does not exist.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Makes the writes so far durable, as a server does before it exits.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Sets the values of all the keys, a key given twice takes its last
+    /// value. By default one `set` after the other.
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the values of all the keys, in the order of `keys`. By
+    /// default one `get` after the other.
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Removes all the keys, returns for every one of `keys` whether it
+    /// existed: a missing key is no error. By default one `remove` after
+    /// the other.
+    fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut removed = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.remove(key) {
+                Ok(()) => removed.push(true),
+                Err(err) => match err.downcast_ref::<KvsError>() {
+                    Some(KvsError::KeyNotFound) => removed.push(false),
+                    _ => return Err(err),
+                },
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.set_batch(pairs)
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        KvStore::get_many(self, &keys)
+    }
+
+    fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let removed = self.remove_batch(keys.clone())?;
+        Ok(removed_keys(keys, removed))
+    }
+}
+
+/// A `KvsEngine` whose clones are handles to the same data, as the engine
+/// of a `KvsServer` has to be: it hands a clone to every connection.
+///
+/// `SharedKvStore` is one, and so is any engine behind an `Arc<Mutex<_>>`,
+/// whose clones take turns on it. The trait is sealed: an engine whose
+/// clones copy the data, as those of `MemKvStore` do, cannot be one.
+pub trait SharedEngine: KvsEngine + Clone + Send + 'static + sealed::Sealed {}
+
+impl SharedEngine for SharedKvStore {}
+
+impl<E: KvsEngine + Send + 'static> SharedEngine for Arc<Mutex<E>> {}
+
+mod sealed {
+    use crate::{KvsEngine, SharedKvStore};
+    use std::sync::{Arc, Mutex};
+
+    pub trait Sealed {}
+
+    impl Sealed for SharedKvStore {}
+
+    impl<E: KvsEngine + Send + 'static> Sealed for Arc<Mutex<E>> {}
+}
+
+impl<E: KvsEngine> KvsEngine for Arc<Mutex<E>> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        lock(self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        lock(self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        lock(self).remove(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        lock(self).flush()
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        lock(self).set_many(pairs)
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        lock(self).get_many(keys)
+    }
+
+    fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        lock(self).remove_many(keys)
+    }
+}
+
+// the engine, as the last request left it if one panicked
+fn lock<E>(engine: &Mutex<E>) -> MutexGuard<'_, E> {
+    engine
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// whether every one of keys is in removed, the keys a batch removed; a key
+// given again was no longer there
+pub(crate) fn removed_keys(keys: Vec<String>, removed: Vec<String>) -> Vec<bool> {
+    let mut removed: HashSet<String> = removed.into_iter().collect();
+    keys.into_iter().map(|key| removed.remove(&key)).collect()
+}
+
+/// Returns the name of the engine which owns the data directory `dir`, as
+/// written in its `engine` file by the first open, `None` if no engine
+/// wrote one.
+///
+/// `KvStore` writes `kvs`; a server picks the engine it names for a
+/// directory which is not empty.
+pub fn engine_marker(dir: impl AsRef<Path>) -> Result<Option<String>> {
+    match fs::read_to_string(dir.as_ref().join(ENGINE_FILE)) {
+        // an empty file was cut short before the name was written
+        Ok(name) => Ok(Some(name.trim().to_owned()).filter(|name| !name.is_empty())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// fail with a `KvsError::WrongEngine` error unless dir is owned by the
+// engine named expected or by none, which it then claims for it if claim
+pub(crate) fn check_engine(dir: &Path, expected: &str, claim: bool) -> Result<()> {
+    match engine_marker(dir)? {
+        Some(found) if found != expected => Err(KvsError::WrongEngine {
+            expected: expected.to_owned(),
+            found,
+        }
+        .into()),
+        Some(_) => Ok(()),
+        None if claim => Ok(fs::write(dir.join(ENGINE_FILE), expected)?),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,194 @@
+// failure_derive wraps the impls it generates in a const block
+#![allow(non_local_definitions)]
+
+use failure::{Error, Fail};
+use std::result;
+
+/// the alias of result::Result
+pub type Result<T> = result::Result<T, Error>;
+
+/// the errors raised by the KvStore itself
+///
+/// They are carried inside `failure::Error`, use `downcast_ref` to match them.
+#[derive(Fail, Debug)]
+pub enum KvsError {
+    /// the key does not exist
+    #[fail(display = "Key not found")]
+    KeyNotFound,
+    /// the key already exists
+    #[fail(display = "Key already exists: {}", key)]
+    KeyExists {
+        /// key
+        key: String,
+    },
+    /// the value of the key can not be parsed as an i64
+    #[fail(display = "value of key {} is not an integer: {:?}", key, value)]
+    NotAnInteger {
+        /// key
+        key: String,
+        /// the current value
+        value: String,
+    },
+    /// a record of an imported file can not be parsed
+    #[fail(display = "malformed record at line {}: {}", line, reason)]
+    MalformedImport {
+        /// the line of the record, starting from 1
+        line: usize,
+        /// what is wrong with the record
+        reason: String,
+    },
+    /// the new value of the key does not fit in an i64
+    #[fail(display = "increment of key {} overflows", key)]
+    IntegerOverflow {
+        /// key
+        key: String,
+    },
+    /// keys live in both merged KvStores have different values
+    #[fail(display = "merge conflict on keys {:?}", keys)]
+    MergeConflict {
+        /// the conflicting keys
+        keys: Vec<String>,
+    },
+    /// the data given to `KvStore::restore` is not a valid dump
+    #[fail(display = "invalid dump: {}", reason)]
+    InvalidDump {
+        /// what is wrong with the dump
+        reason: String,
+    },
+    /// the KvStore was opened read-only
+    #[fail(display = "KvStore is read-only")]
+    ReadOnly,
+    /// the value of the key is not the JSON of the requested type
+    #[fail(display = "value of key {} is not valid JSON: {}", key, reason)]
+    InvalidJsonValue {
+        /// key
+        key: String,
+        /// what serde_json failed with
+        reason: String,
+    },
+    /// the value is bytes which are not UTF-8, see `KvStore::get_bytes`
+    #[fail(display = "value is not UTF-8, read it as bytes")]
+    BinaryValue,
+    /// the key is longer than `KvStoreOptions::max_key_size`
+    #[fail(display = "key of {} bytes exceeds the limit of {} bytes", size, limit)]
+    KeyTooLarge {
+        /// the length of the key in bytes
+        size: usize,
+        /// the largest allowed length
+        limit: usize,
+    },
+    /// the target of `KvStore::restore_from` already holds a log
+    #[fail(
+        display = "log {} already exists, restore with force to replace it",
+        path
+    )]
+    LogExists {
+        /// the path of the existing log
+        path: String,
+    },
+    /// the reader given to `KvStore::set_from_reader` did not yield the
+    /// announced number of bytes
+    #[fail(
+        display = "value length mismatch: expected {} bytes, read {}",
+        expected, read
+    )]
+    ValueLengthMismatch {
+        /// the announced length
+        expected: u64,
+        /// the bytes read before giving up, one more than expected if the
+        /// reader had more
+        read: u64,
+    },
+    /// the value is longer than `KvStoreOptions::max_value_size`
+    #[fail(
+        display = "value of {} bytes exceeds the limit of {} bytes",
+        size, limit
+    )]
+    ValueTooLarge {
+        /// the length of the value in bytes
+        size: usize,
+        /// the largest allowed length
+        limit: usize,
+    },
+    /// a record of the log does not match its checksum
+    #[fail(display = "corrupt record at offset {}", offset)]
+    Corruption {
+        /// the offset of the record in the log
+        offset: u64,
+    },
+    /// the log was written in a format this version can not read
+    #[fail(
+        display = "incompatible log format {}, supported format is {}",
+        found, supported
+    )]
+    IncompatibleFormat {
+        /// the format version of the log
+        found: u16,
+        /// the format version this version reads and writes
+        supported: u16,
+    },
+    /// the record at offset holds a compressed value and the crate was built
+    /// without the `compression` feature
+    #[fail(
+        display = "compressed record at offset {} needs the compression feature",
+        offset
+    )]
+    CompressionUnsupported {
+        /// the offset of the record in the log
+        offset: u64,
+    },
+    /// the log is encrypted with another key than the one given, or it is
+    /// encrypted and no key was given, or the other way round
+    #[fail(display = "wrong encryption key for the log")]
+    BadEncryptionKey,
+    /// the lock of the KvStore is held by another KvStore which has it open,
+    /// in this process or another
+    #[fail(display = "KvStore is already open, {} is locked", path)]
+    AlreadyLocked {
+        /// the path of the lock file
+        path: String,
+    },
+    /// the data given to `KvStore::apply_backup` is not a valid backup
+    #[fail(display = "invalid backup: {}", reason)]
+    InvalidBackup {
+        /// what is wrong with the backup
+        reason: String,
+    },
+    /// the records after a sequence number are missing: compacted away when
+    /// backing up, not applied yet when applying a backup
+    #[fail(display = "no backup follows on from sequence number {}", seq)]
+    BackupGap {
+        /// the last sequence number the backup can follow on from
+        seq: u64,
+    },
+    /// the server failed to answer a request of `KvsClient`, with the
+    /// message of its error
+    #[fail(display = "{}", message)]
+    Server {
+        /// the message of the error of the server
+        message: String,
+    },
+    /// the server takes no request before the token of
+    /// `KvsClient::connect_with_token`
+    #[fail(display = "the server requires an authentication token")]
+    AuthRequired,
+    /// the server refused the token of `KvsClient::connect_with_token`
+    #[fail(display = "authentication token refused by the server")]
+    AuthFailed,
+    /// the server serves as many connections as it may, see
+    /// `KvsServer::max_connections`
+    #[fail(display = "the server is busy, it serves as many connections as it may")]
+    Busy,
+    /// the data directory is owned by another engine, see
+    /// `kvs::engine_marker`
+    #[fail(
+        display = "data directory belongs to engine {}, not {}",
+        found, expected
+    )]
+    WrongEngine {
+        /// the engine which opened the directory
+        expected: String,
+        /// the engine its `engine` file names
+        found: String,
+    },
+}
@@ -0,0 +1,59 @@
+use crate::Result;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// How a KvStore syncs its files to disk, see `KvStoreOptions::file_sync`.
+///
+/// Every method defaults to the system call, `OsFileSync` keeps them all.
+/// Another implementation can count the syncs or fail them, to test what a
+/// KvStore does when the disk does not keep up.
+pub trait FileSync: Debug + Send + Sync {
+    /// Syncs the data of the file, like `File::sync_data`.
+    fn sync_data(&self, file: &File) -> io::Result<()> {
+        file.sync_data()
+    }
+
+    /// Syncs the data and the metadata of the file, like `File::sync_all`.
+    fn sync_all(&self, file: &File) -> io::Result<()> {
+        file.sync_all()
+    }
+
+    /// Syncs the directory, which makes the files created, renamed and
+    /// deleted in it durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+}
+
+/// The `FileSync` of the system calls, the default one.
+#[derive(Debug, Default)]
+pub struct OsFileSync;
+
+impl FileSync for OsFileSync {}
+
+// move the file at temp_path, whose handle is temp, over target and return
+// the handle of target
+//
+// The temporary file is made next to its target, so a rename across
+// filesystems only happens when the target is a mount point of its own: temp
+// is then copied over the target, which is not atomic, and synced.
+pub(crate) fn replace_file(
+    file_sync: &dyn FileSync,
+    temp_path: &Path,
+    temp: File,
+    target: &Path,
+) -> Result<File> {
+    match fs::rename(temp_path, target) {
+        Ok(()) => Ok(temp),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(temp_path, target)?;
+            let file = OpenOptions::new().read(true).write(true).open(target)?;
+            file_sync.sync_all(&file)?;
+            fs::remove_file(temp_path)?;
+            Ok(file)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
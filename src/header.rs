@@ -0,0 +1,198 @@
+use crate::crypt::{Cipher, KEY_CHECK_LEN};
+use crate::{KvsError, LogFormat, Result};
+use std::convert::TryInto;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+// the length of the header starting every log: magic bytes, format version,
+// two bytes reserved and flags, then the key check of an encrypted log
+pub(crate) const HEADER_LEN: usize = 16;
+const MAGIC: &[u8; 8] = b"KVS-LOG\0";
+// the formats of the records: 1 for newline-delimited JSON, 2 for binary
+// frames
+pub(crate) const JSON_VERSION: u16 = 1;
+pub(crate) const BINARY_VERSION: u16 = 2;
+// the latest format, every earlier one is still read
+pub(crate) const FORMAT_VERSION: u16 = BINARY_VERSION;
+// set if every record of the log carries a checksum
+pub(crate) const FLAG_CHECKSUMS: u32 = 1;
+// set on a segment merged from the sealed segments before it, which it
+// replaces: ones left over by an interrupted compaction are deleted
+pub(crate) const FLAG_MERGED: u32 = 2;
+// set on the active segment by `close` and cleared by `open`, so a log
+// without it was not closed cleanly
+pub(crate) const FLAG_CLEAN: u32 = 4;
+// set on a segment whose records are encrypted, its header then ends with
+// a key check
+const FLAG_ENCRYPTED: u32 = 8;
+// set on a merged segment whose records are sorted by key, one per key, for
+// a sampled index
+pub(crate) const FLAG_SORTED: u32 = 16;
+// set on an encrypted segment whose records are sealed with their place,
+// the id of the segment and their offset, so none opens anywhere else;
+// those of one written before are sealed with their type alone
+const FLAG_PLACED: u32 = 32;
+
+// the header of a log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub(crate) version: u16,
+    pub(crate) flags: u32,
+    pub(crate) key_check: Option<[u8; KEY_CHECK_LEN]>,
+}
+
+impl Header {
+    // the header of a new log whose records are in format
+    pub(crate) fn new(format: LogFormat) -> Header {
+        let version = match format {
+            LogFormat::Json => JSON_VERSION,
+            LogFormat::Binary => BINARY_VERSION,
+        };
+        Header {
+            version,
+            flags: FLAG_CHECKSUMS,
+            key_check: None,
+        }
+    }
+
+    // the header with a key check of cipher if there is one, for a new
+    // segment whose records are encrypted with it
+    pub(crate) fn encrypted_with(self, cipher: Option<&Cipher>) -> Header {
+        match cipher {
+            Some(cipher) => Header {
+                flags: self.flags | FLAG_ENCRYPTED | FLAG_PLACED,
+                key_check: Some(cipher.key_check()),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    // the header of a copy of the records of a log whose header is old,
+    // None for a log written before headers, whose records are JSON and may
+    // have no checksum
+    pub(crate) fn rewritten(old: Option<Header>) -> Header {
+        Header {
+            version: old.map_or(JSON_VERSION, |old| old.version),
+            flags: old.map_or(0, |old| {
+                old.flags & !(FLAG_MERGED | FLAG_CLEAN | FLAG_SORTED)
+            }),
+            key_check: old.and_then(|old| old.key_check),
+        }
+    }
+
+    // the header of a log holding the records copied from logs with the
+    // headers, all of one format: rewritten, with checksums only if every
+    // record carries one
+    pub(crate) fn copied(headers: impl IntoIterator<Item = Option<Header>>) -> Header {
+        let mut copied: Option<Header> = None;
+        for old in headers {
+            let old = Header::rewritten(old);
+            copied = Some(match copied {
+                Some(header) => Header {
+                    flags: header.flags & old.flags,
+                    ..header
+                },
+                None => old,
+            });
+        }
+        copied.unwrap_or_else(|| Header::rewritten(None))
+    }
+
+    pub(crate) fn format(&self) -> LogFormat {
+        match self.version {
+            JSON_VERSION => LogFormat::Json,
+            _ => LogFormat::Binary,
+        }
+    }
+
+    pub(crate) fn checksums(&self) -> bool {
+        self.flags & FLAG_CHECKSUMS != 0
+    }
+
+    pub(crate) fn merged(&self) -> bool {
+        self.flags & FLAG_MERGED != 0
+    }
+
+    pub(crate) fn sorted(&self) -> bool {
+        self.flags & FLAG_SORTED != 0
+    }
+
+    pub(crate) fn clean(&self) -> bool {
+        self.flags & FLAG_CLEAN != 0
+    }
+
+    pub(crate) fn encrypted(&self) -> bool {
+        self.key_check.is_some()
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn placed(&self) -> bool {
+        self.flags & FLAG_PLACED != 0
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; HEADER_LEN];
+        buf[..8].copy_from_slice(MAGIC);
+        buf[8..10].copy_from_slice(&self.version.to_le_bytes());
+        buf[12..].copy_from_slice(&self.flags.to_le_bytes());
+        if let Some(check) = &self.key_check {
+            buf.extend_from_slice(check);
+        }
+        buf
+    }
+
+    // write the header at the start of log
+    pub(crate) fn write(&self, log: &File) -> Result<()> {
+        log.write_all_at(&self.encode(), 0)?;
+        Ok(())
+    }
+
+    // read the header of log, None if it starts with no magic bytes: it was
+    // written before headers, or is empty
+    pub(crate) fn read(log: &File) -> Result<Option<Header>> {
+        let mut buf = [0; HEADER_LEN];
+        if log.metadata()?.len() < HEADER_LEN as u64 {
+            return Ok(None);
+        }
+        log.read_exact_at(&mut buf, 0)?;
+        if &buf[..8] != MAGIC {
+            return Ok(None);
+        }
+        let version = u16::from_le_bytes(buf[8..10].try_into().unwrap_or_default());
+        if !(JSON_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(KvsError::IncompatibleFormat {
+                found: version,
+                supported: FORMAT_VERSION,
+            }
+            .into());
+        }
+        let flags = u32::from_le_bytes(buf[12..].try_into().unwrap_or_default());
+        let key_check = if flags & FLAG_ENCRYPTED != 0 {
+            let mut check = [0; KEY_CHECK_LEN];
+            log.read_exact_at(&mut check, HEADER_LEN as u64)?;
+            Some(check)
+        } else {
+            None
+        };
+        Ok(Some(Header {
+            version,
+            flags,
+            key_check,
+        }))
+    }
+}
+
+// where the records of a log with header start
+pub(crate) fn records_start(header: Option<Header>) -> u64 {
+    match header {
+        Some(header) if header.encrypted() => (HEADER_LEN + KEY_CHECK_LEN) as u64,
+        Some(_) => HEADER_LEN as u64,
+        None => 0,
+    }
+}
+
+// the format of the records of a log with header
+pub(crate) fn record_format(header: Option<Header>) -> LogFormat {
+    header.map_or(LogFormat::Json, |header| header.format())
+}
@@ -0,0 +1,241 @@
+use crate::header::{records_start, Header};
+use crate::index::{Index, Key, Meta, OffsetLen};
+use crate::record::{put_bytes, Fields};
+use crate::segment::segment_name;
+use crate::{now_millis, KvStore, Result};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+// the start of every hint file, followed by the version byte
+const HINT_MAGIC: &[u8; 7] = b"KVSHINT";
+const HINT_VERSION: u8 = 3;
+// the bytes at the end of a segment whose CRC32 the hint keeps
+const TAIL_LEN: u64 = 64;
+
+// a copy of the index at the end of the log, which open loads instead of
+// replaying the records before that end
+//
+// The hint file starts with HINT_MAGIC and HINT_VERSION, then holds the last
+// sequence number, the segments covered and the entries of the index, all
+// numbers as u64 LE, and ends with the CRC32 of everything before it as u32
+// LE. A segment is its id, the inode of its file, the offset the hint
+// covers it up to and the CRC32 of the last TAIL_LEN bytes of records
+// before that offset. An
+// entry is the key after its length as u32 LE, then the fields of its
+// OffsetLen: segment, offset, len, expiration time (0 for none), version,
+// creation and update times, the bytes compression saved, and 1 followed by
+// the value after its length as u32 LE for a value kept inline, 0 for none.
+pub(crate) struct Hint {
+    pub(crate) kvs: Index,
+    pub(crate) last_seq: u64,
+    pub(crate) ends: BTreeMap<u32, u64>, // where the hint ends in each segment
+}
+
+// the name of the hint file of the log named log_file_name
+pub(crate) fn hint_name(log_file_name: &str) -> String {
+    format!("{}.hint", log_file_name)
+}
+
+impl KvStore {
+    /// Writes the index to a hint file next to the log, `kvs.log.hint`, which
+    /// `open` loads instead of replaying the records it covers.
+    ///
+    /// `close` writes one, and so does `compact` after every compaction; a
+    /// hint is only loaded for a log closed cleanly. The hint file is synced
+    /// and renamed into place, and only holds for the log it was written
+    /// for: the records written after it are replayed on top of it, and a
+    /// log rewritten, truncated or replaced since makes `open` ignore it and
+    /// replay the whole log. The records a hint covers are not checked
+    /// against their checksums by `open`. No hint is written for an
+    /// encrypted log, it would hold the keys in plain text, nor for a
+    /// sampled index, it would hold every key, see
+    /// `KvStoreOptions::index_sampling`.
+    pub fn write_hint(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.log.is_none() || self.options.cipher.is_some() || self.options.index_sampling > 1 {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        buf.extend_from_slice(HINT_MAGIC);
+        buf.push(HINT_VERSION);
+        buf.extend_from_slice(&(self.next_seq - 1).to_le_bytes());
+        let segments = self.segment_files();
+        buf.extend_from_slice(&(segments.len() as u64).to_le_bytes());
+        for (id, file, header, end) in segments {
+            let fields = [
+                u64::from(id),
+                file.metadata()?.ino(),
+                end,
+                u64::from(tail_crc(file, header, end)?),
+            ];
+            for field in fields.iter() {
+                buf.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        for (key, off2len) in self.kvs.iter()? {
+            put_bytes(&mut buf, key.as_bytes())?;
+            let fields = [
+                u64::from(off2len.segment),
+                off2len.offset,
+                off2len.len as u64,
+                off2len.expires_at.unwrap_or(0),
+                off2len.meta.version,
+                off2len.meta.created_at,
+                off2len.meta.updated_at,
+                u64::from(off2len.saved),
+                u64::from(off2len.inline.is_some()),
+            ];
+            for field in fields.iter() {
+                buf.extend_from_slice(&field.to_le_bytes());
+            }
+            if let Some(inline) = &off2len.inline {
+                put_bytes(&mut buf, inline.as_bytes())?;
+            }
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        let name = hint_name(&self.options.log_file_name);
+        let path = self.log_name.with_file_name(name);
+        let (temp_path, mut temp) = self.create_swap_file()?;
+        let written = temp
+            .write_all(&buf)
+            .and_then(|_| self.options.file_sync.sync_all(&temp))
+            .and_then(|_| fs::rename(&temp_path, &path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        Ok(written?)
+    }
+
+    // delete the hint file, which no longer holds for the log
+    pub(crate) fn remove_hint(&self) -> Result<()> {
+        let name = hint_name(&self.options.log_file_name);
+        match fs::remove_file(self.log_name.with_file_name(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// the hint of the log named log_file_name in dir, whose segments have the
+// ids, None if there is none, it fails its checksum or is stale; index is
+// whether the index is ordered and the longest value it keeps inline
+pub(crate) fn load_hint(
+    dir: &Path,
+    log_file_name: &str,
+    ids: &[u32],
+    index: (bool, usize),
+) -> Option<Hint> {
+    let bytes = fs::read(dir.join(hint_name(log_file_name))).ok()?;
+    read_hint(&bytes, dir, log_file_name, ids, index)
+        .ok()
+        .flatten()
+}
+
+fn read_hint(
+    bytes: &[u8],
+    dir: &Path,
+    log_file_name: &str,
+    ids: &[u32],
+    (ordered, inline_max): (bool, usize),
+) -> Result<Option<Hint>> {
+    let start = HINT_MAGIC.len() + 1;
+    if bytes.len() < start + 4
+        || &bytes[..start - 1] != HINT_MAGIC
+        || bytes[start - 1] != HINT_VERSION
+    {
+        return Ok(None);
+    }
+    let (content, crc) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(content).to_le_bytes() != crc {
+        return Ok(None);
+    }
+    let mut fields = Fields::new(&content[start..], start as u64);
+    let last_seq = fields.u64()?;
+
+    // the hint covers the first segments of the log, all but the last of
+    // them as they are now
+    let mut ends = BTreeMap::new();
+    let count = fields.u64()? as usize;
+    if count == 0 || count > ids.len() {
+        return Ok(None);
+    }
+    for (i, id) in ids.iter().take(count).enumerate() {
+        let (hint_id, inode, end, crc) =
+            (fields.u64()?, fields.u64()?, fields.u64()?, fields.u64()?);
+        let file = File::open(dir.join(segment_name(log_file_name, *id)))?;
+        let header = Header::read(&file)?;
+        let metadata = file.metadata()?;
+        let grown = metadata.len() > end && i + 1 == count;
+        if hint_id != u64::from(*id)
+            || metadata.ino() != inode
+            || (metadata.len() != end && !grown)
+            || u64::from(tail_crc(&file, header, end)?) != crc
+        {
+            return Ok(None);
+        }
+        ends.insert(*id, end);
+    }
+
+    let mut kvs = Index::new(ordered);
+    kvs.set_inline_max(inline_max);
+    let now = now_millis();
+    while !fields.is_empty() {
+        let key = Key::from_bytes(fields.bytes()?.to_vec());
+        let segment = match u32::try_from(fields.u64()?) {
+            Ok(segment) if ends.contains_key(&segment) => segment,
+            _ => return Ok(None),
+        };
+        let mut off2len = OffsetLen {
+            segment,
+            saved: 0,
+            offset: fields.u64()?,
+            len: fields.u64()? as usize,
+            expires_at: match fields.u64()? {
+                0 => None,
+                time => Some(time),
+            },
+            meta: Meta {
+                version: fields.u64()?,
+                created_at: fields.u64()?,
+                updated_at: fields.u64()?,
+            },
+            inline: None,
+        };
+        off2len.saved = u32::try_from(fields.u64()?).unwrap_or(u32::MAX);
+        if fields.u64()? == 1 {
+            off2len.inline = match String::from_utf8(fields.bytes()?.to_vec()) {
+                Ok(inline) => Some(inline.into()),
+                Err(_) => return Ok(None),
+            };
+        }
+        // expired while the KvStore was closed
+        if !off2len.is_expired(now) {
+            kvs.insert(key, off2len)?;
+        }
+    }
+    Ok(Some(Hint {
+        kvs,
+        last_seq,
+        ends,
+    }))
+}
+
+// the CRC32 of the bytes of the records of file, whose header is header,
+// before end, TAIL_LEN at most
+//
+// The header is left out, its flags change on open and close.
+fn tail_crc(file: &File, header: Option<Header>, end: u64) -> io::Result<u32> {
+    let start = end
+        .saturating_sub(TAIL_LEN)
+        .max(records_start(header))
+        .min(end);
+    let mut buf = vec![0; (end - start) as usize];
+    file.read_exact_at(&mut buf, start)?;
+    Ok(crc32fast::hash(&buf))
+}
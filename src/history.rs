@@ -0,0 +1,99 @@
+use crate::crypt::open_record;
+use crate::header::{record_format, records_start};
+use crate::record::{decode, logged_value, read_next};
+use crate::{KvStore, OptData, Result, Value};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+/// A record of the log which touched a key, see `KvStore::history`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// the offset of the record in the log, or in its segment for a log
+    /// split in segments
+    pub offset: u64,
+    /// the value set by the record, decompressed, as the base64 of bytes
+    /// which are not UTF-8 or were streamed to a JSON log, `None` for a
+    /// removal
+    pub value: Option<String>,
+    /// the version of the key after a set, 0 for a removal
+    pub version: u64,
+    /// the sequence number of the record, 0 if it was logged before them
+    pub seq: u64,
+}
+
+impl KvStore {
+    /// Returns every set and removal of the key still in the log, in log
+    /// order over all its segments.
+    ///
+    /// The log is read from the start through a buffered reader. Only what
+    /// the log holds now is seen: a compaction drops every record which is
+    /// not live, so the older history of a key may be gone.
+    pub fn history(&mut self, key: &str) -> Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+        for (segment, log, header, end) in self.segment_files() {
+            let format = record_format(header);
+            let mut offset = records_start(header);
+            let mut log = log.try_clone()?;
+            log.seek(SeekFrom::Start(offset))?;
+            let mut reader = BufReader::new(log.take(end - offset));
+            let mut record = Vec::new();
+            loop {
+                let len = read_next(format, &mut reader, &mut record)?;
+                if len == 0 {
+                    break;
+                }
+                let record = open_record(
+                    self.segments.cipher.as_ref(),
+                    &record,
+                    (header, segment, offset),
+                )?;
+                match decode(format, &record, offset, false)? {
+                    OptData::SetData {
+                        key: k,
+                        key_base64: false,
+                        value,
+                        version,
+                        seq,
+                        compressed: Some(len),
+                        ..
+                    } if k == key => entries.push(HistoryEntry {
+                        offset,
+                        value: Some(match logged_value(value, true, Some(len), offset)? {
+                            Value::Text(text) => text,
+                            Value::Bytes(bytes) => BASE64.encode(bytes),
+                        }),
+                        version,
+                        seq,
+                    }),
+                    OptData::SetData {
+                        key: k,
+                        key_base64: false,
+                        value,
+                        version,
+                        seq,
+                        ..
+                    } if k == key => entries.push(HistoryEntry {
+                        offset,
+                        value: Some(value),
+                        version,
+                        seq,
+                    }),
+                    OptData::RmData {
+                        key: k,
+                        key_base64: false,
+                        seq,
+                    } if k == key => entries.push(HistoryEntry {
+                        offset,
+                        value: None,
+                        version: 0,
+                        seq,
+                    }),
+                    _ => {}
+                }
+                offset += len as u64;
+            }
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,702 @@
+use crate::run::Run;
+use crate::Result;
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Clone, Debug)]
+pub(crate) struct OffsetLen {
+    pub(crate) segment: u32, // the id of the segment of the log holding the record
+    pub(crate) saved: u32,   // the bytes compression saved on the value
+    pub(crate) offset: u64,  // the offset of serialized OptData in log file
+    pub(crate) len: usize,   // the length of serialized OptData(include '\n')
+    pub(crate) expires_at: Option<u64>, // the expiration time, in ms since the unix epoch
+    pub(crate) meta: Meta,
+    pub(crate) inline: Option<Box<str>>, // a copy of a small value, see KvStoreOptions::inline_values
+}
+
+// the metadata logged with every SetData, times in ms since the unix epoch
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Meta {
+    pub(crate) version: u64, // 1 for the first set of the key
+    pub(crate) created_at: u64,
+    pub(crate) updated_at: u64,
+}
+
+impl Meta {
+    // the metadata of a set of the key which currently has old
+    pub(crate) fn next(old: Option<&Meta>, now: u64) -> Meta {
+        match old {
+            Some(old) => Meta {
+                version: old.version + 1,
+                created_at: old.created_at,
+                updated_at: now,
+            },
+            None => Meta {
+                version: 1,
+                created_at: now,
+                updated_at: now,
+            },
+        }
+    }
+}
+
+impl OffsetLen {
+    // where the record is in the log, records sort in log order by it
+    pub(crate) fn position(&self) -> (u32, u64) {
+        (self.segment, self.offset)
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+}
+
+// a key of the index: its bytes, kept as a String when they are UTF-8 so
+// the String API can hand out &String
+//
+// Keys compare, hash and sort as their bytes, and can be looked up by &[u8].
+#[derive(Clone, Debug)]
+pub(crate) enum Key {
+    Text(String),
+    Bytes(Vec<u8>), // never valid UTF-8
+}
+
+impl Key {
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> Key {
+        match String::from_utf8(bytes) {
+            Ok(text) => Key::Text(text),
+            Err(err) => Key::Bytes(err.into_bytes()),
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            Key::Text(text) => text.as_bytes(),
+            Key::Bytes(bytes) => bytes,
+        }
+    }
+
+    pub(crate) fn as_string(&self) -> Option<&String> {
+        match self {
+            Key::Text(text) => Some(text),
+            Key::Bytes(_) => None,
+        }
+    }
+}
+
+impl From<String> for Key {
+    fn from(key: String) -> Key {
+        Key::Text(key)
+    }
+}
+
+impl Borrow<[u8]> for Key {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    // the same hash as the [u8] it borrows as
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    // UTF-8 keys sort like their Strings
+    fn cmp(&self, other: &Key) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+type Iter<'a> = Box<dyn Iterator<Item = (&'a Key, &'a OffsetLen)> + 'a>;
+type IterMut<'a> = Box<dyn Iterator<Item = (&'a Key, &'a mut OffsetLen)> + 'a>;
+
+#[derive(Clone)]
+enum Map {
+    // no order, the default
+    Hash(HashMap<Key, OffsetLen>),
+    // keys kept sorted, for range queries
+    Ordered(BTreeMap<Key, OffsetLen>),
+}
+
+// the in-memory index: key -> position of its latest SetData in the log
+//
+// Expired entries stay in the index until they are dropped, so callers
+// have to filter them out with `OffsetLen::is_expired`.
+//
+// A sampled index also has a run, the sorted segment written by the last
+// compaction, of which it only keeps some entries in memory: the entries
+// of the map, the resident ones, come first and the keys of the run they
+// replace or removed are hidden.
+#[derive(Clone)]
+pub(crate) struct Index {
+    map: Map,
+    deadlines: Deadlines,     // the expiration times of the entries
+    bytes: HashMap<u32, u64>, // the sum of the lengths of the entries by segment
+    run: Option<Arc<Run>>,
+    hidden: HashSet<Key>, // the keys of the run whose entry is dead or resident
+    run_len: usize,       // the number of live entries of the run
+    run_bytes: u64,       // the sum of their lengths
+    run_saved: u64,       // the bytes compression saved on them
+    // every entry of the run, read once the whole index is walked and
+    // dropped by the next write
+    loaded: OnceLock<Arc<Vec<(Key, OffsetLen)>>>,
+    inline_max: usize,        // the length of the longest value kept inline
+    changes: Option<Changes>, // what changed since take_changes, once tracked
+}
+
+// the entries of an index changed since it was last asked: those of some
+// keys, or any of them
+#[derive(Clone)]
+pub(crate) enum Changes {
+    Keys(HashSet<Key>),
+    All,
+}
+
+impl Index {
+    pub(crate) fn new(ordered: bool) -> Index {
+        let map = if ordered {
+            Map::Ordered(BTreeMap::new())
+        } else {
+            Map::Hash(HashMap::new())
+        };
+        Index {
+            map,
+            deadlines: Deadlines::default(),
+            bytes: HashMap::new(),
+            run: None,
+            hidden: HashSet::new(),
+            run_len: 0,
+            run_bytes: 0,
+            run_saved: 0,
+            loaded: OnceLock::new(),
+            inline_max: 0,
+            changes: None,
+        }
+    }
+
+    // keep track of the entries changed from now on, see take_changes
+    pub(crate) fn track_changes(&mut self) {
+        self.changes = Some(Changes::Keys(HashSet::new()));
+    }
+
+    // the entries changed since the last call, all of them unless they are
+    // tracked
+    pub(crate) fn take_changes(&mut self) -> Changes {
+        match &mut self.changes {
+            Some(changes) => mem::replace(changes, Changes::Keys(HashSet::new())),
+            None => Changes::All,
+        }
+    }
+
+    fn changed(&mut self, key: &[u8]) {
+        if let Some(Changes::Keys(keys)) = &mut self.changes {
+            if !keys.contains(key) {
+                keys.insert(Key::from_bytes(key.to_vec()));
+            }
+        }
+    }
+
+    fn changed_all(&mut self) {
+        if self.changes.is_some() {
+            self.changes = Some(Changes::All);
+        }
+    }
+
+    // the length of the longest value an entry keeps inline, 0 for none
+    pub(crate) fn inline_max(&self) -> usize {
+        self.inline_max
+    }
+
+    // keep the values up to max bytes inline, and drop the longer ones
+    pub(crate) fn set_inline_max(&mut self, max: usize) {
+        self.inline_max = max;
+        for (_, value) in self.iter_mut() {
+            strip_inline(value, max);
+        }
+    }
+
+    // the entry of key, read from the run if it is not resident
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Cow<'_, OffsetLen>>> {
+        if let Some(value) = self.resident(key) {
+            return Ok(Some(Cow::Borrowed(value)));
+        }
+        let run = match &self.run {
+            Some(run) if !self.hidden.contains(key) => run,
+            _ => return Ok(None),
+        };
+        if let Some(loaded) = self.loaded.get() {
+            let found = loaded.binary_search_by(|(other, _)| other.as_bytes().cmp(key));
+            return Ok(found.ok().map(|i| Cow::Borrowed(&loaded[i].1)));
+        }
+        Ok(run.find(key)?.map(Cow::Owned))
+    }
+
+    // get for the callers which cannot fail, a failed read of the run counts
+    // as no entry
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Cow<'_, OffsetLen>> {
+        self.get(key).ok().flatten()
+    }
+
+    // the entry of key if it is resident
+    fn resident(&self, key: &[u8]) -> Option<&OffsetLen> {
+        match &self.map {
+            Map::Hash(map) => map.get(key),
+            Map::Ordered(map) => map.get(key),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: Key, mut value: OffsetLen) -> Result<Option<OffsetLen>> {
+        self.loaded = OnceLock::new();
+        self.changed(key.as_bytes());
+        strip_inline(&mut value, self.inline_max);
+        // without a run there is nothing to hide, and no need to look the
+        // key up twice
+        let hidden = match &self.run {
+            Some(_) if self.resident(key.as_bytes()).is_none() => self.hide(key.as_bytes())?,
+            _ => None,
+        };
+        if let Some(expires_at) = value.expires_at {
+            self.deadlines.add(expires_at);
+        }
+        *self.bytes.entry(value.segment).or_default() += value.len as u64;
+        let old = match &mut self.map {
+            Map::Hash(map) => map.insert(key, value),
+            Map::Ordered(map) => map.insert(key, value),
+        };
+        self.forget(&old);
+        Ok(old.or(hidden))
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Result<Option<OffsetLen>> {
+        self.loaded = OnceLock::new();
+        self.changed(key);
+        let old = match &mut self.map {
+            Map::Hash(map) => map.remove(key),
+            Map::Ordered(map) => map.remove(key),
+        };
+        self.forget(&old);
+        match old {
+            Some(old) => Ok(Some(old)),
+            None => self.hide(key),
+        }
+    }
+
+    // hide the entry of key in the run, if it has a live one, and return it
+    fn hide(&mut self, key: &[u8]) -> Result<Option<OffsetLen>> {
+        let old = match &self.run {
+            Some(run) if !self.hidden.contains(key) => run.find(key)?,
+            _ => None,
+        };
+        if let Some(old) = &old {
+            self.hidden.insert(Key::from_bytes(key.to_vec()));
+            self.run_len -= 1;
+            self.run_bytes -= old.len as u64;
+            self.run_saved -= u64::from(old.saved);
+            forget_bytes(&mut self.bytes, old);
+        }
+        Ok(old)
+    }
+
+    // keeps the resident entries for which f returns true
+    pub(crate) fn retain<F: FnMut(&Key, &OffsetLen) -> bool>(&mut self, mut f: F) {
+        self.changed_all();
+        let bytes = &mut self.bytes;
+        let deadlines = &mut self.deadlines;
+        let mut keep = |key: &Key, value: &mut OffsetLen| {
+            let keep = f(key, value);
+            if !keep {
+                if let Some(expires_at) = value.expires_at {
+                    deadlines.forget(expires_at);
+                }
+                forget_bytes(bytes, value);
+            }
+            keep
+        };
+        match &mut self.map {
+            Map::Hash(map) => map.retain(|key, value| keep(key, value)),
+            Map::Ordered(map) => map.retain(|key, value| keep(key, value)),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.changed_all();
+        match &mut self.map {
+            Map::Hash(map) => map.clear(),
+            Map::Ordered(map) => map.clear(),
+        }
+        self.deadlines = Deadlines::default();
+        self.bytes.clear();
+        self.drop_run();
+    }
+
+    fn forget(&mut self, old: &Option<OffsetLen>) {
+        if let Some(old) = old {
+            if let Some(expires_at) = old.expires_at {
+                self.deadlines.forget(expires_at);
+            }
+            forget_bytes(&mut self.bytes, old);
+        }
+    }
+
+    // the run of the index, with the keys it hides
+    pub(crate) fn run(&self) -> Option<(&Run, &HashSet<Key>)> {
+        self.run.as_deref().map(|run| (run, &self.hidden))
+    }
+
+    // make run the run of the index, in place of the one it has; kept are
+    // the entries of the run to keep resident, none of them in the index
+    pub(crate) fn set_run(&mut self, run: Run, kept: Vec<(Key, OffsetLen)>) -> Result<()> {
+        self.drop_run();
+        self.run_len = run.len;
+        self.run_bytes = run.bytes;
+        self.run_saved = run.saved;
+        *self.bytes.entry(run.segment).or_default() += run.bytes;
+        self.run = Some(Arc::new(run));
+        for (key, value) in kept {
+            self.hidden.insert(key.clone());
+            self.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn drop_run(&mut self) {
+        self.changed_all();
+        if let Some(run) = self.run.take() {
+            forget_segment_bytes(&mut self.bytes, run.segment, self.run_bytes);
+        }
+        self.hidden.clear();
+        self.run_len = 0;
+        self.run_bytes = 0;
+        self.run_saved = 0;
+        self.loaded = OnceLock::new();
+    }
+
+    // the number of live entries of the run, the sum of their lengths and
+    // the bytes compression saved on them
+    pub(crate) fn run_totals(&self) -> (usize, u64, u64) {
+        (self.run_len, self.run_bytes, self.run_saved)
+    }
+
+    // the number of entries kept in memory: the resident ones and the
+    // samples of the run
+    pub(crate) fn resident_len(&self) -> usize {
+        let samples = self.run.as_ref().map_or(0, |run| run.samples().len());
+        self.map_len() + samples
+    }
+
+    // every entry of the run, read from the log the first time
+    fn loaded(&self, run: &Run) -> Result<&[(Key, OffsetLen)]> {
+        if let Some(loaded) = self.loaded.get() {
+            return Ok(loaded);
+        }
+        let loaded = Arc::new(run.load()?);
+        Ok(self.loaded.get_or_init(|| loaded))
+    }
+
+    // true if most of the allocation of the map is unused
+    pub(crate) fn is_sparse(&self) -> bool {
+        match &self.map {
+            Map::Hash(map) => map.len() < map.capacity() / 4,
+            Map::Ordered(_) => false,
+        }
+    }
+
+    // a B-tree frees its nodes as it goes, only a hash map is shrunk
+    pub(crate) fn shrink_to_fit(&mut self) {
+        if let Map::Hash(map) = &mut self.map {
+            map.shrink_to_fit();
+        }
+        self.hidden.shrink_to_fit();
+    }
+
+    // an estimate of the memory held by the entries kept in memory, their
+    // keys and inline values
+    pub(crate) fn memory_usage(&self) -> usize {
+        let slots = match &self.map {
+            Map::Hash(map) => map.capacity(),
+            Map::Ordered(map) => map.len(),
+        };
+        let samples = self.run.as_ref().map_or(&[][..], |run| run.samples());
+        let keys: usize = self
+            .resident_iter()
+            .chain(samples.iter().map(|kv| (&kv.0, &kv.1)))
+            .map(|kv| key_capacity(kv.0) + kv.1.inline.as_ref().map_or(0, |inline| inline.len()))
+            .chain(self.hidden.iter().map(key_capacity))
+            .sum();
+        (slots + samples.len()) * mem::size_of::<(Key, OffsetLen)>()
+            + self.hidden.capacity() * mem::size_of::<Key>()
+            + keys
+    }
+
+    // the bytes of the log the entries point at, expired or not
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.values().sum()
+    }
+
+    // the bytes of the segment the entries point at, expired or not
+    pub(crate) fn segment_bytes(&self, segment: u32) -> u64 {
+        self.bytes.get(&segment).copied().unwrap_or(0)
+    }
+
+    // the number of entries which are not expired at now, the entries of
+    // the run never expire
+    pub(crate) fn live_len(&self, now: u64) -> usize {
+        self.len() - self.deadlines.passed(now)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map_len() + self.run_len
+    }
+
+    fn map_len(&self) -> usize {
+        match &self.map {
+            Map::Hash(map) => map.len(),
+            Map::Ordered(map) => map.len(),
+        }
+    }
+
+    // in key order for the ordered index, arbitrary order otherwise; the
+    // run, if any, is read whole
+    pub(crate) fn iter(&self) -> Result<Iter<'_>> {
+        let run = match &self.run {
+            Some(run) => self.loaded(run)?,
+            None => return Ok(self.resident_iter()),
+        };
+        let hidden = &self.hidden;
+        let run = run
+            .iter()
+            .filter(move |kv| !hidden.contains(&kv.0))
+            .map(|kv| (&kv.0, &kv.1));
+        match &self.map {
+            Map::Hash(map) => Ok(Box::new(map.iter().chain(run))),
+            Map::Ordered(map) => Ok(Box::new(merge_sorted(map.iter(), run))),
+        }
+    }
+
+    // the resident entries, in key order for the ordered index
+    fn resident_iter(&self) -> Iter<'_> {
+        match &self.map {
+            Map::Hash(map) => Box::new(map.iter()),
+            Map::Ordered(map) => Box::new(map.iter()),
+        }
+    }
+
+    // the entries which are not expired at now
+    pub(crate) fn live(&self, now: u64) -> Result<Iter<'_>> {
+        Ok(Box::new(
+            self.iter()?.filter(move |kv| !kv.1.is_expired(now)),
+        ))
+    }
+
+    // live for the callers which cannot fail, a failed read of the run
+    // leaves its entries out like lookup does
+    pub(crate) fn lookup_live(&self, now: u64) -> Iter<'_> {
+        self.live(now).unwrap_or_else(|_| self.resident_live(now))
+    }
+
+    // the resident entries which are not expired at now, none of the run
+    pub(crate) fn resident_live(&self, now: u64) -> Iter<'_> {
+        Box::new(self.resident_iter().filter(move |kv| !kv.1.is_expired(now)))
+    }
+
+    // the live entries whose key is UTF-8, for the String API
+    pub(crate) fn live_strings(
+        &self,
+        now: u64,
+    ) -> Result<impl Iterator<Item = (&String, &OffsetLen)>> {
+        Ok(self
+            .live(now)?
+            .filter_map(|(key, off2len)| Some((key.as_string()?, off2len))))
+    }
+
+    // the offsets and lengths of the resident entries may be changed, not
+    // the expiration times, then recount_bytes has to be called
+    pub(crate) fn iter_mut(&mut self) -> IterMut<'_> {
+        self.changed_all();
+        match &mut self.map {
+            Map::Hash(map) => Box::new(map.iter_mut()),
+            Map::Ordered(map) => Box::new(map.iter_mut()),
+        }
+    }
+
+    // sum the lengths of the entries again, after iter_mut changed them
+    pub(crate) fn recount_bytes(&mut self) {
+        let mut bytes = HashMap::new();
+        for (_, value) in self.resident_iter() {
+            *bytes.entry(value.segment).or_default() += value.len as u64;
+        }
+        if let Some(run) = &self.run {
+            *bytes.entry(run.segment).or_default() += self.run_bytes;
+        }
+        self.bytes = bytes;
+    }
+
+    // the entries whose key is in range, always in key order
+    pub(crate) fn range<R: RangeBounds<String>>(
+        &self,
+        range: R,
+    ) -> Result<Vec<(&Key, &OffsetLen)>> {
+        let range = (bytes(range.start_bound()), bytes(range.end_bound()));
+        match &self.map {
+            Map::Ordered(map) if self.run.is_none() => Ok(map.range::<[u8], _>(range).collect()),
+            _ => {
+                let mut entries: Vec<_> = self
+                    .iter()?
+                    .filter(|kv| range.contains(kv.0.as_bytes()))
+                    .collect();
+                entries.sort_by(|l, r| l.0.cmp(r.0));
+                Ok(entries)
+            }
+        }
+    }
+}
+
+// the expiration times of the entries, which count the expired ones as
+// time passes without walking the index: every deadline is passed once
+#[derive(Default)]
+struct Deadlines(Mutex<Schedule>);
+
+#[derive(Clone, Default)]
+struct Schedule {
+    pending: BTreeMap<u64, usize>, // the number of entries by expiration time
+    passed_at: u64,                // the time the deadlines were passed up to
+    passed: usize,                 // the entries expired by then
+}
+
+impl Deadlines {
+    fn add(&mut self, expires_at: u64) {
+        let schedule = self.schedule();
+        if expires_at <= schedule.passed_at {
+            schedule.passed += 1;
+        } else {
+            *schedule.pending.entry(expires_at).or_default() += 1;
+        }
+    }
+
+    fn forget(&mut self, expires_at: u64) {
+        let schedule = self.schedule();
+        if expires_at <= schedule.passed_at {
+            schedule.passed -= 1;
+        } else if let Some(count) = schedule.pending.get_mut(&expires_at) {
+            *count -= 1;
+            if *count == 0 {
+                schedule.pending.remove(&expires_at);
+            }
+        }
+    }
+
+    // the number of entries expired at now; a clock set back does not
+    // bring the ones counted back
+    fn passed(&self, now: u64) -> usize {
+        let mut schedule = lock(&self.0);
+        if now > schedule.passed_at {
+            while let Some(first) = schedule.pending.first_entry() {
+                if *first.key() > now {
+                    break;
+                }
+                schedule.passed += first.remove();
+            }
+            schedule.passed_at = now;
+        }
+        schedule.passed
+    }
+
+    fn schedule(&mut self) -> &mut Schedule {
+        self.0
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Clone for Deadlines {
+    fn clone(&self) -> Deadlines {
+        Deadlines(Mutex::new(lock(&self.0).clone()))
+    }
+}
+
+fn lock(schedule: &Mutex<Schedule>) -> std::sync::MutexGuard<'_, Schedule> {
+    // the counts are whole whatever panicked while it was held
+    schedule
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn strip_inline(value: &mut OffsetLen, max: usize) {
+    if value
+        .inline
+        .as_ref()
+        .is_some_and(|inline| max == 0 || inline.len() > max)
+    {
+        value.inline = None;
+    }
+}
+
+// the entries of two iterators in key order, merged in key order
+fn merge_sorted<'a>(
+    left: impl Iterator<Item = (&'a Key, &'a OffsetLen)>,
+    right: impl Iterator<Item = (&'a Key, &'a OffsetLen)>,
+) -> impl Iterator<Item = (&'a Key, &'a OffsetLen)> {
+    let (mut left, mut right) = (left.peekable(), right.peekable());
+    iter::from_fn(move || match (left.peek(), right.peek()) {
+        (Some(l), Some(r)) if r.0 < l.0 => right.next(),
+        (Some(_), _) => left.next(),
+        (None, _) => right.next(),
+    })
+}
+
+fn key_capacity(key: &Key) -> usize {
+    match key {
+        Key::Text(text) => text.capacity(),
+        Key::Bytes(bytes) => bytes.capacity(),
+    }
+}
+
+// take the length of an entry dropped from the index off bytes
+fn forget_bytes(bytes: &mut HashMap<u32, u64>, old: &OffsetLen) {
+    forget_segment_bytes(bytes, old.segment, old.len as u64);
+}
+
+// take len off the bytes of segment
+fn forget_segment_bytes(bytes: &mut HashMap<u32, u64>, segment: u32, len: u64) {
+    if let Some(sum) = bytes.get_mut(&segment) {
+        *sum -= len;
+        if *sum == 0 {
+            bytes.remove(&segment);
+        }
+    }
+}
+
+// a bound on String keys as a bound on their bytes
+fn bytes(bound: Bound<&String>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
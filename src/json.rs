@@ -0,0 +1,161 @@
+use crate::{KvStore, KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+// the number of pairs imported with a single log write
+const IMPORT_BATCH: usize = 1024;
+
+// one line of an exported file
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    key: String,
+    value: String,
+}
+
+/// What `KvStore::import_json` did with the imported pairs.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// the keys which did not exist
+    pub inserted: usize,
+    /// the existing keys which got the imported value
+    pub overwritten: usize,
+    /// the existing keys which kept their value
+    pub skipped: usize,
+}
+
+impl KvStore {
+    /// Inserts the JSON serialization of `value` as the value of a key.
+    pub fn set_json<T: Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+        let value = serde_json::to_string(value)?;
+        self.set(key, value)?;
+        Ok(())
+    }
+
+    /// Returns the value of a key deserialized from JSON.
+    ///
+    /// A value which is not the JSON of a `T` is reported as a
+    /// `KvsError::InvalidJsonValue` error naming the key.
+    pub fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let value = match self.get(String::from(key))? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match serde_json::from_str(&value) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => Err(KvsError::InvalidJsonValue {
+                key: String::from(key),
+                reason: err.to_string(),
+            }
+            .into()),
+        }
+    }
+
+    /// Writes every live key-value pair to the file at `path`, which is
+    /// created or truncated. Returns the number of pairs written.
+    ///
+    /// The file is newline-delimited JSON, one `{"key": ..., "value": ...}`
+    /// object per line, written as the values are read from the log.
+    /// A value which is not UTF-8 fails the export with a
+    /// `KvsError::BinaryValue` error.
+    pub fn export_json(&mut self, path: impl AsRef<Path>) -> Result<u64> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut count = 0;
+        for pair in self.iter() {
+            let (key, value) = pair?;
+            serde_json::to_writer(&mut writer, &JsonPair { key, value })?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Loads the key-value pairs of the file at `path` into the KvStore.
+    ///
+    /// The file is either the newline-delimited JSON written by `export_json`,
+    /// recognized by its first line, or a single JSON object mapping keys to
+    /// values. Existing keys get the imported value only if `overwrite` is set.
+    /// The whole file is parsed before anything is written, a malformed record
+    /// is reported as a `KvsError::MalformedImport` error with its line, and
+    /// a pair over the size limits fails the import too. The pairs are then
+    /// written in batches.
+    pub fn import_json(&mut self, path: impl AsRef<Path>, overwrite: bool) -> Result<ImportReport> {
+        let pairs = read_json_pairs(path.as_ref())?;
+        for (key, value) in pairs.iter() {
+            self.check_size(key.as_bytes(), value.as_bytes())?;
+        }
+        let mut report = ImportReport::default();
+        let mut batch = Vec::new();
+        let mut batch_keys = HashSet::new();
+        for (key, value) in pairs {
+            let exists = self.contains_key(&key) || batch_keys.contains(&key);
+            if exists && !overwrite {
+                report.skipped += 1;
+                continue;
+            }
+            if exists {
+                report.overwritten += 1;
+            } else {
+                report.inserted += 1;
+            }
+            batch_keys.insert(key.clone());
+            batch.push((key, value));
+            if batch.len() == IMPORT_BATCH {
+                self.set_batch(mem::take(&mut batch))?;
+                batch_keys.clear();
+            }
+        }
+        self.set_batch(batch)?;
+        Ok(report)
+    }
+}
+
+fn read_json_pairs(path: &Path) -> Result<Vec<(String, String)>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut pairs = Vec::new();
+    let mut line = String::new();
+    let mut line_no = 0;
+    // the first line tells newline-delimited pairs from a JSON object
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(pairs);
+        }
+        line_no += 1;
+        if !line.trim().is_empty() {
+            break;
+        }
+    }
+    if serde_json::from_str::<JsonPair>(&line).is_err() {
+        // parsed from a string, the reader would report the line after a peek
+        let mut text = String::new();
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_string(&mut text)?;
+        let map: BTreeMap<String, String> =
+            serde_json::from_str(&text).map_err(|err| KvsError::MalformedImport {
+                line: err.line(),
+                reason: err.to_string(),
+            })?;
+        return Ok(map.into_iter().collect());
+    }
+    loop {
+        if !line.trim().is_empty() {
+            let pair: JsonPair =
+                serde_json::from_str(&line).map_err(|err| KvsError::MalformedImport {
+                    line: line_no,
+                    reason: err.to_string(),
+                })?;
+            pairs.push((pair.key, pair.value));
+        }
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(pairs);
+        }
+        line_no += 1;
+    }
+}
@@ -1,20 +1,115 @@
 #![deny(missing_docs)]
 //! kvs is an in-memory key/value store
 extern crate serde;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::seq::IteratorRandom;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::RangeBounds;
 use std::os::unix::fs::FileExt;
-use std::path::PathBuf;
-use std::result;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 extern crate failure;
-use failure::Error;
 
-/// the alias of result::Result
-pub type Result<T> = result::Result<T, Error>;
+#[cfg(all(feature = "encryption", feature = "test-internals"))]
+pub mod aead;
+mod backup;
+mod bucket;
+mod cache;
+mod checkpoint;
+mod checksum;
+mod client;
+mod close;
+mod compact;
+mod compress;
+mod crypt;
+mod dump;
+mod engine;
+mod error;
+mod fsync;
+mod header;
+mod hint;
+mod history;
+mod index;
+mod json;
+mod lock;
+mod memory;
+mod merge;
+mod migrate;
+mod options;
+pub mod protocol;
+mod record;
+mod recovery;
+mod resp;
+mod run;
+mod segment;
+mod server;
+mod shared;
+mod snapshot;
+mod stats;
+mod stream;
+mod subscribe;
+mod sync;
+pub mod thread_pool;
+mod txn;
+mod verify;
+#[cfg(feature = "bench-internals")]
+pub mod workload;
+mod writer;
+pub use backup::BackupStats;
+pub use bucket::Bucket;
+use cache::ValueCache;
+pub use checkpoint::CheckpointInfo;
+pub use client::{KvsClient, KvsClientBuilder, Pipeline};
+pub use compact::CompactionStats;
+use compact::{copy_records, install_copy};
+use compress::saved_bytes;
+pub use compress::Compression;
+use crypt::{check_key, move_records, open_record, seal_record, Cipher, Place};
+use engine::{check_engine, KVS_ENGINE};
+pub use engine::{engine_marker, KvsEngine, SharedEngine};
+pub use error::{KvsError, Result};
+use fsync::replace_file;
+pub use fsync::{FileSync, OsFileSync};
+use header::{record_format, records_start, Header};
+use hint::load_hint;
+pub use history::HistoryEntry;
+use index::{Index, Key, Meta, OffsetLen};
+pub use json::ImportReport;
+use lock::lock_dir;
+pub use memory::MemKvStore;
+pub use merge::{MergeReport, MergeStrategy};
+pub use migrate::MigrateReport;
+pub use options::KvStoreOptions;
+pub use record::LogFormat;
+use record::{decode, decode_value, encode, encode_set, read_next, SetDataRef};
+pub use recovery::{RecoveryMode, RecoveryReport};
+use run::replay_run;
+use segment::{missing_segment, remove_swap_files, segment_ids, segment_name, swap_name, Segments};
+pub use server::{KvsServer, ServerProtocol, ShutdownHandle};
+pub use shared::{SharedKvStore, SharedReadGuard, SharedWriteGuard};
+pub use snapshot::Snapshot;
+pub use stats::StoreStats;
+use subscribe::Subscribers;
+pub use subscribe::{ChangeEvent, SubscriptionId};
+pub use sync::SyncPolicy;
+use sync::SyncState;
+pub use txn::Txn;
+pub use verify::VerifyReport;
+use writer::LogWriter;
+
+// the number of pairs written with a single log write by try_extend
+const EXTEND_BATCH: usize = 1024;
+// the temporary files this process created, which make their names unique
+static SWAP_FILES: AtomicUsize = AtomicUsize::new(0);
 
 /// opt data
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,132 +120,571 @@ pub enum OptData {
         key: String,
         /// value
         value: String,
+        /// expiration time in ms since the unix epoch, absent for no expiration
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// the number of sets of the key, 0 in logs written before versions
+        #[serde(default)]
+        version: u64,
+        /// creation time of the key in ms since the unix epoch, 0 if unknown
+        #[serde(default)]
+        created_at: u64,
+        /// time of this set in ms since the unix epoch, 0 if unknown
+        #[serde(default)]
+        updated_at: u64,
+        /// sequence number of the record, 0 in logs written before them
+        #[serde(default)]
+        seq: u64,
+        /// true if value is the base64 of bytes which are not UTF-8, or which
+        /// were streamed by `KvStore::set_from_reader`
+        #[serde(default, skip_serializing_if = "is_false")]
+        base64: bool,
+        /// true if key is the base64 of bytes which are not UTF-8
+        #[serde(default, skip_serializing_if = "is_false")]
+        key_base64: bool,
+        /// the length of the value before compression, absent if it is not
+        /// compressed: value is then the base64 of its DEFLATE stream, see
+        /// `KvStoreOptions::compression`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compressed: Option<u64>,
     },
     /// remove data: key
     RmData {
         /// key
         key: String,
+        /// true if key is the base64 of bytes which are not UTF-8
+        #[serde(default, skip_serializing_if = "is_false")]
+        key_base64: bool,
+        /// sequence number of the record, 0 in logs written before them
+        #[serde(default)]
+        seq: u64,
     },
     /// get data: key
     GetData {
         /// key
         key: String,
     },
+    /// transaction: the number of records which follow and are applied
+    /// together, or not at all if the log ends before the last of them
+    TxnData {
+        /// the number of records of the transaction
+        count: usize,
+    },
+    /// sequence number: the last one used, logged by `clear` and by a
+    /// compaction which dropped the record holding it
+    SeqData {
+        /// sequence number
+        seq: u64,
+    },
 }
 
-#[derive(Debug)]
-struct OffsetLen {
-    offset: usize, // the offset of serialized OptData in log file
-    len: usize,    // the length of serialized OptData(include '\n')
+/// The metadata of a key, see `KvStore::metadata`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// the number of sets of the key since it was created
+    pub version: u64,
+    /// when the key was first set
+    pub created_at: SystemTime,
+    /// when the key was last set
+    pub updated_at: SystemTime,
 }
 
-/// KvStore store the key-value in HashMap
-pub struct KvStore {
-    kvs: HashMap<String, OffsetLen>,
-    log: Option<File>, // the object of log file
-    log_off: usize,    // current offset of log file
-    log_name: PathBuf, // the name of log file
+/// What `KvStore::set_if_version` did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SetIfResult {
+    /// the value was set
+    Written {
+        /// the new version of the key
+        version: u64,
+    },
+    /// the key had another version, nothing was written
+    Conflict {
+        /// the current version of the key, 0 if it does not exist
+        version: u64,
+        /// the current value of the key
+        value: Option<String>,
+    },
 }
 
-impl Default for KvStore {
-    fn default() -> Self {
-        match env::current_dir() {
-            Ok(pathbuf) => match KvStore::open(pathbuf) {
-                Ok(kv) => kv,
-                Err(_) => KvStore {
-                    kvs: HashMap::new(),
-                    log: None,
-                    log_off: 0,
-                    log_name: PathBuf::new(),
-                },
-            },
-            Err(_) => KvStore {
-                kvs: HashMap::new(),
-                log: None,
-                log_off: 0,
-                log_name: PathBuf::new(),
-            },
-        }
-    }
+/// KvStore store the key-value in HashMap
+pub struct KvStore {
+    kvs: Index,
+    log: Option<File>,                    // the object of log file
+    segment: u32,                         // the id of the active segment, log
+    segments: Segments,                   // every segment of the log, for reads
+    writer: Option<BufWriter<LogWriter>>, // appends to log, flushed by every write
+    log_off: u64,                         // current offset of log file
+    header: Option<Header>,               // None for a log written before headers
+    log_name: PathBuf,                    // the name of log file
+    snapshots: Arc<()>,                   // cloned by every live Snapshot
+    subscribers: Subscribers,
+    cache: ValueCache,    // the values read last
+    next_seq: u64,        // the sequence number of the next record
+    compactions: usize,   // the number of rebuilds of the log since open
+    truncated_bytes: u64, // the bytes of a torn record open cut off the log
+    sync: SyncState,
+    read_only: bool,     // set by open_at and open_read_only
+    _lock: Option<File>, // holds the lock of the log while the KvStore has it open
+    options: KvStoreOptions,
 }
 
 impl KvStore {
     /// Inserts a key-value pair into the KvStore.
     ///
+    /// Returns true if the key was not in the KvStore before, false if its
+    /// value got overwritten.
+    ///
+    /// Every set is logged and counts a version, unless the options tell it
+    /// to write nothing when the key already has this value and no
+    /// expiration, see `KvStoreOptions::skip_unchanged_writes`. A key or a
+    /// value longer than the limits of `KvStoreOptions` is refused with a
+    /// `KvsError::KeyTooLarge` or `KvsError::ValueTooLarge` error.
+    ///
     /// # Examples
     ///
     /// ```
     /// use kvs::KvStore;
+    /// # use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::new();
+    /// # fn main() -> kvs::Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
     ///
-    /// store.set("key1".to_owned(), "value1".to_owned());
-    /// assert_eq!(store.get("key1".to_owned()), Some("value1".to_owned()));
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn set(&mut self, k: String, v: String) -> Result<()> {
-        let data = OptData::SetData {
-            key: String::from(&k),
-            value: String::from(&v),
+    pub fn set(&mut self, k: String, v: String) -> Result<bool> {
+        self.set_value(Key::from(k), Value::Text(v))
+    }
+
+    /// Inserts a key with an arbitrary byte value into the KvStore.
+    ///
+    /// Bytes which are valid UTF-8 are logged as text and can be read by
+    /// `get` too, other bytes are logged as base64 and only `get_bytes`
+    /// reads them.
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set_value(Key::from(key), Value::from_bytes(value))?;
+        Ok(())
+    }
+
+    /// Inserts a key-value pair with arbitrary bytes as key and value.
+    ///
+    /// A key which is valid UTF-8 is the same key for the `String` API, other
+    /// keys are logged as base64 and only the `_raw` methods reach them:
+    /// `keys`, `iter` and the other methods yielding `String` keys skip them.
+    pub fn set_raw(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_value(Key::from_bytes(key), Value::from_bytes(value))?;
+        Ok(())
+    }
+
+    fn set_value(&mut self, k: Key, v: Value) -> Result<bool> {
+        self.check_writable()?;
+        self.check_size(k.as_bytes(), v.as_bytes())?;
+        if self.options.skip_unchanged_writes && self.is_unchanged(k.as_bytes(), &v)? {
+            return Ok(false);
+        }
+        self.set_record(k, v, None)
+    }
+
+    // true if the key is live with the value and no expiration
+    fn is_unchanged(&self, key: &[u8], value: &Value) -> Result<bool> {
+        let off2len = match self.kvs.get(key)? {
+            Some(off2len) if off2len.expires_at.is_none() => off2len,
+            _ => return Ok(false),
         };
-        let mut offset: usize = self.log_off;
-        match self.kvs.get(&k) {
-            Some(off2len) => {
-                offset = off2len.offset;
+        Ok(read_record(&self.segments, &off2len)?.as_ref() == Some(value))
+    }
+
+    /// Inserts a key-value pair into the KvStore which expires after `ttl`.
+    ///
+    /// The expiration time is logged with the value, so it still holds after
+    /// a reopen. Once expired the key is treated as absent, and it is dropped
+    /// for good by the next log rebuild. A later `set` clears the expiration.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.check_size(key.as_bytes(), value.as_bytes())?;
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_record(Key::from(key), Value::Text(value), Some(expires_at))?;
+        Ok(())
+    }
+
+    /// Returns the time left before the key expires, `None` if the key does
+    /// not exist or has no expiration.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let now = now_millis();
+        match self.kvs.lookup(key.as_bytes()).as_deref() {
+            Some(OffsetLen {
+                expires_at: Some(expires_at),
+                ..
+            }) if *expires_at > now => Some(Duration::from_millis(expires_at - now)),
+            _ => None,
+        }
+    }
+
+    /// Returns the version and the creation and update times of a key, `None`
+    /// if it does not exist.
+    ///
+    /// The version is 1 for the first set of the key and grows by one with
+    /// every logged set, so it can be used as a fencing token. A removed key starts
+    /// over at 1. Keys last written before versions were logged have
+    /// version 0 and their times are `UNIX_EPOCH`.
+    pub fn metadata(&self, key: &str) -> Option<KeyMetadata> {
+        let meta = self.live_meta(key.as_bytes()).ok().flatten();
+        meta.map(|meta| KeyMetadata {
+            version: meta.version,
+            created_at: UNIX_EPOCH + Duration::from_millis(meta.created_at),
+            updated_at: UNIX_EPOCH + Duration::from_millis(meta.updated_at),
+        })
+    }
+
+    // the metadata of the key unless it is missing or expired
+    fn live_meta(&self, key: &[u8]) -> Result<Option<Meta>> {
+        let now = now_millis();
+        match self.kvs.get(key)? {
+            Some(off2len) if !off2len.is_expired(now) => Ok(Some(off2len.meta)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Removes the expiration of a key, which then lives until removed.
+    ///
+    /// The value is logged again without expiration so this holds after a
+    /// reopen. Returns false, writing nothing, if the key does not exist or
+    /// has no expiration.
+    pub fn persist(&mut self, key: &str) -> Result<bool> {
+        if self.ttl(key).is_none() {
+            return Ok(false);
+        }
+        match self.get_value(key.as_bytes())? {
+            Some(value) => {
+                self.set_record(Key::from(String::from(key)), value, None)?;
+                Ok(true)
             }
-            None => {}
+            None => Ok(false),
         }
-        let mut data_str = serde_json::to_string(&data)?;
-        data_str.push('\n');
-        let value = self.kvs.entry(k).or_insert(OffsetLen {
-            offset: offset,
-            len: data_str.len(),
-        });
-        if value.len != data_str.len() {
-            self.rebuild_log(offset, &data_str)?;
-        } else {
-            match &mut self.log {
-                Some(log) => {
-                    log.write_at(data_str.as_bytes(), offset as u64)?;
-                    if offset + data_str.len() > self.log_off {
-                        self.log_off = offset + data_str.len();
-                    }
-                }
-                None => {}
+    }
+
+    fn set_record(&mut self, k: Key, v: Value, expires_at: Option<u64>) -> Result<bool> {
+        self.check_writable()?;
+        let meta = Meta::next(self.live_meta(k.as_bytes())?.as_ref(), now_millis());
+        let seq = self.take_seq();
+        let data = SetDataRef {
+            key: k.as_bytes(),
+            value: v.as_bytes(),
+            expires_at,
+            meta,
+            seq,
+            deflated: self.deflate_value(v.as_bytes()),
+        };
+        let record = self.encode_set_record(&data, 0)?;
+        let saved = data.saved();
+        // the old record, if any, is left as garbage for compaction
+        let offset = self.append_log(&record)?;
+        self.cache.invalidate(k.as_bytes());
+        self.subscribers
+            .notify(seq, k.as_bytes(), Some(v.as_bytes()));
+        let inline = self.inline(v.as_bytes());
+        let old = self.kvs.insert(
+            k,
+            OffsetLen {
+                segment: self.segment,
+                saved,
+                offset,
+                len: record.len(),
+                expires_at,
+                meta,
+                inline,
+            },
+        )?;
+        let inserted = old.is_none_or(|old| old.is_expired(now_millis()));
+        self.maybe_compact()?;
+        Ok(inserted)
+    }
+
+    /// Inserts all the key-value pairs into the KvStore with a single log write.
+    ///
+    /// The index is only updated once the write succeeded, so on error none of
+    /// the pairs is inserted. A key given twice takes its last value. The
+    /// sizes of all the pairs are checked before anything is written.
+    pub fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs.iter() {
+            self.check_size(key.as_bytes(), value.as_bytes())?;
+        }
+        let mut buf = Vec::new();
+        let mut records = Vec::with_capacity(pairs.len());
+        // the metadata of the keys set earlier in the batch
+        let mut metas: HashMap<&str, Meta> = HashMap::new();
+        let now = now_millis();
+        for (key, value) in pairs.iter() {
+            let old = match metas.get(key.as_str()) {
+                Some(meta) => Some(*meta),
+                None => self.live_meta(key.as_bytes())?,
+            };
+            let meta = Meta::next(old.as_ref(), now);
+            metas.insert(key, meta);
+            let seq = self.take_seq();
+            let data = SetDataRef {
+                key: key.as_bytes(),
+                value: value.as_bytes(),
+                expires_at: None,
+                meta,
+                seq,
+                deflated: self.deflate_value(value.as_bytes()),
+            };
+            let record = self.encode_set_record(&data, buf.len())?;
+            buf.extend_from_slice(&record);
+            records.push((record.len(), data.saved(), meta, seq));
+        }
+        let mut offset = self.append_log(&buf)?;
+        for ((key, value), (len, saved, meta, seq)) in pairs.into_iter().zip(records) {
+            self.subscribers
+                .notify(seq, key.as_bytes(), Some(value.as_bytes()));
+            let inline = self.inline(value.as_bytes());
+            self.kvs.insert(
+                Key::from(key),
+                OffsetLen {
+                    segment: self.segment,
+                    saved,
+                    offset,
+                    len,
+                    expires_at: None,
+                    meta,
+                    inline,
+                },
+            )?;
+            offset += len as u64;
+        }
+        self.maybe_compact()
+    }
+
+    /// Inserts all the key-value pairs of `iter` into the KvStore, in batches
+    /// of `set_batch` and with a single sync to disk at the end. Returns the
+    /// number of pairs.
+    ///
+    /// On error the pairs of the batches written so far are in the KvStore,
+    /// the others are not.
+    pub fn try_extend<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        iter: I,
+    ) -> Result<usize> {
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(EXTEND_BATCH);
+        for pair in iter {
+            batch.push(pair);
+            count += 1;
+            if batch.len() == EXTEND_BATCH {
+                self.set_batch(mem::take(&mut batch))?;
             }
         }
-        Ok(())
+        if !batch.is_empty() {
+            self.set_batch(batch)?;
+        }
+        self.sync()?;
+        Ok(count)
     }
 
-    /// Removes a key from the KvStore
+    /// Removes a key from the KvStore and returns its value.
+    ///
+    /// Returns a `KvsError::KeyNotFound` error if the key does not exist.
     ///
     /// # Examples
     ///
     /// ```
     /// use kvs::KvStore;
+    /// # use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::new();
+    /// # fn main() -> kvs::Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
     ///
-    /// store.set("key1".to_owned(), "value1".to_owned());
-    /// store.remove("key1".to_owned());
-    /// assert_eq!(store.get("key1".to_owned()), None);
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// assert_eq!(store.remove("key1".to_owned())?, "value1");
+    /// assert_eq!(store.get("key1".to_owned())?, None);
+    /// # Ok(())
+    /// # }
     /// ```
     pub fn remove(&mut self, k: String) -> Result<String> {
-        let data = OptData::RmData {
-            key: String::from(&k),
+        self.check_writable()?;
+        // read the value before its record becomes garbage
+        let value = match self.get(String::from(&k))? {
+            Some(value) => value,
+            None => return Err(KvsError::KeyNotFound.into()),
+        };
+        self.remove_key(k.as_bytes())?;
+        Ok(value)
+    }
+
+    /// Removes a key set by `set_raw`, or any other key by its bytes, and
+    /// returns its value as bytes.
+    ///
+    /// Returns a `KvsError::KeyNotFound` error if the key does not exist.
+    pub fn remove_raw(&mut self, key: &[u8]) -> Result<Vec<u8>> {
+        self.check_writable()?;
+        let value = match self.get_value(key)? {
+            Some(value) => value,
+            None => return Err(KvsError::KeyNotFound.into()),
         };
-        let mut data_str = serde_json::to_string(&data)?;
-        match &mut self.log {
-            Some(log) => {
-                data_str.push('\n');
-                log.write_all(data_str.as_bytes())?;
-                self.log_off += data_str.len();
+        self.remove_key(key)?;
+        Ok(value.into_bytes())
+    }
+
+    // log the tombstone of an existing key and drop it from the index
+    fn remove_key(&mut self, key: &[u8]) -> Result<()> {
+        let seq = self.take_seq();
+        self.append_log(&self.encode_record(&rm_data(key, seq), 0)?)?;
+        self.kvs.remove(key)?;
+        self.cache.invalidate(key);
+        self.subscribers.notify(seq, key, None);
+        self.maybe_compact()
+    }
+
+    /// Removes all the keys from the KvStore with a single log write.
+    ///
+    /// Tombstones are only written for the keys which exist. Returns those
+    /// keys, the missing ones are skipped.
+    pub fn remove_batch(&mut self, keys: Vec<String>) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        let mut seen = HashSet::new();
+        for key in keys {
+            if self.contains_key(&key) && seen.insert(key.clone()) {
+                removed.push(key);
             }
-            None => {}
         }
-        match self.kvs.remove(&k) {
-            Some(_) => Ok(String::from(&k)),
-            None => Err(failure::format_err!("Key not found")),
+        self.remove_keys(&removed)?;
+        if self.kvs.is_sparse() {
+            self.kvs.shrink_to_fit();
+        }
+        Ok(removed)
+    }
+
+    // log the tombstones of distinct existing keys with a single write and
+    // drop them from the index
+    fn remove_keys<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        let mut seqs = Vec::with_capacity(keys.len());
+        for key in keys {
+            let seq = self.take_seq();
+            self.push_record(&mut buf, &rm_data(key.as_ref(), seq))?;
+            seqs.push(seq);
+        }
+        self.append_log(&buf)?;
+        for (key, seq) in keys.iter().zip(seqs) {
+            self.kvs.remove(key.as_ref())?;
+            self.subscribers.notify(seq, key.as_ref(), None);
         }
+        self.maybe_compact()
+    }
+
+    /// Moves the value of `from`, and its expiration, to the key `to`.
+    ///
+    /// `to` is overwritten if it exists. Returns a `KvsError::KeyNotFound`
+    /// error if `from` does not exist. The set of `to` and the tombstone of
+    /// `from` are logged with a single write.
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let value = match self.get_value(from.as_bytes())? {
+            Some(value) => value,
+            None => return Err(KvsError::KeyNotFound.into()),
+        };
+        if from == to {
+            return Ok(());
+        }
+        self.check_size(to.as_bytes(), value.as_bytes())?;
+        let expires_at = self.kvs.get(from.as_bytes())?.and_then(|v| v.expires_at);
+        let meta = Meta::next(self.live_meta(to.as_bytes())?.as_ref(), now_millis());
+        let mut buf = Vec::new();
+        let seq = self.take_seq();
+        let set = self.compress(set_data(
+            to.as_bytes(),
+            value.clone(),
+            expires_at,
+            meta,
+            seq,
+        ))?;
+        let len = self.push_record(&mut buf, &set)?;
+        let rm_seq = self.take_seq();
+        self.push_record(&mut buf, &rm_data(from.as_bytes(), rm_seq))?;
+        let offset = self.append_log(&buf)?;
+        self.subscribers
+            .notify(seq, to.as_bytes(), Some(value.as_bytes()));
+        self.subscribers.notify(rm_seq, from.as_bytes(), None);
+        self.kvs.insert(
+            Key::from(to),
+            OffsetLen {
+                segment: self.segment,
+                saved: saved_bytes(&set),
+                offset,
+                len,
+                expires_at,
+                meta,
+                inline: self.inline(value.as_bytes()),
+            },
+        )?;
+        self.kvs.remove(from.as_bytes())?;
+        self.maybe_compact()
+    }
+
+    /// Copies the value of `from`, and its expiration, to the key `to`.
+    ///
+    /// The value is read from the log and appended again under `to`. Returns
+    /// false if `from` does not exist. An existing `to` is replaced when
+    /// `overwrite` is set, otherwise a `KvsError::KeyExists` error is returned.
+    pub fn copy(&mut self, from: &str, to: String, overwrite: bool) -> Result<bool> {
+        let value = match self.get_value(from.as_bytes())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if !overwrite && self.contains_key(&to) {
+            return Err(KvsError::KeyExists { key: to }.into());
+        }
+        self.check_size(to.as_bytes(), value.as_bytes())?;
+        let expires_at = self.kvs.get(from.as_bytes())?.and_then(|v| v.expires_at);
+        let meta = Meta::next(self.live_meta(to.as_bytes())?.as_ref(), now_millis());
+        let mut buf = Vec::new();
+        let seq = self.take_seq();
+        let set = self.compress(set_data(
+            to.as_bytes(),
+            value.clone(),
+            expires_at,
+            meta,
+            seq,
+        ))?;
+        let len = self.push_record(&mut buf, &set)?;
+        let offset = self.append_log(&buf)?;
+        self.subscribers
+            .notify(seq, to.as_bytes(), Some(value.as_bytes()));
+        self.kvs.insert(
+            Key::from(to),
+            OffsetLen {
+                segment: self.segment,
+                saved: saved_bytes(&set),
+                offset,
+                len,
+                expires_at,
+                meta,
+                inline: self.inline(value.as_bytes()),
+            },
+        )?;
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    /// Removes every key for which `f` returns false, with a single log write.
+    ///
+    /// `f` only sees the keys, no value is read from the log. Returns the
+    /// number of removed keys.
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut f: F) -> Result<usize> {
+        let doomed: Vec<String> = self
+            .kvs
+            .live(now_millis())?
+            .filter_map(|kv| kv.0.as_string())
+            .filter(|key| !f(key))
+            .cloned()
+            .collect();
+        Ok(self.remove_batch(doomed)?.len())
     }
 
     /// Returns a copy of the value corresponding to the key.
@@ -159,115 +693,1311 @@ impl KvStore {
     ///
     /// ```
     /// use kvs::KvStore;
+    /// # use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::new();
+    /// # fn main() -> kvs::Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
     ///
-    /// store.set("key1".to_owned(), "value1".to_owned());
-    /// assert_eq!(store.get("key1".to_owned()), Some("value1".to_owned()));
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn get(&mut self, k: String) -> Result<Option<String>> {
-        let off2len = match self.kvs.get(&k) {
+    pub fn get(&self, k: String) -> Result<Option<String>> {
+        self.get_value(k.as_bytes())?
+            .map(Value::into_string)
+            .transpose()
+    }
+
+    /// Returns a copy of the value corresponding to the key as bytes, for
+    /// values set by `set_bytes` as well as by `set`.
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_value(key.as_bytes())?.map(Value::into_bytes))
+    }
+
+    /// Returns a copy of the value corresponding to the key as bytes, for
+    /// keys set by `set_raw` as well as by `set`.
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_value(key)?.map(Value::into_bytes))
+    }
+
+    // an expired entry is left in the index, the next rebuild of the log
+    // drops it
+    fn get_value(&self, k: &[u8]) -> Result<Option<Value>> {
+        let off2len = match self.kvs.get(k)? {
             Some(v) => v,
             None => {
                 return Ok(None);
             }
         };
-        match &mut self.log {
-            Some(log) => {
-                let mut buf = String::new();
-                log.seek(SeekFrom::Start(off2len.offset as u64))?;
-                log.take(off2len.len as u64).read_to_string(&mut buf)?;
-                let decode: OptData = serde_json::from_str(&buf)?;
-                match decode {
-                    OptData::SetData { key: _, value } => return Ok(Some(value)),
-                    _ => return Ok(None),
+        if off2len.is_expired(now_millis()) {
+            return Ok(None);
+        }
+        if let Some(value) = self.cache.get(k, &off2len) {
+            return Ok(Some(value));
+        }
+        let value = read_record_checked(&self.segments, &off2len, self.options.verify_checksums)?;
+        if let Some(value) = &value {
+            self.cache.insert(k, &off2len, value);
+        }
+        Ok(value)
+    }
+
+    // the copy of value an index entry keeps, if it is small enough, see
+    // `KvStoreOptions::inline_values`
+    fn inline(&self, value: &[u8]) -> Option<Box<str>> {
+        let max = self.kvs.inline_max();
+        if max == 0 || value.len() > max {
+            return None;
+        }
+        str::from_utf8(value).ok().map(Box::from)
+    }
+
+    /// Returns the values of all the keys, in the order of `keys`.
+    ///
+    /// The records are read in log order rather than in the order of `keys`
+    /// to avoid random seeks. A missing key gets `None`.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let now = now_millis();
+        let kvs = &self.kvs;
+        let mut sorted: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| match kvs.get(key.as_bytes()) {
+                Ok(Some(off2len)) if off2len.is_expired(now) => None,
+                Ok(Some(off2len)) => Some(Ok((i, off2len))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<_>>()?;
+        sorted.sort_by_key(|kv| kv.1.position());
+        let mut values = vec![None; keys.len()];
+        for (i, off2len) in sorted {
+            values[i] = read_value(&self.segments, &off2len)?;
+        }
+        Ok(values)
+    }
+
+    /// Sets the key to `new` only if its current value is `expected`.
+    ///
+    /// `None` stands for an absent key, so a `None` expected value asks for the
+    /// key to be missing and a `None` new value removes it. Returns whether the
+    /// swap happened. The `&mut self` receiver keeps the read and the write
+    /// from interleaving with any other operation on the KvStore.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let current = self.get(String::from(&key))?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                self.set(key, value)?;
+            }
+            None => {
+                if current.is_some() {
+                    self.remove(key)?;
                 }
             }
-            None => {}
-        }
-        Ok(None)
-    }
-
-    // create a temporary log file to rebuild the new log
-    // then rename temporary log file to self.log_name
-    fn rebuild_log(&mut self, offset: usize, data: &String) -> Result<()> {
-        let mut sorted: Vec<_> = self.kvs.iter_mut().collect();
-        sorted.sort_by(|l, r| l.1.offset.cmp(&r.1.offset));
-
-        let mut new_path = PathBuf::from(&self.log_name);
-        new_path.set_file_name("kvs.log.swp");
-        let new_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&new_path)?;
-
-        let mut new_file_offset: i32 = 0;
-        let mut diff: i32 = 0;
-        match &mut self.log {
-            Some(log) => {
-                for (_, value) in sorted.iter_mut() {
-                    let mut buf = String::new();
-                    if offset == value.offset {
-                        if data.len() != value.len {
-                            diff = data.len() as i32 - value.len as i32;
-                        }
-                        buf = String::from(data);
-                    } else {
-                        log.seek(SeekFrom::Start(value.offset as u64))?;
-                        log.take(value.len as u64).read_to_string(&mut buf)?;
-                        new_file_offset = value.offset as i32 + diff;
-                    }
-                    new_file.write_at(&buf.as_bytes(), new_file_offset as u64)?;
-                    value.offset = new_file_offset as usize;
-                    value.len = buf.len();
-                    new_file_offset = value.offset as i32 + value.len as i32;
+        }
+        Ok(true)
+    }
+
+    /// Sets the key to `value` only if its current version is
+    /// `expected_version`, see `metadata`.
+    ///
+    /// An absent key has version 0. When the versions match the value is
+    /// always logged, even if unchanged, so the version grows. Otherwise the
+    /// result carries the current version and value to retry with.
+    pub fn set_if_version(
+        &mut self,
+        key: String,
+        value: String,
+        expected_version: u64,
+    ) -> Result<SetIfResult> {
+        self.check_writable()?;
+        self.check_size(key.as_bytes(), value.as_bytes())?;
+        let version = self
+            .live_meta(key.as_bytes())?
+            .map_or(0, |meta| meta.version);
+        if version != expected_version {
+            let value = self.get(key)?;
+            return Ok(SetIfResult::Conflict { version, value });
+        }
+        self.set_record(Key::from(key), Value::Text(value), None)?;
+        Ok(SetIfResult::Written {
+            version: version + 1,
+        })
+    }
+
+    /// Returns the value of a key with its version, `None` if it does not
+    /// exist.
+    pub fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+        let version = match self.live_meta(key.as_bytes())? {
+            Some(meta) => meta.version,
+            None => return Ok(None),
+        };
+        Ok(self.get(String::from(key))?.map(|value| (value, version)))
+    }
+
+    /// Sets the value of a key and returns its previous value, if any.
+    pub fn get_and_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.get(String::from(&key))?;
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    /// Returns the value of a key, first setting it to what `f` computes if
+    /// the key does not exist.
+    ///
+    /// `f` is only called for a missing key, and its value is written by
+    /// `set` like any other.
+    pub fn get_or_insert_with<F: FnOnce() -> String>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> Result<String> {
+        if let Some(value) = self.get(String::from(&key))? {
+            return Ok(value);
+        }
+        let value = f();
+        self.set(key, String::from(&value))?;
+        Ok(value)
+    }
+
+    /// Replaces the value of a key by what `f` computes from the current one.
+    ///
+    /// `f` gets `None` for an absent key and its result is set, or removes the
+    /// key if it is `None`. Nothing is written when `f` returns the current
+    /// value. Returns the new value.
+    pub fn update<F: FnOnce(Option<String>) -> Option<String>>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> Result<Option<String>> {
+        let current = self.get(String::from(&key))?;
+        let new = f(current.clone());
+        if new == current {
+            return Ok(new);
+        }
+        match &new {
+            Some(value) => {
+                self.set(key, String::from(value))?;
+            }
+            // current is Some, or the values would be equal
+            None => {
+                self.remove(key)?;
+            }
+        }
+        Ok(new)
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// A missing key counts as 0. Returns a `KvsError::NotAnInteger` error if
+    /// the current value is not an i64, and `KvsError::IntegerOverflow` if the
+    /// result does not fit in one.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.get(String::from(&key))? {
+            Some(value) => match value.parse::<i64>() {
+                Ok(n) => n,
+                Err(_) => return Err(KvsError::NotAnInteger { key, value }.into()),
+            },
+            None => 0,
+        };
+        let new = match current.checked_add(delta) {
+            Some(n) => n,
+            None => return Err(KvsError::IntegerOverflow { key }.into()),
+        };
+        self.set(key, new.to_string())?;
+        Ok(new)
+    }
+
+    /// Appends `suffix` to the value of a key, a missing key is set to `suffix`.
+    ///
+    /// The whole new value is logged as a regular set. Returns the length in
+    /// bytes of the new value.
+    pub fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        let mut value = self.get(String::from(&key))?.unwrap_or_default();
+        value.push_str(&suffix);
+        let len = value.len();
+        self.set(key, value)?;
+        Ok(len)
+    }
+
+    /// Returns true if the KvStore contains the key.
+    ///
+    /// Only the in-memory index is consulted, the log file is not read, but
+    /// for a key a sampled index does not keep, see
+    /// `KvStoreOptions::index_sampling`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// # fn main() -> kvs::Result<()> {
+    /// # let temp_dir = TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
+    ///
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    /// assert!(store.contains_key("key1"));
+    /// assert!(!store.contains_key("key2"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn contains_key(&self, key: &str) -> bool {
+        let now = now_millis();
+        self.kvs
+            .lookup(key.as_bytes())
+            .is_some_and(|v| !v.is_expired(now))
+    }
+
+    /// Returns the sequence number of the last record logged, 0 if none has
+    /// one.
+    ///
+    /// Every write logs its records with the next sequence numbers, in log
+    /// order, and `open` goes on after the largest one in the log. Compaction
+    /// keeps the sequence numbers of the records it copies, and the last
+    /// one even if its record is dropped.
+    pub fn last_sequence(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Returns the number of live keys in the KvStore.
+    ///
+    /// This is O(1) and never touches the log: the keys which expire are
+    /// counted as their expiration time passes, each one once.
+    pub fn len(&self) -> usize {
+        self.kvs.live_len(now_millis())
+    }
+
+    /// Returns true if the KvStore holds no live keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Releases the memory the in-memory index keeps for removed keys.
+    ///
+    /// `remove_batch` and `retain` call it when most of the index is unused.
+    pub fn shrink_to_fit(&mut self) {
+        self.kvs.shrink_to_fit();
+    }
+
+    /// Returns an estimate in bytes of the memory held by the in-memory
+    /// index: its entries, including the unused ones, the bytes of the keys
+    /// and those of the values kept inline, see
+    /// `KvStoreOptions::inline_values`.
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.kvs.memory_usage()
+    }
+
+    /// Returns an iterator over the live keys of the KvStore, in arbitrary order.
+    ///
+    /// Only the in-memory index is consulted, the log file is not read, but
+    /// for the sorted segment of a sampled index. Keys which are not UTF-8
+    /// are skipped.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.kvs
+            .lookup_live(now_millis())
+            .filter_map(|kv| kv.0.as_string())
+    }
+
+    /// Returns a live key picked uniformly at random, `None` if the KvStore
+    /// is empty.
+    ///
+    /// The keys are reservoir sampled from the in-memory index, in O(len).
+    pub fn random_key(&self) -> Option<&String> {
+        self.keys().choose(&mut rand::thread_rng())
+    }
+
+    /// Returns `n` distinct live keys picked uniformly at random, or all the
+    /// keys if there are fewer. The order of the keys is arbitrary.
+    ///
+    /// The keys are reservoir sampled from the in-memory index, in O(len).
+    pub fn sample_keys(&self, n: usize) -> Vec<&String> {
+        self.keys().choose_multiple(&mut rand::thread_rng(), n)
+    }
+
+    /// Returns an iterator over the live key-value pairs of the KvStore.
+    ///
+    /// Pairs are yielded in log order so the reads are sequential. Every
+    /// value is read from the log, a failed read is returned as an `Err`
+    /// item and the iteration goes on with the next pair.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        // a failed read of the index is the only item
+        let (mut sorted, failed): (Vec<_>, _) = match self.kvs.live_strings(now_millis()) {
+            Ok(entries) => (entries.collect(), None),
+            Err(err) => (Vec::new(), Some(Err(err))),
+        };
+        sorted.sort_by_key(|kv| kv.1.position());
+        failed
+            .into_iter()
+            .chain(sorted.into_iter().filter_map(move |(key, off2len)| {
+                match read_value(&self.segments, off2len) {
+                    Ok(Some(value)) => Some(Ok((key.clone(), value))),
+                    Ok(None) => None,
+                    Err(err) => Some(Err(err)),
                 }
+            }))
+    }
+
+    /// Returns the live key-value pairs whose key starts with `prefix`.
+    ///
+    /// The matching keys are taken from the index and their values read in
+    /// log order. An empty prefix matches every key.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut sorted: Vec<_> = self
+            .kvs
+            .live_strings(now_millis())?
+            .filter(|kv| kv.0.starts_with(prefix))
+            .collect();
+        sorted.sort_by_key(|kv| kv.1.position());
+        let mut pairs = Vec::with_capacity(sorted.len());
+        for (key, off2len) in sorted {
+            if let Some(value) = read_value(&self.segments, off2len)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Removes every key from the KvStore.
+    ///
+    /// The log is truncated to its header and a record of the last sequence
+    /// number, which `clear` takes, and synced to disk before returning, its
+    /// sealed segments are deleted. While snapshots are alive the log is
+    /// replaced by an empty one instead, the snapshots keep reading the old
+    /// one.
+    pub fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let header = self.options.new_header();
+        // the only record left, so the sequence numbers go on after it
+        let seq = self.take_seq();
+        let marker = encode(header.format(), &OptData::SeqData { seq })?;
+        // the sealed segments go, the active one is emptied
+        let active = self.segment;
+        let place = (Some(header), active, records_start(Some(header)));
+        let marker = seal_record(self.options.cipher.as_ref(), marker, place)?;
+        let sealed: Vec<u32> = self.segments.sealed(active).map(|(id, _)| id).collect();
+        for id in sealed {
+            let name = segment_name(&self.options.log_file_name, id);
+            fs::remove_file(self.log_name.with_file_name(name))?;
+            self.segments.remove(id);
+        }
+        if self.log.is_some() && self.has_snapshots() {
+            let (new_path, new_file) = self.create_swap_file()?;
+            header.write(&new_file)?;
+            new_file.write_all_at(&marker, records_start(Some(header)))?;
+            self.options.file_sync.sync_all(&new_file)?;
+            let file_sync = &*self.options.file_sync;
+            let new_file = replace_file(file_sync, &new_path, new_file, &self.log_name)?;
+            self.log = Some(new_file);
+            self.sync_dir()?;
+        } else if let Some(log) = &mut self.log {
+            log.set_len(0)?;
+            header.write(log)?;
+            log.write_all_at(&marker, records_start(Some(header)))?;
+            self.options.file_sync.sync_all(log)?;
+        }
+        if let Some(log) = &self.log {
+            self.segments.insert(active, log, Some(header))?;
+            self.remove_hint()?;
+        }
+        self.header = Some(header);
+        if !self.subscribers.is_empty() {
+            let now = now_millis();
+            for (key, _) in self.kvs.lookup_live(now) {
+                self.subscribers.notify(seq, key.as_bytes(), None);
+            }
+        }
+        self.kvs.clear();
+        self.cache.clear();
+        self.log_off = records_start(self.header) + marker.len() as u64;
+        self.reset_writer()
+    }
+
+    // append data at the end of the log, return the offset it was written at
+    //
+    // Every write to the log goes through here or `set_from_reader`, both
+    // flush the writer before returning so the records can be read back.
+    fn append_log(&mut self, data: &[u8]) -> Result<u64> {
+        self.check_writable()?;
+        let sealed_at = self.place(0);
+        self.maybe_rotate()?;
+        // the records were sealed at the end of the segment rotated away from
+        let data = move_records(self.options.cipher.as_ref(), data, sealed_at, self.place(0))?;
+        let offset = self.log_off;
+        if let Some(writer) = &mut self.writer {
+            let written = writer.write_all(&data).and_then(|_| writer.flush());
+            if let Err(err) = written {
+                self.discard_write()?;
+                return Err(err.into());
+            }
+            self.log_off += data.len() as u64;
+            self.sync_after_write()?;
+        }
+        Ok(offset)
+    }
+
+    // point the writer at log_off of the current log, dropping what it holds
+    fn reset_writer(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            // into_parts does not flush, unlike dropping the writer
+            drop(writer.into_parts());
+        }
+        if let (Some(log), false) = (&self.log, self.read_only) {
+            self.writer = Some(LogWriter::buffered(log, self.log_off)?);
+        }
+        Ok(())
+    }
+
+    // drop what a failed write left in the writer and in the log past log_off
+    fn discard_write(&mut self) -> Result<()> {
+        if let Some(log) = &self.log {
+            log.set_len(self.log_off)?;
+        }
+        self.reset_writer()
+    }
+
+    // create a temporary log file to rebuild the new log, sync it, then
+    // rename temporary log file to self.log_name and sync the directory
+    //
+    // A log written before headers gets one, with the flags which hold for
+    // its copied records.
+    fn rebuild_log(&mut self) -> Result<()> {
+        if self.log.is_none() {
+            return Ok(());
+        }
+        // expired keys are not copied
+        let now = now_millis();
+        self.kvs.retain(|_, v| !v.is_expired(now));
+        let header = Header::rewritten(self.header);
+        let (new_path, new_file) = self.create_swap_file()?;
+        let file_sync = Arc::clone(&self.options.file_sync);
+        // the sealed segments are left as they are
+        let active = self.segment;
+        let mut sorted: Vec<_> = self
+            .kvs
+            .iter_mut()
+            .filter(|kv| kv.1.segment == active)
+            .collect();
+        sorted.sort_by_key(|kv| kv.1.position());
+        // the last record holds the last sequence number, which is logged
+        // anew if the record is dropped
+        let log_off = self.log_off;
+        let copied: u64 = sorted.iter().map(|kv| kv.1.len as u64).sum();
+        let trailer_at = (Some(header), active, records_start(Some(header)) + copied);
+        let trailer = match sorted.last() {
+            Some((_, last)) if last.offset + last.len as u64 == log_off => Vec::new(),
+            _ if self.next_seq == 1 => Vec::new(),
+            _ => seal_record(
+                self.options.cipher.as_ref(),
+                encode(
+                    header.format(),
+                    &OptData::SeqData {
+                        seq: self.next_seq - 1,
+                    },
+                )?,
+                trailer_at,
+            )?,
+        };
+
+        // live records are copied back to back, dead records are dropped
+        let records = sorted.iter().map(|kv| &*kv.1);
+        let segments = &self.segments;
+        let (new_file, (offsets, end)) =
+            install_copy(&*file_sync, (&new_path, new_file), &self.log_name, |temp| {
+                copy_records(segments, (records, &trailer), (active, header), temp)
+            })?;
+        for ((_, value), offset) in sorted.iter_mut().zip(offsets) {
+            value.offset = offset;
+        }
+        self.kvs.recount_bytes();
+        self.segments.insert(active, &new_file, Some(header))?;
+        // the old handle points at the replaced file
+        self.log = Some(new_file);
+        self.log_off = end;
+        self.header = Some(header);
+        self.compactions += 1;
+        self.reset_writer()?;
+        self.sync_dir()
+    }
+
+    // create the empty temporary file a new log is written to, named like
+    // the log with the id of the process and a count, so no other file has
+    // its name
+    fn create_swap_file(&self) -> Result<(PathBuf, File)> {
+        loop {
+            let count = SWAP_FILES.fetch_add(1, atomic::Ordering::Relaxed);
+            let name = swap_name(&self.options.log_file_name, process::id(), count);
+            let new_path = self.log_name.with_file_name(name);
+            let opened = OpenOptions::new()
+                .create_new(true)
+                .read(true)
+                .write(true)
+                .open(&new_path);
+            match opened {
+                Ok(new_file) => return Ok((new_path, new_file)),
+                // left by an earlier process which had the same id
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    // the sequence number for a new record
+    fn take_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    // the record logged for data in the log, encrypted if the log is and
+    // sealed where it goes once the buffered bytes before it are appended
+    fn encode_record(&self, data: &OptData, buffered: usize) -> Result<Vec<u8>> {
+        let record = encode(self.format(), data)?;
+        seal_record(self.options.cipher.as_ref(), record, self.place(buffered))
+    }
+
+    // encode_record for a SetData record borrowing its key and value
+    fn encode_set_record(&self, data: &SetDataRef, buffered: usize) -> Result<Vec<u8>> {
+        let record = encode_set(self.format(), data)?;
+        seal_record(self.options.cipher.as_ref(), record, self.place(buffered))
+    }
+
+    // encode_record at the end of buf, return its length
+    fn push_record(&self, buf: &mut Vec<u8>, data: &OptData) -> Result<usize> {
+        let record = self.encode_record(data, buf.len())?;
+        buf.extend_from_slice(&record);
+        Ok(record.len())
+    }
 
-                fs::rename(&new_path, &self.log_name)?;
-                Ok(())
+    // the place of the end of the log, once the buffered bytes are appended
+    fn place(&self, buffered: usize) -> Place {
+        (self.header, self.segment, self.log_off + buffered as u64)
+    }
+
+    // the format of the records of the log
+    fn format(&self) -> LogFormat {
+        record_format(self.header)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    // refuse a key or a value longer than the limits of the options
+    fn check_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() > self.options.max_key_size {
+            return Err(KvsError::KeyTooLarge {
+                size: key.len(),
+                limit: self.options.max_key_size,
+            }
+            .into());
+        }
+        if value.len() > self.options.max_value_size {
+            return Err(KvsError::ValueTooLarge {
+                size: value.len(),
+                limit: self.options.max_value_size,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn has_snapshots(&self) -> bool {
+        Arc::strong_count(&self.snapshots) > 1
+    }
+
+    /// Returns a point-in-time read-only view of the KvStore.
+    ///
+    /// The snapshot keeps a copy of the index and its own handles on the
+    /// segments of the log, later writes to the KvStore are not visible
+    /// through it. A compaction writes new segments and leaves the snapshot
+    /// reading the old ones.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let mut kvs = self.kvs.clone();
+        let now = now_millis();
+        kvs.retain(|_, v| !v.is_expired(now));
+        Ok(Snapshot::new(
+            kvs,
+            self.segments.clone(),
+            self.options.log_file_name.clone(),
+            Arc::clone(&self.snapshots),
+        ))
+    }
+
+    /// Returns the live key-value pairs whose key is in `range`, in key order.
+    ///
+    /// Bounds behave like the ones of `BTreeMap::range`. The KvStore opened by
+    /// `open_ordered` answers from its sorted index, any other one has to sort
+    /// the matching keys first. Keys which are not UTF-8 are skipped.
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let now = now_millis();
+        let mut entries = self.kvs.range(range)?;
+        entries.retain(|kv| !kv.1.is_expired(now));
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, off2len) in entries {
+            let key = match key.as_string() {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(value) = read_value(&self.segments, off2len)? {
+                pairs.push((key.clone(), value));
             }
-            None => Ok(()),
         }
+        Ok(pairs)
     }
 
     /// Open the KvStore at a given path. Return the KvStore.
+    ///
+    /// An exclusive advisory lock is taken on the `kvs.lock` file next to
+    /// the log and held until the KvStore is dropped or closed. A log which
+    /// another KvStore has open, in this process or another, makes `open`
+    /// fail with a `KvsError::AlreadyLocked` error.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut pathbuf = path.into();
-        pathbuf.push("kvs.log");
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&pathbuf)?;
-        let mut kvs: HashMap<String, OffsetLen> = HashMap::new();
-        let mut offset: usize = 0;
-        for line in io::BufReader::new(&file).lines() {
-            let line = line?;
-            let decode: OptData = serde_json::from_str(&line)?;
-            match decode {
-                OptData::SetData { key, value: _ } => {
-                    kvs.insert(
-                        key,
-                        OffsetLen {
-                            offset,
-                            len: line.len() + 1,
-                        },
-                    );
+        KvStoreOptions::new().open(path)
+    }
+
+    /// Open the KvStore at a given path with its keys kept sorted in memory,
+    /// which makes `range` cheap. Return the KvStore.
+    pub fn open_ordered(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStoreOptions::new().ordered(true).open(path)
+    }
+
+    fn open_with_options(pathbuf: PathBuf, options: KvStoreOptions) -> Result<KvStore> {
+        let (store, _) = KvStore::open_recovering(pathbuf, options, RecoveryMode::Strict)?;
+        Ok(store)
+    }
+
+    fn open_recovering(
+        dir: PathBuf,
+        options: KvStoreOptions,
+        mode: RecoveryMode,
+    ) -> Result<(KvStore, RecoveryReport)> {
+        let lock = lock_dir(&dir, &options.log_file_name, false)?;
+        check_engine(&dir, KVS_ENGINE, true)?;
+        let opened = replay_segments(&dir, &options, u64::MAX, mode, true)?;
+        let replay = opened.replay;
+        let writer = Some(LogWriter::buffered(&opened.log, replay.end)?);
+        let mut store = KvStore {
+            kvs: opened.kvs,
+            log: Some(opened.log),
+            segment: opened.segment,
+            segments: opened.segments,
+            writer,
+            log_off: replay.end,
+            header: replay.header,
+            log_name: opened.log_name,
+            snapshots: Arc::new(()),
+            subscribers: Subscribers::default(),
+            cache: ValueCache::new(options.cache_capacity),
+            next_seq: replay.last_seq + 1,
+            compactions: 0,
+            truncated_bytes: replay.report.truncated_bytes,
+            sync: SyncState::new(),
+            read_only: false,
+            _lock: Some(lock),
+            options,
+        };
+        store.mark_open()?;
+        // the skipped records are left out of a rewritten log
+        if mode == RecoveryMode::SkipCorrupt && replay.report.dropped_records > 0 {
+            store.rebuild_log()?;
+        }
+        Ok((store, replay.report))
+    }
+    /// Open the KvStore at a given path as it was after the record with
+    /// sequence number `upto_seq`. Return the KvStore.
+    ///
+    /// The later records are not applied, records logged before sequence
+    /// numbers existed always are. The log is opened read-only and every
+    /// write returns a `KvsError::ReadOnly` error, so history can not fork.
+    /// Records dropped by a compaction are lost: a key set again since
+    /// `upto_seq` then looks absent at `upto_seq`.
+    pub fn open_at(path: impl Into<PathBuf>, upto_seq: u64) -> Result<KvStore> {
+        KvStore::open_read_only_at(path.into(), upto_seq, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path for reading only. Return the KvStore.
+    ///
+    /// The log is opened with read-only permissions and is not created if it
+    /// does not exist. A shared lock is taken, so any number of read-only
+    /// KvStores can have the log open together but none while a writer has
+    /// it, see `KvStore::open`. Every write, including `set`, `remove` and
+    /// `clear`, returns a `KvsError::ReadOnly` error.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStoreOptions::new().read_only(true).open(path)
+    }
+
+    fn open_read_only_at(dir: PathBuf, upto_seq: u64, options: KvStoreOptions) -> Result<KvStore> {
+        let lock = lock_dir(&dir, &options.log_file_name, true)?;
+        check_engine(&dir, KVS_ENGINE, false)?;
+        let opened = replay_segments(&dir, &options, upto_seq, RecoveryMode::Strict, false)?;
+        let replay = opened.replay;
+        Ok(KvStore {
+            kvs: opened.kvs,
+            log: Some(opened.log),
+            segment: opened.segment,
+            segments: opened.segments,
+            writer: None,
+            log_off: replay.end,
+            header: replay.header,
+            log_name: opened.log_name,
+            snapshots: Arc::new(()),
+            subscribers: Subscribers::default(),
+            cache: ValueCache::new(options.cache_capacity),
+            next_seq: replay.last_seq + 1,
+            compactions: 0,
+            truncated_bytes: 0,
+            sync: SyncState::new(),
+            read_only: true,
+            _lock: Some(lock),
+            options,
+        })
+    }
+
+    /// Open the KvStore at a given path and insert all the key-value pairs of
+    /// `iter` with `try_extend`. Return the KvStore.
+    pub fn from_iter_at<I: IntoIterator<Item = (String, String)>>(
+        path: impl Into<PathBuf>,
+        iter: I,
+    ) -> Result<KvStore> {
+        let mut store = KvStore::open(path)?;
+        store.try_extend(iter)?;
+        Ok(store)
+    }
+}
+
+impl Extend<(String, String)> for KvStore {
+    /// Inserts the key-value pairs with `KvStore::try_extend`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if writing to the log fails, use `try_extend` to get the error.
+    fn extend<I: IntoIterator<Item = (String, String)>>(&mut self, iter: I) {
+        if let Err(err) = self.try_extend(iter) {
+            panic!("unable to extend the KvStore: {}", err);
+        }
+    }
+}
+
+// what replay_log found
+struct Replay {
+    end: u64,       // the end of the last complete record or transaction
+    last_seq: u64,  // the largest sequence number seen
+    records: usize, // the number of records applied
+    report: RecoveryReport,
+    header: Option<Header>,
+}
+
+// what replay_segments found
+struct Opened {
+    kvs: Index,
+    segments: Segments,
+    log: File, // the active segment
+    segment: u32,
+    log_name: PathBuf,
+    replay: Replay, // the end and the header of the active segment
+}
+
+// replay every segment of the log in dir in id order, creating the first
+// one of a new log if writable
+//
+// A merged segment holds the records of every segment before it: those
+// left behind by an interrupted compaction are deleted, or ignored if not
+// writable. The records after a transaction cut short or a corrupt record
+// are truncated away if writable.
+fn replay_segments(
+    dir: &Path,
+    options: &KvStoreOptions,
+    upto_seq: u64,
+    mode: RecoveryMode,
+    writable: bool,
+) -> Result<Opened> {
+    let name = &options.log_file_name;
+    if writable {
+        remove_swap_files(dir, name)?;
+    }
+    let mut ids = segment_ids(dir, name)?;
+    let (mut merged, mut clean) = (None, false);
+    for id in ids.iter() {
+        let file = File::open(dir.join(segment_name(name, *id)))?;
+        let header = Header::read(&file)?;
+        if header.is_some_and(|header| header.merged()) {
+            merged = Some(*id);
+        }
+        // only the active segment, the last one, counts
+        clean = header.is_some_and(|header| header.clean());
+    }
+    if let Some(merged) = merged {
+        for id in ids.iter().filter(|id| **id < merged) {
+            if writable {
+                fs::remove_file(dir.join(segment_name(name, *id)))?;
+            }
+        }
+        ids.retain(|id| *id >= merged);
+    }
+    if ids.is_empty() {
+        // a new log is segmented from the start if segments are wanted
+        ids.push(if options.max_segment_size.is_some() {
+            1
+        } else {
+            0
+        });
+    }
+    // a hint spares the replay of the records it covers, a log not closed
+    // cleanly, recovering, going back in time and a sampled index replay
+    // everything
+    let hint = if clean
+        && mode == RecoveryMode::Strict
+        && upto_seq == u64::MAX
+        && options.cipher.is_none()
+        && options.index_sampling == 1
+    {
+        load_hint(dir, name, &ids, (options.ordered, options.inline_values))
+    } else {
+        None
+    };
+    let (mut kvs, mut last_seq, ends) = match hint {
+        Some(hint) => (hint.kvs, hint.last_seq, hint.ends),
+        None => (Index::new(options.ordered), 0, BTreeMap::new()),
+    };
+    kvs.set_inline_max(options.inline_values);
+    let mut segments = Segments::default();
+    segments.cipher = options.cipher.clone();
+    let mut last = None;
+    let (mut records, mut report) = (0, RecoveryReport::default());
+    let last_id = ids.last().copied();
+    for id in ids {
+        let log_name = dir.join(segment_name(name, id));
+        let file = if writable {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&log_name)?;
+            if file.metadata()?.len() == 0 {
+                options.new_header().write(&file)?;
+            }
+            file
+        } else {
+            OpenOptions::new().read(true).open(&log_name)?
+        };
+        let from = ends.get(&id).copied().unwrap_or(0);
+        let tail = Some(id) == last_id;
+        let cipher = options.cipher.as_ref();
+        // the sorted segment a compaction merged everything into is the run
+        // of a sampled index
+        let sampled = options.index_sampling > 1 && Some(id) == merged && !tail;
+        let run = match Header::read(&file)? {
+            Some(header) if sampled && header.sorted() && upto_seq == u64::MAX => {
+                replay_run((id, &file, header), cipher, options.index_sampling)?
+            }
+            _ => None,
+        };
+        let replay = match run {
+            Some(run) => {
+                let header = Some(run.run.header());
+                kvs.set_run(run.run, run.kept)?;
+                Replay {
+                    end: run.end,
+                    last_seq: run.last_seq,
+                    records: run.records,
+                    report: RecoveryReport::default(),
+                    header,
+                }
+            }
+            None => replay_log(&file, &mut kvs, (id, tail), from, upto_seq, mode, cipher)?,
+        };
+        // drop the records of a transaction the segment ends in the middle
+        // of, a torn record at the end of the log and what follows a corrupt
+        // record for TruncateAtError
+        if writable && file.metadata()?.len() > replay.end {
+            file.set_len(replay.end)?;
+        }
+        segments.insert(id, &file, replay.header)?;
+        segments.seal(id, replay.end);
+        last_seq = last_seq.max(replay.last_seq);
+        records += replay.records;
+        report.dropped_records += replay.report.dropped_records;
+        report.dropped_bytes += replay.report.dropped_bytes;
+        report.truncated_bytes += replay.report.truncated_bytes;
+        last = Some((id, file, log_name, replay));
+    }
+    let (segment, log, log_name, replay) = last.expect("a log has a segment");
+    Ok(Opened {
+        kvs,
+        segments,
+        log,
+        segment,
+        log_name,
+        replay: Replay {
+            last_seq,
+            records,
+            report,
+            ..replay
+        },
+    })
+}
+
+// replay the records of the log from offset from, or its first record, with
+// a sequence number up to upto_seq into kvs as records of segment, a corrupt
+// record is handled as mode says but the last one of the log, at its tail;
+// an encrypted log is decrypted with cipher
+fn replay_log(
+    file: &File,
+    kvs: &mut Index,
+    (segment, tail): (u32, bool),
+    from: u64,
+    upto_seq: u64,
+    mode: RecoveryMode,
+    cipher: Option<&Cipher>,
+) -> Result<Replay> {
+    let header = Header::read(file)?;
+    check_key(cipher, header)?;
+    let format = record_format(header);
+    let required = header.is_some_and(|header| header.checksums());
+    let mut offset = records_start(header).max(from);
+    let mut replay = Replay {
+        end: offset,
+        last_seq: 0,
+        records: 0,
+        report: RecoveryReport::default(),
+        header,
+    };
+    let now = now_millis();
+    // the number of records of the current transaction, its start and the
+    // records read, None for a corrupt one
+    let mut txn: Option<(usize, u64)> = None;
+    let mut txn_records = Vec::new();
+    let mut reader = io::BufReader::new(file);
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut record = Vec::new();
+    loop {
+        let len = read_next(format, &mut reader, &mut record)?;
+        if len == 0 {
+            break;
+        }
+        let record_offset = offset;
+        offset += len as u64;
+        let parsed = open_record(cipher, &record, (header, segment, record_offset))
+            .and_then(|record| decode(format, &record, record_offset, required));
+        let decode = match parsed {
+            Ok(decode) => Some(decode),
+            // the last record of the log, cut short by a crash while it was
+            // appended: the log ends with the record before, in every mode
+            Err(_) if tail && reader.fill_buf()?.is_empty() => {
+                replay.report.truncated_bytes = file.metadata()?.len().saturating_sub(replay.end);
+                return Ok(replay);
+            }
+            Err(err) => match mode {
+                RecoveryMode::Strict => return Err(err),
+                RecoveryMode::SkipCorrupt => {
+                    replay.report.dropped_records += 1;
+                    replay.report.dropped_bytes += len as u64;
+                    None
+                }
+                RecoveryMode::TruncateAtError => {
+                    // the bad record, what follows and its transaction go
+                    let mut dropped = 1 + txn.map_or(0, |_| 1 + txn_records.len());
+                    while read_next(format, &mut reader, &mut record)? > 0 {
+                        dropped += 1;
+                    }
+                    replay.report.dropped_records += dropped;
+                    replay.report.dropped_bytes +=
+                        file.metadata()?.len().saturating_sub(replay.end);
+                    return Ok(replay);
+                }
+            },
+        };
+        if let Some(OptData::TxnData { count }) = decode {
+            if txn.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "transaction inside a transaction",
+                )
+                .into());
+            }
+            txn = Some((count, record_offset));
+        } else if txn.is_some() {
+            txn_records.push(decode.map(|decode| (decode, record_offset, len)));
+        } else {
+            if let Some(decode) = decode {
+                replay_record(
+                    kvs,
+                    decode,
+                    (segment, record_offset),
+                    len,
+                    upto_seq,
+                    now,
+                    &mut replay.last_seq,
+                )?;
+                replay.records += 1;
+            }
+            replay.end = offset;
+            continue;
+        }
+        if let Some((count, start)) = txn {
+            if count != txn_records.len() {
+                continue;
+            }
+            if txn_records.iter().all(Option::is_some) {
+                for (decode, offset, len) in txn_records.drain(..).flatten() {
+                    replay_record(
+                        kvs,
+                        decode,
+                        (segment, offset),
+                        len,
+                        upto_seq,
+                        now,
+                        &mut replay.last_seq,
+                    )?;
+                    replay.records += 1;
                 }
-                OptData::RmData { key } => {
-                    kvs.remove(&key);
+            } else {
+                // a transaction is applied whole or not at all
+                let valid: Vec<_> = txn_records.drain(..).flatten().collect();
+                let corrupt_bytes: usize = valid.iter().map(|record| record.2).sum();
+                replay.report.dropped_records += 1 + valid.len();
+                replay.report.dropped_bytes +=
+                    (offset - start).saturating_sub(corrupt_bytes as u64);
+            }
+            txn = None;
+            replay.end = offset;
+        }
+    }
+    Ok(replay)
+}
+
+// apply the record of len bytes at position, a segment and an offset in
+// it, to kvs
+fn replay_record(
+    kvs: &mut Index,
+    decode: OptData,
+    (segment, offset): (u32, u64),
+    len: usize,
+    upto_seq: u64,
+    now: u64,
+    last_seq: &mut u64,
+) -> Result<()> {
+    let seq = match decode {
+        OptData::SetData { seq, .. } | OptData::RmData { seq, .. } | OptData::SeqData { seq } => {
+            seq
+        }
+        OptData::GetData { .. } | OptData::TxnData { .. } => 0,
+    };
+    *last_seq = (*last_seq).max(seq);
+    if seq > upto_seq {
+        return Ok(());
+    }
+    match decode {
+        OptData::SetData { .. } => {
+            let inline_max = kvs.inline_max();
+            if let Some((key, off2len)) = set_entry(decode, (segment, offset), len, inline_max)? {
+                // expired while the KvStore was closed
+                if off2len.is_expired(now) {
+                    kvs.remove(key.as_bytes())?;
+                } else {
+                    kvs.insert(key, off2len)?;
                 }
-                // ignore get
-                _ => {}
+            }
+        }
+        OptData::RmData {
+            key, key_base64, ..
+        } => {
+            kvs.remove(decode_key(key, key_base64)?.as_bytes())?;
+        }
+        // ignore get
+        _ => {}
+    };
+    Ok(())
+}
+
+// the key and the index entry of a SetData record of len bytes at position,
+// None for any other record
+fn set_entry(
+    decode: OptData,
+    (segment, offset): (u32, u64),
+    len: usize,
+    inline_max: usize,
+) -> Result<Option<(Key, OffsetLen)>> {
+    let saved = saved_bytes(&decode);
+    match decode {
+        OptData::SetData {
+            key,
+            key_base64,
+            value,
+            base64,
+            compressed,
+            expires_at,
+            version,
+            created_at,
+            updated_at,
+            ..
+        } => {
+            // the value as logged, unless it is encoded
+            let inline = Some(value)
+                .filter(|value| !base64 && compressed.is_none() && value.len() <= inline_max)
+                .filter(|_| inline_max > 0)
+                .map(String::into_boxed_str);
+            let off2len = OffsetLen {
+                segment,
+                saved,
+                offset,
+                len,
+                expires_at,
+                meta: Meta {
+                    version,
+                    created_at,
+                    updated_at,
+                },
+                inline,
             };
-            offset += line.len() + 1;
+            Ok(Some((decode_key(key, key_base64)?, off2len)))
         }
-        Ok(KvStore {
-            kvs,
-            log: Some(file),
-            log_off: offset,
-            log_name: PathBuf::from(pathbuf),
-        })
+        _ => Ok(None),
+    }
+}
+
+// a SetData record carrying meta
+fn set_data(key: &[u8], value: Value, expires_at: Option<u64>, meta: Meta, seq: u64) -> OptData {
+    let (value, base64) = match value {
+        Value::Text(text) => (text, false),
+        Value::Bytes(bytes) => (BASE64.encode(bytes), true),
+    };
+    let (key, key_base64) = encode_key(key);
+    OptData::SetData {
+        key,
+        value,
+        expires_at,
+        version: meta.version,
+        created_at: meta.created_at,
+        updated_at: meta.updated_at,
+        seq,
+        base64,
+        key_base64,
+        compressed: None,
     }
 }
+
+// a RmData record
+fn rm_data(key: &[u8], seq: u64) -> OptData {
+    let (key, key_base64) = encode_key(key);
+    OptData::RmData {
+        key,
+        key_base64,
+        seq,
+    }
+}
+
+// a key as logged: the key itself if it is UTF-8, its base64 otherwise
+fn encode_key(key: &[u8]) -> (String, bool) {
+    match std::str::from_utf8(key) {
+        Ok(key) => (String::from(key), false),
+        Err(_) => (BASE64.encode(key), true),
+    }
+}
+
+// the key of a record, as encoded by encode_key
+fn decode_key(key: String, key_base64: bool) -> Result<Key> {
+    if key_base64 {
+        Ok(Key::from_bytes(BASE64.decode(key)?))
+    } else {
+        Ok(Key::from(key))
+    }
+}
+
+// a value as held by a SetData record
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Text(String),
+    Bytes(Vec<u8>), // never valid UTF-8
+}
+
+impl Value {
+    fn from_bytes(bytes: Vec<u8>) -> Value {
+        match String::from_utf8(bytes) {
+            Ok(text) => Value::Text(text),
+            Err(err) => Value::Bytes(err.into_bytes()),
+        }
+    }
+
+    fn into_string(self) -> Result<String> {
+        match self {
+            Value::Text(text) => Ok(text),
+            Value::Bytes(_) => Err(KvsError::BinaryValue.into()),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Value::Text(text) => text.into_bytes(),
+            Value::Bytes(bytes) => bytes,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Value::Text(text) => text.as_bytes(),
+            Value::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+// read the value of the SetData record pointed by off2len, it must be text
+fn read_value(segments: &Segments, off2len: &OffsetLen) -> Result<Option<String>> {
+    read_record(segments, off2len)?
+        .map(Value::into_string)
+        .transpose()
+}
+
+// read the value of the SetData record pointed by off2len, at its offset so
+// the file cursor is left alone and readers can share the log
+fn read_record(segments: &Segments, off2len: &OffsetLen) -> Result<Option<Value>> {
+    read_record_checked(segments, off2len, false)
+}
+
+// read_record, checking the record against its checksum first if verify
+fn read_record_checked(
+    segments: &Segments,
+    off2len: &OffsetLen,
+    verify: bool,
+) -> Result<Option<Value>> {
+    if let Some(inline) = &off2len.inline {
+        return Ok(Some(Value::Text(String::from(&**inline))));
+    }
+    let log = segments.file(off2len.segment).ok_or_else(missing_segment)?;
+    let mut buf = vec![0; off2len.len];
+    log.read_exact_at(&mut buf, off2len.offset)?;
+    let place = (
+        segments.header(off2len.segment),
+        off2len.segment,
+        off2len.offset,
+    );
+    let buf = open_record(segments.cipher.as_ref(), &buf, place)?;
+    decode_value(&buf, off2len.offset, verify)
+}
+
+// the current time in ms since the unix epoch
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,51 @@
+use crate::{KvsError, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+// the name of the lock file of the log named log_file_name, the log name
+// with a `.lock` extension: kvs.lock for kvs.log
+pub(crate) fn lock_name(log_file_name: &str) -> String {
+    let name = Path::new(log_file_name).with_extension("lock");
+    name.to_string_lossy().into_owned()
+}
+
+// take the advisory lock on the log named log_file_name in dir, shared for
+// a KvStore which only reads, exclusive otherwise; the lock is held until
+// the returned file is dropped
+//
+// A lock held by another KvStore, in this process or another, fails with a
+// `KvsError::AlreadyLocked` error instead of waiting for it.
+pub(crate) fn lock_dir(dir: &Path, log_file_name: &str, shared: bool) -> Result<File> {
+    let path = dir.join(lock_name(log_file_name));
+    let opened = if shared {
+        // a reader only creates the lock file if no writer ever did
+        match OpenOptions::new().read(true).open(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            opened => Some(opened?),
+        }
+    } else {
+        None
+    };
+    let file = match opened {
+        Some(file) => file,
+        None => OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?,
+    };
+    let locked = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::AlreadyLocked {
+            path: path.display().to_string(),
+        }
+        .into()),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
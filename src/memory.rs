@@ -0,0 +1,51 @@
+use crate::{KvsEngine, KvsError, Result};
+use std::collections::HashMap;
+
+/// A `KvsEngine` which keeps its pairs in a `HashMap` and never touches the
+/// filesystem, for tests and caches: what it holds is gone once it is
+/// dropped. A clone is a copy of the pairs, a `KvsServer` serves one behind
+/// an `Arc<Mutex<_>>`, see `SharedEngine`.
+#[derive(Clone, Debug, Default)]
+pub struct MemKvStore {
+    map: HashMap<String, String>,
+}
+
+impl MemKvStore {
+    /// An empty MemKvStore.
+    pub fn new() -> MemKvStore {
+        MemKvStore::default()
+    }
+
+    /// Returns the number of keys.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the MemKvStore holds no key.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KvsEngine for MemKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self.map.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound.into()),
+        }
+    }
+
+    // nothing is on disk
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::compress::saved_bytes;
+use crate::index::{Key, Meta, OffsetLen};
+use crate::{now_millis, read_record, set_data, KvStore, KvStoreOptions, KvsError, Result};
+use std::path::PathBuf;
+
+// the number of records merged with a single log write
+const MERGE_BATCH: usize = 1024;
+
+/// What `KvStore::merge_from` does with a key live in both KvStores whose
+/// values differ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// keep the value of this KvStore
+    KeepExisting,
+    /// take the value of the other KvStore
+    Overwrite,
+    /// merge nothing and return a `KvsError::MergeConflict` error
+    ErrorOnConflict,
+}
+
+/// What `KvStore::merge_from` did with the live keys of the other KvStore.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// the keys which did not exist
+    pub inserted: usize,
+    /// the keys which already had the same value
+    pub unchanged: usize,
+    /// the keys whose values differ, in the log order of the other KvStore,
+    /// with the bytes which are not UTF-8 replaced
+    pub conflicts: Vec<String>,
+}
+
+impl KvStore {
+    /// Merges the live key-value pairs of the KvStore in `other_dir` into
+    /// this one.
+    ///
+    /// The other log, named like this one with all its segments, is opened
+    /// read-only and replayed, so only its final
+    /// state matters and its tombstones remove nothing here. A key with an
+    /// expiration keeps it. Which value a conflicting key ends up with
+    /// depends on `strategy`; with `ErrorOnConflict` the conflicts are all
+    /// found before anything is written.
+    pub fn merge_from(
+        &mut self,
+        other_dir: impl Into<PathBuf>,
+        strategy: MergeStrategy,
+    ) -> Result<MergeReport> {
+        let other = KvStoreOptions::new()
+            .read_only(true)
+            .log_file_name(self.options.log_file_name.clone())
+            .open(other_dir)?;
+        let mut sorted: Vec<_> = other.kvs.iter()?.collect();
+        sorted.sort_by_key(|kv| kv.1.position());
+
+        // find what to write before writing anything
+        let mut report = MergeReport::default();
+        let mut merged = Vec::new();
+        for (key, off2len) in sorted {
+            if self.live_meta(key.as_bytes())?.is_none() {
+                report.inserted += 1;
+                merged.push((key, off2len));
+                continue;
+            }
+            let value = read_record(&other.segments, off2len)?;
+            if self.get_value(key.as_bytes())? == value {
+                report.unchanged += 1;
+                continue;
+            }
+            report
+                .conflicts
+                .push(String::from_utf8_lossy(key.as_bytes()).into_owned());
+            if strategy == MergeStrategy::Overwrite {
+                merged.push((key, off2len));
+            }
+        }
+        if strategy == MergeStrategy::ErrorOnConflict && !report.conflicts.is_empty() {
+            return Err(KvsError::MergeConflict {
+                keys: report.conflicts,
+            }
+            .into());
+        }
+
+        for chunk in merged.chunks(MERGE_BATCH) {
+            let mut buf = Vec::new();
+            let mut entries = Vec::with_capacity(chunk.len());
+            for (key, off2len) in chunk {
+                let value = match read_record(&other.segments, off2len)? {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let meta = Meta::next(self.live_meta(key.as_bytes())?.as_ref(), now_millis());
+                let seq = self.take_seq();
+                let expires_at = off2len.expires_at;
+                let data = set_data(key.as_bytes(), value.clone(), expires_at, meta, seq);
+                let data = self.compress(data)?;
+                let len = self.push_record(&mut buf, &data)?;
+                let saved = saved_bytes(&data);
+                entries.push((key, value, (len, saved), expires_at, meta, seq));
+            }
+            let mut offset = self.append_log(&buf)?;
+            for (key, value, (len, saved), expires_at, meta, seq) in entries {
+                self.subscribers
+                    .notify(seq, key.as_bytes(), Some(value.as_bytes()));
+                self.kvs.insert(
+                    Key::clone(key),
+                    OffsetLen {
+                        segment: self.segment,
+                        saved,
+                        offset,
+                        len,
+                        expires_at,
+                        meta,
+                        inline: self.inline(value.as_bytes()),
+                    },
+                )?;
+                offset += len as u64;
+            }
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,133 @@
+use crate::engine::{check_engine, KVS_ENGINE};
+use crate::header::{record_format, records_start, Header};
+use crate::index::Index;
+use crate::lock::lock_dir;
+use crate::record::{decode, encode};
+use crate::segment::segment_ids;
+use crate::writer::LogWriter;
+use crate::{
+    now_millis, replay_log, KvStore, KvStoreOptions, LogFormat, OptData, RecoveryMode, Result,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// the suffixes of the file the binary log is written to, and of the name
+// the JSON log is kept under
+const TEMP_SUFFIX: &str = ".migrate";
+const BACKUP_SUFFIX: &str = ".v1.bak";
+
+/// What `KvStore::migrate` did to a log.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// the number of sets and removals replayed from the JSON log
+    pub records_read: usize,
+    /// the number of records of the binary log, one per live key
+    pub records_written: usize,
+    /// the length of the log before the migration
+    pub bytes_before: u64,
+    /// the length of the log after the migration
+    pub bytes_after: u64,
+}
+
+impl KvStore {
+    /// Rewrites the JSON log of the KvStore at a given path as a compacted
+    /// binary log. Return what was done.
+    ///
+    /// The binary log is written to a temporary file, synced and renamed
+    /// over the log, the JSON log is kept as `kvs.log.v1.bak`. The log is
+    /// replaced in a single rename, so a migration interrupted at any point
+    /// leaves a KvStore which opens with its old data. A log which is binary
+    /// already is left alone and no record is counted, a log split in
+    /// segments is refused. A KvStore which has the log open makes the
+    /// migration fail with a `KvsError::AlreadyLocked` error.
+    pub fn migrate(path: impl Into<PathBuf>) -> Result<MigrateReport> {
+        let dir = path.into();
+        let log_file_name = KvStoreOptions::default().log_file_name;
+        if segment_ids(&dir, &log_file_name)?.iter().any(|id| *id > 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a log split in segments can not be migrated",
+            )
+            .into());
+        }
+        // held until the migrated log is in place
+        let _lock = lock_dir(&dir, &log_file_name, false)?;
+        check_engine(&dir, KVS_ENGINE, false)?;
+        let log_name = dir.join(log_file_name);
+        let log = File::open(&log_name)?;
+        let bytes_before = log.metadata()?.len();
+        let mut report = MigrateReport {
+            bytes_before,
+            bytes_after: bytes_before,
+            ..MigrateReport::default()
+        };
+        if record_format(Header::read(&log)?) == LogFormat::Binary {
+            return Ok(report);
+        }
+        let mut kvs = Index::new(false);
+        let mode = RecoveryMode::Strict;
+        let replay = replay_log(&log, &mut kvs, (0, true), 0, u64::MAX, mode, None)?;
+        report.records_read = replay.records;
+        let required = replay.header.is_some_and(|header| header.checksums());
+        let mut sorted: Vec<_> = kvs.live(now_millis())?.collect();
+        sorted.sort_by_key(|kv| kv.1.position());
+
+        let temp_name = with_suffix(&log_name, TEMP_SUFFIX);
+        let temp = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&temp_name)?;
+        let header = Header::new(LogFormat::Binary);
+        header.write(&temp)?;
+        {
+            let mut writer = LogWriter::buffered(&temp, records_start(Some(header)))?;
+            let mut buf = Vec::new();
+            let mut last_seq = 0;
+            for (_, off2len) in sorted {
+                buf.resize(off2len.len, 0);
+                log.read_exact_at(&mut buf, off2len.offset)?;
+                // decoded again rather than rebuilt from the index, which
+                // holds no sequence number
+                let data = decode(LogFormat::Json, &buf, off2len.offset, required)?;
+                if let OptData::SetData { seq, .. } = data {
+                    last_seq = seq;
+                }
+                writer.write_all(&encode(LogFormat::Binary, &data)?)?;
+                report.records_written += 1;
+            }
+            // the record of the last sequence number was dropped
+            if replay.last_seq > last_seq {
+                let data = OptData::SeqData {
+                    seq: replay.last_seq,
+                };
+                writer.write_all(&encode(LogFormat::Binary, &data)?)?;
+            }
+            writer.flush()?;
+        }
+        temp.sync_all()?;
+        report.bytes_after = temp.metadata()?.len();
+
+        // the backup is a second name of the JSON log, which keeps its own
+        // until the rename
+        let backup = with_suffix(&log_name, BACKUP_SUFFIX);
+        match fs::remove_file(&backup) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        fs::hard_link(&log_name, &backup)?;
+        fs::rename(&temp_name, &log_name)?;
+        File::open(&dir)?.sync_all()?;
+        Ok(report)
+    }
+}
+
+// the path of a file named like the one at path with suffix
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    path.with_file_name(name)
+}
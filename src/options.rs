@@ -0,0 +1,322 @@
+use crate::crypt::Cipher;
+use crate::header::Header;
+use crate::{Compression, FileSync, KvStore, LogFormat, OsFileSync, Result, SyncPolicy};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Options to open a KvStore with, `KvStore::open` uses the defaults.
+///
+/// # Examples
+///
+/// ```
+/// use kvs::KvStoreOptions;
+/// # use tempfile::TempDir;
+///
+/// # fn main() -> kvs::Result<()> {
+/// # let temp_dir = TempDir::new()?;
+/// let store = KvStoreOptions::new()
+///     .sync_on_write(true)
+///     .log_file_name("data.log")
+///     .open(temp_dir.path())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) skip_unchanged_writes: bool,
+    pub(crate) read_only: bool,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) log_file_name: String,
+    pub(crate) ordered: bool,
+    pub(crate) max_key_size: usize,
+    pub(crate) max_value_size: usize,
+    pub(crate) compaction_threshold: Option<u64>,
+    pub(crate) verify_checksums: bool,
+    pub(crate) log_format: LogFormat,
+    pub(crate) max_segment_size: Option<u64>,
+    pub(crate) file_sync: Arc<dyn FileSync>,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) compress_min_size: usize,
+    pub(crate) cipher: Option<Cipher>,
+    pub(crate) index_sampling: usize,
+    pub(crate) cache_capacity: usize,
+    pub(crate) inline_values: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            skip_unchanged_writes: false,
+            read_only: false,
+            sync_policy: SyncPolicy::Never,
+            log_file_name: String::from("kvs.log"),
+            ordered: false,
+            max_key_size: 1 << 20,
+            max_value_size: 256 << 20,
+            compaction_threshold: Some(1 << 20),
+            verify_checksums: false,
+            log_format: LogFormat::default(),
+            max_segment_size: None,
+            file_sync: Arc::new(OsFileSync),
+            compression: None,
+            compress_min_size: 4 << 10,
+            cipher: None,
+            index_sampling: 1,
+            cache_capacity: 0,
+            inline_values: 0,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Returns the default options.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets whether `set` writes nothing when the key already has the value
+    /// and no expiration, false by default.
+    ///
+    /// Checking costs a read of the current value for every overwrite, and
+    /// a skipped `set` leaves the version as it was, so it no longer fences
+    /// the writes off each other. Off, every `set` is logged and counted.
+    pub fn skip_unchanged_writes(&mut self, skip: bool) -> &mut KvStoreOptions {
+        self.skip_unchanged_writes = skip;
+        self
+    }
+
+    /// Sets whether the KvStore is opened for reading only, false by default.
+    ///
+    /// See `KvStore::open_read_only`.
+    pub fn read_only(&mut self, read_only: bool) -> &mut KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets whether every write is synced to disk before returning, false by
+    /// default: `SyncPolicy::Always` or `SyncPolicy::Never`.
+    pub fn sync_on_write(&mut self, sync: bool) -> &mut KvStoreOptions {
+        self.sync_policy = if sync {
+            SyncPolicy::Always
+        } else {
+            SyncPolicy::Never
+        };
+        self
+    }
+
+    /// Sets when the writes to the log are synced to disk,
+    /// `SyncPolicy::Never` by default.
+    ///
+    /// A write is synced before it returns when the policy says so, a write
+    /// which is not can be lost on power loss even though it returned.
+    /// `KvStore::sync` syncs whatever the policy. A rebuilt log is always
+    /// synced before it replaces the old one, and its directory after.
+    pub fn sync_policy(&mut self, policy: SyncPolicy) -> &mut KvStoreOptions {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Sets the name of the log file in the KvStore directory, `kvs.log` by
+    /// default.
+    ///
+    /// Compaction and hint files are written to a temporary file in the same
+    /// directory first, named like the log with the id of the process, a
+    /// count and a `.swp` suffix: `kvs.log.1234.0.swp`. A writable `open`
+    /// deletes the ones a crashed process left behind. The lock file is
+    /// named like the log with a `.lock` extension, `kvs.lock`.
+    pub fn log_file_name(&mut self, name: impl Into<String>) -> &mut KvStoreOptions {
+        self.log_file_name = name.into();
+        self
+    }
+
+    /// Sets whether the keys are kept sorted in memory, false by default.
+    ///
+    /// See `KvStore::open_ordered`.
+    pub fn ordered(&mut self, ordered: bool) -> &mut KvStoreOptions {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Sets the largest key in bytes a write accepts, 1 MiB by default.
+    ///
+    /// A longer key fails the write with a `KvsError::KeyTooLarge` error.
+    /// Keys already in the log are not checked.
+    pub fn max_key_size(&mut self, size: usize) -> &mut KvStoreOptions {
+        self.max_key_size = size;
+        self
+    }
+
+    /// Sets the largest value in bytes a write accepts, 256 MiB by default.
+    ///
+    /// A longer value fails the write with a `KvsError::ValueTooLarge`
+    /// error. Values already in the log are not checked.
+    pub fn max_value_size(&mut self, size: usize) -> &mut KvStoreOptions {
+        self.max_value_size = size;
+        self
+    }
+
+    /// Sets how many bytes of garbage the log may hold before a write which
+    /// adds to it compacts the log, 1 MiB by default, `None` to never compact
+    /// automatically.
+    ///
+    /// Overwritten values and removals make the garbage, see
+    /// `KvStore::compact`.
+    pub fn compaction_threshold(&mut self, threshold: Option<u64>) -> &mut KvStoreOptions {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// Sets whether `get`, `get_bytes` and `get_raw` check the record they
+    /// read against its checksum, false by default.
+    ///
+    /// A record which does not match fails the read with a
+    /// `KvsError::Corruption` error. `open` always checks every record of
+    /// the log but the ones a hint file covers, records logged before
+    /// checksums are never checked.
+    pub fn verify_checksums(&mut self, verify: bool) -> &mut KvStoreOptions {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Sets the format the records of a new log are written in,
+    /// `LogFormat::Binary` by default.
+    ///
+    /// `open` finds the format of an existing log in its header and keeps
+    /// writing records in it, only a new or cleared log takes this one.
+    pub fn log_format(&mut self, format: LogFormat) -> &mut KvStoreOptions {
+        self.log_format = format;
+        self
+    }
+
+    /// Sets how many bytes a segment of the log may hold before writes go to
+    /// a new one, `None` by default to keep the whole log in one file.
+    ///
+    /// Segments are named like the log file with their id before the
+    /// extension, `kvs-000001.log`, `kvs-000002.log`. A write which finds
+    /// the active segment full starts the next one, so a segment can end up
+    /// larger by one write. A new log is started as segment 1 with this set,
+    /// an existing `kvs.log` is kept as segment 0. See `KvStore::compact`
+    /// for what happens to the older segments.
+    pub fn max_segment_size(&mut self, size: Option<u64>) -> &mut KvStoreOptions {
+        self.max_segment_size = size;
+        self
+    }
+
+    /// Sets how the files of the KvStore are synced to disk, `OsFileSync` by
+    /// default.
+    pub fn file_sync(&mut self, file_sync: impl FileSync + 'static) -> &mut KvStoreOptions {
+        self.file_sync = Arc::new(file_sync);
+        self
+    }
+
+    /// Sets how the values of at least `compress_min_size` bytes are
+    /// compressed in the records written, `None` by default.
+    ///
+    /// A value which does not shrink is logged as it is. The type of a
+    /// binary record, or a `compressed` field in a JSON one, tells a
+    /// compressed value, which `get` and the other reads decompress: a log
+    /// can mix both, and reading it needs the `compression` feature whatever
+    /// this option. Compaction copies the records as they are, see
+    /// `StoreStats::logical_bytes` for what compression saves.
+    pub fn compression(&mut self, compression: Option<Compression>) -> &mut KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the length in bytes from which a value is compressed when
+    /// `compression` is set, 4 KiB by default.
+    pub fn compress_min_size(&mut self, size: usize) -> &mut KvStoreOptions {
+        self.compress_min_size = size;
+        self
+    }
+
+    /// Sets the 32-byte key the records of the log are encrypted with,
+    /// `None` by default; needs the `encryption` feature, which is not a
+    /// default one.
+    ///
+    /// The ChaCha20-Poly1305 is implemented by this crate, and checked
+    /// against the test vectors of RFC 8439 only: it has not been audited,
+    /// so it is no protection yet for data which matters.
+    ///
+    /// Every record of a new log is encrypted with ChaCha20-Poly1305 under a
+    /// nonce of its own, kept at the start of its payload, so an encrypted
+    /// log is binary whatever `log_format`. The tag of a record also covers
+    /// its place, the segment and the offset, so a record moved or replayed
+    /// elsewhere in the log is corrupt; compaction copies the records still
+    /// encrypted and seals them at their new place. Opening a log encrypted
+    /// with another key, an encrypted log without its key or a plain one
+    /// with a key fails with a `KvsError::BadEncryptionKey` error. The keys
+    /// are only in plain text in memory: no hint file is written for an
+    /// encrypted log, but `dump` and `backup_since` write what they read in
+    /// plain text.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(&mut self, key: Option<[u8; 32]>) -> &mut KvStoreOptions {
+        self.cipher = key.map(Cipher::new);
+        self
+    }
+
+    /// Sets how sparse the index of the compacted log is: only every `n`th
+    /// of its keys is kept in memory, `1` by default to keep all of them.
+    ///
+    /// With `n` above 1 compaction merges the whole log, sorted by key, into
+    /// a sealed segment and a new one is started for the writes after it.
+    /// The index keeps every `n`th key of the sorted segment with the
+    /// position of its record, and those of the keys written since; a read
+    /// of another key scans the at most `n` records between its neighbours
+    /// in the index. Until the first compaction every key is kept, and so
+    /// are the keys with an expiration time. Listing
+    /// the keys, `iter`, `range` and the like read the whole sorted segment
+    /// and keep its keys in memory until the next write. `contains_key`,
+    /// `ttl`, `metadata` and `keys`, which cannot fail, treat a failed read
+    /// as a missing key. No hint file is written, see `StoreStats` for how
+    /// many keys are kept in memory.
+    pub fn index_sampling(&mut self, n: usize) -> &mut KvStoreOptions {
+        self.index_sampling = n.max(1);
+        self
+    }
+
+    /// Sets the capacity in bytes of the cache of the values `get` read
+    /// last, `0` by default for no cache.
+    ///
+    /// `get` and the like answer from the cache before they read the log,
+    /// the value read least recently is dropped once the cache is full. A
+    /// cached value is only served for the record it was read from, so a
+    /// key set or removed since is read again, and a compaction keeps the
+    /// values of the records it moves. See `StoreStats` for the hits and
+    /// misses of the cache.
+    pub fn cache_capacity(&mut self, bytes: usize) -> &mut KvStoreOptions {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// Sets the length in bytes of the longest value the index keeps a copy
+    /// of, `0` by default to keep none.
+    ///
+    /// The UTF-8 values up to `max_len` bytes are kept in memory along with
+    /// the position of their record as they are set or replayed, and `get`
+    /// and the like answer from them without reading the log, which is left
+    /// as it is. A compressed value is read from the log. The copies count
+    /// in `approximate_memory_usage` and are saved in the hint file.
+    pub fn inline_values(&mut self, max_len: usize) -> &mut KvStoreOptions {
+        self.inline_values = max_len;
+        self
+    }
+
+    /// Open the KvStore at a given path with these options. Return the
+    /// KvStore.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        if self.read_only {
+            KvStore::open_read_only_at(path.into(), u64::MAX, self.clone())
+        } else {
+            KvStore::open_with_options(path.into(), self.clone())
+        }
+    }
+
+    // the header of a new segment of a log opened with these options
+    pub(crate) fn new_header(&self) -> Header {
+        match &self.cipher {
+            Some(cipher) => Header::new(LogFormat::Binary).encrypted_with(Some(cipher)),
+            None => Header::new(self.log_format),
+        }
+    }
+}
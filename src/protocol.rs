@@ -0,0 +1,161 @@
+//! The protocol of `kvs-server`: every message is a frame, its length as a
+//! u32 LE then as many bytes of JSON.
+//!
+//! A client writes `Request` frames and reads the `Response` frames to them,
+//! any number of times over one connection. The server answers the requests
+//! of a connection in order, so a client may write several before reading
+//! their responses, see `KvsClient::pipeline`.
+//!
+//! A server started with an authentication token answers `AuthRequired` to
+//! every request of a connection until an `Auth` one sends its token, and
+//! closes the connection after `MAX_AUTH_FAILURES` requests of either kind
+//! failed.
+
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+/// The longest frame read, 64 MiB: a longer one fails with an
+/// `InvalidData` error instead of being allocated.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// The failed authentications, and requests before one, after which a
+/// server closes the connection.
+pub const MAX_AUTH_FAILURES: u32 = 3;
+
+/// What a client asks the server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// the value of key
+    Get {
+        /// the key
+        key: String,
+    },
+    /// set key to value
+    Set {
+        /// the key
+        key: String,
+        /// the value
+        value: String,
+    },
+    /// remove key
+    Remove {
+        /// the key
+        key: String,
+    },
+    /// the values of keys, with one request
+    MultiGet {
+        /// the keys
+        keys: Vec<String>,
+    },
+    /// set every key to its value, with one request
+    MultiSet {
+        /// the key-value pairs
+        pairs: Vec<(String, String)>,
+    },
+    /// remove keys, with one request; missing keys are no error
+    MultiRemove {
+        /// the keys
+        keys: Vec<String>,
+    },
+    /// authenticate the connection, the first request to a server started
+    /// with an authentication token
+    Auth {
+        /// the token of the server
+        token: String,
+    },
+    /// the connections of the server and its limits
+    Stats,
+}
+
+/// What the server answers to a `Request`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// the value of the key of a `Get`, `None` if it does not exist
+    Value(Option<String>),
+    /// the values of the keys of a `MultiGet`, in their order
+    Values(Vec<Option<String>>),
+    /// whether every key of a `MultiRemove` existed, in their order
+    Removed(Vec<bool>),
+    /// the `Set`, the `Remove`, the `MultiSet` or the `Auth` is done
+    Done,
+    /// the connection has to be authenticated before any other request
+    AuthRequired,
+    /// the token of the `Auth` is not the one of the server
+    AuthFailed,
+    /// the connections of the server, answering `Stats`
+    Stats(ServerStats),
+    /// the server serves as many connections as it may, it closes this one
+    /// after sending this before any request
+    Busy,
+    /// the key of a `Remove` does not exist
+    KeyNotFound,
+    /// the engine failed, with the message of its error
+    Error(String),
+}
+
+/// The connections of a server and its limits, see
+/// `KvsClient::server_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// the connections served
+    pub connections: usize,
+    /// the most connections served at once, `None` for no limit
+    pub max_connections: Option<usize>,
+    /// the connections turned away with `Busy` since the server started
+    pub rejected_connections: u64,
+    /// how long a connection may take to send a request, or a write of
+    /// its response wait, in milliseconds, `None` for no limit
+    pub request_timeout_ms: Option<u64>,
+    /// the connections dropped as one timed out since the server started
+    pub timed_out_connections: u64,
+}
+
+/// Writes `message` as a frame to `w` and flushes it.
+pub fn write_frame<T: Serialize>(mut w: impl Write, message: &T) -> Result<()> {
+    push_frame(&mut w, message)?;
+    w.flush()?;
+    Ok(())
+}
+
+// write_frame without the flush, for the frames which go out together
+pub(crate) fn push_frame<T: Serialize>(mut w: impl Write, message: &T) -> Result<()> {
+    let json = serde_json::to_vec(message)?;
+    let len = u32::try_from(json.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too long"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&json)?;
+    Ok(())
+}
+
+/// Reads the next frame of `r`, `None` if the stream ends before it starts.
+pub fn read_frame<T: DeserializeOwned>(mut r: impl Read) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    // an end of stream is only clean before the first byte of a frame
+    match r.read(&mut len[..1]) {
+        Ok(0) => return Ok(None),
+        Ok(_) => r.read_exact(&mut len[1..])?,
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long").into());
+    }
+    let json = read_bytes(&mut r, len)?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+// the next len bytes of r, read as they come in rather than allocated up
+// front: a length the peer only claims costs no more than what it sends
+pub(crate) fn read_bytes(r: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
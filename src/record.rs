@@ -0,0 +1,467 @@
+use crate::checksum::{check_record, record_line};
+use crate::compress::inflate;
+use crate::index::Meta;
+use crate::{rm_data, set_data, KvsError, OptData, Result, Value};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, BufRead, Read};
+
+/// The format the records of a log are written in, see
+/// `KvStoreOptions::log_format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// one JSON object per line, the format of the logs written before
+    /// binary records
+    Json,
+    /// length-prefixed binary frames, which hold keys and values as their
+    /// bytes
+    #[default]
+    Binary,
+}
+
+// a binary record starts with a frame: the type of the record, the length
+// of its payload and the CRC32 of its payload, as u8, u32 LE and u32 LE
+pub(crate) const FRAME_LEN: usize = 9;
+// the types of the records, none is the '{' a JSON record starts with
+pub(crate) const SET: u8 = 1;
+const RM: u8 = 2;
+const GET: u8 = 3;
+const TXN: u8 = 4;
+const SEQ: u8 = 5;
+// the flag of the type of a SetData record whose value is compressed: the
+// value then holds its length before compression as u64 LE and its DEFLATE
+// stream
+const COMPRESSED: u8 = 0x80;
+pub(crate) const SET_COMPRESSED: u8 = SET | COMPRESSED;
+// the payload of a SetData record starts with its expiration time (0 for
+// none), version, creation time, update time and sequence number as u64 LE,
+// then holds the key and the value each after its length as u32 LE
+pub(crate) const SET_FIELDS_LEN: usize = 40;
+
+// the record logged for data in format
+pub(crate) fn encode(format: LogFormat, data: &OptData) -> Result<Vec<u8>> {
+    if format == LogFormat::Json {
+        return Ok(record_line(data)?.into_bytes());
+    }
+    let mut payload = Vec::new();
+    let kind = match data {
+        OptData::SetData {
+            key,
+            value,
+            expires_at,
+            version,
+            created_at,
+            updated_at,
+            seq,
+            base64,
+            key_base64,
+            compressed,
+        } => {
+            let fields = [
+                expires_at.unwrap_or(0),
+                *version,
+                *created_at,
+                *updated_at,
+                *seq,
+            ];
+            for field in fields.iter() {
+                payload.extend_from_slice(&field.to_le_bytes());
+            }
+            put_bytes(&mut payload, &logged_bytes(key, *key_base64)?)?;
+            match compressed {
+                Some(len) => {
+                    let mut bytes = len.to_le_bytes().to_vec();
+                    bytes.extend_from_slice(&BASE64.decode(value)?);
+                    put_bytes(&mut payload, &bytes)?;
+                    SET_COMPRESSED
+                }
+                None => {
+                    put_bytes(&mut payload, &logged_bytes(value, *base64)?)?;
+                    SET
+                }
+            }
+        }
+        OptData::RmData {
+            key,
+            key_base64,
+            seq,
+        } => {
+            payload.extend_from_slice(&seq.to_le_bytes());
+            put_bytes(&mut payload, &logged_bytes(key, *key_base64)?)?;
+            RM
+        }
+        OptData::GetData { key } => {
+            put_bytes(&mut payload, key.as_bytes())?;
+            GET
+        }
+        OptData::TxnData { count } => {
+            payload.extend_from_slice(&(*count as u64).to_le_bytes());
+            TXN
+        }
+        OptData::SeqData { seq } => {
+            payload.extend_from_slice(&seq.to_le_bytes());
+            SEQ
+        }
+    };
+    let mut record = frame(kind, payload.len() as u64, crc32fast::hash(&payload))?.to_vec();
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+// a SetData record borrowing the key and the value it logs, so a write
+// needs no OptData of its own; its value is logged as the compression of
+// deflated if there is one
+pub(crate) struct SetDataRef<'a> {
+    pub(crate) key: &'a [u8],
+    pub(crate) value: &'a [u8], // logged as text if it is UTF-8, like a Value
+    pub(crate) expires_at: Option<u64>,
+    pub(crate) meta: Meta,
+    pub(crate) seq: u64,
+    pub(crate) deflated: Option<(u64, Vec<u8>)>, // the length before and the DEFLATE stream
+}
+
+impl SetDataRef<'_> {
+    // the bytes compression saves on the value
+    pub(crate) fn saved(&self) -> u32 {
+        self.deflated.as_ref().map_or(0, |(len, deflated)| {
+            let saved = len.saturating_sub(deflated.len() as u64);
+            u32::try_from(saved).unwrap_or(u32::MAX)
+        })
+    }
+}
+
+// the JSON of a SetDataRef, the one of the OptData it stands for
+#[derive(Serialize)]
+enum JsonRef<'a> {
+    SetData {
+        key: Cow<'a, str>,
+        value: Cow<'a, str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        version: u64,
+        created_at: u64,
+        updated_at: u64,
+        seq: u64,
+        #[serde(skip_serializing_if = "crate::is_false")]
+        base64: bool,
+        #[serde(skip_serializing_if = "crate::is_false")]
+        key_base64: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compressed: Option<u64>,
+    },
+}
+
+// the record logged for data in format, the one encode logs for the
+// OptData data stands for
+pub(crate) fn encode_set(format: LogFormat, data: &SetDataRef) -> Result<Vec<u8>> {
+    if format == LogFormat::Json {
+        let (key, key_base64) = text_or_base64(data.key);
+        let (value, base64, compressed) = match &data.deflated {
+            Some((len, deflated)) => (Cow::Owned(BASE64.encode(deflated)), true, Some(*len)),
+            None => {
+                let (value, base64) = text_or_base64(data.value);
+                (value, base64, None)
+            }
+        };
+        let json = JsonRef::SetData {
+            key,
+            value,
+            expires_at: data.expires_at,
+            version: data.meta.version,
+            created_at: data.meta.created_at,
+            updated_at: data.meta.updated_at,
+            seq: data.seq,
+            base64,
+            key_base64,
+            compressed,
+        };
+        return Ok(record_line(&json)?.into_bytes());
+    }
+    let value = data.value;
+    let mut payload = Vec::with_capacity(SET_FIELDS_LEN + 16 + data.key.len() + value.len());
+    let fields = [
+        data.expires_at.unwrap_or(0),
+        data.meta.version,
+        data.meta.created_at,
+        data.meta.updated_at,
+        data.seq,
+    ];
+    for field in fields.iter() {
+        payload.extend_from_slice(&field.to_le_bytes());
+    }
+    put_bytes(&mut payload, data.key)?;
+    let kind = match &data.deflated {
+        Some((len, deflated)) => {
+            let value_len = u32::try_from(8 + deflated.len()).map_err(|_| too_long())?;
+            payload.extend_from_slice(&value_len.to_le_bytes());
+            payload.extend_from_slice(&len.to_le_bytes());
+            payload.extend_from_slice(deflated);
+            SET_COMPRESSED
+        }
+        None => {
+            put_bytes(&mut payload, value)?;
+            SET
+        }
+    };
+    let mut record = Vec::with_capacity(FRAME_LEN + payload.len());
+    record.extend_from_slice(&frame(
+        kind,
+        payload.len() as u64,
+        crc32fast::hash(&payload),
+    )?);
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+// a key or a value as text, or as base64 if it is not UTF-8, like
+// encode_key
+fn text_or_base64(key: &[u8]) -> (Cow<'_, str>, bool) {
+    match std::str::from_utf8(key) {
+        Ok(key) => (Cow::Borrowed(key), false),
+        Err(_) => (Cow::Owned(BASE64.encode(key)), true),
+    }
+}
+
+// the frame of a binary record of type kind whose payload of len bytes has
+// the checksum crc
+pub(crate) fn frame(kind: u8, len: u64, crc: u32) -> Result<[u8; FRAME_LEN]> {
+    let len = u32::try_from(len).map_err(|_| too_long())?;
+    let mut frame = [kind; FRAME_LEN];
+    frame[1..5].copy_from_slice(&len.to_le_bytes());
+    frame[5..].copy_from_slice(&crc.to_le_bytes());
+    Ok(frame)
+}
+
+// the record at offset in a log whose records are in format, checked
+// against its checksum, which records logged before checksums only have if
+// required
+pub(crate) fn decode(
+    format: LogFormat,
+    record: &[u8],
+    offset: u64,
+    required: bool,
+) -> Result<OptData> {
+    if format == LogFormat::Json {
+        check_record(record, offset, required)?;
+        return Ok(serde_json::from_slice(record)?);
+    }
+    let (kind, payload) = unframe(record, offset, true)?;
+    let mut fields = Fields::new(payload, offset);
+    let data = match kind {
+        SET | SET_COMPRESSED => {
+            let expires_at = match fields.u64()? {
+                0 => None,
+                time => Some(time),
+            };
+            let meta = Meta {
+                version: fields.u64()?,
+                created_at: fields.u64()?,
+                updated_at: fields.u64()?,
+            };
+            let seq = fields.u64()?;
+            let key = fields.bytes()?;
+            let mut value = Fields::new(fields.bytes()?, offset);
+            if kind == SET {
+                set_data(
+                    key,
+                    Value::from_bytes(value.bytes.to_vec()),
+                    expires_at,
+                    meta,
+                    seq,
+                )
+            } else {
+                let len = value.u64()?;
+                let mut data = set_data(
+                    key,
+                    Value::Bytes(value.bytes.to_vec()),
+                    expires_at,
+                    meta,
+                    seq,
+                );
+                if let OptData::SetData { compressed, .. } = &mut data {
+                    *compressed = Some(len);
+                }
+                data
+            }
+        }
+        RM => {
+            let seq = fields.u64()?;
+            rm_data(fields.bytes()?, seq)
+        }
+        GET => {
+            let key = String::from_utf8(fields.bytes()?.to_vec());
+            OptData::GetData {
+                key: key.map_err(|_| KvsError::Corruption { offset })?,
+            }
+        }
+        TXN => OptData::TxnData {
+            count: fields.u64()? as usize,
+        },
+        SEQ => OptData::SeqData { seq: fields.u64()? },
+        _ => return Err(KvsError::Corruption { offset }.into()),
+    };
+    if !fields.bytes.is_empty() {
+        return Err(KvsError::Corruption { offset }.into());
+    }
+    Ok(data)
+}
+
+// the value of the SetData record at offset, None for another record,
+// checked against its checksum first if verify
+//
+// The record is one the index points at, which replay_log decoded already:
+// its first byte tells its format.
+pub(crate) fn decode_value(record: &[u8], offset: u64, verify: bool) -> Result<Option<Value>> {
+    if record.first() == Some(&b'{') {
+        if verify {
+            check_record(record, offset, false)?;
+        }
+        return match serde_json::from_slice(record)? {
+            OptData::SetData {
+                value,
+                base64,
+                compressed,
+                ..
+            } => Ok(Some(logged_value(value, base64, compressed, offset)?)),
+            _ => Ok(None),
+        };
+    }
+    let (kind, payload) = unframe(record, offset, verify)?;
+    if kind != SET && kind != SET_COMPRESSED {
+        return Ok(None);
+    }
+    let mut fields = Fields::new(payload, offset);
+    for _ in 0..SET_FIELDS_LEN / 8 {
+        fields.u64()?;
+    }
+    fields.bytes()?;
+    let mut value = Fields::new(fields.bytes()?, offset);
+    if kind == SET {
+        return Ok(Some(Value::from_bytes(value.bytes.to_vec())));
+    }
+    let len = value.u64()?;
+    Ok(Some(Value::from_bytes(inflate(value.bytes, len, offset)?)))
+}
+
+// the value of the SetData record at offset from its logged fields
+pub(crate) fn logged_value(
+    value: String,
+    base64: bool,
+    compressed: Option<u64>,
+    offset: u64,
+) -> Result<Value> {
+    match (base64, compressed) {
+        (_, Some(len)) => Ok(Value::from_bytes(inflate(
+            &BASE64.decode(value)?,
+            len,
+            offset,
+        )?)),
+        (true, None) => Ok(Value::from_bytes(BASE64.decode(value)?)),
+        (false, None) => Ok(Value::Text(value)),
+    }
+}
+
+// read the next record of a log whose records are in format into buf,
+// return its length, 0 at the end of the log
+//
+// A binary record cut short is read as far as it goes, it then fails to
+// decode.
+pub(crate) fn read_next(
+    format: LogFormat,
+    reader: &mut impl BufRead,
+    buf: &mut Vec<u8>,
+) -> io::Result<usize> {
+    buf.clear();
+    if format == LogFormat::Json {
+        return reader.read_until(b'\n', buf);
+    }
+    (&mut *reader).take(FRAME_LEN as u64).read_to_end(buf)?;
+    if buf.len() == FRAME_LEN {
+        let len = u32::from_le_bytes(buf[1..5].try_into().unwrap_or_default());
+        (&mut *reader).take(len as u64).read_to_end(buf)?;
+    }
+    Ok(buf.len())
+}
+
+// the type and the payload of the binary record at offset, checked against
+// its checksum if check
+fn unframe(record: &[u8], offset: u64, check: bool) -> Result<(u8, &[u8])> {
+    if record.len() < FRAME_LEN {
+        return Err(KvsError::Corruption { offset }.into());
+    }
+    let (frame, payload) = record.split_at(FRAME_LEN);
+    let len = u32::from_le_bytes(frame[1..5].try_into().unwrap_or_default());
+    let crc = u32::from_le_bytes(frame[5..].try_into().unwrap_or_default());
+    if len as usize != payload.len() || (check && crc != crc32fast::hash(payload)) {
+        return Err(KvsError::Corruption { offset }.into());
+    }
+    Ok((frame[0], payload))
+}
+
+// the bytes of a key or a value logged as text, or as base64 if base64
+fn logged_bytes(text: &str, base64: bool) -> Result<Cow<'_, [u8]>> {
+    if base64 {
+        Ok(Cow::Owned(BASE64.decode(text)?))
+    } else {
+        Ok(Cow::Borrowed(text.as_bytes()))
+    }
+}
+
+// append bytes after their length to buf
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| too_long())?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn too_long() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "key or value too long for a binary record",
+    )
+}
+
+// the fields of the payload of the binary record at offset, consumed in
+// order
+pub(crate) struct Fields<'a> {
+    bytes: &'a [u8],
+    offset: u64,
+}
+
+impl<'a> Fields<'a> {
+    pub(crate) fn new(bytes: &'a [u8], offset: u64) -> Fields<'a> {
+        Fields { bytes, offset }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(KvsError::Corruption {
+                offset: self.offset,
+            }
+            .into());
+        }
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(
+            self.take(8)?.try_into().unwrap_or_default(),
+        ))
+    }
+
+    // bytes after their length
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap_or_default());
+        self.take(len as usize)
+    }
+}
@@ -0,0 +1,43 @@
+use crate::{KvStore, KvStoreOptions, Result};
+use std::path::PathBuf;
+
+/// How `KvStore::open_with_recovery` handles a record of the log which does
+/// not parse.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// fail to open, like `KvStore::open`
+    Strict,
+    /// skip the record, and the whole transaction it belongs to, then
+    /// rewrite the log without them
+    SkipCorrupt,
+    /// truncate the log at the record, or at the start of its transaction
+    TruncateAtError,
+}
+
+/// What `KvStore::open_with_recovery` dropped from the log.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// the number of records dropped, corrupt or not
+    pub dropped_records: usize,
+    /// the number of bytes of the dropped records
+    pub dropped_bytes: u64,
+    /// the number of bytes cut off the end of the log: a record which does
+    /// not parse and ends the log was torn by a crash while it was appended,
+    /// it is truncated off in every mode
+    pub truncated_bytes: u64,
+}
+
+impl KvStore {
+    /// Open the KvStore at a given path, handling corrupt records of its log
+    /// as `mode` says. Return the KvStore and what was dropped.
+    ///
+    /// The repair is written to disk before returning, so later opens find a
+    /// clean log. With `RecoveryMode::Strict` nothing is ever dropped but a
+    /// torn last record.
+    pub fn open_with_recovery(
+        path: impl Into<PathBuf>,
+        mode: RecoveryMode,
+    ) -> Result<(KvStore, RecoveryReport)> {
+        KvStore::open_recovering(path.into(), KvStoreOptions::new(), mode)
+    }
+}
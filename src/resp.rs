@@ -0,0 +1,261 @@
+use crate::protocol::{read_bytes, ServerStats, MAX_FRAME_LEN};
+use crate::server::Auth;
+use crate::{KvsEngine, KvsError, Result};
+use std::io::{self, BufRead, Read, Write};
+
+// the most arguments of a multi-bulk command; together they are no longer
+// than MAX_FRAME_LEN, like a request of the kvs protocol
+const MAX_ARGS: usize = 64 << 10;
+// the longest line, of an inline command or of a length
+const MAX_LINE_LEN: usize = 64 << 10;
+
+// a reply of RESP2
+pub(crate) enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(usize),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl Reply {
+    pub(crate) fn write(&self, mut w: impl Write) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(w, "+{}\r\n", status),
+            // a line break would end the error early
+            Reply::Error(message) => write!(w, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(w, ":{}\r\n", n),
+            Reply::Bulk(Some(bytes)) => {
+                write!(w, "${}\r\n", bytes.len())?;
+                w.write_all(bytes)?;
+                w.write_all(b"\r\n")
+            }
+            Reply::Bulk(None) => w.write_all(b"$-1\r\n"),
+        }
+    }
+}
+
+// the arguments of the next command of r, in the multi-bulk form or inline;
+// None once the client closed the connection between commands
+//
+// A request which breaks the protocol fails with an InvalidData error, the
+// connection cannot go on after it.
+pub(crate) fn read_command(r: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(r)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let args = match line.strip_prefix(b"*") {
+            Some(count) => read_bulks(r, count)?,
+            None => line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect(),
+        };
+        // an empty command gets no reply
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+// the count bulk strings following a `*count` line
+fn read_bulks(r: &mut impl BufRead, count: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let count = match parse_len(count) {
+        Some(count) if count <= MAX_ARGS => count,
+        // `*-1` is no command
+        _ if count == b"-1" => return Ok(Vec::new()),
+        _ => return Err(protocol_error("invalid multibulk length")),
+    };
+    let mut args = Vec::new();
+    let mut left = MAX_FRAME_LEN;
+    for _ in 0..count {
+        let line = read_line(r)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        let len = match line.split_first() {
+            Some((b'$', len)) => parse_len(len)
+                .filter(|len| *len <= left)
+                .ok_or_else(|| protocol_error("invalid bulk length"))?,
+            Some((b, _)) => {
+                return Err(protocol_error(&format!(
+                    "expected '$', got '{}'",
+                    char::from(*b)
+                )))
+            }
+            None => return Err(protocol_error("expected '$', got an empty line")),
+        };
+        left -= len;
+        let mut arg = read_bytes(r, len + 2)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not ended by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+// the next line of r without its line break, which is CRLF or LF alone;
+// None at the end of the stream
+fn read_line(r: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = r
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() > MAX_LINE_LEN {
+            protocol_error("too big request line")
+        } else {
+            protocol_error("unexpected end of stream")
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Option<usize> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn protocol_error(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", reason),
+    )
+}
+
+// the reply of engine to the command of args, which is not empty
+pub(crate) fn answer<E: KvsEngine>(engine: &mut E, mut args: Vec<Vec<u8>>) -> Reply {
+    let name = String::from_utf8_lossy(&args.remove(0)).into_owned();
+    let command = name.to_ascii_lowercase();
+    let arity_ok = match command.as_str() {
+        "ping" => args.len() <= 1,
+        "get" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" => !args.is_empty(),
+        _ => return unknown_command(&name, &args),
+    };
+    if !arity_ok {
+        return Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command
+        ));
+    }
+    let answered = match command.as_str() {
+        "ping" => Ok(match args.pop() {
+            Some(message) => Reply::Bulk(Some(message)),
+            None => Reply::Status("PONG"),
+        }),
+        "get" => utf8(args.remove(0)).and_then(|key| {
+            let value = engine.get(key)?;
+            Ok(Reply::Bulk(value.map(String::into_bytes)))
+        }),
+        // the options of SET, such as EX or NX, are not supported
+        "set" if args.len() > 2 => return Reply::Error(String::from("ERR syntax error")),
+        "set" => {
+            let value = args.pop().expect("set has a value");
+            let key = args.pop().expect("set has a key");
+            utf8(key).and_then(|key| {
+                engine.set(key, utf8(value)?)?;
+                Ok(Reply::Status("OK"))
+            })
+        }
+        "del" => count_keys(args, |key| match engine.remove(key) {
+            Ok(()) => Ok(true),
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::KeyNotFound) => Ok(false),
+                _ => Err(err),
+            },
+        }),
+        _ => count_keys(args, |key| Ok(engine.get(key)?.is_some())),
+    };
+    answered.unwrap_or_else(|err| Reply::Error(format!("ERR {}", err)))
+}
+
+// the reply to AUTH, whose password is the token of the server
+pub(crate) fn auth(auth: &mut Auth, mut args: Vec<Vec<u8>>) -> Reply {
+    if args.len() != 2 && args.len() != 3 {
+        return Reply::Error(String::from(
+            "ERR wrong number of arguments for 'auth' command",
+        ));
+    }
+    if !auth.required() {
+        return Reply::Error(String::from(
+            "ERR AUTH <password> called without any password configured for the default user. \
+             Are you sure your configuration is correct?",
+        ));
+    }
+    let password = args.pop().expect("auth has a password");
+    // `AUTH username password`, of which only the default user exists
+    let passed = match args.get(1) {
+        Some(user) if user != b"default" => {
+            auth.refuse();
+            false
+        }
+        _ => auth.check(&String::from_utf8_lossy(&password)),
+    };
+    if passed {
+        Reply::Status("OK")
+    } else {
+        Reply::Error(String::from(
+            "WRONGPASS invalid username-password pair or user is disabled.",
+        ))
+    }
+}
+
+// the reply to INFO, with the fields Redis has for the connections and one
+// for the timeout; a maxclients of 0 is no limit
+pub(crate) fn info(stats: &ServerStats) -> Reply {
+    let info = format!(
+        "# Clients\r\n\
+         connected_clients:{}\r\n\
+         maxclients:{}\r\n\
+         \r\n\
+         # Stats\r\n\
+         rejected_connections:{}\r\n\
+         timedout_connections:{}\r\n\
+         request_timeout_ms:{}\r\n",
+        stats.connections,
+        stats.max_connections.unwrap_or(0),
+        stats.rejected_connections,
+        stats.timed_out_connections,
+        stats.request_timeout_ms.unwrap_or(0),
+    );
+    Reply::Bulk(Some(info.into_bytes()))
+}
+
+// the number of keys f holds for, as DEL and EXISTS reply
+fn count_keys<F>(keys: Vec<Vec<u8>>, mut f: F) -> Result<Reply>
+where
+    F: FnMut(String) -> Result<bool>,
+{
+    let mut n = 0;
+    for key in keys {
+        if f(utf8(key)?)? {
+            n += 1;
+        }
+    }
+    Ok(Reply::Integer(n))
+}
+
+// the error Redis replies to a command it does not know
+fn unknown_command(name: &str, args: &[Vec<u8>]) -> Reply {
+    let mut message = format!("ERR unknown command '{}', with args beginning with: ", name);
+    for arg in args {
+        message.push_str(&format!("'{}' ", String::from_utf8_lossy(arg)));
+    }
+    Reply::Error(message)
+}
+
+// the engine keeps strings, a key or a value which is not UTF-8 is refused
+fn utf8(arg: Vec<u8>) -> Result<String> {
+    String::from_utf8(arg).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "invalid UTF-8 in argument").into()
+    })
+}
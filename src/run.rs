@@ -0,0 +1,354 @@
+use crate::crypt::{move_record, open_record, Cipher};
+use crate::header::{records_start, Header};
+use crate::index::{Key, OffsetLen};
+use crate::record::{decode, read_next};
+use crate::segment::{missing_segment, Segments};
+use crate::stream::LogRange;
+use crate::writer::LogWriter;
+use crate::{set_entry, OptData, Result};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+// a sealed segment whose records are sorted by key, one per key, of which
+// a sampled index only keeps every nth key in memory, see
+// `KvStoreOptions::index_sampling`
+//
+// A key between two samples is found by reading the records between them.
+// The entries with an expiration time are kept in the index instead, so
+// the entries of a run never expire.
+#[derive(Clone)]
+pub(crate) struct Run {
+    pub(crate) segment: u32,
+    file: Arc<File>,
+    header: Header,
+    cipher: Option<Cipher>,
+    samples: Vec<(Key, OffsetLen)>, // every nth entry, in key order
+    end: u64,                       // where the records end
+    pub(crate) len: usize,          // the number of entries, but the kept ones
+    pub(crate) bytes: u64,          // the sum of their lengths
+    pub(crate) saved: u64,          // the bytes compression saved on them
+}
+
+impl Run {
+    // the entry of key, None if the run has no record of it
+    pub(crate) fn find(&self, key: &[u8]) -> Result<Option<OffsetLen>> {
+        let next = self
+            .samples
+            .partition_point(|(sample, _)| sample.as_bytes() <= key);
+        let (sample_key, sample) = match next.checked_sub(1) {
+            Some(prev) => &self.samples[prev],
+            None => return Ok(None),
+        };
+        if sample_key.as_bytes() == key {
+            return Ok(Some(sample.clone()));
+        }
+        let from = sample.offset + sample.len as u64;
+        let to = self
+            .samples
+            .get(next)
+            .map_or(self.end, |(_, next)| next.offset);
+        let mut found = None;
+        self.scan(from, to, |_, entry, _| match entry.0.as_bytes().cmp(key) {
+            Ordering::Less => Ok(true),
+            Ordering::Equal => {
+                found = Some(entry.1);
+                Ok(false)
+            }
+            Ordering::Greater => Ok(false),
+        })?;
+        Ok(found)
+    }
+
+    // every entry of the run in key order, those kept in the index too
+    pub(crate) fn load(&self) -> Result<Vec<(Key, OffsetLen)>> {
+        let mut entries = Vec::with_capacity(self.len);
+        self.scan(records_start(Some(self.header)), self.end, |_, entry, _| {
+            entries.push(entry);
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    pub(crate) fn header(&self) -> Header {
+        self.header
+    }
+
+    // the keys the run keeps in memory, with their entries
+    pub(crate) fn samples(&self) -> &[(Key, OffsetLen)] {
+        &self.samples
+    }
+
+    // call f with the record, the entry and the sequence number of every
+    // record from `from` to `to`, in log order, until it returns false
+    fn scan<F>(&self, from: u64, to: u64, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], (Key, OffsetLen), u64) -> Result<bool>,
+    {
+        let range = LogRange {
+            log: &self.file,
+            pos: from,
+            end: to,
+        };
+        let mut reader = BufReader::new(range);
+        let mut record = Vec::new();
+        let mut offset = from;
+        loop {
+            let len = read_next(self.header.format(), &mut reader, &mut record)?;
+            if len == 0 {
+                return Ok(());
+            }
+            let data = self.decode(&record, offset)?;
+            let seq = match data {
+                OptData::SetData { seq, .. } => seq,
+                _ => 0,
+            };
+            let entry = set_entry(data, (self.segment, offset), len, 0)?.ok_or_else(not_sorted)?;
+            if !f(&record, entry, seq)? {
+                return Ok(());
+            }
+            offset += len as u64;
+        }
+    }
+
+    fn decode(&self, record: &[u8], offset: u64) -> Result<OptData> {
+        let place = (Some(self.header), self.segment, offset);
+        let record = open_record(self.cipher.as_ref(), record, place)?;
+        decode(
+            self.header.format(),
+            &record,
+            offset,
+            self.header.checksums(),
+        )
+    }
+}
+
+// the run of the records of a segment, given in key order, as they are read
+// or written
+pub(crate) struct RunBuilder {
+    sampling: usize,
+    count: usize, // the number of records
+    samples: Vec<(Key, OffsetLen)>,
+    kept: Vec<(Key, OffsetLen)>, // the entries with an expiration time
+    last: Option<Key>,
+    len: usize,
+    bytes: u64,
+    saved: u64,
+}
+
+impl RunBuilder {
+    pub(crate) fn new(sampling: usize) -> RunBuilder {
+        RunBuilder {
+            sampling,
+            count: 0,
+            samples: Vec::new(),
+            kept: Vec::new(),
+            last: None,
+            len: 0,
+            bytes: 0,
+            saved: 0,
+        }
+    }
+
+    // add the entry of the next record, false if its key does not sort
+    // after the one before
+    pub(crate) fn push(&mut self, key: Key, off2len: OffsetLen) -> bool {
+        if self.last.as_ref().is_some_and(|last| *last >= key) {
+            return false;
+        }
+        if self.count.is_multiple_of(self.sampling) {
+            let sample = OffsetLen {
+                inline: None,
+                ..off2len.clone()
+            };
+            self.samples.push((key.clone(), sample));
+        }
+        self.count += 1;
+        if off2len.expires_at.is_some() {
+            self.kept.push((key.clone(), off2len));
+        } else {
+            self.len += 1;
+            self.bytes += off2len.len as u64;
+            self.saved += u64::from(off2len.saved);
+        }
+        self.last = Some(key);
+        true
+    }
+
+    // the run of segment, whose records end at end in file, and the entries
+    // the index has to keep
+    pub(crate) fn finish(
+        self,
+        (segment, file, header): (u32, &File, Header),
+        cipher: Option<&Cipher>,
+        end: u64,
+    ) -> io::Result<(Run, Vec<(Key, OffsetLen)>)> {
+        let run = Run {
+            segment,
+            file: Arc::new(file.try_clone()?),
+            header,
+            cipher: cipher.cloned(),
+            samples: self.samples,
+            end,
+            len: self.len,
+            bytes: self.bytes,
+            saved: self.saved,
+        };
+        Ok((run, self.kept))
+    }
+}
+
+// what replaying a sorted segment found
+pub(crate) struct RunReplay {
+    pub(crate) run: Run,
+    pub(crate) kept: Vec<(Key, OffsetLen)>, // the entries the index keeps
+    pub(crate) end: u64,
+    pub(crate) records: usize,
+    pub(crate) last_seq: u64,
+}
+
+// replay segment, a sorted one, into a run; None if its records are not
+// SetData in key order, for it to be replayed like any other
+pub(crate) fn replay_run(
+    (segment, file, header): (u32, &File, Header),
+    cipher: Option<&Cipher>,
+    sampling: usize,
+) -> Result<Option<RunReplay>> {
+    let end = file.metadata()?.len();
+    // an empty run, to read the records with
+    let (reader, _) = RunBuilder::new(sampling).finish((segment, file, header), cipher, end)?;
+    let mut builder = RunBuilder::new(sampling);
+    let mut last_seq = 0;
+    let scanned = reader.scan(
+        records_start(Some(header)),
+        end,
+        |_, (key, off2len), seq| {
+            last_seq = last_seq.max(seq);
+            if !builder.push(key, off2len) {
+                return Err(not_sorted().into());
+            }
+            Ok(true)
+        },
+    );
+    if scanned.is_err() {
+        return Ok(None);
+    }
+    let records = builder.count;
+    let (run, kept) = builder.finish((segment, file, header), cipher, end)?;
+    Ok(Some(RunReplay {
+        run,
+        kept,
+        end,
+        records,
+        last_seq,
+    }))
+}
+
+// writes the live entries of a run, and entries which are in key order and
+// none of them in the run, merged by key to a sorted segment
+pub(crate) struct SortedWriter<'a> {
+    segments: &'a Segments,
+    writer: BufWriter<LogWriter>,
+    builder: RunBuilder,
+    segment: u32, // the id of the segment written
+    header: Header,
+    offset: u64, // where the next record goes
+    buf: Vec<u8>,
+}
+
+impl SortedWriter<'_> {
+    // a writer of segment to temp, after header
+    pub(crate) fn new<'a>(
+        segments: &'a Segments,
+        (segment, header): (u32, Header),
+        temp: &File,
+        sampling: usize,
+    ) -> Result<SortedWriter<'a>> {
+        header.write(temp)?;
+        let offset = records_start(Some(header));
+        Ok(SortedWriter {
+            segments,
+            writer: LogWriter::buffered(temp, offset)?,
+            builder: RunBuilder::new(sampling),
+            segment,
+            header,
+            offset,
+            buf: Vec::new(),
+        })
+    }
+
+    // write the live entries of run, those whose key is not hidden, and
+    // entries in key order; return the builder of the run written and where
+    // its records end
+    pub(crate) fn write(
+        mut self,
+        run: Option<(&Run, &HashSet<Key>)>,
+        entries: Vec<(Key, OffsetLen)>,
+    ) -> Result<(RunBuilder, u64)> {
+        let mut entries = entries.into_iter().peekable();
+        if let Some((run, hidden)) = run {
+            let start = records_start(Some(run.header));
+            run.scan(start, run.end, |record, (key, off2len), _| {
+                while let Some(entry) = entries.next_if(|entry| entry.0 < key) {
+                    self.copy(entry)?;
+                }
+                if !hidden.contains(key.as_bytes()) {
+                    self.push(key, off2len, record)?;
+                }
+                Ok(true)
+            })?;
+        }
+        for entry in entries {
+            self.copy(entry)?;
+        }
+        self.writer.flush()?;
+        Ok((self.builder, self.offset))
+    }
+
+    // push the record of the entry, read from its segment
+    fn copy(&mut self, (key, off2len): (Key, OffsetLen)) -> Result<()> {
+        let log = self
+            .segments
+            .file(off2len.segment)
+            .ok_or_else(missing_segment)?;
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.resize(off2len.len, 0);
+        log.read_exact_at(&mut buf, off2len.offset)?;
+        let pushed = self.push(key, off2len, &buf);
+        self.buf = buf;
+        pushed
+    }
+
+    // write record, the one of the entry, as the next of the segment
+    fn push(&mut self, key: Key, off2len: OffsetLen, record: &[u8]) -> Result<()> {
+        let segments = self.segments;
+        let from = (
+            segments.header(off2len.segment),
+            off2len.segment,
+            off2len.offset,
+        );
+        let to = (Some(self.header), self.segment, self.offset);
+        self.writer
+            .write_all(&move_record(segments.cipher.as_ref(), record, from, to)?)?;
+        let off2len = OffsetLen {
+            segment: self.segment,
+            offset: self.offset,
+            ..off2len
+        };
+        self.offset += record.len() as u64;
+        if !self.builder.push(key, off2len) {
+            return Err(not_sorted().into());
+        }
+        Ok(())
+    }
+}
+
+fn not_sorted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "records of a sorted segment out of order",
+    )
+}
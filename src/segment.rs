@@ -0,0 +1,259 @@
+use crate::crypt::Cipher;
+use crate::header::{records_start, Header};
+use crate::{KvStore, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// the number of digits of the id in the name of a segment
+const ID_DIGITS: usize = 6;
+
+// a file of the log
+#[derive(Clone)]
+pub(crate) struct Segment {
+    pub(crate) file: Arc<File>,
+    pub(crate) header: Option<Header>,
+    pub(crate) len: u64, // the length once sealed, stale for the active one
+}
+
+// the segments of a log by id, replayed in id order: the active one, which
+// is written to, has the largest id and the sealed ones are never written
+// again
+//
+// Snapshots clone it and keep reading the files of the segments they saw,
+// whatever the KvStore does with them afterwards.
+#[derive(Clone, Default)]
+pub(crate) struct Segments {
+    map: BTreeMap<u32, Segment>,
+    pub(crate) cipher: Option<Cipher>, // the cipher of the encrypted segments
+    version: u64,                      // counts the segments inserted and removed
+}
+
+impl Segments {
+    // the file of the segment, None if there is none with the id
+    pub(crate) fn file(&self, id: u32) -> Option<&File> {
+        self.map.get(&id).map(|segment| &*segment.file)
+    }
+
+    // the header of the segment, None if there is none with the id or it
+    // has none
+    pub(crate) fn header(&self, id: u32) -> Option<Header> {
+        self.map.get(&id).and_then(|segment| segment.header)
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        id: u32,
+        file: &File,
+        header: Option<Header>,
+    ) -> io::Result<()> {
+        let segment = Segment {
+            file: Arc::new(file.try_clone()?),
+            header,
+            len: 0,
+        };
+        self.map.insert(id, segment);
+        self.version += 1;
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, id: u32) {
+        self.map.remove(&id);
+        self.version += 1;
+    }
+
+    // changes whenever a segment is inserted or removed
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    // record the final length of the segment, which is no longer written to
+    pub(crate) fn seal(&mut self, id: u32, len: u64) {
+        if let Some(segment) = self.map.get_mut(&id) {
+            segment.len = len;
+        }
+    }
+
+    // every segment, in id order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u32, &Segment)> {
+        self.map.iter().map(|(id, segment)| (*id, segment))
+    }
+
+    // the segments older than active, in id order
+    pub(crate) fn sealed(&self, active: u32) -> impl Iterator<Item = (u32, &Segment)> {
+        self.map.range(..active).map(|(id, segment)| (*id, segment))
+    }
+
+    // the bytes of the records of the segments older than active
+    pub(crate) fn sealed_records(&self, active: u32) -> u64 {
+        self.sealed(active)
+            .map(|(_, segment)| segment.len - records_start(segment.header))
+            .sum()
+    }
+}
+
+// the error of a record in a segment which is not there, deleted or never
+// written, as a damaged directory has
+pub(crate) fn missing_segment() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "missing segment of the log")
+}
+
+// the name of the segment id of the log named log_file_name: "kvs.log"
+// itself for 0, the id of a log written before segments, "kvs-000001.log"
+// for 1
+pub(crate) fn segment_name(log_file_name: &str, id: u32) -> String {
+    if id == 0 {
+        return String::from(log_file_name);
+    }
+    let (stem, ext) = name_parts(log_file_name);
+    format!("{}-{:0width$}{}", stem, id, ext, width = ID_DIGITS)
+}
+
+// the ids of the segments of the log named log_file_name in dir, in order
+pub(crate) fn segment_ids(dir: &Path, log_file_name: &str) -> io::Result<Vec<u32>> {
+    let (stem, ext) = name_parts(log_file_name);
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name == log_file_name {
+            ids.push(0);
+            continue;
+        }
+        let id = name
+            .strip_prefix(stem)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .and_then(|rest| rest.strip_suffix(ext))
+            .filter(|digits| {
+                digits.len() == ID_DIGITS && digits.bytes().all(|b| b.is_ascii_digit())
+            })
+            .and_then(|digits| digits.parse::<u32>().ok());
+        if let Some(id) = id.filter(|id| *id > 0) {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+// the name of a temporary file of the log named log_file_name, unique to
+// the process with id pid for every count: "kvs.log.1234.0.swp"
+pub(crate) fn swap_name(log_file_name: &str, pid: u32, count: usize) -> String {
+    format!("{}.{}.{}.swp", log_file_name, pid, count)
+}
+
+// delete the temporary files of the log named log_file_name in dir, left by
+// a process which crashed while it wrote them
+//
+// They are never data: a rebuilt log only counts once renamed.
+pub(crate) fn remove_swap_files(dir: &Path, log_file_name: &str) -> io::Result<()> {
+    // the name used before names were unique
+    let fixed = format!("{}.swp", log_file_name);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let is_swap = name == fixed
+            || name
+                .strip_prefix(log_file_name)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .and_then(|rest| rest.strip_suffix(".swp"))
+                .is_some_and(|rest| {
+                    let parts: Vec<_> = rest.split('.').collect();
+                    parts.len() == 2 && parts.iter().all(|part| is_number(part))
+                });
+        if is_swap {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn is_number(digits: &str) -> bool {
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+// a log file name split before its extension, "kvs" and ".log"
+fn name_parts(log_file_name: &str) -> (&str, &str) {
+    match log_file_name.rfind('.') {
+        Some(dot) if dot > 0 => log_file_name.split_at(dot),
+        _ => (log_file_name, ""),
+    }
+}
+
+impl KvStore {
+    // seal the active segment once it holds max_segment_size bytes of the
+    // options and start the next one, before data is appended
+    pub(crate) fn maybe_rotate(&mut self) -> Result<()> {
+        match self.options.max_segment_size {
+            Some(max) if self.log_off >= max && self.log_off > records_start(self.header) => {
+                self.rotate()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn rotate(&mut self) -> Result<()> {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        // a sealed segment is never written again
+        self.options.file_sync.sync_data(log)?;
+        self.segments.seal(self.segment, self.log_off);
+        let id = self.segment + 1;
+        let name = segment_name(&self.options.log_file_name, id);
+        let log_name = self.log_name.with_file_name(name);
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&log_name)?;
+        let header = Header::new(self.format()).encrypted_with(self.options.cipher.as_ref());
+        header.write(&file)?;
+        self.segments.insert(id, &file, Some(header))?;
+        self.log = Some(file);
+        self.segment = id;
+        self.log_name = log_name;
+        self.header = Some(header);
+        self.log_off = records_start(self.header);
+        self.reset_writer()
+    }
+
+    // the length of all the segments of the log
+    pub(crate) fn log_bytes(&self) -> u64 {
+        let sealed: u64 = self.segments.sealed(self.segment).map(|(_, s)| s.len).sum();
+        sealed + self.log_off
+    }
+
+    // the bytes of the records of all the segments of the log, without their
+    // headers
+    pub(crate) fn records_bytes(&self) -> u64 {
+        let active = self.log_off - records_start(self.header);
+        self.segments.sealed_records(self.segment) + active
+    }
+
+    // every segment of the log in id order, with its header and the offset
+    // its records end at
+    pub(crate) fn segment_files(&self) -> Vec<(u32, &File, Option<Header>, u64)> {
+        self.segments
+            .iter()
+            .map(|(id, segment)| {
+                let end = if id == self.segment {
+                    self.log_off
+                } else {
+                    segment.len
+                };
+                (id, &*segment.file, segment.header, end)
+            })
+            .collect()
+    }
+}
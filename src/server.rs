@@ -0,0 +1,551 @@
+use crate::protocol::{push_frame, read_frame, Request, Response, ServerStats, MAX_AUTH_FAILURES};
+use crate::resp::{self, Reply};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsEngine, KvsError, Result, SharedEngine};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A server of the requests of `kvs::protocol` over TCP, answered by an
+/// engine.
+///
+/// Every connection is served by a thread of the pool, with a clone of the
+/// engine: the clones share the data, see `SharedEngine`.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    protocol: ServerProtocol,
+    auth_token: Option<Arc<str>>,
+    limits: Limits,
+    drain_timeout: Duration,
+    shutdown: Arc<ShutdownState>,
+    connections: Arc<Connections>,
+}
+
+/// The protocol a `KvsServer` speaks, see `KvsServer::protocol`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ServerProtocol {
+    /// the frames of `kvs::protocol`, which `KvsClient` sends
+    #[default]
+    Kvs,
+    /// enough of RESP2, the protocol of Redis, for its clients: `GET`,
+    /// `SET` without options, `DEL`, `EXISTS` and `PING`, in the multi-bulk
+    /// form or inline; other commands get an `ERR unknown command` error
+    Resp,
+}
+
+/// Stops a `KvsServer` running on another thread, see
+/// `KvsServer::shutdown_handle`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    stopped: AtomicBool,
+    addr: Mutex<Option<SocketAddr>>, // where run listens, to wake its accept
+}
+
+// what one connection may take from the server
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    max_connections: Option<usize>,
+    request_timeout: Option<Duration>,
+}
+
+// the connections being served, by id
+#[derive(Default)]
+struct Connections {
+    live: Mutex<Live>,
+    done: Condvar, // notified whenever one is closed
+    rejected: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+#[derive(Default)]
+struct Live {
+    streams: HashMap<u64, TcpStream>,
+    next_id: u64,
+}
+
+impl<E: SharedEngine, P: ThreadPool> KvsServer<E, P> {
+    /// A server answering with `engine`, on the threads of `pool`.
+    pub fn new(engine: E, pool: P) -> KvsServer<E, P> {
+        KvsServer {
+            engine,
+            pool,
+            protocol: ServerProtocol::Kvs,
+            auth_token: None,
+            limits: Limits::default(),
+            drain_timeout: Duration::from_secs(10),
+            shutdown: Arc::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    /// Sets the protocol the server speaks, `ServerProtocol::Kvs` by
+    /// default.
+    pub fn protocol(&mut self, protocol: ServerProtocol) -> &mut KvsServer<E, P> {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the token every connection has to authenticate with before
+    /// its requests are answered, see `KvsClient::connect_with_token`; by
+    /// default the connections are not authenticated.
+    ///
+    /// In RESP the token is the password of the `AUTH` command.
+    pub fn auth_token(&mut self, token: Option<String>) -> &mut KvsServer<E, P> {
+        self.auth_token = token.map(Arc::from);
+        self
+    }
+
+    /// Sets the most connections served at once, by default there is no
+    /// limit. A connection over it gets a `Response::Busy`, or the error
+    /// Redis sends in RESP, and is closed.
+    pub fn max_connections(&mut self, max: Option<usize>) -> &mut KvsServer<E, P> {
+        self.limits.max_connections = max;
+        self
+    }
+
+    /// Sets how long a connection may take to send a request, from when the
+    /// server starts waiting for it, and how long a write of its response
+    /// may wait; by default there is no limit. A client which sends
+    /// nothing, or sends a request slower than that, however steadily, or
+    /// stalls reading its response, is dropped once it passes. A timeout of
+    /// 0, like `None`, is no limit.
+    pub fn request_timeout(&mut self, timeout: Option<Duration>) -> &mut KvsServer<E, P> {
+        self.limits.request_timeout = timeout.filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// Returns the connections of the server and its limits, like the
+    /// `Stats` request and the `INFO` command of RESP.
+    pub fn stats(&self) -> ServerStats {
+        self.connections.stats(self.limits)
+    }
+
+    /// Sets how long a shutdown waits for the requests in flight, 10 seconds
+    /// by default. The connections still served then are closed.
+    pub fn drain_timeout(&mut self, timeout: Duration) -> &mut KvsServer<E, P> {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// A handle which stops `run`, from any thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::clone(&self.shutdown),
+        }
+    }
+
+    /// Serves the connections of `listener` until `ShutdownHandle::shutdown`
+    /// is called.
+    ///
+    /// A connection which fails, or sends what is not a request, is closed
+    /// and the error printed to stderr; the others are served on.
+    ///
+    /// On shutdown no connection is accepted anymore, the ones served have
+    /// the request they sent answered and are closed, for up to the drain
+    /// timeout; then the engine is flushed, see `KvsEngine::flush`, and run
+    /// returns.
+    pub fn run(&self, listener: &TcpListener) -> Result<()> {
+        let mut addr = listener.local_addr()?;
+        if addr.ip().is_unspecified() {
+            let loopback = match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            addr.set_ip(loopback);
+        }
+        *lock(&self.shutdown.addr) = Some(addr);
+        // checked before each accept too: a shutdown before the address was
+        // stored had none to connect to, to wake it
+        while !self.shutdown.is_stopped() {
+            let accepted = listener.accept();
+            if self.shutdown.is_stopped() {
+                break;
+            }
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    eprintln!("connection failed: {}", err);
+                    continue;
+                }
+            };
+            let limits = self.limits;
+            let added = stream
+                .set_write_timeout(limits.request_timeout)
+                .and_then(|_| self.connections.add(&stream, limits.max_connections));
+            let id = match added {
+                Ok(Some(id)) => id,
+                Ok(None) => {
+                    if let Err(err) = busy(self.protocol, &stream) {
+                        eprintln!("connection failed: {}", err);
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    eprintln!("connection failed: {}", err);
+                    continue;
+                }
+            };
+            let connections = Arc::clone(&self.connections);
+            let mut engine = self.engine.clone();
+            let protocol = self.protocol;
+            let token = self.auth_token.clone();
+            self.pool.spawn(move || {
+                // drops it from the live ones even if serve panics
+                let _closed = Closed {
+                    connections: Arc::clone(&connections),
+                    id,
+                };
+                let stats = || connections.stats(limits);
+                let auth = Auth::new(token.as_deref());
+                let served = match protocol {
+                    ServerProtocol::Kvs => serve(&mut engine, stream, limits, auth, stats),
+                    ServerProtocol::Resp => serve_resp(&mut engine, stream, limits, auth, stats),
+                };
+                match served {
+                    Err(err) if is_timeout(&err) => {
+                        connections.timed_out.fetch_add(1, Ordering::Relaxed);
+                        eprintln!("connection timed out");
+                    }
+                    Err(err) => eprintln!("connection failed: {}", err),
+                    Ok(()) => {}
+                }
+            });
+        }
+        self.connections.drain(self.drain_timeout);
+        self.engine.clone().flush()
+    }
+}
+
+impl ShutdownHandle {
+    /// Tells the server to stop accepting connections and to return from
+    /// `KvsServer::run` once the ones served are drained.
+    pub fn shutdown(&self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        // the connection wakes the accept of run, which then stops
+        if let Some(addr) = *lock(&self.state.addr) {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+}
+
+impl ShutdownState {
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+impl Connections {
+    // add stream to the live connections, return its id; None if max of
+    // them are served already
+    fn add(&self, stream: &TcpStream, max: Option<usize>) -> io::Result<Option<u64>> {
+        let stream = stream.try_clone()?;
+        let mut live = lock(&self.live);
+        if max.is_some_and(|max| live.streams.len() >= max) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+        let id = live.next_id;
+        live.next_id += 1;
+        live.streams.insert(id, stream);
+        Ok(Some(id))
+    }
+
+    fn stats(&self, limits: Limits) -> ServerStats {
+        ServerStats {
+            connections: lock(&self.live).streams.len(),
+            max_connections: limits.max_connections,
+            rejected_connections: self.rejected.load(Ordering::Relaxed),
+            request_timeout_ms: limits
+                .request_timeout
+                .map(|timeout| timeout.as_millis() as u64),
+            timed_out_connections: self.timed_out.load(Ordering::Relaxed),
+        }
+    }
+
+    // stop reading from the live connections, so they close once the
+    // request they are answering is, then wait up to timeout for them and
+    // close those left
+    fn drain(&self, timeout: Duration) {
+        let live = lock(&self.live);
+        for stream in live.streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let (live, _) = self
+            .done
+            .wait_timeout_while(live, timeout, |live| !live.streams.is_empty())
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for stream in live.streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// drops a connection from the live ones once its job is done
+struct Closed {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        lock(&self.connections.live).streams.remove(&self.id);
+        self.connections.done.notify_all();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // the maps are whole whatever panicked while they were held
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// answer every request of stream in order, until the client closes it
+//
+// The responses are only flushed once no request is left in the buffer of
+// the reader, so those of the requests a client pipelined go out together.
+fn serve<E, S>(
+    engine: &mut E,
+    stream: TcpStream,
+    limits: Limits,
+    mut auth: Auth,
+    stats: S,
+) -> Result<()>
+where
+    E: KvsEngine,
+    S: Fn() -> ServerStats,
+{
+    let mut reader = BufReader::new(Deadline::new(&stream, limits.request_timeout));
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        reader.get_mut().start();
+        let request = match read_frame(&mut reader)? {
+            Some(request) => request,
+            None => break,
+        };
+        let response = match request {
+            Request::Auth { token } => {
+                if auth.check(&token) {
+                    Response::Done
+                } else {
+                    Response::AuthFailed
+                }
+            }
+            _ if !auth.passed() => {
+                auth.refuse();
+                Response::AuthRequired
+            }
+            Request::Stats => Response::Stats(stats()),
+            request => answer(engine, request),
+        };
+        push_frame(&mut writer, &response)?;
+        if auth.exhausted() {
+            writer.flush()?;
+            return Err(too_many_failures());
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// answer every command of stream in RESP, until the client closes it or
+// breaks the protocol, which gets an error reply first
+fn serve_resp<E, S>(
+    engine: &mut E,
+    stream: TcpStream,
+    limits: Limits,
+    mut auth: Auth,
+    stats: S,
+) -> Result<()>
+where
+    E: KvsEngine,
+    S: Fn() -> ServerStats,
+{
+    let mut reader = BufReader::new(Deadline::new(&stream, limits.request_timeout));
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        reader.get_mut().start();
+        let reply = match resp::read_command(&mut reader) {
+            Ok(Some(args)) if args[0].eq_ignore_ascii_case(b"auth") => resp::auth(&mut auth, args),
+            Ok(Some(_)) if !auth.passed() => {
+                auth.refuse();
+                Reply::Error(String::from("NOAUTH Authentication required."))
+            }
+            Ok(Some(args)) if args[0].eq_ignore_ascii_case(b"info") => resp::info(&stats()),
+            Ok(Some(args)) => resp::answer(engine, args),
+            Ok(None) => {
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("ERR {}", err)).write(&mut writer)?;
+                writer.flush()?;
+                return Err(err.into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        reply.write(&mut writer)?;
+        if auth.exhausted() {
+            writer.flush()?;
+            return Err(too_many_failures());
+        }
+        // like serve, for the commands a client pipelined
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+// the reads of a stream, which time out together: a request has until the
+// deadline set once the server starts waiting for it, however slowly its
+// bytes come in
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Deadline<'_> {
+    fn new(stream: &TcpStream, timeout: Option<Duration>) -> Deadline<'_> {
+        Deadline {
+            stream,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    // start the time of the next request
+    fn start(&mut self) {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(left))?;
+        }
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+// tell the client of stream the server is busy, before it is closed
+fn busy(protocol: ServerProtocol, stream: &TcpStream) -> Result<()> {
+    let mut writer = BufWriter::new(stream);
+    match protocol {
+        ServerProtocol::Kvs => push_frame(&mut writer, &Response::Busy)?,
+        ServerProtocol::Resp => {
+            Reply::Error(String::from("ERR max number of clients reached")).write(&mut writer)?
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// whether err is a read or a write which timed out
+fn is_timeout(err: &failure::Error) -> bool {
+    err.downcast_ref::<io::Error>().is_some_and(|err| {
+        err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+    })
+}
+
+// the authentication of a connection, to a server with a token or not
+pub(crate) struct Auth<'a> {
+    token: Option<&'a str>,
+    passed: bool,
+    failures: u32,
+}
+
+impl Auth<'_> {
+    fn new(token: Option<&str>) -> Auth<'_> {
+        Auth {
+            token,
+            passed: token.is_none(),
+            failures: 0,
+        }
+    }
+
+    // whether the connection may send requests
+    pub(crate) fn passed(&self) -> bool {
+        self.passed
+    }
+
+    // whether the server has a token to check
+    pub(crate) fn required(&self) -> bool {
+        self.token.is_some()
+    }
+
+    // authenticate with given, a failure unless it is the token; any token
+    // passes on a server without one
+    pub(crate) fn check(&mut self, given: &str) -> bool {
+        let passed = self
+            .token
+            .is_none_or(|token| same_token(given.as_bytes(), token.as_bytes()));
+        if passed {
+            self.passed = true;
+        } else {
+            self.failures += 1;
+        }
+        passed
+    }
+
+    // count a request sent before the connection was authenticated
+    pub(crate) fn refuse(&mut self) {
+        self.failures += 1;
+    }
+
+    fn exhausted(&self) -> bool {
+        self.failures >= MAX_AUTH_FAILURES
+    }
+}
+
+// whether given is token, in a time which only depends on their lengths
+fn same_token(given: &[u8], token: &[u8]) -> bool {
+    let mut diff = u8::from(given.len() != token.len());
+    for (i, b) in given.iter().enumerate() {
+        diff |= b ^ token.get(i).copied().unwrap_or(!b);
+    }
+    diff == 0
+}
+
+fn too_many_failures() -> failure::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "closed after too many failed authentications",
+    )
+    .into()
+}
+
+// the response of engine to request
+fn answer<E: KvsEngine>(engine: &mut E, request: Request) -> Response {
+    let answered = match request {
+        Request::Get { key } => engine.get(key).map(Response::Value),
+        Request::Set { key, value } => engine.set(key, value).map(|_| Response::Done),
+        Request::Remove { key } => engine.remove(key).map(|_| Response::Done),
+        Request::MultiGet { keys } => engine.get_many(keys).map(Response::Values),
+        Request::MultiSet { pairs } => engine.set_many(pairs).map(|_| Response::Done),
+        Request::MultiRemove { keys } => engine.remove_many(keys).map(Response::Removed),
+        Request::Auth { .. } | Request::Stats => unreachable!("answered by serve"),
+    };
+    answered.unwrap_or_else(|err| match err.downcast_ref::<KvsError>() {
+        Some(KvsError::KeyNotFound) => Response::KeyNotFound,
+        _ => Response::Error(err.to_string()),
+    })
+}
@@ -0,0 +1,289 @@
+use crate::engine::removed_keys;
+use crate::index::{Changes, Index, Key, OffsetLen};
+use crate::segment::Segments;
+use crate::{now_millis, read_record_checked, CompactionStats, KvStore, KvsEngine, Result, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// the entries changed since the index of the view was copied which are kept
+// beside it, on top of half as many as it has, before it is copied again
+const CHANGED_MIN: usize = 1024;
+
+/// A handle to a `KvStore` shared by threads: its clones are handles to the
+/// same KvStore, and they can be sent to other threads.
+///
+/// Writes take turns on the KvStore. Reads do not wait for them: `get`
+/// looks the key up in the index as the last write left it and reads the
+/// record with a positioned read on its own handle of the segment, so any
+/// number of reads run beside a write, and beside a compaction, which
+/// leaves them reading the segments they started with.
+///
+/// The KvStore compacts itself once the threshold of its options is
+/// passed, within the write which passed it, see `SharedKvStore::compact`.
+/// After a compaction, or once many keys changed, the write also copies the
+/// whole index for the reads, and the next writes wait for the copy.
+#[derive(Clone)]
+pub struct SharedKvStore {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    store: Mutex<KvStore>,
+    view: RwLock<View>,
+    verify: bool, // verify_checksums of the options
+}
+
+// what `get` reads: the index and the segments as the last write left them
+//
+// The index is a copy which is never changed, the entries the writes
+// changed since it was taken are kept beside it until it is taken again: a
+// write only updates the entries of its keys, a compaction swaps in a new
+// copy. The copy takes time in the number of keys, the write which made it
+// holds the KvStore for that long, but the lock of the view is only held to
+// update the entries or to swap the copy in.
+struct View {
+    kvs: Arc<Index>,
+    changed: HashMap<Key, Option<OffsetLen>>, // None for a removed key
+    segments: Arc<Segments>,
+    version: u64,    // the version of the segments
+    _guard: Arc<()>, // keeps the KvStore from overwriting records
+}
+
+impl View {
+    fn new(store: &KvStore) -> View {
+        View {
+            kvs: Arc::new(store.kvs.clone()),
+            changed: HashMap::new(),
+            segments: Arc::new(store.segments.clone()),
+            version: store.segments.version(),
+            _guard: Arc::clone(&store.snapshots),
+        }
+    }
+}
+
+impl SharedKvStore {
+    /// A handle to `store`.
+    pub fn new(mut store: KvStore) -> SharedKvStore {
+        store.kvs.track_changes();
+        let view = View::new(&store);
+        let verify = store.options.verify_checksums;
+        SharedKvStore {
+            shared: Arc::new(Shared {
+                store: Mutex::new(store),
+                view: RwLock::new(view),
+                verify,
+            }),
+        }
+    }
+
+    /// Opens the KvStore at a given path, see `KvStore::open`, and returns
+    /// a handle to it.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SharedKvStore> {
+        Ok(SharedKvStore::new(KvStore::open(path)?))
+    }
+
+    /// Returns the value of a key, like `KvStore::get`.
+    ///
+    /// The value is the one the last write which is done left, a write in
+    /// flight is not waited for.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let (entry, segments) = self.shared.entry(key.as_bytes())?;
+        let entry = match entry {
+            Some(entry) if !entry.is_expired(now_millis()) => entry,
+            _ => return Ok(None),
+        };
+        read_record_checked(&segments, &entry, self.shared.verify)?
+            .map(Value::into_string)
+            .transpose()
+    }
+
+    /// Sets the value of a key, like `KvStore::set`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.write().set(key, value)?;
+        Ok(())
+    }
+
+    /// Removes a key, like `KvStore::remove`.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.write().remove(key)?;
+        Ok(())
+    }
+
+    /// Compacts the KvStore, like `KvStore::compact`, without holding up
+    /// the reads of `get`.
+    ///
+    /// The reads go on from the segments before the compaction until it is
+    /// done. It renames the new log over the old one, or deletes the merged
+    /// segments, but the reads keep their handles on them: their space is
+    /// only given back once the last read of them is done.
+    pub fn compact(&self) -> Result<CompactionStats> {
+        self.write().compact()
+    }
+
+    /// The KvStore, for the reads of its whole API; they wait for the write
+    /// in flight.
+    pub fn read(&self) -> SharedReadGuard<'_> {
+        SharedReadGuard(self.shared.lock())
+    }
+
+    /// The KvStore, for the writes of its whole API; `get` reads what they
+    /// changed once the guard is dropped.
+    pub fn write(&self) -> SharedWriteGuard<'_> {
+        SharedWriteGuard {
+            store: self.shared.lock(),
+            shared: &self.shared,
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, KvStore> {
+        // a panic leaves the KvStore as its last write left it
+        self.store
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn view(&self) -> RwLockReadGuard<'_, View> {
+        self.view
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn view_mut(&self) -> RwLockWriteGuard<'_, View> {
+        self.view
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // the entry of key as the last write left it, with the segments it
+    // points into; the lock is only held to clone them
+    fn entry(&self, key: &[u8]) -> Result<(Option<OffsetLen>, Arc<Segments>)> {
+        let (kvs, segments) = {
+            let view = self.view();
+            if let Some(entry) = view.changed.get(key) {
+                return Ok((entry.clone(), Arc::clone(&view.segments)));
+            }
+            (Arc::clone(&view.kvs), Arc::clone(&view.segments))
+        };
+        let entry = kvs.get(key)?.map(Cow::into_owned);
+        Ok((entry, segments))
+    }
+
+    // publish what the writes to store changed since the last time: the
+    // entries of the keys they changed, or a new copy of the index once
+    // too many are kept or any entry may have changed
+    fn publish(&self, store: &mut KvStore) {
+        let changed = match store.kvs.take_changes() {
+            Changes::Keys(keys) => keys
+                .into_iter()
+                .map(|key| {
+                    let entry = store.kvs.get(key.as_bytes())?.map(Cow::into_owned);
+                    Ok((key, entry))
+                })
+                .collect::<Result<Vec<_>>>()
+                .ok(),
+            Changes::All => None,
+        };
+        let version = store.segments.version();
+        {
+            let mut view = self.view_mut();
+            match changed {
+                Some(changed)
+                    if view.changed.len() + changed.len() <= view.kvs.len() / 2 + CHANGED_MIN =>
+                {
+                    if view.version != version {
+                        view.segments = Arc::new(store.segments.clone());
+                        view.version = version;
+                    }
+                    view.changed.extend(changed);
+                    return;
+                }
+                _ => {}
+            }
+        }
+        // the copy is taken before the lock of the view, which only the swap
+        // holds, and the old view dropped after it is released; the writes
+        // wait for it all the same
+        let view = View::new(store);
+        let old = mem::replace(&mut *self.view_mut(), view);
+        drop(old);
+    }
+}
+
+/// The KvStore of a `SharedKvStore`, for the reads of its whole API, see
+/// `SharedKvStore::read`.
+pub struct SharedReadGuard<'a>(MutexGuard<'a, KvStore>);
+
+impl Deref for SharedReadGuard<'_> {
+    type Target = KvStore;
+
+    fn deref(&self) -> &KvStore {
+        &self.0
+    }
+}
+
+/// The KvStore of a `SharedKvStore`, for the writes of its whole API, see
+/// `SharedKvStore::write`.
+pub struct SharedWriteGuard<'a> {
+    store: MutexGuard<'a, KvStore>,
+    shared: &'a Shared,
+}
+
+impl Deref for SharedWriteGuard<'_> {
+    type Target = KvStore;
+
+    fn deref(&self) -> &KvStore {
+        &self.store
+    }
+}
+
+impl DerefMut for SharedWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut KvStore {
+        &mut self.store
+    }
+}
+
+impl Drop for SharedWriteGuard<'_> {
+    // `get` reads what the writes changed from now on
+    fn drop(&mut self) {
+        self.shared.publish(&mut self.store);
+    }
+}
+
+impl KvsEngine for SharedKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        SharedKvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        SharedKvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        SharedKvStore::remove(self, key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write().sync()
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.write().set_batch(pairs)
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter()
+            .map(|key| SharedKvStore::get(self, key))
+            .collect()
+    }
+
+    fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let removed = self.write().remove_batch(keys.clone())?;
+        Ok(removed_keys(keys, removed))
+    }
+}
@@ -0,0 +1,95 @@
+use crate::checkpoint::{write_checkpoint, CheckpointInfo};
+use crate::index::Index;
+use crate::segment::Segments;
+use crate::{read_value, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A point-in-time read-only view of a KvStore, see `KvStore::snapshot`.
+pub struct Snapshot {
+    kvs: Index,
+    segments: Segments, // handles on the segments the index points into
+    log_file_name: String,
+    _guard: Arc<()>, // keeps the KvStore from overwriting records
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        kvs: Index,
+        segments: Segments,
+        log_file_name: String,
+        guard: Arc<()>,
+    ) -> Snapshot {
+        Snapshot {
+            kvs,
+            segments,
+            log_file_name,
+            _guard: guard,
+        }
+    }
+
+    /// Writes the key-value pairs of the snapshot to the directory
+    /// `dest_dir` like `KvStore::checkpoint`.
+    ///
+    /// The KvStore is only paused while the snapshot is taken, it can be
+    /// written to during the copy. A key which expired since the snapshot
+    /// was taken is not copied.
+    pub fn checkpoint(&self, dest_dir: impl Into<PathBuf>) -> Result<CheckpointInfo> {
+        write_checkpoint(
+            &self.kvs,
+            &self.segments,
+            dest_dir.into(),
+            &self.log_file_name,
+        )
+    }
+
+    /// Returns a copy of the value the key had when the snapshot was taken.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let off2len = match self.kvs.get(key.as_bytes())? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        read_value(&self.segments, &off2len)
+    }
+
+    /// Returns true if the key existed when the snapshot was taken.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.kvs.lookup(key.as_bytes()).is_some()
+    }
+
+    /// Returns the number of keys when the snapshot was taken.
+    pub fn len(&self) -> usize {
+        self.kvs.len()
+    }
+
+    /// Returns true if the KvStore was empty when the snapshot was taken.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot, in log
+    /// order. A failed read is returned as an `Err` item. Keys which are not
+    /// UTF-8 are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        // a failed read of the index is the only item
+        let (mut sorted, failed): (Vec<_>, _) = match self.kvs.iter() {
+            Ok(entries) => (
+                entries
+                    .filter_map(|(key, off2len)| Some((key.as_string()?, off2len)))
+                    .collect(),
+                None,
+            ),
+            Err(err) => (Vec::new(), Some(Err(err))),
+        };
+        sorted.sort_by_key(|kv| kv.1.position());
+        failed
+            .into_iter()
+            .chain(sorted.into_iter().filter_map(move |(key, off2len)| {
+                match read_value(&self.segments, off2len) {
+                    Ok(Some(value)) => Some(Ok((key.clone(), value))),
+                    Ok(None) => None,
+                    Err(err) => Some(Err(err)),
+                }
+            }))
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{now_millis, KvStore};
+
+/// Figures about a KvStore and its log, see `KvStore::stats`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// the number of live keys
+    pub live_keys: usize,
+    /// the number of index entries kept in memory, `live_keys` or more for
+    /// the dense index and fewer for a sampled one, see
+    /// `KvStoreOptions::index_sampling`
+    pub resident_keys: usize,
+    /// the length of the log in bytes, over all its segments
+    pub log_bytes: u64,
+    /// the bytes of the log holding the records of the live keys
+    pub live_bytes: u64,
+    /// the bytes the records of the live keys would take with no value
+    /// compressed, `live_bytes` if none is, see
+    /// `KvStoreOptions::compression`
+    pub logical_bytes: u64,
+    /// the bytes of the log a compaction would drop
+    pub dead_bytes: u64,
+    /// the number of times the log was rewritten since the KvStore was opened
+    pub compactions: usize,
+    /// the number of times the log was synced since the KvStore was opened,
+    /// see `KvStoreOptions::sync_policy`
+    pub syncs: usize,
+    /// the bytes of a torn record `open` cut off the end of the log, see
+    /// `RecoveryReport::truncated_bytes`
+    pub truncated_bytes: u64,
+    /// the reads the value cache answered since the KvStore was opened, see
+    /// `KvStoreOptions::cache_capacity`
+    pub cache_hits: u64,
+    /// the reads of the log the value cache did not spare
+    pub cache_misses: u64,
+}
+
+impl KvStore {
+    /// Returns figures about the KvStore and its log, computed from the
+    /// index without reading the log.
+    ///
+    /// The records of expired keys, removals and overwritten values count as
+    /// dead bytes, the headers of the segments of the log as neither live
+    /// nor dead.
+    pub fn stats(&self) -> StoreStats {
+        let mut stats = StoreStats {
+            log_bytes: self.log_bytes(),
+            compactions: self.compactions,
+            syncs: self.syncs(),
+            truncated_bytes: self.truncated_bytes,
+            resident_keys: self.kvs.resident_len(),
+            ..StoreStats::default()
+        };
+        (stats.cache_hits, stats.cache_misses) = self.cache.counters();
+        for (_, off2len) in self.kvs.resident_live(now_millis()) {
+            stats.live_keys += 1;
+            stats.live_bytes += off2len.len as u64;
+            stats.logical_bytes += (off2len.len + off2len.saved as usize) as u64;
+        }
+        // the entries of a run do not expire
+        let (run_len, run_bytes, run_saved) = self.kvs.run_totals();
+        stats.live_keys += run_len;
+        stats.live_bytes += run_bytes;
+        stats.logical_bytes += run_bytes + run_saved;
+        stats.dead_bytes = self.records_bytes().saturating_sub(stats.live_bytes);
+        stats
+    }
+}
@@ -0,0 +1,464 @@
+use crate::checksum::crc_suffix;
+use crate::index::{Key, Meta, OffsetLen};
+use crate::record::{decode_value, encode, frame, FRAME_LEN, SET, SET_COMPRESSED, SET_FIELDS_LEN};
+use crate::segment::missing_segment;
+use crate::writer::LogWriter;
+use crate::{
+    now_millis, read_record, set_data, KvStore, KvsError, LogFormat, OptData, Result, Value,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::read::DecoderReader;
+use base64::write::EncoderWriter;
+use crc32fast::Hasher;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+
+// how every SetData record starts, before the key and the value
+const RECORD_START: &[u8] = b"{\"SetData\":{\"key\":\"";
+const VALUE_START: &[u8] = b",\"value\":\"";
+// the field of a record whose value is base64, only found unescaped among
+// the fields after the value
+const BASE64_FIELD: &[u8] = b",\"base64\":true";
+// the field of a record whose value is compressed, found the same way
+const COMPRESSED_FIELD: &[u8] = b",\"compressed\":";
+// the longest the fields from base64 on can be
+const TAIL_LEN: usize = 96;
+
+impl KvStore {
+    /// Returns a reader over the value of a key, `None` if the key does not
+    /// exist.
+    ///
+    /// The value is decoded from its log record as it is read, so it is
+    /// never held in memory as a whole, but for a compressed or encrypted
+    /// value which is decoded first. Values set by `set_bytes` are read as
+    /// their bytes. The reader borrows the KvStore, which can not be
+    /// written to until it is dropped.
+    pub fn get_reader(&self, key: &str) -> Result<Option<impl Read + '_>> {
+        let off2len = match self.kvs.get(key.as_bytes())? {
+            Some(off2len) if !off2len.is_expired(now_millis()) => off2len,
+            _ => return Ok(None),
+        };
+        let header = self.segments.header(off2len.segment);
+        if header.is_some_and(|header| header.encrypted()) {
+            return Ok(read_record(&self.segments, &off2len)?.map(whole_value_reader));
+        }
+        let log = self
+            .segments
+            .file(off2len.segment)
+            .ok_or_else(missing_segment)?;
+        Ok(Some(value_reader(log, &off2len)?))
+    }
+
+    /// Inserts a key whose value is the `len` bytes read from `r`.
+    ///
+    /// The bytes are copied to the end of the log as they are read, so the
+    /// value is never held in memory as a whole, but for an encrypted log
+    /// which encrypts it whole. A JSON log holds them as base64 whether they
+    /// are UTF-8 or not. If `r` yields fewer or more bytes than `len`,
+    /// or fails, the log is truncated back to where the record started and a
+    /// `KvsError::ValueLengthMismatch` or the io error is returned. Callbacks
+    /// of `on_change`, if any, get the value read back from the log.
+    pub fn set_from_reader(&mut self, key: String, len: u64, r: impl Read) -> Result<()> {
+        self.check_writable()?;
+        self.check_size(key.as_bytes(), &[])?;
+        if len > self.options.max_value_size as u64 {
+            return Err(KvsError::ValueTooLarge {
+                size: len as usize,
+                limit: self.options.max_value_size,
+            }
+            .into());
+        }
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        if self.options.cipher.is_some() {
+            let mut r = r.take(len + 1);
+            let mut value = Vec::new();
+            let copied = (&mut r).take(len).read_to_end(&mut value)?;
+            check_length(copied as u64, len, r)?;
+            self.set_record(Key::from(key), Value::from_bytes(value), None)?;
+            return Ok(());
+        }
+        let meta = Meta::next(self.live_meta(key.as_bytes())?.as_ref(), now_millis());
+        let seq = self.next_seq;
+        // the record of an empty value, the streamed one goes in between
+        let data = set_data(key.as_bytes(), Value::Bytes(Vec::new()), None, meta, seq);
+
+        self.maybe_rotate()?;
+        let offset = self.log_off;
+        let format = self.format();
+        let written = match &mut self.writer {
+            Some(writer) if format == LogFormat::Json => write_streamed(writer, &data, len, r),
+            Some(writer) => write_framed(writer, &data, len, r),
+            None => return Ok(()),
+        };
+        let end = match written {
+            Ok(end) => end,
+            Err(err) => {
+                self.discard_write()?;
+                return Err(err);
+            }
+        };
+        if self.log.is_none() {
+            return Ok(());
+        }
+        self.sync_after_write()?;
+        self.next_seq += 1;
+        self.log_off = end;
+        let off2len = OffsetLen {
+            segment: self.segment,
+            saved: 0,
+            offset,
+            len: (end - offset) as usize,
+            expires_at: None,
+            meta,
+            inline: None,
+        };
+        if !self.subscribers.is_empty() {
+            if let Some(value) = read_record(&self.segments, &off2len)? {
+                self.subscribers
+                    .notify(seq, key.as_bytes(), Some(value.as_bytes()));
+            }
+        }
+        self.kvs.insert(Key::from(key), off2len)?;
+        Ok(())
+    }
+}
+
+// append the JSON record of data with the base64 of the len bytes of r as
+// its value, and its checksum, return the offset the record ends at
+fn write_streamed(
+    writer: &mut BufWriter<LogWriter>,
+    data: &OptData,
+    len: u64,
+    r: impl Read,
+) -> Result<u64> {
+    let record = serde_json::to_vec(data)?;
+    let split = record
+        .windows(VALUE_START.len())
+        .position(|field| field == VALUE_START)
+        .unwrap_or_default()
+        + VALUE_START.len();
+    let mut writer = Crc32Writer {
+        inner: writer,
+        hasher: Hasher::new(),
+    };
+    writer.write_all(&record[..split])?;
+    let mut r = r.take(len + 1);
+    let copied = {
+        let mut encoder = EncoderWriter::new(&mut writer, &BASE64);
+        let copied = io::copy(&mut (&mut r).take(len), &mut encoder)?;
+        encoder.finish()?;
+        copied
+    };
+    check_length(copied, len, r)?;
+    // the closing "}}" are replaced by the checksum field
+    let (fields, end) = record[split..].split_at(record.len() - split - 2);
+    writer.write_all(fields)?;
+    writer.hasher.update(end);
+    let crc = writer.hasher.finalize();
+    let writer = writer.inner;
+    writer.write_all(crc_suffix(crc).as_bytes())?;
+    writer.flush()?;
+    Ok(writer.get_ref().pos())
+}
+
+// append the binary record of data with the len bytes of r as its value,
+// return the offset the record ends at
+//
+// The checksum is only known once the value is copied, it is written over
+// the frame last.
+fn write_framed(
+    writer: &mut BufWriter<LogWriter>,
+    data: &OptData,
+    len: u64,
+    r: impl Read,
+) -> Result<u64> {
+    // the record of an empty value ends with the length of the value
+    let record = encode(LogFormat::Binary, data)?;
+    let fields = &record[FRAME_LEN..record.len() - 4];
+    let start = writer.get_ref().pos();
+    writer.write_all(&frame(SET, (fields.len() + 4) as u64 + len, 0)?)?;
+    let mut writer = Crc32Writer {
+        inner: writer,
+        hasher: Hasher::new(),
+    };
+    writer.write_all(fields)?;
+    // frame checked that len fits
+    writer.write_all(&(len as u32).to_le_bytes())?;
+    let mut r = r.take(len + 1);
+    let copied = io::copy(&mut (&mut r).take(len), &mut writer)?;
+    check_length(copied, len, r)?;
+    let crc = writer.hasher.finalize();
+    let writer = writer.inner;
+    writer.flush()?;
+    let log = writer.get_ref();
+    log.file().write_all_at(&crc.to_le_bytes(), start + 5)?;
+    Ok(log.pos())
+}
+
+// fail unless exactly len bytes were copied from r, whose limit is one more
+// byte: it tells a reader longer than len
+fn check_length(copied: u64, len: u64, mut r: impl Read) -> Result<()> {
+    let read = copied + r.read(&mut [0])? as u64;
+    if read != len {
+        return Err(KvsError::ValueLengthMismatch {
+            expected: len,
+            read,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+// hashes what it writes to inner
+struct Crc32Writer<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for Crc32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// a reader over the value of the SetData record pointed by off2len
+fn value_reader<'a>(log: &'a File, off2len: &OffsetLen) -> Result<Box<dyn Read + 'a>> {
+    let start = off2len.offset;
+    let end = start + off2len.len as u64;
+    let mut first = [0];
+    log.read_exact_at(&mut first, start)?;
+    if first[0] == SET_COMPRESSED {
+        return inflated_value_reader(log, off2len);
+    }
+    if first[0] != b'{' {
+        return framed_value_reader(log, start, end);
+    }
+    let mut tail = vec![0; TAIL_LEN.min(off2len.len)];
+    let tail_start = end - tail.len() as u64;
+    log.read_exact_at(&mut tail, tail_start)?;
+    let has_field = |name: &[u8]| tail.windows(name.len()).any(|field| field == name);
+    if has_field(COMPRESSED_FIELD) {
+        return inflated_value_reader(log, off2len);
+    }
+    let base64 = has_field(BASE64_FIELD);
+
+    let mut record = BufReader::new(LogRange {
+        log,
+        pos: start,
+        end,
+    });
+    expect(&mut record, RECORD_START)?;
+    // the key is skipped, it is only as long as the size limits allow
+    JsonStr::new(&mut record).read_to_end(&mut Vec::new())?;
+    expect(&mut record, VALUE_START)?;
+    let value = JsonStr::new(record);
+    if base64 {
+        Ok(Box::new(DecoderReader::new(value, &BASE64)))
+    } else {
+        Ok(Box::new(value))
+    }
+}
+
+// a reader over the compressed value of the SetData record pointed by
+// off2len, which is decompressed as a whole first
+fn inflated_value_reader<'a>(log: &'a File, off2len: &OffsetLen) -> Result<Box<dyn Read + 'a>> {
+    let mut record = vec![0; off2len.len];
+    log.read_exact_at(&mut record, off2len.offset)?;
+    let value = decode_value(&record, off2len.offset, false)?.ok_or_else(layout_error)?;
+    Ok(whole_value_reader(value))
+}
+
+// a reader over a value read whole
+fn whole_value_reader<'a>(value: Value) -> Box<dyn Read + 'a> {
+    Box::new(io::Cursor::new(value.as_bytes().to_vec()))
+}
+
+// a reader over the value of the binary SetData record from start to end
+fn framed_value_reader(log: &File, start: u64, end: u64) -> Result<Box<dyn Read + '_>> {
+    // up to the length of the key
+    let mut head = [0; FRAME_LEN + SET_FIELDS_LEN + 4];
+    log.read_exact_at(&mut head, start)?;
+    if head[0] != SET {
+        return Err(layout_error().into());
+    }
+    let key_len = u32::from_le_bytes(head[head.len() - 4..].try_into().unwrap_or_default());
+    let value_len_at = start + head.len() as u64 + key_len as u64;
+    let mut value_len = [0; 4];
+    log.read_exact_at(&mut value_len, value_len_at)?;
+    let value_start = value_len_at + value_len.len() as u64;
+    if value_start + u32::from_le_bytes(value_len) as u64 != end {
+        return Err(layout_error().into());
+    }
+    Ok(Box::new(LogRange {
+        log,
+        pos: value_start,
+        end,
+    }))
+}
+
+// consume bytes from r, which must be the expected ones
+fn expect(r: &mut impl Read, expected: &[u8]) -> io::Result<()> {
+    let mut buf = vec![0; expected.len()];
+    r.read_exact(&mut buf)?;
+    if buf != expected {
+        return Err(layout_error());
+    }
+    Ok(())
+}
+
+fn layout_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unexpected layout of a SetData record",
+    )
+}
+
+// the bytes of the log from pos to end, read at their offset so the file
+// cursor is left alone
+pub(crate) struct LogRange<'a> {
+    pub(crate) log: &'a File,
+    pub(crate) pos: u64,
+    pub(crate) end: u64,
+}
+
+impl Read for LogRange<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = (self.end - self.pos).min(buf.len() as u64) as usize;
+        let n = self.log.read_at(&mut buf[..left], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+// decodes a JSON string whose opening quote was consumed, up to and
+// including its closing quote
+struct JsonStr<R> {
+    inner: R,
+    pending: [u8; 4], // the UTF-8 of an escaped char not returned yet
+    pending_pos: usize,
+    pending_len: usize,
+    done: bool,
+}
+
+impl<R: BufRead> JsonStr<R> {
+    fn new(inner: R) -> JsonStr<R> {
+        JsonStr {
+            inner,
+            pending: [0; 4],
+            pending_pos: 0,
+            pending_len: 0,
+            done: false,
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.inner.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let mut digits = [0; 4];
+        self.inner.read_exact(&mut digits)?;
+        std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(invalid_escape)
+    }
+
+    // decode the escape sequence after a backslash into pending
+    fn unescape(&mut self) -> io::Result<()> {
+        let c = match self.next_byte()? {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\x08',
+            b'f' => '\x0c',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let mut code = self.hex4()?;
+                if (0xd800..0xdc00).contains(&code) {
+                    // a surrogate pair
+                    if self.next_byte()? != b'\\' || self.next_byte()? != b'u' {
+                        return Err(invalid_escape());
+                    }
+                    let low = self.hex4()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(invalid_escape());
+                    }
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                }
+                std::char::from_u32(code).ok_or_else(invalid_escape)?
+            }
+            _ => return Err(invalid_escape()),
+        };
+        self.pending_len = c.encode_utf8(&mut self.pending).len();
+        self.pending_pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for JsonStr<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            if self.pending_pos < self.pending_len {
+                buf[n] = self.pending[self.pending_pos];
+                self.pending_pos += 1;
+                n += 1;
+                continue;
+            }
+            if self.done {
+                break;
+            }
+            let available = self.inner.fill_buf()?;
+            if available.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            // copy the plain bytes up to the next quote or backslash
+            let plain = available
+                .iter()
+                .take(buf.len() - n)
+                .position(|b| *b == b'"' || *b == b'\\')
+                .unwrap_or_else(|| available.len().min(buf.len() - n));
+            buf[n..n + plain].copy_from_slice(&available[..plain]);
+            n += plain;
+            let special = available.get(plain).copied();
+            self.inner.consume(plain);
+            if n == buf.len() {
+                break;
+            }
+            match special {
+                Some(b'"') => {
+                    self.inner.consume(1);
+                    self.done = true;
+                }
+                Some(_) => {
+                    self.inner.consume(1);
+                    self.unescape()?;
+                }
+                None => {}
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn invalid_escape() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid escape in a JSON string",
+    )
+}
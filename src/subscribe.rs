@@ -0,0 +1,85 @@
+use crate::KvStore;
+use std::str;
+
+/// A change of the KvStore, passed to the callbacks of `KvStore::on_change`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChangeEvent<'a> {
+    /// the changed key, empty if it is not UTF-8
+    pub key: &'a str,
+    /// the changed key as bytes
+    pub raw_key: &'a [u8],
+    /// the new value of the key, `None` if it got removed or is not UTF-8
+    pub value: Option<&'a str>,
+    /// the new value of the key as bytes, `None` if it got removed
+    pub bytes: Option<&'a [u8]>,
+    /// the sequence number of the record of the change, see
+    /// `KvStore::last_sequence`
+    pub seq: u64,
+}
+
+/// Identifies a callback registered with `KvStore::on_change`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Callback = Box<dyn FnMut(&ChangeEvent) + Send + Sync>;
+
+// the callbacks, in subscription order
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    next_id: u64,
+    callbacks: Vec<(SubscriptionId, Callback)>,
+}
+
+impl Subscribers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    pub(crate) fn notify(&mut self, seq: u64, raw_key: &[u8], bytes: Option<&[u8]>) {
+        if self.is_empty() {
+            return;
+        }
+        let key = str::from_utf8(raw_key).unwrap_or_default();
+        let value = bytes.and_then(|bytes| str::from_utf8(bytes).ok());
+        let event = ChangeEvent {
+            key,
+            raw_key,
+            value,
+            bytes,
+            seq,
+        };
+        for (_, callback) in self.callbacks.iter_mut() {
+            callback(&event);
+        }
+    }
+}
+
+impl KvStore {
+    /// Registers `f` to be called with every change of the KvStore.
+    ///
+    /// `f` is only called once the change is written to the log, failed
+    /// writes and calls which change nothing are not reported. Removals done
+    /// by `clear` are reported key by key, all with the sequence number of
+    /// the `clear`, expirations are not reported.
+    /// Returns the id to give to `unsubscribe`. `f` is `Send` and `Sync` so
+    /// the KvStore can be shared between threads.
+    pub fn on_change<F>(&mut self, f: F) -> SubscriptionId
+    where
+        F: FnMut(&ChangeEvent) + Send + Sync + 'static,
+    {
+        let subscribers = &mut self.subscribers;
+        let id = SubscriptionId(subscribers.next_id);
+        subscribers.next_id += 1;
+        subscribers.callbacks.push((id, Box::new(f)));
+        id
+    }
+
+    /// Removes a callback registered with `on_change`. Returns false if it
+    /// was already removed.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let callbacks = &mut self.subscribers.callbacks;
+        let len = callbacks.len();
+        callbacks.retain(|(other, _)| *other != id);
+        callbacks.len() != len
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{KvStore, Result};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// When the writes to the log are synced to disk, see
+/// `KvStoreOptions::sync_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// after every write
+    Always,
+    /// after every n writes
+    EveryN(usize),
+    /// on the first write once the duration passed since the last sync, so
+    /// the writes after it wait for the next one
+    Interval(Duration),
+    /// never, the system writes the log back when it sees fit
+    #[default]
+    Never,
+}
+
+// the writes since the log was last synced
+pub(crate) struct SyncState {
+    unsynced: usize,
+    last_sync: Instant,
+    syncs: usize, // the number of syncs since open
+}
+
+impl SyncState {
+    pub(crate) fn new() -> SyncState {
+        SyncState {
+            unsynced: 0,
+            last_sync: Instant::now(),
+            syncs: 0,
+        }
+    }
+}
+
+impl KvStore {
+    /// Flushes the writes to the log and syncs them to disk, whatever the
+    /// sync policy.
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        if let Some(log) = &self.log {
+            self.options.file_sync.sync_data(log)?;
+            self.sync.syncs += 1;
+        }
+        self.sync.unsynced = 0;
+        self.sync.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // sync the log if the sync policy of the options says so after a write
+    pub(crate) fn sync_after_write(&mut self) -> Result<()> {
+        self.sync.unsynced += 1;
+        let due = match self.options.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => self.sync.unsynced >= n,
+            SyncPolicy::Interval(interval) => self.sync.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // the number of syncs of the log since open
+    pub(crate) fn syncs(&self) -> usize {
+        self.sync.syncs
+    }
+}
@@ -0,0 +1,155 @@
+//! Thread pools running the connections of `KvsServer`.
+
+use crate::Result;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+#[cfg(feature = "rayon-pool")]
+use std::sync::Condvar;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// A pool of threads which run the jobs spawned on it.
+pub trait ThreadPool {
+    /// A pool of `threads` threads, at least one.
+    fn new(threads: usize) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on a thread of the pool once one is free. A job which
+    /// panics does not take its thread down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A `ThreadPool` whose threads take the jobs from a shared queue, in the
+/// order they are spawned.
+///
+/// Dropping the pool waits for the jobs spawned to finish.
+pub struct SharedQueueThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: usize) -> Result<SharedQueueThreadPool> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::with_capacity(threads.max(1));
+        for id in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let worker = thread::Builder::new()
+                .name(format!("kvs-worker-{}", id))
+                .spawn(move || run_jobs(&receiver))
+                .map_err(|err| io::Error::new(err.kind(), err))?;
+            workers.push(worker);
+        }
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            workers,
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            // the workers only stop once the sender is dropped
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // the workers run the queued jobs, then see the queue closed
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// run the jobs of receiver until the pool is dropped; the panic of a job
+// is caught, so the thread goes on with the next
+fn run_jobs(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = {
+            let receiver = receiver
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            receiver.recv()
+        };
+        match job {
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// A `ThreadPool` on a rayon pool, whose threads steal the jobs from each
+/// other; needs the `rayon-pool` feature.
+///
+/// Dropping the pool waits for the jobs spawned to finish, like
+/// `SharedQueueThreadPool`.
+#[cfg(feature = "rayon-pool")]
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    pending: Arc<(Mutex<usize>, Condvar)>, // the jobs spawned but not done
+}
+
+#[cfg(feature = "rayon-pool")]
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: usize) -> Result<RayonThreadPool> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.max(1))
+            .thread_name(|id| format!("kvs-worker-{}", id))
+            .build()
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        Ok(RayonThreadPool {
+            pool,
+            pending: Arc::new((Mutex::new(0), Condvar::new())),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        *lock(&self.pending.0) += 1;
+        let pending = Arc::clone(&self.pending);
+        self.pool.spawn(move || {
+            // rayon aborts the process on the panic of a job
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            let (count, done) = &*pending;
+            *lock(count) -= 1;
+            done.notify_all();
+        });
+    }
+}
+
+#[cfg(feature = "rayon-pool")]
+impl Drop for RayonThreadPool {
+    fn drop(&mut self) {
+        let (count, done) = &*self.pending;
+        let mut count = lock(count);
+        while *count > 0 {
+            count = done
+                .wait(count)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+#[cfg(feature = "rayon-pool")]
+fn lock(count: &Mutex<usize>) -> std::sync::MutexGuard<'_, usize> {
+    count
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
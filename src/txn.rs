@@ -0,0 +1,136 @@
+use crate::compress::saved_bytes;
+use crate::index::{Key, Meta, OffsetLen};
+use crate::{now_millis, rm_data, set_data, KvStore, OptData, Result, Value};
+use std::collections::BTreeMap;
+
+/// A write transaction on a KvStore, see `KvStore::transaction`.
+///
+/// Sets and removals are buffered until `commit`, dropping the transaction
+/// discards them.
+pub struct Txn<'a> {
+    store: &'a mut KvStore,
+    writes: BTreeMap<String, Option<String>>, // None for a removal
+}
+
+impl KvStore {
+    /// Starts a write transaction on the KvStore.
+    ///
+    /// The KvStore is borrowed until the transaction is committed or
+    /// dropped, nested transactions are not supported.
+    pub fn transaction(&mut self) -> Txn<'_> {
+        Txn {
+            store: self,
+            writes: BTreeMap::new(),
+        }
+    }
+}
+
+impl Txn<'_> {
+    /// Buffers the set of a key.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Buffers the removal of a key. Returns false if the key does not exist,
+    /// as seen by the transaction.
+    pub fn remove(&mut self, key: String) -> bool {
+        let exists = self.contains_key(&key);
+        self.writes.insert(key, None);
+        exists
+    }
+
+    /// Returns the value of a key as seen by the transaction: its buffered
+    /// writes first, then the KvStore.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.store.get(String::from(key)),
+        }
+    }
+
+    /// Returns true if the key exists as seen by the transaction.
+    pub fn contains_key(&self, key: &str) -> bool {
+        match self.writes.get(key) {
+            Some(value) => value.is_some(),
+            None => self.store.contains_key(key),
+        }
+    }
+
+    /// Writes all the buffered sets and removals to the log as one block,
+    /// then applies them to the KvStore.
+    ///
+    /// The block starts with a record counting the records of the
+    /// transaction, a log which ends before the last of them is replayed
+    /// without any, and the partial block is truncated by the next `open`.
+    /// Removals of absent keys write nothing. On error nothing is applied.
+    pub fn commit(self) -> Result<()> {
+        let store = self.store;
+        store.check_writable()?;
+        for (key, value) in self.writes.iter() {
+            store.check_size(key.as_bytes(), value.as_deref().unwrap_or("").as_bytes())?;
+        }
+        let writes: Vec<_> = self
+            .writes
+            .into_iter()
+            .filter(|(key, value)| value.is_some() || store.contains_key(key))
+            .collect();
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = Vec::new();
+        let header = OptData::TxnData {
+            count: writes.len(),
+        };
+        let header_len = store.push_record(&mut buf, &header)?;
+        let now = now_millis();
+        let mut records = Vec::with_capacity(writes.len());
+        for (key, value) in writes.iter() {
+            let seq = store.take_seq();
+            let record = match value {
+                Some(value) => {
+                    let meta = Meta::next(store.live_meta(key.as_bytes())?.as_ref(), now);
+                    let value = Value::Text(String::from(value));
+                    let data = store.compress(set_data(key.as_bytes(), value, None, meta, seq))?;
+                    let saved = saved_bytes(&data);
+                    (store.push_record(&mut buf, &data)?, Some((saved, meta)))
+                }
+                None => (
+                    store.push_record(&mut buf, &rm_data(key.as_bytes(), seq))?,
+                    None,
+                ),
+            };
+            records.push((record, seq));
+        }
+        let mut offset = store.append_log(&buf)? + header_len as u64;
+        for ((key, value), ((len, meta), seq)) in writes.into_iter().zip(records) {
+            let bytes = value.as_ref().map(|value| value.as_bytes());
+            store.subscribers.notify(seq, key.as_bytes(), bytes);
+            match meta {
+                Some((saved, meta)) => {
+                    store.kvs.insert(
+                        Key::from(key),
+                        OffsetLen {
+                            segment: store.segment,
+                            saved,
+                            offset,
+                            len,
+                            expires_at: None,
+                            meta,
+                            inline: bytes.and_then(|bytes| store.inline(bytes)),
+                        },
+                    )?;
+                }
+                None => {
+                    store.kvs.remove(key.as_bytes())?;
+                }
+            }
+            offset += len as u64;
+        }
+        store.maybe_compact()
+    }
+
+    /// Discards all the buffered sets and removals, like dropping the
+    /// transaction.
+    pub fn rollback(self) {}
+}
@@ -0,0 +1,106 @@
+use crate::crypt::open_record;
+use crate::header::{record_format, records_start};
+use crate::record::{decode, read_next};
+use crate::{decode_key, KvStore, OptData, Result};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+/// What `KvStore::verify` found, every problem with its offset in the log,
+/// or in its segment for a log split in segments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// the number of records which parsed
+    pub records: usize,
+    /// the offsets of the records which do not parse, or do not match their
+    /// checksum
+    pub unparseable: Vec<u64>,
+    /// the index entries pointing at the start of a record which is not a
+    /// set of their key, or has another length, as the key with the bytes
+    /// which are not UTF-8 replaced and the offset
+    pub mismatched: Vec<(String, u64)>,
+    /// the index entries pointing inside a record or past the end of the
+    /// log, as the key and the offset
+    pub orphaned: Vec<(String, u64)>,
+}
+
+impl VerifyReport {
+    /// Returns true if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.unparseable.is_empty() && self.mismatched.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+impl KvStore {
+    /// Reads the whole log and checks that every record parses and that
+    /// every entry of the index points at the set of its key.
+    ///
+    /// The log is read once, in order and segment by segment, alongside the
+    /// index entries sorted by position. Nothing is repaired.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut entries: Vec<_> = self.kvs.iter()?.collect();
+        entries.sort_by_key(|kv| kv.1.position());
+        let mut entries = entries.into_iter().peekable();
+        for (id, log, header, end) in self.segment_files() {
+            let format = record_format(header);
+            let required = header.is_some_and(|header| header.checksums());
+            let mut offset = records_start(header);
+            let mut log = log.try_clone()?;
+            log.seek(SeekFrom::Start(offset))?;
+            let mut reader = BufReader::new(log.take(end - offset));
+            let mut record = Vec::new();
+            loop {
+                let len = read_next(format, &mut reader, &mut record)?;
+                if len == 0 {
+                    break;
+                }
+                // the entries skipped over point inside the previous record,
+                // or past the end of the previous segment
+                while let Some((key, off2len)) =
+                    entries.next_if(|kv| kv.1.position() < (id, offset))
+                {
+                    report
+                        .orphaned
+                        .push((lossy(key.as_bytes()), off2len.offset));
+                }
+                let cipher = self.segments.cipher.as_ref();
+                let parsed = open_record(cipher, &record, (header, id, offset))
+                    .and_then(|record| decode(format, &record, offset, required));
+                let record_key = match parsed {
+                    Ok(OptData::SetData {
+                        key, key_base64, ..
+                    }) => {
+                        report.records += 1;
+                        decode_key(key, key_base64).ok()
+                    }
+                    Ok(_) => {
+                        report.records += 1;
+                        None
+                    }
+                    Err(_) => {
+                        report.unparseable.push(offset);
+                        None
+                    }
+                };
+                while let Some((key, off2len)) =
+                    entries.next_if(|kv| kv.1.position() == (id, offset))
+                {
+                    let matches = record_key.as_ref() == Some(key) && off2len.len == len;
+                    if !matches {
+                        report.mismatched.push((lossy(key.as_bytes()), offset));
+                    }
+                }
+                offset += len as u64;
+            }
+        }
+        for (key, off2len) in entries {
+            report
+                .orphaned
+                .push((lossy(key.as_bytes()), off2len.offset));
+        }
+        Ok(report)
+    }
+}
+
+fn lossy(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
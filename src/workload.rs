@@ -0,0 +1,94 @@
+//! Deterministic workloads for the benchmarks; needs the `bench-internals`
+//! feature.
+//!
+//! The keys, the values and the orders are computed from a seed with
+//! SplitMix64 and nothing else, so a workload is the same on every machine
+//! and across versions of the dependencies.
+
+/// A workload of `len` keys with values of `value_len` bytes.
+#[derive(Clone, Debug)]
+pub struct Workload {
+    seed: u64,
+    len: usize,
+    value_len: usize,
+}
+
+impl Workload {
+    /// A workload of `len` keys with values of `value_len` bytes, drawn from
+    /// `seed`.
+    pub fn new(seed: u64, len: usize, value_len: usize) -> Workload {
+        Workload {
+            seed,
+            len,
+            value_len,
+        }
+    }
+
+    /// The number of keys.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// True if the workload has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The `i`th key, `key` and `i` zero padded to 8 digits, so the keys
+    /// sort in order.
+    pub fn key(&self, i: usize) -> String {
+        format!("key{:08}", i)
+    }
+
+    /// The value of the `i`th key: `value_len` alphanumeric bytes.
+    pub fn value(&self, i: usize) -> String {
+        const ALPHABET: &[u8; 62] =
+            b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+        let mut rng = SplitMix64(self.seed ^ (i as u64).wrapping_mul(GOLDEN_GAMMA));
+        (0..self.value_len)
+            .map(|_| char::from(ALPHABET[(rng.next() % 62) as usize]))
+            .collect()
+    }
+
+    /// Every key with its value, in key order.
+    pub fn pairs(&self) -> Vec<(String, String)> {
+        (0..self.len)
+            .map(|i| (self.key(i), self.value(i)))
+            .collect()
+    }
+
+    /// `n` keys picked uniformly at random, a key possibly more than once.
+    pub fn random_keys(&self, n: usize) -> Vec<String> {
+        let mut rng = SplitMix64(self.seed.wrapping_add(1));
+        (0..n)
+            .map(|_| self.key((rng.next() % self.len.max(1) as u64) as usize))
+            .collect()
+    }
+
+    /// Every key with its value, in a random order.
+    pub fn shuffled_pairs(&self) -> Vec<(String, String)> {
+        let mut pairs = self.pairs();
+        let mut rng = SplitMix64(self.seed.wrapping_add(2));
+        // Fisher-Yates
+        for i in (1..pairs.len()).rev() {
+            let j = (rng.next() % (i as u64 + 1)) as usize;
+            pairs.swap(i, j);
+        }
+        pairs
+    }
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// the SplitMix64 generator of Steele, Lea and Flood
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(GOLDEN_GAMMA);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+
+// appends to the log at the position it keeps itself, so the file cursor
+// moved by the reads which walk the log never matters
+pub(crate) struct LogWriter {
+    file: File,
+    pos: u64, // where the next byte goes
+}
+
+impl LogWriter {
+    // a buffered writer appending to log from pos, through its own handle
+    pub(crate) fn buffered(log: &File, pos: u64) -> io::Result<BufWriter<LogWriter>> {
+        let file = log.try_clone()?;
+        Ok(BufWriter::new(LogWriter { file, pos }))
+    }
+
+    pub(crate) fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
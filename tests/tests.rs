@@ -1,16 +1,35 @@
 // copy from https://github.com/pingcap/talent-plan/blob/master/courses/rust/projects/project-2/tests/tests.rs
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::protocol::{read_frame, write_frame, Request, Response};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ChangeEvent, CheckpointInfo, CompactionStats, FileSync, HistoryEntry, ImportReport, KvStore,
+    KvStoreOptions, KvsClient, KvsClientBuilder, KvsEngine, KvsError, KvsServer, LogFormat,
+    MemKvStore, MergeReport, MergeStrategy, MigrateReport, RecoveryMode, RecoveryReport, Result,
+    SetIfResult, SharedKvStore, StoreStats, SyncPolicy, VerifyReport,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 // `kvs` with no args should exit with a non-zero code.
 #[test]
 fn cli_no_args() {
-    Command::cargo_bin("kvs").unwrap().assert().failure();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
 }
 
 // `kvs -V` should print the version
@@ -18,7 +37,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -29,7 +48,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -42,7 +61,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -55,7 +74,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -73,7 +92,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -81,7 +100,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -101,7 +120,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -109,7 +128,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -122,13 +141,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -137,19 +156,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -158,13 +177,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -173,7 +192,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -192,7 +211,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -231,7 +250,7 @@ fn get_non_existent_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -255,6 +274,151 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// The semantics every `KvsEngine` has to follow, run against an empty engine.
+fn engine_conformance<E: KvsEngine>(engine: &mut E) -> Result<()> {
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // A set replaces the value, and keys and values may be empty.
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    engine.set(String::new(), String::new())?;
+    assert_eq!(engine.get(String::new())?, Some(String::new()));
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    for key in ["key1", "missing"].iter() {
+        match engine.remove(key.to_string()) {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::KeyNotFound) => {}
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("remove of a missing key succeeded"),
+        }
+    }
+
+    // A removed key can be set again.
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// KvStore follows the semantics of a KvsEngine, and keeps them on disk.
+#[test]
+fn kv_store_engine_conformance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    engine_conformance(&mut store)?;
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    let engine: &mut dyn KvsEngine = &mut store;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+// MemKvStore follows the semantics of a KvsEngine, with no log.
+#[test]
+fn mem_kv_store_engine_conformance() -> Result<()> {
+    let mut store = MemKvStore::new();
+    assert!(store.is_empty());
+    engine_conformance(&mut store)?;
+    assert_eq!(store.len(), 3);
+
+    // A clone holds the same pairs and is written separately.
+    let mut clone = store.clone();
+    clone.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(clone.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// The batch methods every `KvsEngine` has, run against an empty engine.
+fn engine_batch_conformance<E: KvsEngine>(engine: &mut E) -> Result<()> {
+    let keys = |names: &[&str]| -> Vec<String> { names.iter().map(|k| k.to_string()).collect() };
+    engine.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key1".to_owned(), "value3".to_owned()),
+    ])?;
+    assert_eq!(
+        engine.get_many(keys(&["key2", "missing", "key1"]))?,
+        vec![Some("value2".to_owned()), None, Some("value3".to_owned())]
+    );
+    assert_eq!(engine.get_many(Vec::new())?, Vec::new());
+
+    // A removal reports every key, one given twice was gone the second time.
+    assert_eq!(
+        engine.remove_many(keys(&["key1", "missing", "key1"]))?,
+        vec![true, false, false]
+    );
+    assert_eq!(
+        engine.get_many(keys(&["key1", "key2"]))?,
+        vec![None, Some("value2".to_owned())]
+    );
+    Ok(())
+}
+
+// KvStore, MemKvStore and SharedKvStore follow the semantics of the batch
+// methods of a KvsEngine.
+#[test]
+fn engine_batch_methods() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shared_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    engine_batch_conformance(&mut store)?;
+    engine_batch_conformance(&mut MemKvStore::new())?;
+    let mut shared = SharedKvStore::open(shared_dir.path())?;
+    engine_batch_conformance(&mut shared)?;
+    drop((store, shared));
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// The first open claims the directory for kvs, a directory another engine
+// claimed is refused by every open.
+#[test]
+fn engine_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(kvs::engine_marker(temp_dir.path())?, None);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(kvs::engine_marker(temp_dir.path())?, Some("kvs".to_owned()));
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    std::fs::write(temp_dir.path().join("engine"), "sled\n")?;
+    let opens: [fn(&std::path::Path) -> Result<KvStore>; 2] =
+        [|dir| KvStore::open(dir), |dir| KvStore::open_read_only(dir)];
+    for open in opens.iter() {
+        match open(temp_dir.path()) {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::WrongEngine { expected, found }) => {
+                    assert_eq!((expected.as_str(), found.as_str()), ("kvs", "sled"));
+                }
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("open of a directory of another engine succeeded"),
+        }
+    }
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -290,7 +454,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -300,3 +464,5503 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// `contains_key` should follow set/remove and survive a reopen.
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.contains_key("key1"));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.contains_key("key1"));
+    assert!(store.contains_key("key2"));
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1"));
+    assert!(store.contains_key("key2"));
+
+    // Open from disk again, the tombstone of key1 is still in the log.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1"));
+    assert!(store.contains_key("key2"));
+
+    Ok(())
+}
+
+// `len` should count live keys only, overwrites and removals included.
+#[test]
+fn len_and_is_empty() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(!store.is_empty());
+    assert_eq!(store.len(), 2);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    store.remove("key1".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert!(store.is_empty());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+// `keys` should list exactly the live keys, before and after a reopen.
+#[test]
+fn keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..10000).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let expected: HashSet<String> = (1..10000)
+        .step_by(2)
+        .map(|id| format!("key{}", id))
+        .collect();
+    assert_eq!(store.keys().cloned().collect::<HashSet<_>>(), expected);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().count(), 5000);
+    assert_eq!(store.keys().cloned().collect::<HashSet<_>>(), expected);
+
+    Ok(())
+}
+
+// `iter` should yield every live pair in log order, escaped and multi-byte values included.
+#[test]
+fn iter_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let pairs = vec![
+        ("key1".to_owned(), "quote \" and backslash \\".to_owned()),
+        ("key2".to_owned(), "new\nline\tand tab".to_owned()),
+        ("ключ".to_owned(), "значение".to_owned()),
+        ("key4".to_owned(), "中文 🦀".to_owned()),
+        ("key5".to_owned(), "".to_owned()),
+    ];
+    for (key, value) in pairs.iter() {
+        store.set(key.clone(), value.clone())?;
+    }
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, pairs);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, pairs);
+
+    Ok(())
+}
+
+// `scan_prefix` should return only the pairs under the prefix.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:1:name".to_owned(), "Alice".to_owned())?;
+    store.set("user:10".to_owned(), "bob".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.set("user:2".to_owned(), "carol".to_owned())?;
+    store.remove("user:2".to_owned())?;
+
+    let sorted = |mut pairs: Vec<(String, String)>| {
+        pairs.sort();
+        pairs
+    };
+    let pair = |k: &str, v: &str| (k.to_owned(), v.to_owned());
+
+    assert_eq!(
+        sorted(store.scan_prefix("user:1")?),
+        vec![
+            pair("user:1", "alice"),
+            pair("user:10", "bob"),
+            pair("user:1:name", "Alice")
+        ]
+    );
+    assert_eq!(
+        store.scan_prefix("user:1:name")?,
+        vec![pair("user:1:name", "Alice")]
+    );
+    assert_eq!(store.scan_prefix("user:2")?, vec![]);
+    assert_eq!(store.scan_prefix("nothing")?, vec![]);
+    assert_eq!(store.scan_prefix("")?.len(), 4);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan_prefix("group:")?,
+        vec![pair("group:1", "admins")]
+    );
+    assert_eq!(store.scan_prefix("")?.len(), 4);
+
+    Ok(())
+}
+
+// `range` should honor std bound semantics and return pairs in key order.
+#[test]
+fn range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_ordered(temp_dir.path())?;
+
+    for key in &["d", "a", "m", "b", "z", "c"] {
+        store.set(key.to_string(), format!("value_{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(value, format!("value_{}", key));
+                key
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Open from disk again, the ordered index is rebuilt from the log.
+    drop(store);
+    for ordered in &[true, false] {
+        let store = if *ordered {
+            KvStore::open_ordered(temp_dir.path())?
+        } else {
+            KvStore::open(temp_dir.path())?
+        };
+        assert_eq!(
+            keys(store.range("a".to_owned().."m".to_owned())?),
+            vec!["a", "b", "d"]
+        );
+        assert_eq!(
+            keys(store.range("a".to_owned()..="m".to_owned())?),
+            vec!["a", "b", "d", "m"]
+        );
+        assert_eq!(
+            keys(store.range("b".to_owned()..)?),
+            vec!["b", "d", "m", "z"]
+        );
+        assert_eq!(keys(store.range(.."d".to_owned())?), vec!["a", "b"]);
+        assert_eq!(keys(store.range(..)?), vec!["a", "b", "d", "m", "z"]);
+        assert!(store.range("n".to_owned().."y".to_owned())?.is_empty());
+    }
+
+    Ok(())
+}
+
+// `clear` should drop every key, on disk too, and leave the store usable.
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.clear()?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.clear()?;
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `set_batch` should behave like a sequence of `set` calls.
+#[test]
+fn set_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set_batch(vec![])?;
+    let mut pairs: Vec<_> = (0..1000)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    pairs.push(("key1".to_owned(), "last".to_owned()));
+    store.set_batch(pairs)?;
+    store.set("key2".to_owned(), "after".to_owned())?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.len(), 1000);
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("after".to_owned()));
+        assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+        Ok(())
+    };
+    check(&mut store)?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)
+}
+
+// `set_batch` compacts the log once the values it overwrites pass the
+// compaction threshold, like `set` does.
+#[test]
+fn set_batch_compacts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(Some(4096))
+        .open(temp_dir.path())?;
+    for iter in 0..10 {
+        let pairs = (0..100)
+            .map(|key_id| (format!("key{}", key_id), format!("value{}", iter)))
+            .collect();
+        store.set_batch(pairs)?;
+    }
+    assert!(store.stats().compactions > 0);
+    assert!(store.stats().dead_bytes <= 4096);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key42".to_owned())?, Some("value9".to_owned()));
+    Ok(())
+}
+
+// `remove_batch` should report the removed keys and only log their tombstones.
+#[test]
+fn remove_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let log_len = || {
+        std::fs::metadata(temp_dir.path().join("kvs.log"))
+            .expect("unable to stat the log")
+            .len()
+    };
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let size = log_len();
+    assert!(store.remove_batch(vec!["missing".to_owned()])?.is_empty());
+    assert_eq!(log_len(), size);
+
+    let removed = store.remove_batch(vec![
+        "key1".to_owned(),
+        "missing".to_owned(),
+        "key3".to_owned(),
+        "key1".to_owned(),
+    ])?;
+    assert_eq!(removed, vec!["key1".to_owned(), "key3".to_owned()]);
+    assert_eq!(store.len(), 8);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 8);
+    assert!(!store.contains_key("key1"));
+    assert!(!store.contains_key("key3"));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `compare_and_swap` should only write when the current value is the expected one.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let key1 = || "key1".to_owned();
+    let some = |value: &str| Some(value.to_owned());
+
+    // expected None, new Some: insert only if absent
+    assert!(store.compare_and_swap(key1(), None, some("value1"))?);
+    assert!(!store.compare_and_swap(key1(), None, some("value2"))?);
+    assert_eq!(store.get(key1())?, some("value1"));
+
+    // expected Some, new Some: swap only on a match
+    assert!(!store.compare_and_swap(key1(), some("other"), some("value2"))?);
+    assert!(store.compare_and_swap(key1(), some("value1"), some("value2"))?);
+    assert_eq!(store.get(key1())?, some("value2"));
+
+    // expected Some, new None: remove only on a match
+    assert!(!store.compare_and_swap(key1(), some("value1"), None)?);
+    assert!(store.contains_key("key1"));
+    assert!(store.compare_and_swap(key1(), some("value2"), None)?);
+    assert!(!store.contains_key("key1"));
+
+    // expected None, new None: succeed only if absent
+    assert!(store.compare_and_swap(key1(), None, None)?);
+    store.set(key1(), "value3".to_owned())?;
+    assert!(!store.compare_and_swap(key1(), None, None)?);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(key1())?, some("value3"));
+
+    Ok(())
+}
+
+// `get_and_set` should return the value it replaced.
+#[test]
+fn get_and_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.get_and_set("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.get_and_set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    // a value of another length moves the record
+    assert_eq!(
+        store.get_and_set("key1".to_owned(), "longer value".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("longer value".to_owned())
+    );
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_and_set("key1".to_owned(), "value3".to_owned())?,
+        Some("longer value".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `set` should tell an insert from an overwrite.
+#[test]
+fn set_reports_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set("key1".to_owned(), "value2".to_owned())?);
+    assert!(!store.set("key1".to_owned(), "other length".to_owned())?);
+    store.remove("key1".to_owned())?;
+    assert!(store.set("key1".to_owned(), "value3".to_owned())?);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.set("key1".to_owned(), "value4".to_owned())?);
+    assert!(store.set("key2".to_owned(), "value1".to_owned())?);
+
+    Ok(())
+}
+
+// `remove` should hand back the removed value.
+#[test]
+fn remove_returns_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.remove("key1".to_owned())?, "value1");
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.remove("key2".to_owned())?, "value2");
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+// `update` should apply the closure once and skip identical writes.
+#[test]
+fn update() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let log_len = || {
+        std::fs::metadata(temp_dir.path().join("kvs.log"))
+            .expect("unable to stat the log")
+            .len()
+    };
+
+    let new = store.update("key1".to_owned(), |current| {
+        assert_eq!(current, None);
+        Some("a".to_owned())
+    })?;
+    assert_eq!(new, Some("a".to_owned()));
+    let append_b = |current: Option<String>| current.map(|v| v + "b");
+    assert_eq!(
+        store.update("key1".to_owned(), append_b)?,
+        Some("ab".to_owned())
+    );
+    assert_eq!(store.update("key2".to_owned(), append_b)?, None);
+    assert!(!store.contains_key("key2"));
+
+    let size = log_len();
+    assert_eq!(
+        store.update("key1".to_owned(), |v| v)?,
+        Some("ab".to_owned())
+    );
+    assert_eq!(log_len(), size);
+
+    assert_eq!(store.update("key1".to_owned(), |_| None)?, None);
+    assert!(!store.contains_key("key1"));
+    store.update("key3".to_owned(), |_| Some("c".to_owned()))?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("c".to_owned()));
+
+    Ok(())
+}
+
+// `increment` should count from 0 and reject non-integer values.
+#[test]
+fn increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("counter".to_owned(), 1)?, 1);
+    assert_eq!(store.increment("counter".to_owned(), 41)?, 42);
+    assert_eq!(store.increment("counter".to_owned(), -50)?, -8);
+    assert_eq!(store.get("counter".to_owned())?, Some("-8".to_owned()));
+
+    store.set("text".to_owned(), "abc".to_owned())?;
+    match store.increment("text".to_owned(), 1) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::NotAnInteger { key, value }) => {
+                assert_eq!(key, "text");
+                assert_eq!(value, "abc");
+            }
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("increment of a non-integer value succeeded"),
+    }
+    assert_eq!(store.get("text".to_owned())?, Some("abc".to_owned()));
+
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(store.increment("max".to_owned(), 1).is_err());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter".to_owned(), 8)?, 0);
+
+    Ok(())
+}
+
+// `kvs incr <KEY> [DELTA]` should print the new value.
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let incr = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("incr")
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    incr(&["counter"]).success().stdout(eq("1").trim());
+    incr(&["counter", "10"]).success().stdout(eq("11").trim());
+    incr(&["counter", "-20"]).success().stdout(eq("-9").trim());
+    incr(&["counter", "NaN"]).failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "text", "abc"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    incr(&["text"]).failure().stdout(contains("not an integer"));
+}
+
+// `append` should grow the value and survive a reopen.
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.append("key1".to_owned(), "line1\n".to_owned())?, 6);
+    assert_eq!(store.append("key1".to_owned(), "line2\n".to_owned())?, 12);
+    assert_eq!(store.append("key1".to_owned(), "".to_owned())?, 12);
+    assert_eq!(store.append("key2".to_owned(), "é".to_owned())?, 2);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("line1\nline2\n".to_owned())
+    );
+    assert_eq!(store.append("key2".to_owned(), "e".to_owned())?, 3);
+    assert_eq!(store.get("key2".to_owned())?, Some("ée".to_owned()));
+
+    Ok(())
+}
+
+// Keys set with a ttl should vanish once expired, across reopens and rebuilds.
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("forever".to_owned(), "value".to_owned())?;
+    assert!(store.ttl("short").unwrap() <= Duration::from_millis(200));
+    assert!(store.ttl("long").unwrap() > Duration::from_secs(3500));
+    assert_eq!(store.ttl("forever"), None);
+    assert_eq!(store.ttl("missing"), None);
+    assert!(store.contains_key("short"));
+    assert_eq!(store.len(), 3);
+
+    // Open from disk again, the expirations are persisted.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("long").is_some());
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(250));
+    assert!(!store.contains_key("short"));
+    assert_eq!(store.ttl("short"), None);
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(store.remove("short".to_owned()).is_err());
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+
+    // a plain set clears the expiration
+    store.set_with_ttl(
+        "forever".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    store.set("forever".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(store.ttl("forever"), None);
+    assert!(store.contains_key("forever"));
+
+    Ok(())
+}
+
+// `len` counts the keys which expired out as their time passes, and keeps
+// counting right once they are overwritten or compacted away.
+#[test]
+fn len_with_expirations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        let ttl = Duration::from_millis(if i < 5 { 100 } else { 3_600_000 });
+        store.set_with_ttl(format!("key{}", i), "value".to_owned(), ttl)?;
+    }
+    store.set("forever".to_owned(), "value".to_owned())?;
+    assert_eq!(store.len(), 11);
+
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.len(), 6);
+    store.set("key0".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.len(), 8);
+    store.remove("key5".to_owned())?;
+    assert_eq!(store.len(), 7);
+    store.compact()?;
+    assert_eq!(store.len(), 7);
+    assert_eq!(store.keys().count(), 7);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 7);
+    Ok(())
+}
+
+// A key which expires while the store is closed should be absent on reopen
+// and dropped from the log by the next compaction.
+#[test]
+fn ttl_expires_while_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "expired".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(150));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("short"));
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1"]);
+
+    store.set("key1".to_owned(), "longer value1".to_owned())?;
+    store.compact()?;
+    let log = std::fs::read(temp_dir.path().join("kvs.log"))?;
+    assert!(!String::from_utf8_lossy(&log).contains("expired"));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("longer value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// `persist` should clear an expiration for good and report whether it did.
+#[test]
+fn persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.persist("key1")?);
+    assert!(!store.persist("key1")?);
+    assert!(!store.persist("key2")?);
+    assert!(!store.persist("missing")?);
+    assert_eq!(store.ttl("key1"), None);
+
+    // Open from disk again after the former expiration time.
+    drop(store);
+    thread::sleep(Duration::from_millis(250));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.ttl("key1"), None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs persist <KEY>` should print whether an expiration was cleared.
+#[test]
+fn cli_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    drop(store);
+
+    let persist = |key: &str| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["persist", key])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+    persist("key1").stdout(eq("true").trim());
+    persist("key1").stdout(eq("false").trim());
+    persist("missing").stdout(eq("false").trim());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.ttl("key1"), None);
+    assert!(store.contains_key("key1"));
+
+    Ok(())
+}
+
+// `rename` should move the value and fail on a missing source.
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.rename("key1".to_owned(), "new1".to_owned())?;
+    store.rename("key3".to_owned(), "key2".to_owned())?;
+    store.rename("new1".to_owned(), "new1".to_owned())?;
+    match store.rename("missing".to_owned(), "key2".to_owned()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("rename of a missing key succeeded"),
+    }
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("new1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+        assert!(store.ttl("key2").is_some());
+        Ok(())
+    };
+    check(&mut store)?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)
+}
+
+// `rename` compacts the log once the records it leaves behind pass the
+// compaction threshold, like `set` and `remove` do.
+#[test]
+fn rename_compacts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(Some(4096))
+        .open(temp_dir.path())?;
+    store.set("key0".to_owned(), "x".repeat(100))?;
+    for key_id in 0..100 {
+        store.rename(format!("key{}", key_id), format!("key{}", key_id + 1))?;
+    }
+    assert!(store.stats().compactions > 0);
+    assert!(store.stats().dead_bytes <= 4096);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key100"]);
+    assert_eq!(store.get("key100".to_owned())?, Some("x".repeat(100)));
+    Ok(())
+}
+
+// `kvs rename <FROM> <TO>` should move the value, or print "Key not found".
+#[test]
+fn cli_rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rename", "key1", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rename", "key1", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// `copy` should duplicate the value and respect `overwrite`.
+#[test]
+fn copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let big = "x".repeat(1 << 20);
+    store.set("key1".to_owned(), big.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.copy("key1", "copy1".to_owned(), false)?);
+    assert!(!store.copy("missing", "copy2".to_owned(), true)?);
+    assert!(!store.contains_key("copy2"));
+    match store.copy("key1", "key2".to_owned(), false) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyExists { key }) => assert_eq!(key, "key2"),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("copy over an existing key succeeded"),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.copy("key2", "key1".to_owned(), true)?);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("copy1".to_owned())?, Some(big));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `copy` over an existing key compacts the log once the values it replaces
+// pass the compaction threshold, like `set` does.
+#[test]
+fn copy_compacts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(Some(4096))
+        .open(temp_dir.path())?;
+    store.set("from".to_owned(), "x".repeat(100))?;
+    for _ in 0..100 {
+        assert!(store.copy("from", "to".to_owned(), true)?);
+    }
+    assert!(store.stats().compactions > 0);
+    assert!(store.stats().dead_bytes <= 4096);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("to".to_owned())?, Some("x".repeat(100)));
+    Ok(())
+}
+
+// `get_many` should return the values in the order of the keys asked for.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key50".to_owned())?;
+    let keys: Vec<String> = vec!["key99", "key0", "missing", "key50", "key7", "key0"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let expected = vec![
+        Some("value99".to_owned()),
+        Some("value0".to_owned()),
+        None,
+        None,
+        Some("value7".to_owned()),
+        Some("value0".to_owned()),
+    ];
+    assert_eq!(store.get_many(&keys)?, expected);
+    assert!(store.get_many(&[])?.is_empty());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_many(&keys)?, expected);
+
+    Ok(())
+}
+
+// `retain` should drop the keys rejected by the predicate.
+#[test]
+fn retain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("session:{}", key_id), "token".to_owned())?;
+        store.set(format!("user:{}", key_id), "name".to_owned())?;
+    }
+    assert_eq!(store.retain(|key| !key.starts_with("session:"))?, 100);
+    assert_eq!(store.retain(|key| !key.starts_with("session:"))?, 0);
+    assert_eq!(store.len(), 100);
+    assert!(store.keys().all(|key| key.starts_with("user:")));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    assert!(!store.contains_key("session:1"));
+    assert_eq!(store.retain(|_| false)?, 100);
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+// A snapshot should keep serving the values it captured while the store keeps writing.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let snapshot = store.snapshot()?;
+
+    // same length, different length, removal, new key
+    store.set("key1".to_owned(), "VALUE1".to_owned())?;
+    store.set("key2".to_owned(), "a much longer value".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set("key10".to_owned(), "value10".to_owned())?;
+
+    assert_eq!(snapshot.len(), 10);
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(snapshot.get("key10")?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("VALUE1".to_owned()));
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("a much longer value".to_owned())
+    );
+
+    // the old log is still readable through the snapshot after a clear
+    store.clear()?;
+    let pairs = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = (0..10)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    assert_eq!(pairs, expected);
+    drop(snapshot);
+
+    // the store goes back to rebuilding once the snapshot is gone
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "longer value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("longer value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `export_json` should write every live pair as one JSON object per line.
+#[test]
+fn export_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "quote \" and\nnewline".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let path = temp_dir.path().join("export.json");
+    assert_eq!(store.export_json(&path)?, 2);
+    let exported = std::fs::read_to_string(&path)?;
+    let lines: Vec<_> = exported.lines().collect();
+    assert_eq!(lines.len(), 2);
+    let mut pairs: Vec<(String, String)> = lines
+        .into_iter()
+        .map(|line| {
+            let object: serde_json::Value = serde_json::from_str(line).unwrap();
+            let field = |name: &str| object[name].as_str().unwrap().to_owned();
+            (field("key"), field("value"))
+        })
+        .collect();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "quote \" and\nnewline".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    store.clear()?;
+    assert_eq!(store.export_json(&path)?, 0);
+    assert!(std::fs::read_to_string(&path)?.is_empty());
+
+    Ok(())
+}
+
+// `import_json` should load what `export_json` wrote, and JSON maps too.
+#[test]
+fn import_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut src = KvStore::open(src_dir.path())?;
+    src.set("key1".to_owned(), "quote \" and\nnewline".to_owned())?;
+    src.set("key2".to_owned(), "value2".to_owned())?;
+    let export = temp_dir.path().join("export.json");
+    src.export_json(&export)?;
+
+    let map = temp_dir.path().join("map.json");
+    std::fs::write(
+        &map,
+        "{\n  \"key2\": \"mapped2\",\n  \"key3\": \"mapped3\"\n}\n",
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    let report = store.import_json(&export, false)?;
+    assert_eq!(
+        report,
+        ImportReport {
+            inserted: 1,
+            overwritten: 0,
+            skipped: 1,
+        }
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("old".to_owned()));
+    let report = store.import_json(&export, true)?;
+    assert_eq!(report.overwritten, 2);
+    let report = store.import_json(&map, true)?;
+    assert_eq!((report.inserted, report.overwritten), (1, 1));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("quote \" and\nnewline".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("mapped2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("mapped3".to_owned()));
+
+    Ok(())
+}
+
+// A malformed record should fail the import, naming its line, before any write.
+#[test]
+fn import_json_malformed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let path = temp_dir.path().join("import.json");
+    std::fs::write(
+        &path,
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\n{\"key\":\"key2\"}\n",
+    )?;
+
+    match store.import_json(&path, true) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::MalformedImport { line, .. }) => assert_eq!(*line, 3),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("import of a malformed file succeeded"),
+    }
+    assert!(store.is_empty());
+
+    std::fs::write(&path, "{\n  \"key1\": 1\n}\n")?;
+    match store.import_json(&path, true) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::MalformedImport { line, .. }) => assert_eq!(*line, 2),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("import of a malformed file succeeded"),
+    }
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+// `merge_from` should apply the live state of another store with a strategy.
+#[test]
+fn merge_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path())?;
+    other.set("key1".to_owned(), "other1".to_owned())?;
+    other.set("key2".to_owned(), "value2".to_owned())?;
+    other.set("key3".to_owned(), "other3".to_owned())?;
+    other.set("gone".to_owned(), "value".to_owned())?;
+    other.remove("gone".to_owned())?;
+    other.remove("key4".to_owned()).ok();
+    drop(other);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    match store.merge_from(other_dir.path(), MergeStrategy::ErrorOnConflict) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::MergeConflict { keys }) => assert_eq!(keys, &["key1".to_owned()]),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("conflicting merge succeeded"),
+    }
+    assert!(!store.contains_key("key3"));
+
+    let report = store.merge_from(other_dir.path(), MergeStrategy::KeepExisting)?;
+    assert_eq!(
+        report,
+        MergeReport {
+            inserted: 1,
+            unchanged: 1,
+            conflicts: vec!["key1".to_owned()],
+        }
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("other3".to_owned()));
+
+    let report = store.merge_from(other_dir.path(), MergeStrategy::Overwrite)?;
+    assert_eq!((report.inserted, report.unchanged), (0, 2));
+    assert_eq!(report.conflicts, vec!["key1".to_owned()]);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("other1".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.len(), 4);
+
+    Ok(())
+}
+
+// `restore` should rebuild from a `dump` the store it was taken from.
+#[test]
+fn dump_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let large = "x".repeat(1 << 20);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("empty".to_owned(), String::new())?;
+    store.set("".to_owned(), "empty key".to_owned())?;
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone".to_owned())?;
+    let mut dump = Vec::new();
+    store.dump(&mut dump)?;
+    assert_eq!(&dump[..8], b"KVSDUMP\x01");
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut restored = KvStore::restore(restore_dir.path(), &dump[..])?;
+    assert_eq!(restored.len(), 4);
+    assert_eq!(restored.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(restored.get("empty".to_owned())?, Some(String::new()));
+    assert_eq!(restored.get("".to_owned())?, Some("empty key".to_owned()));
+    assert!(restored.ttl("ttl").is_some());
+    assert_eq!(restored.get("gone".to_owned())?, None);
+    restored.set("key1".to_owned(), "value1".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(restored);
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.len(), 5);
+    assert_eq!(restored.get("large".to_owned())?, Some(large));
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // An existing log is not replaced.
+    assert!(KvStore::restore(restore_dir.path(), &dump[..]).is_err());
+
+    Ok(())
+}
+
+// `restore` should reject data which is not a complete dump.
+#[test]
+fn restore_invalid_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut empty = Vec::new();
+    store.dump(&mut empty)?;
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::restore(restore_dir.path(), &empty[..])?.is_empty());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut dump = Vec::new();
+    store.dump(&mut dump)?;
+    let mut bad_magic = dump.clone();
+    bad_magic[0] = b'J';
+    let mut bad_version = dump.clone();
+    bad_version[7] = 9;
+    for data in [
+        &bad_magic[..],
+        &bad_version[..],
+        &dump[..dump.len() - 3],
+        &dump[..10],
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        match KvStore::restore(temp_dir.path(), data) {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::InvalidDump { .. }) => {}
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("restore of an invalid dump succeeded"),
+        }
+    }
+
+    Ok(())
+}
+
+// `on_change` callbacks should see every write, and only the writes.
+#[test]
+fn on_change() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&events);
+    let first = store.on_change(move |event: &ChangeEvent| {
+        seen.lock()
+            .unwrap()
+            .push((event.key.to_owned(), event.value.map(str::to_owned)));
+    });
+    let count = Arc::new(Mutex::new(0));
+    let counter = Arc::clone(&count);
+    let second = store.on_change(move |_: &ChangeEvent| *counter.lock().unwrap() += 1);
+    assert_ne!(first, second);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "longer value1".to_owned())?;
+    store.set_batch(vec![("key2".to_owned(), "value2".to_owned())])?;
+    store.rename("key2".to_owned(), "key3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    // no-ops are not reported
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.remove_batch(vec!["missing".to_owned()])?;
+    store.update("key3".to_owned(), |v| v)?;
+    assert!(store.copy("key3", "key3".to_owned(), false).is_err());
+    let set = |key: &str, value: &str| (key.to_owned(), Some(value.to_owned()));
+    let rm = |key: &str| (key.to_owned(), None);
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            set("key1", "value1"),
+            set("key1", "longer value1"),
+            set("key2", "value2"),
+            set("key3", "value2"),
+            rm("key2"),
+            rm("key1"),
+        ]
+    );
+    assert_eq!(*count.lock().unwrap(), 6);
+
+    assert!(store.unsubscribe(second));
+    assert!(!store.unsubscribe(second));
+    store.clear()?;
+    assert_eq!(events.lock().unwrap().last(), Some(&rm("key3")));
+    assert_eq!(*count.lock().unwrap(), 6);
+
+    Ok(())
+}
+
+// `metadata` should report the version and times of a key.
+#[test]
+fn metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a record logged before the metadata fields existed
+    std::fs::write(
+        temp_dir.path().join("kvs.log"),
+        "{\"SetData\":{\"key\":\"old\",\"value\":\"value\"}}\n",
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    let old = store.metadata("old").expect("old key has metadata");
+    assert_eq!(old.version, 0);
+    assert_eq!(old.created_at, UNIX_EPOCH);
+    assert_eq!(store.get("old".to_owned())?, Some("value".to_owned()));
+
+    assert!(store.metadata("key1").is_none());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.metadata("key1").expect("key1 has metadata");
+    assert_eq!(first.version, 1);
+    assert_eq!(first.created_at, first.updated_at);
+    thread::sleep(Duration::from_millis(5));
+    store.set("key1".to_owned(), "a longer value1".to_owned())?;
+    store.set_batch(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key1".to_owned(), "value1".to_owned()),
+    ])?;
+    let last = store.metadata("key1").expect("key1 has metadata");
+    assert_eq!(last.version, 4);
+    assert_eq!(last.created_at, first.created_at);
+    assert!(last.updated_at > first.updated_at);
+    store.set("old".to_owned(), "new value".to_owned())?;
+    assert_eq!(store.metadata("old").map(|m| m.version), Some(1));
+
+    store.remove("old".to_owned())?;
+    assert!(store.metadata("old").is_none());
+    store.set("old".to_owned(), "value".to_owned())?;
+    assert_eq!(store.metadata("old").map(|m| m.version), Some(1));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metadata("key1"), Some(last));
+
+    Ok(())
+}
+
+// `history` should return the records of a key still in the log.
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.history("key1")?.is_empty());
+    // a snapshot keeps set from overwriting records in place
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    drop(snapshot);
+
+    let history = store.history("key1")?;
+    let values: Vec<_> = history.iter().map(|e| e.value.as_deref()).collect();
+    assert_eq!(
+        values,
+        vec![Some("value1"), Some("value3"), None, Some("value4")]
+    );
+    let versions: Vec<_> = history.iter().map(|e| e.version).collect();
+    assert_eq!(versions, vec![1, 2, 0, 1]);
+    let seqs: Vec<_> = history.iter().map(|e| e.seq).collect();
+    assert_eq!(seqs, vec![1, 3, 4, 5]);
+    // the first record follows the 16 bytes of the log header
+    assert_eq!(history[0].offset, 16);
+    assert!(history.windows(2).all(|w| w[0].offset < w[1].offset));
+    let key2 = store.history("key2")?;
+    assert_eq!(key2.len(), 1);
+    assert!(key2[0].offset > history[0].offset && key2[0].offset < history[1].offset);
+    assert_eq!(
+        key2[0],
+        HistoryEntry {
+            offset: key2[0].offset,
+            value: Some("value2".to_owned()),
+            version: 1,
+            seq: 2,
+        }
+    );
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key1")?, history);
+
+    Ok(())
+}
+
+// `open_at` should show the store as it was at a sequence number, read-only.
+#[test]
+fn open_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?; // 1
+    store.set("key2".to_owned(), "value2".to_owned())?; // 2
+    store.remove("key1".to_owned())?; // 3
+    store.set_batch(vec![
+        ("key2".to_owned(), "bad value2".to_owned()),
+        ("key3".to_owned(), "bad value3".to_owned()),
+    ])?; // 4, 5
+    drop(store);
+
+    let mut before = KvStore::open_at(temp_dir.path(), 3)?;
+    assert_eq!(before.get("key1".to_owned())?, None);
+    assert_eq!(before.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(before.get("key3".to_owned())?, None);
+    let start = KvStore::open_at(temp_dir.path(), 1)?;
+    assert_eq!(start.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(start.len(), 1);
+    assert!(KvStore::open_at(temp_dir.path(), 0)?.is_empty());
+
+    let log_len = std::fs::metadata(temp_dir.path().join("kvs.log"))?.len();
+    let read_only = |result: Result<()>| match result {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::ReadOnly) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("write to a read-only KvStore succeeded"),
+    };
+    read_only(
+        before
+            .set("key4".to_owned(), "value4".to_owned())
+            .map(|_| ()),
+    );
+    read_only(before.remove("key2".to_owned()).map(|_| ()));
+    read_only(before.clear());
+    read_only(before.set_batch(vec![("key4".to_owned(), "value4".to_owned())]));
+    assert_eq!(
+        std::fs::metadata(temp_dir.path().join("kvs.log"))?.len(),
+        log_len
+    );
+    assert!(!before.contains_key("key4"));
+    assert!(before.contains_key("key2"));
+    drop(before);
+    drop(start);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("bad value3".to_owned()));
+
+    Ok(())
+}
+
+// `set` should write nothing when the value is unchanged, if told to.
+#[test]
+fn skip_unchanged_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .skip_unchanged_writes(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = std::fs::metadata(&log)?.len();
+    assert!(!store.set("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(std::fs::metadata(&log)?.len(), len);
+    assert_eq!(store.metadata("key1").map(|m| m.version), Some(1));
+
+    // an expiration is cleared by the same value
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.ttl("key2"), None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // the record may be overwritten in place, the version tells it was logged
+    assert_eq!(store.metadata("key1").map(|m| m.version), Some(2));
+    assert_eq!(store.history("key1")?.last().map(|e| e.version), Some(2));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `get_or_insert_with` should only compute the value of a missing key.
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let value = store.get_or_insert_with("key1".to_owned(), || panic!("key1 exists"))?;
+    assert_eq!(value, "value1");
+    let mut calls = 0;
+    let value = store.get_or_insert_with("key2".to_owned(), || {
+        calls += 1;
+        "value2".to_owned()
+    })?;
+    assert_eq!(value, "value2");
+    let value = store.get_or_insert_with("key2".to_owned(), || {
+        calls += 1;
+        "other".to_owned()
+    })?;
+    assert_eq!(value, "value2");
+    assert_eq!(calls, 1);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `extend` and `from_iter_at` should load many pairs in batches.
+#[test]
+fn extend() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs = (0..1_000_000).map(|i| (format!("key{}", i), i.to_string()));
+    let mut store = KvStore::from_iter_at(temp_dir.path(), pairs)?;
+    assert_eq!(store.len(), 1_000_000);
+    store.extend(vec![
+        ("key0".to_owned(), "overwritten".to_owned()),
+        ("extra".to_owned(), "value".to_owned()),
+    ]);
+    assert_eq!(store.len(), 1_000_001);
+    assert_eq!(store.try_extend(Vec::new())?, 0);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1_000_001);
+    assert_eq!(
+        store.get("key0".to_owned())?,
+        Some("overwritten".to_owned())
+    );
+    assert_eq!(
+        store.get("key999999".to_owned())?,
+        Some("999999".to_owned())
+    );
+
+    Ok(())
+}
+
+// `random_key` and `sample_keys` should pick distinct live keys.
+#[test]
+fn sample_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.random_key(), None);
+    assert!(store.sample_keys(3).is_empty());
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.set_with_ttl(
+        "expired".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(5));
+
+    let mut seen = HashSet::new();
+    for _ in 0..1000 {
+        let key = store.random_key().expect("store is not empty").clone();
+        assert!(key.starts_with("key"));
+        seen.insert(key);
+    }
+    assert_eq!(seen.len(), 10);
+
+    let sample = store.sample_keys(4);
+    assert_eq!(sample.len(), 4);
+    assert_eq!(sample.iter().collect::<HashSet<_>>().len(), 4);
+    let all: HashSet<_> = store.sample_keys(20).into_iter().cloned().collect();
+    assert_eq!(all.len(), 10);
+    assert!(!all.contains("expired"));
+
+    Ok(())
+}
+
+// `open_read_only` should read the log without ever writing it.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    assert!(!log.exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    let len = std::fs::metadata(&log)?.len();
+
+    for result in [
+        reader
+            .set("key1".to_owned(), "value1".to_owned())
+            .map(|_| ()),
+        reader
+            .set("key3".to_owned(), "value3".to_owned())
+            .map(|_| ()),
+        reader.remove("key1".to_owned()).map(|_| ()),
+        reader.remove("missing".to_owned()).map(|_| ()),
+        reader.clear(),
+    ] {
+        match result {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::ReadOnly) => {}
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("write to a read-only KvStore succeeded"),
+        }
+    }
+    assert_eq!(std::fs::metadata(&log)?.len(), len);
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Open from disk again and check persistent data.
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `KvStoreOptions` should change how the KvStore is opened and written.
+#[test]
+fn store_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .log_file_name("data.log")
+        .sync_on_write(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // a value of another length rebuilds the log
+    store.set("key1".to_owned(), "a longer value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let names: HashSet<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|e| e.file_name().into_string().unwrap_or_default()))
+        .collect::<std::io::Result<_>>()?;
+    assert_eq!(
+        names,
+        vec![
+            "data.lock".to_owned(),
+            "data.log".to_owned(),
+            "engine".to_owned()
+        ]
+        .into_iter()
+        .collect()
+    );
+    drop(store);
+
+    let mut reader = KvStoreOptions::new()
+        .log_file_name("data.log")
+        .read_only(true)
+        .ordered(true)
+        .open(temp_dir.path())?;
+    assert_eq!(
+        reader.range("key0".to_owned()..)?,
+        vec![
+            ("key1".to_owned(), "a longer value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert!(reader.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert!(KvStoreOptions::new()
+        .log_file_name("other.log")
+        .read_only(true)
+        .open(temp_dir.path())
+        .is_err());
+    drop(reader);
+
+    // Open from disk again and check persistent data.
+    let store = KvStoreOptions::new()
+        .log_file_name("data.log")
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("a longer value1".to_owned())
+    );
+    assert!(KvStore::open(temp_dir.path())?.is_empty());
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Shape {
+    Circle { radius: f64 },
+    Square(u32),
+    Empty,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Drawing {
+    name: String,
+    shapes: Vec<Shape>,
+    parent: Option<Box<Drawing>>,
+}
+
+// `set_json` and `get_json` should round-trip serde types.
+#[test]
+fn set_get_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let drawing = Drawing {
+        name: "child".to_owned(),
+        shapes: vec![
+            Shape::Circle { radius: 1.5 },
+            Shape::Square(3),
+            Shape::Empty,
+        ],
+        parent: Some(Box::new(Drawing {
+            name: "parent".to_owned(),
+            shapes: Vec::new(),
+            parent: None,
+        })),
+    };
+    store.set_json("drawing".to_owned(), &drawing)?;
+    store.set_json("shape".to_owned(), &Shape::Square(7))?;
+    assert_eq!(store.get_json::<Drawing>("missing")?, None);
+    assert_eq!(store.get_json::<Shape>("shape")?, Some(Shape::Square(7)));
+
+    store.set("plain".to_owned(), "not json".to_owned())?;
+    for result in [
+        store.get_json::<Shape>("plain").map(|_| ()),
+        store.get_json::<Shape>("drawing").map(|_| ()),
+    ] {
+        match result {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::InvalidJsonValue { key, .. }) => {
+                    assert!(key == "plain" || key == "drawing")
+                }
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("invalid JSON got deserialized"),
+        }
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_json::<Drawing>("drawing")?, Some(drawing));
+
+    Ok(())
+}
+
+// `set_bytes` and `get_bytes` should keep arbitrary bytes intact.
+#[test]
+fn set_get_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let binary = vec![0xff, b'\n', 0, b'a', 0xc3, b'\n'];
+    let text = b"line1\nline2\0end".to_vec();
+    store.set_bytes("binary".to_owned(), binary.clone())?;
+    store.set_bytes("text".to_owned(), text.clone())?;
+    store.set_bytes("empty".to_owned(), Vec::new())?;
+    store.set("string".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.get_bytes("binary")?, Some(binary.clone()));
+    assert_eq!(store.get_bytes("text")?, Some(text.clone()));
+    assert_eq!(store.get_bytes("empty")?, Some(Vec::new()));
+    assert_eq!(store.get_bytes("string")?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("missing")?, None);
+    assert_eq!(
+        store.get("text".to_owned())?,
+        Some("line1\nline2\0end".to_owned())
+    );
+    match store.get("binary".to_owned()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::BinaryValue) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("binary value read as a string"),
+    }
+    store.rename("binary".to_owned(), "moved".to_owned())?;
+    assert_eq!(store.get_bytes("moved")?, Some(binary.clone()));
+
+    let mut dump = Vec::new();
+    store.dump(&mut dump)?;
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored = KvStore::restore(restore_dir.path(), &dump[..])?;
+    assert_eq!(restored.get_bytes("moved")?, Some(binary.clone()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("moved")?, Some(binary));
+    assert_eq!(store.get_bytes("text")?, Some(text));
+    assert_eq!(store.len(), 4);
+
+    Ok(())
+}
+
+// `set_raw`, `get_raw` and `remove_raw` should keep keys which are not UTF-8
+// intact, across a reopen too.
+#[test]
+fn set_get_remove_raw() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let raw_key = vec![0xff, 0, b'\n'];
+    let other_key = vec![0xfe, b'k'];
+    store.set_raw(raw_key.clone(), b"raw value".to_vec())?;
+    store.set_raw(other_key.clone(), vec![0xc3])?;
+    store.set_raw(b"text".to_vec(), b"text value".to_vec())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.get_raw(&raw_key)?, Some(b"raw value".to_vec()));
+    assert_eq!(store.get_raw(&other_key)?, Some(vec![0xc3]));
+    assert_eq!(store.get_raw(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get_raw(&[0xff])?, None);
+    assert_eq!(store.get("text".to_owned())?, Some("text value".to_owned()));
+    assert_eq!(store.len(), 4);
+    let keys: HashSet<_> = store.keys().cloned().collect();
+    let expected: HashSet<_> = ["text".to_owned(), "key1".to_owned()].into();
+    assert_eq!(keys, expected);
+
+    assert_eq!(store.remove_raw(&other_key)?, vec![0xc3]);
+    assert_eq!(store.get_raw(&other_key)?, None);
+    match store.remove_raw(&other_key) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("removed a missing key"),
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_raw(&raw_key)?, Some(b"raw value".to_vec()));
+    assert_eq!(store.get_raw(&other_key)?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    Ok(())
+}
+
+// Writes over the size limits of the options should fail with the offending
+// size and write nothing.
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .max_key_size(4)
+        .max_value_size(8)
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "12345678".to_owned())?;
+
+    let too_large = |result: Result<()>| match result {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyTooLarge { size, limit: 4 }) => ("key", *size),
+            Some(KvsError::ValueTooLarge { size, limit: 8 }) => ("value", *size),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("write over the limits succeeded"),
+    };
+    let result = store.set("key12".to_owned(), "value".to_owned());
+    assert_eq!(too_large(result.map(|_| ())), ("key", 5));
+    let result = store.set("key".to_owned(), "123456789".to_owned());
+    assert_eq!(too_large(result.map(|_| ())), ("value", 9));
+    let result = store.set_raw(b"key".to_vec(), vec![0xff; 10]);
+    assert_eq!(too_large(result), ("value", 10));
+    let result = store.set_with_ttl("key2".to_owned(), "x".repeat(9), Duration::from_secs(60));
+    assert_eq!(too_large(result), ("value", 9));
+    let result = store.rename("key".to_owned(), "long key".to_owned());
+    assert_eq!(too_large(result), ("key", 8));
+    let result = store.set_batch(vec![
+        ("k1".to_owned(), "v1".to_owned()),
+        ("k2".to_owned(), "value too long".to_owned()),
+    ]);
+    assert_eq!(too_large(result), ("value", 14));
+
+    let import = temp_dir.path().join("import.json");
+    std::fs::write(&import, "{\"k3\": \"v3\", \"long key\": \"v4\"}")?;
+    let result = store.import_json(&import, true);
+    assert_eq!(too_large(result.map(|_| ())), ("key", 8));
+
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key".to_owned())?, Some("12345678".to_owned()));
+
+    Ok(())
+}
+
+// `get_reader` should stream the decoded value, whatever the read sizes.
+#[test]
+fn get_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let text = "quote \" backslash \\ newline \n tab \t \u{1} caf\u{e9} \u{1f600} ".repeat(20_000);
+    let binary: Vec<u8> = (0..=255u8).cycle().take(100_000).collect();
+    store.set("text \"key\"".to_owned(), text.clone())?;
+    store.set_bytes("binary".to_owned(), binary.clone())?;
+    store.set("empty".to_owned(), String::new())?;
+
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("text \"key\"")?.expect("missing value");
+    let mut chunk = [0; 7];
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&chunk[..n]);
+    }
+    drop(reader);
+    assert_eq!(String::from_utf8(read)?, text);
+
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("binary")?.expect("missing value");
+    reader.read_to_end(&mut read)?;
+    drop(reader);
+    assert_eq!(read, binary);
+
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("empty")?.expect("missing value");
+    reader.read_to_end(&mut read)?;
+    drop(reader);
+    assert!(read.is_empty());
+    assert!(store.get_reader("missing")?.is_none());
+
+    Ok(())
+}
+
+// `set_from_reader` should stream the value into the log, and leave nothing
+// behind when the reader does not yield the announced length.
+#[test]
+fn set_from_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let text = "line \"quoted\"\n".repeat(10_000);
+    let binary: Vec<u8> = (0..=255u8).cycle().take(70_000).collect();
+    store.set("key1".to_owned(), "old".to_owned())?;
+    store.set_from_reader("key1".to_owned(), text.len() as u64, text.as_bytes())?;
+    store.set_from_reader("binary".to_owned(), binary.len() as u64, &binary[..])?;
+    assert_eq!(store.get("key1".to_owned())?, Some(text.clone()));
+    assert_eq!(store.get_bytes("binary")?, Some(binary.clone()));
+    let mut read = Vec::new();
+    store
+        .get_reader("key1")?
+        .expect("missing value")
+        .read_to_end(&mut read)?;
+    assert_eq!(read, text.as_bytes());
+
+    let log_len = std::fs::metadata(temp_dir.path().join("kvs.log"))?.len();
+    for (len, read) in [(11, 10), (9, 10)] {
+        match store.set_from_reader("key1".to_owned(), len, &[b'x'; 10][..]) {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::ValueLengthMismatch { expected, read: r }) => {
+                    assert_eq!((*expected, *r), (len, read.min(len + 1)));
+                }
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("set a value of the wrong length"),
+        }
+        let new_len = std::fs::metadata(temp_dir.path().join("kvs.log"))?.len();
+        assert_eq!(new_len, log_len);
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some(text.clone()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(text));
+    assert_eq!(store.get_bytes("binary")?, Some(binary));
+    assert_eq!(store.metadata("key1").map(|meta| meta.version), Some(2));
+
+    Ok(())
+}
+
+// `kvs set <KEY> --from-file <PATH>` should set the content of the file.
+#[test]
+fn cli_set_from_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("value.txt");
+    std::fs::write(&path, "value from file")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "--from-file"])
+        .arg(&path)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value from file").trim());
+
+    Ok(())
+}
+
+// `checkpoint` should write only the live records to a directory which opens
+// as a copy of the store.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("key99".to_owned(), "a longer value99".to_owned())?;
+    store.set_with_ttl(
+        "gone".to_owned(),
+        "soon".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    let dest = backup_dir.path().join("nightly");
+    let info = store.checkpoint(&dest)?;
+    let bytes = std::fs::metadata(dest.join("kvs.log"))?.len();
+    assert_eq!(info, CheckpointInfo { entries: 50, bytes });
+    assert!(bytes < std::fs::metadata(temp_dir.path().join("kvs.log"))?.len());
+    assert!(store.checkpoint(&dest).is_err());
+
+    // a snapshot is checkpointed while the store keeps writing
+    let snapshot = store.snapshot()?;
+    store.set("key50".to_owned(), "changed".to_owned())?;
+    let info = snapshot.checkpoint(backup_dir.path().join("snapshot"))?;
+    assert_eq!(info.entries, 50);
+
+    let copy = KvStore::open(&dest)?;
+    assert_eq!(copy.len(), 50);
+    assert_eq!(copy.get("key10".to_owned())?, None);
+    assert_eq!(copy.get("key50".to_owned())?, Some("value50".to_owned()));
+    assert_eq!(
+        copy.get("key99".to_owned())?,
+        Some("a longer value99".to_owned())
+    );
+    assert_eq!(copy.metadata("key99").map(|meta| meta.version), Some(2));
+    let copy = KvStore::open(backup_dir.path().join("snapshot"))?;
+    assert_eq!(copy.get("key50".to_owned())?, Some("value50".to_owned()));
+    assert_eq!(store.get("key50".to_owned())?, Some("changed".to_owned()));
+
+    Ok(())
+}
+
+// `restore_from` should rebuild a store from a checkpoint, and only replace
+// an existing log when forced.
+#[test]
+fn restore_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+
+    // the leftover of an interrupted restore is not data
+    std::fs::write(target_dir.path().join("kvs.log.restore"), "garbage")?;
+    let mut restored = KvStore::restore_from(backup_dir.path(), target_dir.path(), false)?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    restored.set("key3".to_owned(), "value3".to_owned())?;
+    drop(restored);
+
+    match KvStore::restore_from(backup_dir.path(), target_dir.path(), false) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::LogExists { .. }) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("restored over an existing log"),
+    }
+    let restored = KvStore::open(target_dir.path())?;
+    assert_eq!(restored.len(), 3);
+    drop(restored);
+
+    let restored = KvStore::restore_from(backup_dir.path(), target_dir.path(), true)?;
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// `restore_from` should refuse a target a KvStore has open, forced or not,
+// and a forced one should leave none of the segments of the old log.
+#[test]
+fn restore_from_live_target() -> Result<()> {
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut backup = KvStore::open(backup_dir.path())?;
+    backup.set("key1".to_owned(), "value1".to_owned())?;
+    backup.close()?;
+    let mut store = KvStoreOptions::new()
+        .max_segment_size(Some(128))
+        .open(target_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("old{}", key_id), "x".repeat(20))?;
+    }
+    store.write_hint()?;
+
+    for force in [false, true].iter() {
+        match KvStore::restore_from(backup_dir.path(), target_dir.path(), *force) {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::AlreadyLocked { .. }) => {}
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("restored under an open KvStore"),
+        }
+    }
+    assert_eq!(store.get("old19".to_owned())?, Some("x".repeat(20)));
+    assert_eq!(store.len(), 20);
+    drop(store);
+
+    let restored = KvStore::restore_from(backup_dir.path(), target_dir.path(), true)?;
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored.get("old0".to_owned())?, None);
+    drop(restored);
+    let names: Vec<_> = std::fs::read_dir(target_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<std::io::Result<_>>()?;
+    assert!(
+        !names.iter().any(|name| name.starts_with("kvs-")),
+        "segments left: {:?}",
+        names
+    );
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(target_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Buckets should keep their keys apart from each other and from the store,
+// whatever characters the keys hold.
+#[test]
+fn buckets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "store".to_owned())?;
+    store
+        .bucket("sessions")
+        .set("key1".to_owned(), "sessions".to_owned())?;
+    store
+        .bucket("sess")
+        .set("ions\u{0}key1".to_owned(), "sess".to_owned())?;
+    for key_id in 0..10 {
+        let mut bucket = store.bucket("users");
+        bucket.set(format!("user{}", key_id), format!("value{}", key_id))?;
+    }
+
+    assert_eq!(store.get("key1".to_owned())?, Some("store".to_owned()));
+    let sessions = store.bucket("sessions");
+    assert_eq!(sessions.get("key1")?, Some("sessions".to_owned()));
+    assert_eq!(sessions.get("ions\u{0}key1")?, None);
+    assert_eq!(sessions.keys(), vec!["key1".to_owned()]);
+    assert_eq!(
+        store.bucket("sess").get("ions\u{0}key1")?,
+        Some("sess".to_owned())
+    );
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1"]);
+    assert_eq!(store.list_buckets(), vec!["sess", "sessions", "users"]);
+
+    assert_eq!(store.bucket("sess").remove("ions\u{0}key1")?, "sess");
+    assert!(!store.bucket("sess").contains_key("ions\u{0}key1"));
+    assert_eq!(store.drop_bucket("users")?, 10);
+    assert_eq!(store.drop_bucket("users")?, 0);
+    assert_eq!(store.list_buckets(), vec!["sessions"]);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.list_buckets(), vec!["sessions"]);
+    assert_eq!(
+        store.bucket("sessions").get("key1")?,
+        Some("sessions".to_owned())
+    );
+    assert_eq!(store.bucket("users").get("user1")?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("store".to_owned()));
+
+    Ok(())
+}
+
+// A transaction should be applied on commit only, and a log cut inside its
+// block should replay none of it.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut txn = store.transaction();
+    txn.set("key1".to_owned(), "new1".to_owned());
+    assert!(txn.remove("key2".to_owned()));
+    assert!(!txn.remove("missing".to_owned()));
+    txn.set("key3".to_owned(), "value3".to_owned());
+    assert_eq!(txn.get("key1")?, Some("new1".to_owned()));
+    assert_eq!(txn.get("key2")?, None);
+    assert!(!txn.contains_key("key2"));
+    txn.rollback();
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut txn = store.transaction();
+    txn.set("key1".to_owned(), "new1".to_owned());
+    txn.remove("key2".to_owned());
+    txn.set("key3".to_owned(), "value3".to_owned());
+    drop(txn);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut txn = store.transaction();
+    txn.set("key1".to_owned(), "new1".to_owned());
+    txn.remove("key2".to_owned());
+    txn.set("key3".to_owned(), "value3".to_owned());
+    txn.commit()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    let mut txn = store.transaction();
+    txn.set("key4".to_owned(), "value4".to_owned());
+    txn.set("key5".to_owned(), "value5".to_owned());
+    txn.commit()?;
+    drop(store);
+
+    // cut the last record of the transaction, as a crash would
+    let log = temp_dir.path().join("kvs.log");
+    let content = std::fs::read(&log)?;
+    let last_line = content[..content.len() - 1]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .expect("log of a single line");
+    std::fs::write(&log, &content[..last_line + 1])?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, None);
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key6".to_owned())?, Some("value6".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    Ok(())
+}
+
+// A committed transaction compacts the log once the records it leaves behind
+// pass the compaction threshold, like `set` and `remove` do.
+#[test]
+fn transaction_compacts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(Some(4096))
+        .open(temp_dir.path())?;
+    for round in 0..100 {
+        let mut txn = store.transaction();
+        txn.set("key1".to_owned(), format!("{}{}", round, "x".repeat(100)));
+        txn.set("key2".to_owned(), format!("{}", round));
+        txn.commit()?;
+    }
+    assert!(store.stats().compactions > 0);
+    assert!(store.stats().dead_bytes <= 4096);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some(format!("99{}", "x".repeat(100)))
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// `set_if_version` should only write over the expected version, and report
+// the current one otherwise.
+#[test]
+fn set_if_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "value1".to_owned(), 0)?,
+        SetIfResult::Written { version: 1 }
+    );
+    assert_eq!(store.get_versioned("key1")?, Some(("value1".to_owned(), 1)));
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "value2".to_owned(), 0)?,
+        SetIfResult::Conflict {
+            version: 1,
+            value: Some("value1".to_owned()),
+        }
+    );
+    // an unchanged value still moves the version
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "value1".to_owned(), 1)?,
+        SetIfResult::Written { version: 2 }
+    );
+    assert_eq!(
+        store.set_if_version("key2".to_owned(), "value2".to_owned(), 3)?,
+        SetIfResult::Conflict {
+            version: 0,
+            value: None,
+        }
+    );
+    assert_eq!(store.get_versioned("key2")?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1")?, Some(("value1".to_owned(), 2)));
+
+    Ok(())
+}
+
+// `verify` should find nothing wrong after regular writes, and report the
+// records changed behind the store's back.
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.set("key1".to_owned(), "VALUE1".to_owned())?;
+    store.set("key2".to_owned(), "a longer value2".to_owned())?;
+    let report = store.verify()?;
+    assert!(report.is_ok());
+    assert_eq!(report.records, 7);
+
+    store.clear()?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let log = temp_dir.path().join("kvs.log");
+    let mut content = std::fs::read(&log)?;
+    // the first record follows the 16 bytes of the log header and the
+    // record of the sequence number of the clear
+    let first = content.iter().position(|b| *b == b'\n').unwrap() + 1;
+    let second = first + content[first..].iter().position(|b| *b == b'\n').unwrap() + 1;
+    let third = second + content[second..].iter().position(|b| *b == b'\n').unwrap() + 1;
+    content[first] = b'x';
+    let key = second
+        + content[second..]
+            .windows(4)
+            .position(|w| w == b"key1")
+            .unwrap();
+    content[key + 2] = b'z';
+    content.truncate(third);
+    std::fs::write(&log, &content)?;
+
+    // The changed key no longer matches the checksum of its record.
+    assert_eq!(
+        store.verify()?,
+        VerifyReport {
+            records: 1,
+            unparseable: vec![first as u64, second as u64],
+            mismatched: vec![
+                ("key0".to_owned(), first as u64),
+                ("key1".to_owned(), second as u64)
+            ],
+            orphaned: vec![("key2".to_owned(), third as u64)],
+        }
+    );
+
+    Ok(())
+}
+
+// Corrupt records are refused, skipped or truncated at as the recovery mode
+// says, and the repaired log opens cleanly.
+#[test]
+fn open_with_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let mut content = std::fs::read(&log)?;
+    let second = content.iter().position(|b| *b == b'\n').unwrap() + 1;
+    let third = second + content[second..].iter().position(|b| *b == b'\n').unwrap() + 1;
+    content.splice(second..third - 1, vec![0xff, b'{', b'x']);
+    std::fs::write(&log, &content)?;
+    let corrupt = content.clone();
+
+    assert!(KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict).is_err());
+
+    let (store, report) = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::SkipCorrupt)?;
+    assert_eq!(
+        report,
+        RecoveryReport {
+            dropped_records: 1,
+            dropped_bytes: 4,
+            truncated_bytes: 0,
+        }
+    );
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    std::fs::write(&log, &corrupt)?;
+    let (mut store, report) =
+        KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::TruncateAtError)?;
+    assert_eq!(
+        report,
+        RecoveryReport {
+            dropped_records: 2,
+            dropped_bytes: (corrupt.len() - second) as u64,
+            truncated_bytes: 0,
+        }
+    );
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Stats follow the live keys and the garbage of the log.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // the header of the log is neither live nor dead
+    let header = std::fs::metadata(&log)?.len();
+    assert_eq!(
+        store.stats(),
+        StoreStats {
+            log_bytes: header,
+            ..StoreStats::default()
+        }
+    );
+
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let log_bytes = std::fs::metadata(&log)?.len();
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 3);
+    assert_eq!(stats.log_bytes, log_bytes);
+    assert_eq!(stats.live_bytes, log_bytes - header);
+    assert_eq!(stats.dead_bytes, 0);
+
+    store.remove("key0".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.log_bytes, std::fs::metadata(&log)?.len());
+    assert_eq!(
+        stats.live_bytes + stats.dead_bytes,
+        stats.log_bytes - header
+    );
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(stats.compactions, 0);
+
+    // An overwritten value is garbage until the log is compacted.
+    let dead_bytes = stats.dead_bytes;
+    store.set("key1".to_owned(), "a longer value1".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 2);
+    assert!(stats.dead_bytes > dead_bytes);
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.compactions, 1);
+
+    Ok(())
+}
+
+// Compaction keeps one record per live key and reports what it dropped.
+#[test]
+fn compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let header = std::fs::metadata(&log)?.len();
+    assert_eq!(
+        store.compact()?,
+        CompactionStats {
+            bytes_before: header,
+            bytes_after: header,
+            bytes_reclaimed: 0,
+        }
+    );
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let bytes_before = std::fs::metadata(&log)?.len();
+    let stats = store.compact()?;
+    assert_eq!(stats.bytes_before, bytes_before);
+    assert_eq!(stats.bytes_after, std::fs::metadata(&log)?.len());
+    assert_eq!(
+        stats.bytes_reclaimed,
+        stats.bytes_before - stats.bytes_after
+    );
+    assert!(stats.bytes_reclaimed > 0);
+    // the removal of key49 is dropped but not its sequence number, logged
+    // in a record of a 9 byte frame and a u64
+    assert_eq!(store.stats().dead_bytes, 9 + 8);
+    assert_eq!(store.compact()?.bytes_reclaimed, 0);
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    // the record of key0 holds the last sequence number now
+    assert_eq!(store.compact()?.bytes_reclaimed, 9 + 8);
+    assert_eq!(store.len(), 51);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for key_id in 50..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// The log is compacted once removals leave more garbage than the threshold.
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(Some(4096))
+        .open(temp_dir.path())?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+    let mut largest = 0;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+        for key_id in 0..10 {
+            store.remove(format!("key{}", key_id))?;
+        }
+        largest = largest.max(std::fs::metadata(&log)?.len());
+    }
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert!(store.stats().compactions > 0);
+    assert!(largest < 2 * 4096);
+    assert!(store.stats().dead_bytes <= 4096);
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    drop(store);
+
+    // Never compacted without a threshold.
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert_eq!(store.stats().compactions, 0);
+    assert!(std::fs::metadata(&log)?.len() > 4096);
+
+    Ok(())
+}
+
+// The index gives memory back after most keys are removed.
+#[test]
+fn shrink_to_fit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let empty = store.approximate_memory_usage();
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let full = store.approximate_memory_usage();
+    assert!(full > empty + 10000 * "key0".len());
+
+    assert_eq!(store.retain(|key| key.len() < 5)?, 9990);
+    let shrunk = store.approximate_memory_usage();
+    assert!(shrunk < full / 10);
+    store.shrink_to_fit();
+    assert!(store.approximate_memory_usage() <= shrunk);
+    assert_eq!(store.len(), 10);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+// Reads take `&self`, so threads can share a KvStore behind an Arc.
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let store = Arc::new(store);
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for round in 0..10 {
+                    for key_id in 0..100 {
+                        let key_id = (key_id + thread_id * 13 + round) % 100;
+                        assert_eq!(
+                            store.get(format!("key{}", key_id))?,
+                            Some(format!("value{}", key_id))
+                        );
+                    }
+                    assert!(store.contains_key("key0"));
+                    assert_eq!(store.iter().count(), 100);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// Overwrites append their record whatever its length, the old ones become
+// garbage.
+#[test]
+fn overwrite_appends() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .open(temp_dir.path())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    let mut len = std::fs::metadata(&log)?.len();
+    for iter in 0..100 {
+        let value = "v".repeat(iter % 7 + 1);
+        store.set("key".to_owned(), value.clone())?;
+        let new_len = std::fs::metadata(&log)?.len();
+        assert!(new_len > len);
+        len = new_len;
+        assert_eq!(store.get("key".to_owned())?, Some(value));
+    }
+    assert_eq!(store.stats().compactions, 0);
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("v".repeat(99 % 7 + 1)));
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Sets, overwrites and removals interleaved on a few keys should replay to
+// the state they left.
+#[test]
+fn interleaved_writes_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .open(temp_dir.path())?;
+    let mut expected = HashMap::new();
+    for iter in 0..200 {
+        let key = format!("key{}", iter % 5);
+        match iter % 3 {
+            0 | 1 => {
+                let value = format!("{}{}", "v".repeat(iter % 11), iter);
+                store.set(key.clone(), value.clone())?;
+                expected.insert(key, value);
+            }
+            _ => {
+                if expected.remove(&key).is_some() {
+                    store.remove(key)?;
+                }
+            }
+        }
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.len(), expected.len());
+        for key_id in 0..5 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert!(store.verify()?.is_ok());
+
+    Ok(())
+}
+
+// Every record carries a checksum, checked by `open` and optionally by `get`.
+#[test]
+fn checksums() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_from_reader("key3".to_owned(), 6, "value3".as_bytes())?;
+    drop(store);
+    let content = std::fs::read_to_string(&log)?;
+    assert_eq!(
+        content
+            .lines()
+            .filter(|line| line.contains(",\"crc\":"))
+            .count(),
+        3
+    );
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key3")?, Some(b"value3".to_vec()));
+    drop(store);
+
+    // A flipped byte which still parses is caught by the checksum, once the
+    // hint of the clean close is gone.
+    std::fs::remove_file(temp_dir.path().join("kvs.log.hint"))?;
+    let second = content.find('\n').unwrap() + 1;
+    let flipped = content[..second].to_owned() + &content[second..].replacen("value2", "valuE2", 1);
+    std::fs::write(&log, &flipped)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { offset }) => assert_eq!(*offset, second as u64),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("corrupt log opened"),
+    }
+
+    // Checked on `get` too when asked, once the log is open.
+    std::fs::write(&log, &content)?;
+    let store = KvStoreOptions::new()
+        .verify_checksums(true)
+        .open(temp_dir.path())?;
+    std::fs::write(&log, &flipped)?;
+    match store.get("key2".to_owned()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { offset }) => assert_eq!(*offset, second as u64),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(value) => panic!("corrupt value read: {:?}", value),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // Records logged before checksums are still read.
+    let old = "{\"SetData\":{\"key\":\"old\",\"value\":\"value\"}}\n";
+    std::fs::write(&log, old)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("old".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// New logs start with a header, logs written before headers are still
+// opened and get one from the next compaction.
+#[test]
+fn log_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let content = std::fs::read(&log)?;
+    assert!(content.starts_with(b"KVS-LOG\0"));
+    assert_eq!(content[16], b'{');
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A format from a later version is refused.
+    let mut later = content.clone();
+    later[8] = 9;
+    std::fs::write(&log, &later)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::IncompatibleFormat { found, supported }) => {
+                assert_eq!((*found, *supported), (9, 2));
+            }
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("log of a later format opened"),
+    }
+
+    // A log without header is opened as it is.
+    let old = "{\"SetData\":{\"key\":\"old\",\"value\":\"value\"}}\n";
+    std::fs::write(&log, old)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.verify()?.is_ok());
+    assert!(std::fs::read_to_string(&log)?.starts_with(old));
+    store.compact()?;
+    drop(store);
+    assert!(std::fs::read(&log)?.starts_with(b"KVS-LOG\0"));
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("old".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// the offsets of the binary records of a log, after its header
+fn binary_offsets(content: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 16;
+    while offset < content.len() {
+        offsets.push(offset);
+        let mut len = [0; 4];
+        len.copy_from_slice(&content[offset + 1..offset + 5]);
+        offset += 9 + u32::from_le_bytes(len) as usize;
+    }
+    offsets
+}
+
+// New logs hold binary records, any bytes round trip and a JSON log keeps
+// its format.
+#[test]
+fn binary_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "line\nbreak \"quoted\"".to_owned())?;
+    store.set_bytes("key2".to_owned(), vec![0xff, 0, b'\n'])?;
+    store.set_raw(vec![0xfe, 1], b"raw".to_vec())?;
+    store.set_from_reader("key3".to_owned(), 6, "value3".as_bytes())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.remove("key4".to_owned())?;
+    let mut txn = store.transaction();
+    txn.set("key5".to_owned(), "value5".to_owned());
+    txn.set("key6".to_owned(), "value6".to_owned());
+    txn.commit()?;
+    let mut value = String::new();
+    store
+        .get_reader("key3")?
+        .expect("key3 not found")
+        .read_to_string(&mut value)?;
+    assert_eq!(value, "value3");
+    drop(store);
+    let content = std::fs::read(&log)?;
+    assert_eq!(content[8], 2);
+    assert_eq!(content[16], 1);
+
+    // Open from disk again and check persistent data.
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("line\nbreak \"quoted\"".to_owned())
+        );
+        assert_eq!(store.get_bytes("key2")?, Some(vec![0xff, 0, b'\n']));
+        assert_eq!(store.get_raw(&[0xfe, 1])?, Some(b"raw".to_vec()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        assert_eq!(store.get("key6".to_owned())?, Some("value6".to_owned()));
+        Ok(())
+    };
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert!(store.verify()?.is_ok());
+    assert_eq!(store.history("key4")?.len(), 2);
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    drop(store);
+
+    // A flipped byte is caught by the checksum of its record.
+    let offsets = binary_offsets(&content);
+    let mut flipped = content.clone();
+    flipped[offsets[1] - 1] ^= 1;
+    std::fs::write(&log, &flipped)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { offset }) => assert_eq!(*offset, offsets[0] as u64),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("corrupt log opened"),
+    }
+
+    // A transaction cut after a whole record is dropped.
+    std::fs::write(&log, &content[..offsets[offsets.len() - 1]])?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // A JSON log is still written as JSON.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_bytes("key2".to_owned(), vec![0xff])?;
+    store.compact()?;
+    drop(store);
+    let content = std::fs::read(&log)?;
+    assert_eq!(content[8], 1);
+    assert_eq!(content[16], b'{');
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_bytes("key2")?, Some(vec![0xff]));
+    assert!(store.verify()?.is_ok());
+
+    Ok(())
+}
+
+// `migrate` rewrites a JSON log as a compacted binary one and keeps the
+// JSON log aside, a leftover of an interrupted migration changes nothing.
+#[test]
+fn migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new1".to_owned())?;
+    store.set_bytes("key2".to_owned(), vec![0xff, b'\n'])?;
+    drop(store);
+    let json = std::fs::read(&log)?;
+
+    // A migration interrupted before its rename leaves its temporary file.
+    std::fs::write(temp_dir.path().join("kvs.log.migrate"), "partial")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+    drop(store);
+
+    let report = KvStore::migrate(temp_dir.path())?;
+    assert_eq!(
+        report,
+        MigrateReport {
+            records_read: 13,
+            records_written: 9,
+            bytes_before: json.len() as u64,
+            bytes_after: std::fs::metadata(&log)?.len(),
+        }
+    );
+    assert!(report.bytes_after < report.bytes_before);
+    assert_eq!(std::fs::read(temp_dir.path().join("kvs.log.v1.bak"))?, json);
+    assert!(!temp_dir.path().join("kvs.log.migrate").exists());
+    assert_eq!(std::fs::read(&log)?[8], 2);
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 9);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+    assert_eq!(store.get_bytes("key2")?, Some(vec![0xff, b'\n']));
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get_versioned("key1")?, Some(("new1".to_owned(), 2)));
+    assert!(store.verify()?.is_ok());
+    drop(store);
+
+    // A binary log is left alone.
+    let bytes = std::fs::metadata(&log)?.len();
+    assert_eq!(
+        KvStore::migrate(temp_dir.path())?,
+        MigrateReport {
+            bytes_before: bytes,
+            bytes_after: bytes,
+            ..MigrateReport::default()
+        }
+    );
+
+    Ok(())
+}
+
+// `kvs migrate` should migrate the log of the current directory.
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .log_format(LogFormat::Json)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 records"));
+    assert!(temp_dir.path().join("kvs.log.v1.bak").exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// the names of the segment files in dir, sorted
+fn segment_names(dir: &std::path::Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("kvs-") && name.ends_with(".log") {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// A log with a max segment size is split in segments, which are replayed in
+// order; compaction merges the sealed ones and deletes the others.
+#[test]
+fn segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStoreOptions::new();
+    options
+        .max_segment_size(Some(256))
+        .compaction_threshold(None);
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    store.remove("key19".to_owned())?;
+    assert!(!temp_dir.path().join("kvs.log").exists());
+    let names = segment_names(temp_dir.path())?;
+    assert!(names.len() > 2);
+    assert_eq!(names[0], "kvs-000001.log");
+    assert_eq!(names[1], "kvs-000002.log");
+    let log_bytes: u64 = names
+        .iter()
+        .map(|name| std::fs::metadata(temp_dir.path().join(name)).map(|m| m.len()))
+        .sum::<std::io::Result<u64>>()?;
+    assert_eq!(store.stats().log_bytes, log_bytes);
+    assert!(store.verify()?.is_ok());
+    let old_segment = std::fs::read(temp_dir.path().join(&names[0]))?;
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.len(), 19);
+    assert_eq!(store.get("key0".to_owned())?, Some("new0".to_owned()));
+    assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+    assert_eq!(store.get("key19".to_owned())?, None);
+
+    // Only the sealed segments are merged, into the last of them.
+    let stats = store.compact()?;
+    assert!(stats.bytes_after < stats.bytes_before);
+    let merged = segment_names(temp_dir.path())?;
+    assert_eq!(merged.len(), 2);
+    assert_eq!(merged[0], names[names.len() - 2]);
+    assert_eq!(merged[1], names[names.len() - 1]);
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+    assert!(store.verify()?.is_ok());
+    store.set("key20".to_owned(), "value20".to_owned())?;
+    drop(store);
+
+    // A segment a compaction did not get to delete is older than the merged
+    // one, which holds its live records: it is deleted.
+    std::fs::write(temp_dir.path().join(&names[0]), &old_segment)?;
+    let store = options.open(temp_dir.path())?;
+    assert!(!temp_dir.path().join(&names[0]).exists());
+    assert_eq!(store.len(), 20);
+    assert_eq!(store.get("key0".to_owned())?, Some("new0".to_owned()));
+    assert_eq!(store.get("key19".to_owned())?, None);
+    assert_eq!(store.get("key20".to_owned())?, Some("value20".to_owned()));
+    drop(store);
+
+    // Without the option an existing log keeps its segments.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 20);
+    assert!(!temp_dir.path().join("kvs.log").exists());
+    drop(store);
+    assert!(KvStore::migrate(temp_dir.path()).is_err());
+
+    // Sealed segments without a live record are deleted.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = options.open(temp_dir.path())?;
+    for value_id in 0..20 {
+        store.set("key".to_owned(), format!("value{}", value_id))?;
+    }
+    let names = segment_names(temp_dir.path())?;
+    assert!(names.len() > 1);
+    store.compact()?;
+    assert_eq!(segment_names(temp_dir.path())?, &names[names.len() - 1..]);
+    assert_eq!(store.get("key".to_owned())?, Some("value19".to_owned()));
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value19".to_owned()));
+
+    Ok(())
+}
+
+// `open` loads the hint file a compaction wrote and replays only the records
+// after it, a missing, corrupt or stale hint means a full replay.
+#[test]
+fn hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hint = temp_dir.path().join("kvs.log.hint");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    assert!(!hint.exists());
+    store.compact()?;
+    assert!(hint.exists());
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    thread::sleep(Duration::from_millis(100));
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 8);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+    store.set("key11".to_owned(), "value11".to_owned())?;
+    assert_eq!(store.get_versioned("key3")?, Some(("value3".to_owned(), 1)));
+    assert!(store.verify()?.is_ok());
+    drop(store);
+
+    // The records a hint covers are not replayed: a flipped byte in one of
+    // them goes unnoticed.
+    let log = temp_dir.path().join("kvs.log");
+    let mut content = std::fs::read(&log)?;
+    let offset = content
+        .windows(6)
+        .position(|window| window == b"value3")
+        .expect("value3 is in the log");
+    content[offset] = b'V';
+    std::fs::write(&log, &content)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("Value3".to_owned()));
+    drop(store);
+
+    // A hint which fails its checksum is ignored, the full replay finds the
+    // flipped byte.
+    let mut bytes = std::fs::read(&hint)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&hint, &bytes)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { .. }) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("the corrupt record was not found"),
+    }
+    content[offset] = b'v';
+    std::fs::write(&log, &content)?;
+
+    // A hint for another log is stale: the log replaced by a copy of itself
+    // has another inode.
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+    let copy = temp_dir.path().join("kvs.log.copy");
+    std::fs::copy(&log, &copy)?;
+    std::fs::rename(&copy, &log)?;
+    let mut content = std::fs::read(&log)?;
+    let offset = content
+        .windows(6)
+        .position(|window| window == b"value3")
+        .expect("value3 is in the log");
+    content[offset] = b'V';
+    std::fs::write(&log, &content)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    std::fs::remove_file(&log)?;
+
+    // A missing hint means a full replay, clear removes the hint.
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    assert!(hint.exists());
+    store.clear()?;
+    assert!(!hint.exists());
+    store.set("key".to_owned(), "other".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("other".to_owned()));
+
+    Ok(())
+}
+
+// `close` marks the log as closed cleanly, which makes `open` trust the hint;
+// a KvStore which was not closed has its log replayed whole.
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.close()?;
+    assert!(temp_dir.path().join("kvs.log.hint").exists());
+    assert_eq!(std::fs::read(&log)?[12] & 4, 4);
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::read(&log)?[12] & 4, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    // A KvStore which is never dropped leaves its log as after a crash.
+    std::mem::forget(store);
+    // a crash releases the lock too, a new lock file stands in for that
+    std::fs::remove_file(temp_dir.path().join("kvs.lock"))?;
+
+    // The flipped byte is found: the hint is not trusted.
+    let mut content = std::fs::read(&log)?;
+    let offset = content
+        .windows(6)
+        .position(|window| window == b"value1")
+        .expect("value1 is in the log");
+    content[offset] = b'V';
+    std::fs::write(&log, &content)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { .. }) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("the corrupt record was not found"),
+    }
+    content[offset] = b'v';
+    std::fs::write(&log, &content)?;
+
+    // Dropping closes the log too.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    assert_eq!(std::fs::read(&log)?[12] & 4, 4);
+
+    // A read-only KvStore leaves the log alone.
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.len(), 4);
+    store.close()?;
+    assert_eq!(std::fs::read(&log)?[12] & 4, 4);
+
+    Ok(())
+}
+
+// The sync policy decides which writes sync the log, `sync` always does.
+#[test]
+fn sync_policy() -> Result<()> {
+    let policies = [
+        (SyncPolicy::Always, 6),
+        (SyncPolicy::EveryN(2), 3),
+        (SyncPolicy::Interval(Duration::from_secs(3600)), 0),
+        (SyncPolicy::Interval(Duration::from_millis(0)), 6),
+        (SyncPolicy::Never, 0),
+    ];
+    for (policy, syncs) in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStoreOptions::new()
+            .sync_policy(*policy)
+            .open(temp_dir.path())?;
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        assert_eq!(store.stats().syncs, *syncs, "{:?}", policy);
+        store.sync()?;
+        assert_eq!(store.stats().syncs, syncs + 1, "{:?}", policy);
+    }
+
+    Ok(())
+}
+
+// `kvs --sync` should take a sync policy and refuse an unknown one.
+#[test]
+fn cli_sync() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for policy in ["always", "never", "every=10", "interval=100"].iter() {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["--sync", policy, "set", "key1", policy])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("interval=100").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--sync", "sometimes", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// a FileSync which counts the syncs of directories and fails the syncs of
+// files once told to
+#[derive(Debug, Default)]
+struct FailingSync {
+    fail: Arc<AtomicBool>,
+    dir_syncs: Arc<AtomicUsize>,
+}
+
+impl FileSync for FailingSync {
+    fn sync_all(&self, file: &std::fs::File) -> std::io::Result<()> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("injected fsync failure"));
+        }
+        file.sync_all()
+    }
+
+    fn sync_dir(&self, dir: &std::path::Path) -> std::io::Result<()> {
+        self.dir_syncs.fetch_add(1, Ordering::SeqCst);
+        std::fs::File::open(dir)?.sync_all()
+    }
+}
+
+// A compaction whose new log fails to sync leaves the log and the index as
+// they were, one which succeeds syncs the directory after the rename.
+#[test]
+fn compact_sync_failure() -> Result<()> {
+    for max_segment_size in [None, Some(128)].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let fail = Arc::new(AtomicBool::new(false));
+        let dir_syncs = Arc::new(AtomicUsize::new(0));
+        let mut options = KvStoreOptions::new();
+        options
+            .compaction_threshold(None)
+            .max_segment_size(*max_segment_size)
+            .file_sync(FailingSync {
+                fail: Arc::clone(&fail),
+                dir_syncs: Arc::clone(&dir_syncs),
+            });
+        let mut store = options.open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+        }
+        let files = |dir: &std::path::Path| -> Result<Vec<(String, Vec<u8>)>> {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                files.push((name, std::fs::read(entry.path())?));
+            }
+            files.sort();
+            Ok(files)
+        };
+        let before = files(temp_dir.path())?;
+
+        fail.store(true, Ordering::SeqCst);
+        assert!(store.compact().is_err());
+        assert_eq!(files(temp_dir.path())?, before);
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("new{}", key_id))
+            );
+        }
+        assert!(store.verify()?.is_ok());
+
+        fail.store(false, Ordering::SeqCst);
+        let synced = dir_syncs.load(Ordering::SeqCst);
+        let stats = store.compact()?;
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert!(dir_syncs.load(Ordering::SeqCst) > synced);
+        store.set("key10".to_owned(), "value10".to_owned())?;
+        drop(store);
+
+        // Open from disk again and check persistent data.
+        let store = options.open(temp_dir.path())?;
+        assert_eq!(store.len(), 11);
+        assert_eq!(store.get("key3".to_owned())?, Some("new3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Temporary files left behind by a crashed compaction are deleted on open,
+// other files are kept and never read as part of the log.
+#[test]
+fn swap_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let junk = ["kvs.log.swp", "kvs.log.99.3.swp", "kvs.log.1.0.swp"];
+    let kept = ["other.swp", "kvs.log.x.0.swp", "kvs.log.1.swp"];
+    for name in junk.iter().chain(kept.iter()) {
+        std::fs::write(temp_dir.path().join(name), b"{\"junk\": true}\n")?;
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    for name in junk.iter() {
+        assert!(!temp_dir.path().join(name).exists(), "{} was kept", name);
+    }
+    for name in kept.iter() {
+        assert!(temp_dir.path().join(name).exists(), "{} was deleted", name);
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Compaction leaves no temporary file behind.
+    store.compact()?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    let mut names: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<_>>()?;
+    names.sort();
+    let mut expected: Vec<_> = ["engine", "kvs.lock", "kvs.log", "kvs.log.hint"]
+        .iter()
+        .chain(kept.iter())
+        .map(|name| name.to_string())
+        .collect();
+    expected.sort();
+    assert_eq!(names, expected);
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+// A torn record at the end of the log is truncated off on open, in either
+// format, while one followed by more records is still corruption.
+#[test]
+fn torn_tail() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Binary].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log = temp_dir.path().join("kvs.log");
+        let mut store = KvStoreOptions::new()
+            .log_format(*format)
+            .open(temp_dir.path())?;
+        for key_id in 0..3 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        drop(store);
+        let valid = std::fs::read(&log)?;
+        let garbage = b"\x01{\"Set\":{\"key\":\"ke";
+        let mut torn = valid.clone();
+        torn.extend_from_slice(garbage);
+        std::fs::write(&log, &torn)?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.stats().truncated_bytes, garbage.len() as u64);
+        for key_id in 0..3 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        assert_eq!(std::fs::metadata(&log)?.len(), valid.len() as u64);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        // Open from disk again and check persistent data.
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.stats().truncated_bytes, 0);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.len(), 4);
+        drop(store);
+
+        // The recovery modes report the truncated bytes.
+        std::fs::write(&log, &torn)?;
+        let (_, report) = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict)?;
+        assert_eq!(
+            report,
+            RecoveryReport {
+                truncated_bytes: garbage.len() as u64,
+                ..RecoveryReport::default()
+            }
+        );
+
+        // A corrupt record before the last one is not a torn tail: the JSON
+        // of the first record, or its payload after the binary frame.
+        let mut corrupt = valid.clone();
+        let first = if *format == LogFormat::Json {
+            16
+        } else {
+            16 + 9 + 1
+        };
+        corrupt[first] ^= 0xff;
+        // the hint file would vouch for the first record
+        std::fs::remove_file(temp_dir.path().join("kvs.log.hint"))?;
+        std::fs::write(&log, &corrupt)?;
+        assert!(KvStore::open(temp_dir.path()).is_err());
+        assert_eq!(std::fs::read(&log)?, corrupt);
+    }
+    Ok(())
+}
+
+// A log has one writer or any number of readers at a time, the lock goes
+// with the KvStore holding it.
+#[test]
+fn file_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let already_locked = |result: Result<KvStore>| match result {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::AlreadyLocked { path }) => assert!(path.ends_with("kvs.lock")),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("a locked KvStore was opened"),
+    };
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    already_locked(KvStore::open(temp_dir.path()));
+    already_locked(KvStore::open_read_only(temp_dir.path()));
+    already_locked(KvStore::open_at(temp_dir.path(), 1));
+    // another log of the directory has a lock of its own
+    KvStoreOptions::new()
+        .log_file_name("other.log")
+        .open(temp_dir.path())?;
+    store.close()?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    let other_reader = KvStore::open_read_only(temp_dir.path())?;
+    already_locked(KvStore::open(temp_dir.path()));
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        other_reader.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    drop(reader);
+    already_locked(KvStore::open(temp_dir.path()));
+    drop(other_reader);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// The CLI fails with a message instead of writing a log another KvStore has
+// open.
+#[test]
+fn cli_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("already open"));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// Every record gets the next sequence number, which compaction, `clear` and
+// reopening never take back.
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), 0);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&events);
+    store.on_change(move |event| seen.lock().unwrap().push((event.key.to_owned(), event.seq)));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_batch(vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    store.rename("key3".to_owned(), "key4".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.last_sequence(), 6);
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            ("key1".to_owned(), 1),
+            ("key2".to_owned(), 2),
+            ("key3".to_owned(), 3),
+            ("key4".to_owned(), 4),
+            ("key3".to_owned(), 5),
+            ("key1".to_owned(), 6),
+        ]
+    );
+    let seqs: Vec<_> = store.history("key1")?.iter().map(|e| e.seq).collect();
+    assert_eq!(seqs, vec![1, 6]);
+
+    // The removal is dropped by the compaction, its sequence number stays.
+    store.compact()?;
+    assert_eq!(store.history("key2")?[0].seq, 2);
+    assert!(store.history("key1")?.is_empty());
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), 6);
+    assert_eq!(store.history("key4")?[0].seq, 4);
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.history("key5")?[0].seq, 7);
+
+    // `clear` takes a sequence number of its own.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&events);
+    store.on_change(move |event| seen.lock().unwrap().push(event.seq));
+    store.clear()?;
+    assert_eq!(store.last_sequence(), 8);
+    assert_eq!(*events.lock().unwrap(), vec![8, 8, 8]);
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.last_sequence(), 8);
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), 8);
+
+    Ok(())
+}
+
+// A standby follows a KvStore through the backups since its last sequence
+// number, applying one again changes nothing.
+#[test]
+fn incremental_backup() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let standby_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut primary = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .open(primary_dir.path())?;
+    let mut standby = KvStore::open(standby_dir.path())?;
+    let pairs = |store: &KvStore| -> Result<Vec<(String, String)>> {
+        let mut pairs = store.iter().collect::<Result<Vec<_>>>()?;
+        pairs.sort();
+        Ok(pairs)
+    };
+    for key_id in 0..10 {
+        primary.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let mut stream = Vec::new();
+    let stats = primary.backup_since(0, &mut stream)?;
+    assert_eq!(stats.records, 10);
+    assert_eq!(stats.bytes, stream.len() as u64);
+    assert_eq!(stats.last_seq, 10);
+    assert_eq!(standby.apply_backup(&stream[..])?, 10);
+    assert_eq!(pairs(&standby)?, pairs(&primary)?);
+
+    // Only the records since the last backup are streamed, removals too.
+    primary.remove("key0".to_owned())?;
+    primary.set("key1".to_owned(), "new value1".to_owned())?;
+    primary.rename("key2".to_owned(), "key10".to_owned())?;
+    primary.remove("key3".to_owned())?;
+    let mut delta = Vec::new();
+    let stats = primary.backup_since(standby.last_sequence(), &mut delta)?;
+    assert_eq!(stats.records, 5);
+    assert_eq!(standby.apply_backup(&delta[..])?, 15);
+    assert_eq!(pairs(&standby)?, pairs(&primary)?);
+    assert_eq!(standby.metadata("key1"), primary.metadata("key1"));
+
+    // Overlapping streams apply nothing twice.
+    let log_len = std::fs::metadata(standby_dir.path().join("kvs.log"))?.len();
+    assert_eq!(standby.apply_backup(&delta[..])?, 15);
+    assert_eq!(standby.apply_backup(&stream[..])?, 15);
+    assert_eq!(
+        std::fs::metadata(standby_dir.path().join("kvs.log"))?.len(),
+        log_len
+    );
+    assert_eq!(pairs(&standby)?, pairs(&primary)?);
+
+    // A stream after records the standby lacks is refused.
+    primary.set("key4".to_owned(), "new value4".to_owned())?;
+    primary.set("key5".to_owned(), "new value5".to_owned())?;
+    let mut ahead = Vec::new();
+    primary.backup_since(16, &mut ahead)?;
+    match standby.apply_backup(&ahead[..]) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::BackupGap { seq }) => assert_eq!(*seq, 15),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("a backup with a gap was applied"),
+    }
+    // So is a stream cut short, after the records before the cut.
+    let mut cut = Vec::new();
+    primary.backup_since(15, &mut cut)?;
+    cut.truncate(cut.len() - 1);
+    match standby.apply_backup(&cut[..]) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::InvalidBackup { .. }) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("a cut backup was applied"),
+    }
+    assert_eq!(standby.last_sequence(), 17);
+    assert!(standby.apply_backup(&b"KVSDUMP\x01"[..]).is_err());
+
+    // The removals compacted away can not be streamed any more.
+    primary.remove("key6".to_owned())?;
+    primary.compact()?;
+    match primary.backup_since(standby.last_sequence(), &mut Vec::new()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::BackupGap { seq }) => assert_eq!(*seq, 17),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("a backup missing a removal was written"),
+    }
+    let mut empty = Vec::new();
+    assert_eq!(primary.backup_since(18, &mut empty)?.records, 0);
+    drop(standby);
+
+    // Open from disk again and check persistent data.
+    let standby = KvStore::open(standby_dir.path())?;
+    assert_eq!(standby.last_sequence(), 17);
+    assert_eq!(
+        standby.get("key5".to_owned())?,
+        Some("new value5".to_owned())
+    );
+    assert_eq!(standby.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Large values are compressed in either format when asked to, reads
+// decompress them whatever the options and a log can mix both.
+#[cfg(feature = "compression")]
+#[test]
+fn compression() -> Result<()> {
+    let large: String = (0..4096)
+        .map(|i| format!("{{\"id\":{},\"tag\":\"blob\"}}", i % 16))
+        .collect();
+    for format in [LogFormat::Json, LogFormat::Binary].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStoreOptions::new()
+            .log_format(*format)
+            .compression(Some(kvs::Compression::Deflate))
+            .open(temp_dir.path())?;
+        store.set("large".to_owned(), large.clone())?;
+        store.set("small".to_owned(), "value".to_owned())?;
+        store.set_bytes("bytes".to_owned(), vec![0xff; 8192])?;
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get_bytes("bytes")?, Some(vec![0xff; 8192]));
+        let mut read = String::new();
+        store
+            .get_reader("large")?
+            .expect("large is set")
+            .read_to_string(&mut read)?;
+        assert_eq!(read, large);
+        let stats = store.stats();
+        assert!(stats.live_bytes * 10 < stats.logical_bytes);
+        assert!(stats.log_bytes < large.len() as u64);
+        drop(store);
+
+        // Open from disk again and check persistent data.
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.stats(), stats);
+        store.set("plain".to_owned(), large.clone())?;
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        assert_eq!(store.get("plain".to_owned())?, Some(large.clone()));
+        let stats = store.stats();
+        assert!(stats.logical_bytes - stats.live_bytes > large.len() as u64 / 2);
+        store.compact()?;
+        assert_eq!(store.stats().logical_bytes, stats.logical_bytes);
+        drop(store);
+
+        // The log replayed without its hint counts the same bytes.
+        std::fs::remove_file(temp_dir.path().join("kvs.log.hint"))?;
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.stats().logical_bytes, stats.logical_bytes);
+        assert_eq!(store.get_bytes("bytes")?, Some(vec![0xff; 8192]));
+        assert_eq!(
+            store.history("large")?[0].value.as_deref(),
+            Some(large.as_str())
+        );
+    }
+    Ok(())
+}
+
+// An encrypted log holds no key or value in plain text, through compaction
+// and segments, and only opens with its key.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [7; 32];
+    let mut options = KvStoreOptions::new();
+    options
+        .encryption_key(Some(key))
+        .compaction_threshold(None)
+        .max_segment_size(Some(512));
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("secret{}", key_id), format!("hidden{}", key_id))?;
+    }
+    store.remove("secret0".to_owned())?;
+    store.set("secret1".to_owned(), "hidden again".to_owned())?;
+    store.set_from_reader("streamed".to_owned(), 6, &b"hidden"[..])?;
+    assert_eq!(
+        store.get("secret1".to_owned())?,
+        Some("hidden again".to_owned())
+    );
+    let mut read = String::new();
+    store
+        .get_reader("streamed")?
+        .expect("streamed is set")
+        .read_to_string(&mut read)?;
+    assert_eq!(read, "hidden");
+    assert_eq!(store.history("secret2")?.len(), 1);
+    assert!(store.verify()?.is_ok());
+    store.compact()?;
+    store.set("secret20".to_owned(), "hidden20".to_owned())?;
+    drop(store);
+    let mut logs = 0;
+    for entry in WalkDir::new(temp_dir.path()).min_depth(1) {
+        let bytes = std::fs::read(entry?.path())?;
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(!text.contains("secret") && !text.contains("hidden"));
+        logs += 1;
+    }
+    // the segments and the lock file, but no hint
+    assert!(logs > 2);
+    assert!(!temp_dir.path().join("kvs.log.hint").exists());
+
+    // Open from disk again and check persistent data.
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("secret0".to_owned())?, None);
+    assert_eq!(
+        store.get("secret1".to_owned())?,
+        Some("hidden again".to_owned())
+    );
+    assert_eq!(
+        store.get("secret20".to_owned())?,
+        Some("hidden20".to_owned())
+    );
+    assert_eq!(store.len(), 21);
+    drop(store);
+
+    // Another key, no key, or a key for a plain log are all refused.
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(plain_dir.path())?.set("key".to_owned(), "value".to_owned())?;
+    let attempts = [
+        (
+            temp_dir.path(),
+            KvStoreOptions::new().encryption_key(Some([8; 32])).clone(),
+        ),
+        (temp_dir.path(), KvStoreOptions::new()),
+        (
+            plain_dir.path(),
+            KvStoreOptions::new().encryption_key(Some(key)).clone(),
+        ),
+    ];
+    for (path, options) in attempts.iter() {
+        match options.open(*path) {
+            Err(err) => match err.downcast_ref::<KvsError>() {
+                Some(KvsError::BadEncryptionKey) => {}
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(_) => panic!("opened with the wrong key"),
+        }
+    }
+    Ok(())
+}
+
+// A sealed record moved to another place of an encrypted log does not open
+// there: neither over a newer record of its key nor replayed at the end.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_binds_records_to_their_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStoreOptions::new();
+    options
+        .encryption_key(Some([7; 32]))
+        .compaction_threshold(None);
+    let mut store = options.open(temp_dir.path())?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    store.set("key".to_owned(), "new".to_owned())?;
+    store.set("end".to_owned(), "end".to_owned())?;
+    drop(store);
+    let log_path = temp_dir.path().join("kvs.log");
+    let log = std::fs::read(&log_path)?;
+    // the header, its key check, then three records of one length
+    let start = 16 + 28;
+    let len = (log.len() - start) / 3;
+    assert_eq!(start + 3 * len, log.len());
+    let old = log[start..start + len].to_vec();
+
+    // The old record over the new one.
+    let mut moved = log.clone();
+    moved[start + len..start + 2 * len].copy_from_slice(&old);
+    std::fs::write(&log_path, &moved)?;
+    match options.open(temp_dir.path()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption { offset }) => assert_eq!(*offset, (start + len) as u64),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("opened a moved record"),
+    }
+
+    // The old record again at the end of the log, read as a torn one.
+    let mut replayed = log;
+    replayed.extend_from_slice(&old);
+    std::fs::write(&log_path, &replayed)?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.stats().truncated_bytes, len as u64);
+    Ok(())
+}
+
+// the bytes of a hex string, for the test vectors
+#[cfg(feature = "encryption")]
+fn hex(digits: &str) -> Vec<u8> {
+    let digits: Vec<u8> = digits.bytes().filter(u8::is_ascii_hexdigit).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+// The ChaCha20, Poly1305 and AEAD of the encryption give the test vectors of
+// RFC 8439: sections 2.3.2, 2.4.2, 2.5.2 and 2.8.2.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_test_vectors() {
+    use kvs::aead;
+    let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+    let nonce = [0, 0, 0, 9, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    assert_eq!(
+        aead::chacha20_block(key, 1, nonce).to_vec(),
+        hex(
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        )
+    );
+
+    let sunscreen = b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+                      one tip for the future, sunscreen would be it.";
+    let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    assert_eq!(
+        aead::chacha20(key, nonce, sunscreen),
+        hex(
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b
+             f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8
+             07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736
+             5af90bbf74a35be6b40b8eedf2785e42874d"
+        )
+    );
+
+    let one_time_key = hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+    assert_eq!(
+        aead::poly1305(
+            std::convert::TryInto::try_into(one_time_key).unwrap(),
+            b"Cryptographic Forum Research Group"
+        )
+        .to_vec(),
+        hex("a8061dc1305136c6c22b8baf0c0127a9")
+    );
+
+    let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+    let nonce = [7, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+    let aad = hex("50515253c0c1c2c3c4c5c6c7");
+    let (encrypted, tag) = aead::seal(key, nonce, &aad, sunscreen);
+    assert_eq!(
+        encrypted,
+        hex(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc
+             3ff4def08e4b7a9de576d26586cec64b6116"
+        )
+    );
+    assert_eq!(tag.to_vec(), hex("1ae10b594f09e26a7e902ecbd0600691"));
+}
+
+// Records past 4 GiB of a sparse log are indexed, read, appended after and
+// compacted. Ignored by default as it reads gigabytes of holes.
+#[test]
+#[ignore]
+fn large_offsets() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvs.log");
+    let options = KvStoreOptions::new().log_format(LogFormat::Binary).clone();
+    drop(options.open(temp_dir.path())?);
+    std::fs::remove_file(temp_dir.path().join("kvs.log.hint"))?;
+
+    // binary set records of 512 MiB values whose bytes are left as holes
+    let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+    let mut offset = file.metadata()?.len();
+    let value_len = 512u32 << 20;
+    let zeros = vec![0; 1 << 20];
+    for key_id in 0..9u64 {
+        let mut payload = Vec::new();
+        for field in [0, 1, 1, 1, key_id + 1].iter() {
+            payload.extend_from_slice(&u64::to_le_bytes(*field));
+        }
+        let key = format!("hole{}", key_id);
+        payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(key.as_bytes());
+        payload.extend_from_slice(&value_len.to_le_bytes());
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&payload);
+        for _ in 0..value_len as usize / zeros.len() {
+            crc.update(&zeros);
+        }
+        let mut frame = vec![1];
+        frame.extend_from_slice(&(payload.len() as u32 + value_len).to_le_bytes());
+        frame.extend_from_slice(&crc.finalize().to_le_bytes());
+        frame.extend_from_slice(&payload);
+        file.write_all_at(&frame, offset)?;
+        offset += frame.len() as u64 + u64::from(value_len);
+    }
+    file.set_len(offset)?;
+    drop(file);
+    assert!(offset > 4 << 30);
+
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.len(), 9);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("kvs.log.hint"))?;
+
+    // Open from disk again and check persistent data.
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    for key_id in 0..9 {
+        store.remove(format!("hole{}", key_id))?;
+    }
+    store.compact()?;
+    assert!(std::fs::metadata(&log)?.len() < 1 << 20);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A sampled index keeps every nth key of the compacted log in memory and
+// reads the others from the log.
+#[test]
+fn index_sampling() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_sampling(8).clone();
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key000".to_owned(), "overwritten".to_owned())?;
+    store.remove("key099".to_owned())?;
+    // every key is kept until the first compaction
+    assert_eq!(store.stats().resident_keys, 99);
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 99);
+    assert!(stats.resident_keys < 20);
+    assert_eq!(
+        store.get("key000".to_owned())?,
+        Some("overwritten".to_owned())
+    );
+    for key_id in 1..99 {
+        assert_eq!(
+            store.get(format!("key{:03}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    for key in &["key099", "key0505", "a", "z"] {
+        assert_eq!(store.get(key.to_string())?, None);
+    }
+    assert!(store.contains_key("key050"));
+
+    // The keys written after the compaction are kept in memory.
+    store.set("key010".to_owned(), "new10".to_owned())?;
+    store.remove("key011".to_owned())?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.get("key010".to_owned())?, Some("new10".to_owned()));
+    assert_eq!(store.get("key011".to_owned())?, None);
+    let keys: HashSet<_> = store.keys().cloned().collect();
+    assert_eq!(keys.len(), 99);
+    assert!(!keys.contains("key011"));
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?.len(), 99);
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let mut store = options.open(temp_dir.path())?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 99);
+    assert!(stats.resident_keys < 20);
+    assert_eq!(store.get("key010".to_owned())?, Some("new10".to_owned()));
+    assert_eq!(store.get("key011".to_owned())?, None);
+    assert_eq!(store.get("key050".to_owned())?, Some("value50".to_owned()));
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    store.compact()?;
+    assert!(store.stats().resident_keys < 20);
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = options.clone().ordered(true).open(temp_dir.path())?;
+    assert_eq!(store.len(), 99);
+    let range = store.range("key009".to_owned().."key013".to_owned())?;
+    let keys: Vec<_> = range.iter().map(|kv| kv.0.as_str()).collect();
+    assert_eq!(keys, ["key009", "key010", "key012"]);
+    assert_eq!(range[1].1, "new10");
+    drop(store);
+
+    // The dense index keeps every key.
+    let store = KvStoreOptions::new().open(temp_dir.path())?;
+    assert_eq!(store.stats().resident_keys, 99);
+    assert_eq!(store.get("key050".to_owned())?, Some("value50".to_owned()));
+    Ok(())
+}
+
+// The value cache answers repeated reads and never serves a value which
+// was overwritten, removed or moved by a compaction.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cache_capacity(1 << 20).clone();
+    let mut store = options.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+    // An overwritten value is read again, even once a compaction moved the
+    // new record to where the cached one was.
+    store.set("key1".to_owned(), "valueA".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("valueA".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set_batch(vec![("key1".to_owned(), "valueB".to_owned())])?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("valueB".to_owned()));
+
+    // A compaction keeps the values of the records it moves.
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    let hits = store.stats().cache_hits;
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats().cache_hits, hits + 1);
+    store.clear()?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    // A cache too small for a value holds nothing.
+    let mut store = KvStoreOptions::new()
+        .cache_capacity(8)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().cache_hits, 0);
+    Ok(())
+}
+
+// Small values are kept inline in the index and served without reading the
+// log, which stays the source of truth.
+#[test]
+fn inline_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().inline_values(16).clone();
+    let mut store = options.open(temp_dir.path())?;
+    let usage = store.approximate_memory_usage();
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), "v".repeat(100))?;
+    assert!(store.approximate_memory_usage() >= usage + "value".len());
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some("v".repeat(100)));
+    store.set("small".to_owned(), "updated".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("small".to_owned())?, Some("updated".to_owned()));
+    drop(store);
+
+    // Open from disk again and check persistent data, with the hint and
+    // without it.
+    for _ in 0..2 {
+        let store = options.open(temp_dir.path())?;
+        assert_eq!(store.get("small".to_owned())?, Some("updated".to_owned()));
+        assert_eq!(store.get("large".to_owned())?, Some("v".repeat(100)));
+        drop(store);
+        std::fs::remove_file(temp_dir.path().join("kvs.log.hint"))?;
+    }
+
+    // An inline value is served without reading the log, a longer one is
+    // read from it.
+    let store = options.open(temp_dir.path())?;
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("kvs.log"))?;
+    file.set_len(0)?;
+    assert_eq!(store.get("small".to_owned())?, Some("updated".to_owned()));
+    assert!(store.get("large".to_owned()).is_err());
+    Ok(())
+}
+
+// a kvs-server on an ephemeral port, killed once dropped
+struct Server {
+    child: std::process::Child,
+    addr: String,
+    // kept open for the server to log to
+    _stderr: std::io::BufReader<std::process::ChildStderr>,
+}
+
+impl Server {
+    // spawn kvs-server with args, once it is listening
+    fn spawn(args: &[&str]) -> Server {
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:0"])
+            .args(args)
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .expect("unable to spawn kvs-server");
+        let mut stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        std::io::BufRead::read_line(&mut stderr, &mut line).unwrap();
+        let addr = match line.trim_end().rsplit_once("listening on ") {
+            Some((_, addr)) => addr.to_owned(),
+            None => panic!("unexpected startup line: {}", line),
+        };
+        Server {
+            child,
+            addr,
+            _stderr: stderr,
+        }
+    }
+
+    // the response of the server to request, over conn
+    fn request(conn: &mut std::net::TcpStream, request: Request) -> Result<Response> {
+        write_frame(&mut *conn, &request)?;
+        Ok(read_frame(&mut *conn)?.expect("connection closed by the server"))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// kvs-server logs its version, engine and address, then serves get, set
+// and remove over TCP and tells a missing key from an error.
+#[test]
+fn server_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let server = Server::spawn(&["--dir", dir]);
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    let get = |key: &str| Request::Get {
+        key: key.to_owned(),
+    };
+    let set = |key: &str, value: &str| Request::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    let remove = |key: &str| Request::Remove {
+        key: key.to_owned(),
+    };
+
+    assert_eq!(
+        Server::request(&mut conn, get("key1"))?,
+        Response::Value(None)
+    );
+    assert_eq!(
+        Server::request(&mut conn, set("key1", "value1"))?,
+        Response::Done
+    );
+    assert_eq!(
+        Server::request(&mut conn, get("key1"))?,
+        Response::Value(Some("value1".to_owned()))
+    );
+    assert_eq!(Server::request(&mut conn, remove("key1"))?, Response::Done);
+    assert_eq!(
+        Server::request(&mut conn, remove("key1"))?,
+        Response::KeyNotFound
+    );
+    let long = "k".repeat((1 << 20) + 1);
+    match Server::request(&mut conn, set(&long, "value"))? {
+        Response::Error(message) => assert!(message.contains("exceeds the limit")),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    Server::request(&mut conn, set("key2", "value2"))?;
+    drop(conn);
+
+    // A second connection is served the same data.
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    assert_eq!(
+        Server::request(&mut conn, get("key2"))?,
+        Response::Value(Some("value2".to_owned()))
+    );
+    drop(conn);
+    drop(server);
+
+    // Open from disk again and check persistent data.
+    let server = Server::spawn(&["--dir", dir]);
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    assert_eq!(
+        Server::request(&mut conn, get("key2"))?,
+        Response::Value(Some("value2".to_owned()))
+    );
+    assert_eq!(
+        Server::request(&mut conn, get("key1"))?,
+        Response::Value(None)
+    );
+    Ok(())
+}
+
+// kvs-client gets, sets and removes through kvs-server, with the exit
+// codes and messages of kvs.
+#[test]
+fn client_cli() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::spawn(&["--dir", temp_dir.path().to_str().unwrap()]);
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", &server.addr]);
+        cmd
+    };
+
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    client(&["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["rm", "key1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["rm", "key1"])
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(eq("Key not found").trim());
+
+    // With no server to connect to, the client fails.
+    drop(server);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:1"])
+        .assert()
+        .failure();
+}
+
+// KvsClient returns the values of the server, a KeyNotFound error for the
+// removal of a missing key and the errors of the server verbatim.
+#[test]
+fn kvs_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::spawn(&["--dir", temp_dir.path().to_str().unwrap()]);
+    let mut client = KvsClient::connect(&server.addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    client.remove("key1".to_owned())?;
+    match client.remove("key1".to_owned()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("remove of a missing key succeeded"),
+    }
+    match client.set("k".repeat((1 << 20) + 1), "value".to_owned()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Server { message }) => assert_eq!(
+                message,
+                "key of 1048577 bytes exceeds the limit of 1048576 bytes"
+            ),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("set of a key over the limit succeeded"),
+    }
+    // The connection is served on after an error.
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A pipeline of KvsClient gets the results of its requests in order, a
+// failed request fails its own result only, and a pipeline whose responses
+// are much longer than its requests completes.
+#[test]
+fn kvs_client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::spawn(&["--dir", temp_dir.path().to_str().unwrap()]);
+    let mut client = KvsClient::connect(&server.addr)?;
+    let mut pipeline = client.pipeline();
+    pipeline
+        .set("key1".to_owned(), "value1".to_owned())
+        .get("key1".to_owned())
+        .remove("key2".to_owned())
+        .set("key1".to_owned(), "value2".to_owned())
+        .get("key1".to_owned())
+        .remove("key1".to_owned())
+        .get("key1".to_owned());
+    assert_eq!(pipeline.len(), 7);
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 7);
+    assert_eq!(results[0].as_ref().ok(), Some(&None));
+    assert_eq!(results[1].as_ref().ok(), Some(&Some("value1".to_owned())));
+    match &results[2] {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::KeyNotFound) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("remove of a missing key succeeded"),
+    }
+    assert_eq!(results[3].as_ref().ok(), Some(&None));
+    assert_eq!(results[4].as_ref().ok(), Some(&Some("value2".to_owned())));
+    assert_eq!(results[5].as_ref().ok(), Some(&None));
+    assert_eq!(results[6].as_ref().ok(), Some(&None));
+    assert!(client.pipeline().execute()?.is_empty());
+
+    let value = "v".repeat(64 << 10);
+    client.set("big".to_owned(), value.clone())?;
+    let mut pipeline = client.pipeline();
+    for _ in 0..500 {
+        pipeline.get("big".to_owned());
+    }
+    for result in pipeline.execute()? {
+        assert_eq!(result?, Some(value.clone()));
+    }
+    // The connection is served on after a pipeline.
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// KvsClient sets, gets and removes many keys with one request each, and the
+// removal reports the missing keys one by one.
+#[test]
+fn kvs_client_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::spawn(&["--dir", temp_dir.path().to_str().unwrap()]);
+    let mut client = KvsClient::connect(&server.addr)?;
+    let pairs: Vec<_> = (0..1000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs.clone())?;
+    let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let values: Vec<_> = pairs.into_iter().map(|(_, value)| Some(value)).collect();
+    assert_eq!(client.get_many(keys.clone())?, values);
+    assert_eq!(
+        client.get("key999".to_owned())?,
+        Some("value999".to_owned())
+    );
+
+    let removed = client.remove_many(vec![
+        "key1".to_owned(),
+        "missing".to_owned(),
+        "key2".to_owned(),
+    ])?;
+    assert_eq!(removed, vec![true, false, true]);
+    assert_eq!(
+        client.get_many(vec!["key1".to_owned(), "key3".to_owned()])?,
+        vec![None, Some("value3".to_owned())]
+    );
+
+    // A batch with a key over the limit is refused as a whole.
+    let refused = client.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("k".repeat((1 << 20) + 1), "value".to_owned()),
+    ]);
+    match refused {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Server { message }) => assert_eq!(
+                message,
+                "key of 1048577 bytes exceeds the limit of 1048576 bytes"
+            ),
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("set of a key over the limit succeeded"),
+    }
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// a proxy to addr which closes the first connection after the first bytes
+// of its request, as a reset connection would, and forwards the next ones
+fn flaky_proxy(addr: &str) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = listener.local_addr().unwrap().to_string();
+    let addr = addr.to_owned();
+    thread::spawn(move || {
+        for (i, client) in listener.incoming().enumerate() {
+            let mut client = client.unwrap();
+            if i == 0 {
+                let _ = client.read(&mut [0; 4]);
+                continue;
+            }
+            let server = std::net::TcpStream::connect(&addr).unwrap();
+            let (mut from_client, mut to_server) =
+                (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || std::io::copy(&mut from_client, &mut to_server));
+            let (mut from_server, mut to_client) = (server, client);
+            thread::spawn(move || std::io::copy(&mut from_server, &mut to_client));
+        }
+    });
+    proxy
+}
+
+// A KvsClient built with retries sends a get and a set again on a new
+// connection once the first one is closed; without retries the request
+// fails, as does a removal with retries, which is never sent again.
+#[test]
+fn kvs_client_retries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::spawn(&["--dir", temp_dir.path().to_str().unwrap(), "--threads", "8"]);
+    let mut client = KvsClientBuilder::new(flaky_proxy(&server.addr))
+        .retries(3)
+        .backoff(Duration::from_millis(10))
+        .connect()?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut client = KvsClientBuilder::new(flaky_proxy(&server.addr))
+        .retries(3)
+        .backoff(Duration::from_millis(10))
+        .connect()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut client = KvsClient::connect(flaky_proxy(&server.addr))?;
+    let failed = client.set("key2".to_owned(), "value2".to_owned()).err();
+    let failed = failed.expect("set sent without retries on a closed connection");
+    assert!(failed.downcast_ref::<std::io::Error>().is_some());
+    // The client connects again for the next request.
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut client = KvsClientBuilder::new(flaky_proxy(&server.addr))
+        .retries(3)
+        .backoff(Duration::from_millis(10))
+        .connect()?;
+    assert!(client.remove("key2".to_owned()).is_err());
+    client.remove("key2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// kvs-server --auth-token answers no request before the token, refuses a
+// wrong one, closes a connection after MAX_AUTH_FAILURES failures and
+// serves one which sent the token; in RESP too.
+#[test]
+fn server_auth_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    // a thread serves every connection for as long as it is open
+    let server = Server::spawn(&["--dir", dir, "--threads", "8", "--auth-token", "secret"]);
+    let expect_error = |result: Result<Option<String>>, expected: KvsError| match result {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(found) => assert_eq!(found.to_string(), expected.to_string()),
+            None => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("request of an unauthenticated client succeeded"),
+    };
+    // missing token
+    let mut client = KvsClient::connect(&server.addr)?;
+    expect_error(client.get("key1".to_owned()), KvsError::AuthRequired);
+    // wrong token
+    expect_error(
+        KvsClient::connect_with_token(&server.addr, "secret2".to_owned()).map(|_| None),
+        KvsError::AuthFailed,
+    );
+    // correct token
+    let mut client = KvsClient::connect_with_token(&server.addr, "secret".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Failures of either kind add up, the last closes the connection.
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    let auth = |token: &str| Request::Auth {
+        token: token.to_owned(),
+    };
+    let get = Request::Get {
+        key: "key1".to_owned(),
+    };
+    assert_eq!(
+        Server::request(&mut conn, auth("wrong"))?,
+        Response::AuthFailed
+    );
+    assert_eq!(Server::request(&mut conn, auth("secret"))?, Response::Done);
+    assert_eq!(
+        Server::request(&mut conn, get.clone())?,
+        Response::Value(Some("value1".to_owned()))
+    );
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    assert_eq!(
+        Server::request(&mut conn, get.clone())?,
+        Response::AuthRequired
+    );
+    assert_eq!(Server::request(&mut conn, auth(""))?, Response::AuthFailed);
+    assert_eq!(Server::request(&mut conn, get)?, Response::AuthRequired);
+    assert_eq!(kvs::protocol::MAX_AUTH_FAILURES, 3);
+    assert!(read_frame::<Response>(&mut conn)?.is_none());
+
+    // kvs-client takes the token from KVS_AUTH_TOKEN.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &server.addr, "get", "key1"])
+        .env("KVS_AUTH_TOKEN", "secret")
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &server.addr, "get", "key1"])
+        .env_remove("KVS_AUTH_TOKEN")
+        .assert()
+        .failure()
+        .stderr(eq("the server requires an authentication token").trim());
+
+    drop(server);
+    let resp = Server::spawn(&["--dir", dir, "--protocol", "resp", "--auth-token", "secret"]);
+    let mut conn = std::net::TcpStream::connect(&resp.addr)?;
+    let mut exchange = |command: &[u8], expected: &[u8]| -> Result<()> {
+        std::io::Write::write_all(&mut conn, command)?;
+        let mut reply = vec![0; expected.len()];
+        conn.read_exact(&mut reply)?;
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    };
+    exchange(b"GET key1\r\n", b"-NOAUTH Authentication required.\r\n")?;
+    exchange(
+        b"AUTH wrong\r\n",
+        b"-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+    )?;
+    exchange(b"AUTH default secret\r\n", b"+OK\r\n")?;
+    exchange(b"GET key1\r\n", b"$6\r\nvalue1\r\n")?;
+    Ok(())
+}
+
+// kvs-server --max-connections turns the connections over the limit away
+// with Busy, in RESP with the error of Redis, and counts them in its stats.
+#[test]
+fn server_max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let server = Server::spawn(&["--dir", dir, "--threads", "4", "--max-connections", "2"]);
+    let mut first = KvsClient::connect(&server.addr)?;
+    let mut second = KvsClient::connect(&server.addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    assert_eq!(read_frame::<Response>(&mut conn)?, Some(Response::Busy));
+    assert!(read_frame::<Response>(&mut conn)?.is_none());
+    let mut third = KvsClient::connect(&server.addr)?;
+    match third.get("key1".to_owned()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Busy) => {}
+            _ => panic!("unexpected error: {}", err),
+        },
+        Ok(_) => panic!("request over the connection limit succeeded"),
+    }
+    let stats = first.server_stats()?;
+    assert_eq!(stats.connections, 2);
+    assert_eq!(stats.max_connections, Some(2));
+    assert_eq!(stats.rejected_connections, 2);
+    assert_eq!(stats.request_timeout_ms, None);
+
+    // A connection closed makes room for another.
+    drop(second);
+    let mut waited = 0;
+    while first.server_stats()?.connections > 1 {
+        assert!(waited < 500, "closed connection still served");
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+    let mut fourth = KvsClient::connect(&server.addr)?;
+    assert_eq!(fourth.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop((first, fourth, server));
+
+    let server = Server::spawn(&["--dir", dir, "--protocol", "resp", "--max-connections", "1"]);
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    std::io::Write::write_all(&mut conn, b"PING\r\n")?;
+    let mut reply = [0; 7];
+    conn.read_exact(&mut reply)?;
+    let mut refused = std::net::TcpStream::connect(&server.addr)?;
+    let mut reply = Vec::new();
+    refused.read_to_end(&mut reply)?;
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        "-ERR max number of clients reached\r\n"
+    );
+    let info = "# Clients\r\nconnected_clients:1\r\nmaxclients:1\r\n\r\n\
+                # Stats\r\nrejected_connections:1\r\ntimedout_connections:0\r\n\
+                request_timeout_ms:0\r\n";
+    std::io::Write::write_all(&mut conn, b"INFO\r\n")?;
+    let expected = format!("${}\r\n{}\r\n", info.len(), info);
+    let mut reply = vec![0; expected.len()];
+    conn.read_exact(&mut reply)?;
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+    Ok(())
+}
+
+// kvs-server --request-timeout drops a connection which sends nothing, or
+// stops in the middle of a request, once the timeout passed, and counts
+// them in its stats.
+#[test]
+fn server_request_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let server = Server::spawn(&["--dir", dir, "--threads", "4", "--request-timeout", "1"]);
+    let silent = std::net::TcpStream::connect(&server.addr)?;
+    let mut stalled = std::net::TcpStream::connect(&server.addr)?;
+    // the first bytes of the length of a frame
+    std::io::Write::write_all(&mut stalled, &[1, 0])?;
+    let start = std::time::Instant::now();
+    let mut rest = Vec::new();
+    (&silent).read_to_end(&mut rest)?;
+    stalled.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    let waited = start.elapsed();
+    assert!(
+        waited >= Duration::from_millis(900),
+        "reaped after {:?}",
+        waited
+    );
+    assert!(
+        waited < Duration::from_secs(10),
+        "reaped after {:?}",
+        waited
+    );
+
+    // A client which keeps sending is served on.
+    let mut client = KvsClient::connect(&server.addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let stats = client.server_stats()?;
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.request_timeout_ms, Some(1000));
+    assert_eq!(stats.timed_out_connections, 2);
+    Ok(())
+}
+
+// kvs-server --request-timeout drops a connection which sends a request a
+// byte at a time, each before a read of it would time out, once the whole
+// request took longer than the timeout.
+#[test]
+fn server_request_timeout_trickle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let server = Server::spawn(&["--dir", dir, "--request-timeout", "1"]);
+    let mut trickling = std::net::TcpStream::connect(&server.addr)?;
+    let mut frame = Vec::new();
+    write_frame(
+        &mut frame,
+        &Request::Get {
+            key: "key1".repeat(20),
+        },
+    )?;
+    let start = std::time::Instant::now();
+    let mut dropped = false;
+    for byte in frame {
+        if std::io::Write::write_all(&mut trickling, &[byte]).is_err() {
+            dropped = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    if !dropped {
+        // the whole frame came in: the server answers it or has dropped it
+        let mut rest = Vec::new();
+        let _ = trickling.read_to_end(&mut rest);
+        assert!(rest.is_empty(), "answered after {:?}", start.elapsed());
+    }
+    let waited = start.elapsed();
+    assert!(
+        waited < Duration::from_secs(4),
+        "dropped after {:?}",
+        waited
+    );
+
+    let mut client = KvsClient::connect(&server.addr)?;
+    assert_eq!(client.server_stats()?.timed_out_connections, 1);
+    Ok(())
+}
+
+// kvs-server serves with the engine of --engine, kvs, the only one built
+// in, and exits before listening if the directory belongs to another engine
+// or --engine names one it does not have.
+#[test]
+fn server_engine_flag() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let refused = |dir: &str, args: &[&str], found: &str, expected: &str| {
+        let message = format!(
+            "data directory belongs to engine {}, not {}",
+            found, expected
+        );
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:0", "--dir", dir])
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(eq(message.as_str()).trim());
+    };
+
+    // No flag on an empty directory: kvs, which claims it.
+    let server = Server::spawn(&["--dir", dir]);
+    KvsClient::connect(&server.addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    drop(server);
+    assert_eq!(kvs::engine_marker(dir)?, Some("kvs".to_owned()));
+
+    // The flag of the engine owning the directory, or none, serves its data.
+    for args in [&["--engine", "kvs"][..], &[]].iter() {
+        let server = Server::spawn(&[&["--dir", dir][..], args].concat());
+        let mut client = KvsClient::connect(&server.addr)?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    // The flag of another engine is refused, and the data is left alone.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:0", "--dir", dir, "--engine", "sled"])
+        .assert()
+        .failure()
+        .stderr(contains("unknown engine: sled"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A directory of another engine is refused, with the flag or without.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(other_dir.path().join("engine"), "sled")?;
+    let dir = other_dir.path().to_str().unwrap();
+    refused(dir, &["--engine", "kvs"], "sled", "kvs");
+    refused(dir, &[], "sled", "kvs");
+    assert_eq!(std::fs::read_dir(other_dir.path())?.count(), 1);
+    Ok(())
+}
+
+// The behaviour every ThreadPool has to follow, run against a pool of four
+// threads: every job spawned is run, on threads which outlive the jobs
+// panicking on them, in no particular order, and dropping the pool waits for
+// the jobs.
+fn thread_pool_conformance<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let done = Arc::new(AtomicUsize::new(0));
+    for i in 0..100 {
+        let done = Arc::clone(&done);
+        pool.spawn(move || {
+            if i % 10 == 0 {
+                panic!("job {} panics", i);
+            }
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    // the four threads are all alive after the panics, to meet together
+    let barrier = Arc::new(std::sync::Barrier::new(5));
+    for _ in 0..4 {
+        let barrier = Arc::clone(&barrier);
+        pool.spawn(move || {
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    // A job waiting on one spawned after it is not stuck behind it.
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let (done_sender, done_receiver) = std::sync::mpsc::channel();
+    pool.spawn(move || done_sender.send(receiver.recv().unwrap()).unwrap());
+    pool.spawn(move || sender.send("later").unwrap());
+    assert_eq!(
+        done_receiver.recv_timeout(Duration::from_secs(10)),
+        Ok("later")
+    );
+
+    // Dropping the pool waits for the slow jobs spawned before.
+    for _ in 0..8 {
+        let done = Arc::clone(&done);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    drop(pool);
+    assert_eq!(done.load(Ordering::SeqCst), 98);
+    Ok(())
+}
+
+// SharedQueueThreadPool follows the behaviour of a ThreadPool.
+#[test]
+fn shared_queue_thread_pool() -> Result<()> {
+    thread_pool_conformance::<SharedQueueThreadPool>()
+}
+
+// RayonThreadPool follows the behaviour of a ThreadPool.
+#[cfg(feature = "rayon-pool")]
+#[test]
+fn rayon_thread_pool() -> Result<()> {
+    thread_pool_conformance::<kvs::thread_pool::RayonThreadPool>()
+}
+
+// kvs-server serves dozens of clients at the same time, each seeing its own
+// writes and all of them the writes of the others once done.
+#[test]
+fn server_concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::spawn(&["--dir", temp_dir.path().to_str().unwrap(), "--threads", "8"]);
+    // the clients connect together and hold their connections open
+    let barrier = Arc::new(std::sync::Barrier::new(32));
+    let clients: Vec<_> = (0..32)
+        .map(|client_id| {
+            let addr = server.addr.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                barrier.wait();
+                for i in 0..20 {
+                    let key = format!("key{}-{}", client_id, i);
+                    client.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().expect("client panicked")?;
+    }
+
+    let mut client = KvsClient::connect(&server.addr)?;
+    for client_id in 0..32 {
+        for i in 0..20 {
+            let key = format!("key{}-{}", client_id, i);
+            assert_eq!(client.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// A KvsServer stopped through its shutdown handle closes the connections
+// it serves, flushes the KvStore and returns, which releases its lock.
+#[test]
+fn server_shutdown_handle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    server.drain_timeout(Duration::from_secs(10));
+    let handle = server.shutdown_handle();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let running = thread::spawn(move || server.run(&listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    // an idle connection, which the shutdown closes
+    let mut idle = std::net::TcpStream::connect(addr)?;
+    Server::request(
+        &mut idle,
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+    )?;
+    handle.shutdown();
+    running.join().expect("server panicked")?;
+    assert!(read_frame::<Response>(&mut idle)?.is_none());
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    Ok(())
+}
+
+// A KvsServer whose shutdown handle was used before it ran returns from run
+// without accepting a connection.
+#[test]
+fn server_shutdown_before_run() -> Result<()> {
+    let server = KvsServer::new(
+        Arc::new(Mutex::new(MemKvStore::new())),
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.shutdown_handle();
+    handle.shutdown();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let (done, finished) = std::sync::mpsc::channel();
+    thread::spawn(move || done.send(server.run(&listener)));
+    finished
+        .recv_timeout(Duration::from_secs(10))
+        .expect("server kept running")?;
+    Ok(())
+}
+
+// A KvsServer over a MemKvStore behind an Arc<Mutex<_>> serves the same
+// data on every connection.
+#[test]
+fn server_shares_engine() -> Result<()> {
+    let mut server = KvsServer::new(
+        Arc::new(Mutex::new(MemKvStore::new())),
+        SharedQueueThreadPool::new(2)?,
+    );
+    server.drain_timeout(Duration::from_secs(10));
+    let handle = server.shutdown_handle();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let running = thread::spawn(move || server.run(&listener));
+
+    let mut first = KvsClient::connect(addr)?;
+    let mut second = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    second.remove("key1".to_owned())?;
+    assert_eq!(first.get("key1".to_owned())?, None);
+    drop((first, second));
+    handle.shutdown();
+    running.join().expect("server panicked")?;
+    Ok(())
+}
+
+// kvs-server exits 0 on SIGTERM and leaves its data directory to the next
+// KvStore.
+#[test]
+fn server_sigterm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = Server::spawn(&["--dir", temp_dir.path().to_str().unwrap()]);
+    let mut client = KvsClient::connect(&server.addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let killed = unsafe { libc::kill(server.child.id() as libc::pid_t, libc::SIGTERM) };
+    assert_eq!(killed, 0);
+    assert!(server.child.wait()?.success());
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// kvs-server --protocol resp answers RESP commands, in the multi-bulk form
+// and inline, byte for byte like Redis does.
+#[test]
+fn server_resp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let server = Server::spawn(&["--dir", dir, "--protocol", "resp"]);
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    // send command, then read back as many bytes as the reply expected has
+    let mut exchange = |command: &[u8], expected: &[u8]| -> Result<()> {
+        std::io::Write::write_all(&mut conn, command)?;
+        let mut reply = vec![0; expected.len()];
+        conn.read_exact(&mut reply)?;
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    };
+    exchange(b"PING\r\n", b"+PONG\r\n")?;
+    exchange(b"*2\r\n$4\r\nPING\r\n$5\r\nhello\r\n", b"$5\r\nhello\r\n")?;
+    exchange(
+        b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$9\r\nvalue\r\n 1\r\n",
+        b"+OK\r\n",
+    )?;
+    exchange(
+        b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        b"$9\r\nvalue\r\n 1\r\n",
+    )?;
+    exchange(b"set key2 value2\n", b"+OK\r\n")?;
+    exchange(b"get key2\r\n", b"$6\r\nvalue2\r\n")?;
+    exchange(b"GET key3\r\n", b"$-1\r\n")?;
+    // an empty line is no command
+    exchange(b"\r\nEXISTS key1 key3 key2\r\n", b":2\r\n")?;
+    exchange(b"DEL key1 key3\r\n", b":1\r\n")?;
+    exchange(b"GET key1\r\n", b"$-1\r\n")?;
+
+    // errors leave the connection open
+    exchange(
+        b"FLUSHALL\r\n",
+        b"-ERR unknown command 'FLUSHALL', with args beginning with: \r\n",
+    )?;
+    exchange(
+        b"*2\r\n$6\r\nEXPIRE\r\n$4\r\nkey2\r\n",
+        b"-ERR unknown command 'EXPIRE', with args beginning with: 'key2' \r\n",
+    )?;
+    exchange(
+        b"GET\r\n",
+        b"-ERR wrong number of arguments for 'get' command\r\n",
+    )?;
+    exchange(b"SET key2 value2 NX\r\n", b"-ERR syntax error\r\n")?;
+    exchange(b"GET key2\r\n", b"$6\r\nvalue2\r\n")?;
+
+    // a request which breaks the protocol closes the connection
+    exchange(
+        b"*1\r\n+PING\r\n",
+        b"-ERR Protocol error: expected '$', got '+'\r\n",
+    )?;
+    let mut rest = Vec::new();
+    conn.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+// kvs-server --protocol resp refuses a command of too many arguments, or of
+// too long ones, and closes a connection which ends before the bulk string
+// whose length it claimed.
+#[test]
+fn server_resp_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let server = Server::spawn(&["--dir", dir, "--protocol", "resp"]);
+
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    std::io::Write::write_all(&mut conn, b"*65537\r\n")?;
+    let mut reply = Vec::new();
+    conn.read_to_end(&mut reply)?;
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        "-ERR Protocol error: invalid multibulk length\r\n"
+    );
+
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    std::io::Write::write_all(
+        &mut conn,
+        b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$67108857\r\nvalue",
+    )?;
+    conn.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = Vec::new();
+    conn.read_to_end(&mut reply)?;
+    assert!(reply.is_empty());
+    // together the arguments are no longer than 64 MiB
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    std::io::Write::write_all(&mut conn, b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$67108858\r\n")?;
+    let mut reply = Vec::new();
+    conn.read_to_end(&mut reply)?;
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        "-ERR Protocol error: invalid bulk length\r\n"
+    );
+
+    let mut conn = std::net::TcpStream::connect(&server.addr)?;
+    std::io::Write::write_all(&mut conn, b"GET key1\r\n")?;
+    let mut reply = [0; 5];
+    conn.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"$-1\r\n");
+    Ok(())
+}
+
+// SharedKvStore follows the semantics of a KvsEngine, and its clones share
+// the KvStore.
+#[test]
+fn shared_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SharedKvStore::open(temp_dir.path())?;
+    engine_conformance(&mut store)?;
+    let clone = store.clone();
+    clone.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.read().len(), 4);
+    drop((store, clone));
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Readers of a SharedKvStore running with a writer, whose values change
+// length and get compacted, never see a torn value or an older one than
+// they saw, and all of them finish.
+#[test]
+fn shared_kv_store_readers_and_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(
+        KvStoreOptions::new()
+            .compaction_threshold(Some(64 * 1024))
+            .open(temp_dir.path())?,
+    );
+    // the nth write of a key, which tells n from its length too
+    let value = |n: usize| format!("{}:{}", n, "x".repeat(n % 50));
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), value(0))?;
+    }
+    let writing = Arc::new(AtomicBool::new(true));
+    let readers: Vec<_> = (0..8)
+        .map(|reader_id| {
+            let store = store.clone();
+            let writing = Arc::clone(&writing);
+            thread::spawn(move || -> Result<usize> {
+                let mut seen = [0; 20];
+                let mut reads = 0;
+                while writing.load(Ordering::SeqCst) || reads < 100 {
+                    let key_id = (reads * 7 + reader_id) % 20;
+                    let read = store.get(format!("key{}", key_id))?.expect("key is set");
+                    let (n, tail) = read.split_once(':').expect("torn value");
+                    let n: usize = n.parse().expect("torn value");
+                    assert_eq!(tail.len(), n % 50, "torn value {}", read);
+                    assert!(n >= seen[key_id], "older value read");
+                    seen[key_id] = n;
+                    reads += 1;
+                }
+                Ok(reads)
+            })
+        })
+        .collect();
+    let compactions = store.read().stats().compactions;
+    for n in 1..=250 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), value(n))?;
+        }
+    }
+    writing.store(false, Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().expect("reader panicked")? >= 100);
+    }
+    assert!(store.read().stats().compactions > compactions);
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(250)));
+    }
+    Ok(())
+}
+
+// Writes through the guard of SharedKvStore::write compact the KvStore once
+// the threshold is passed, and get reads what they wrote once it is dropped.
+#[test]
+fn shared_kv_store_write_guard() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(
+        KvStoreOptions::new()
+            .compaction_threshold(Some(4096))
+            .open(temp_dir.path())?,
+    );
+    {
+        let mut guard = store.write();
+        for n in 0..200 {
+            guard.set("key".to_owned(), format!("value{}", n))?;
+            guard.rename("key".to_owned(), "renamed".to_owned())?;
+            guard.rename("renamed".to_owned(), "key".to_owned())?;
+        }
+        assert!(guard.stats().compactions > 0);
+    }
+    assert!(store.read().stats().dead_bytes <= 4096);
+    assert_eq!(store.get("key".to_owned())?, Some("value199".to_owned()));
+    assert_eq!(store.get("renamed".to_owned())?, None);
+    drop(store);
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value199".to_owned()));
+    Ok(())
+}
+
+// a FileSync whose next sync of a file, once armed, waits at reached and
+// then at released
+#[derive(Debug)]
+struct PausingSync {
+    armed: Arc<AtomicBool>,
+    reached: Arc<Barrier>,
+    released: Arc<Barrier>,
+}
+
+impl PausingSync {
+    fn pause(&self) {
+        if self.armed.swap(false, Ordering::SeqCst) {
+            self.reached.wait();
+            self.released.wait();
+        }
+    }
+}
+
+impl FileSync for PausingSync {
+    fn sync_data(&self, file: &std::fs::File) -> std::io::Result<()> {
+        self.pause();
+        file.sync_data()
+    }
+
+    fn sync_all(&self, file: &std::fs::File) -> std::io::Result<()> {
+        self.pause();
+        file.sync_all()
+    }
+}
+
+// A get on a SharedKvStore does not wait for a set stuck syncing the log:
+// every key reads as it was, the one set too, until the set is done.
+#[test]
+fn shared_kv_store_get_beside_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let armed = Arc::new(AtomicBool::new(false));
+    let reached = Arc::new(Barrier::new(2));
+    let released = Arc::new(Barrier::new(2));
+    let store = SharedKvStore::new(
+        KvStoreOptions::new()
+            .sync_policy(SyncPolicy::Always)
+            .file_sync(PausingSync {
+                armed: Arc::clone(&armed),
+                reached: Arc::clone(&reached),
+                released: Arc::clone(&released),
+            })
+            .open(temp_dir.path())?,
+    );
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    armed.store(true, Ordering::SeqCst);
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || store.set("key42".to_owned(), "new value".to_owned()))
+    };
+    reached.wait();
+    let (sender, receiver) = mpsc::channel();
+    {
+        let store = store.clone();
+        thread::spawn(move || {
+            let reads: Result<Vec<_>> = (0..100)
+                .map(|key_id| store.get(format!("key{}", (key_id + 43) % 100)))
+                .collect();
+            sender.send(reads)
+        });
+    }
+    let reads = receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("read held up by the write")?;
+    for (key_id, read) in (0..100).map(|key_id| (key_id + 43) % 100).zip(reads) {
+        assert_eq!(read, Some(format!("value{}", key_id)));
+    }
+    released.wait();
+    writer.join().expect("writer panicked")?;
+    assert_eq!(store.get("key42".to_owned())?, Some("new value".to_owned()));
+    Ok(())
+}
+
+// A SharedKvStore reads while a compaction is stuck syncing the new log,
+// from the log before the compaction, and from the new one after it.
+#[test]
+fn shared_kv_store_reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let armed = Arc::new(AtomicBool::new(false));
+    let reached = Arc::new(Barrier::new(2));
+    let released = Arc::new(Barrier::new(2));
+    let store = SharedKvStore::new(
+        KvStoreOptions::new()
+            .compaction_threshold(None)
+            .file_sync(PausingSync {
+                armed: Arc::clone(&armed),
+                reached: Arc::clone(&reached),
+                released: Arc::clone(&released),
+            })
+            .open(temp_dir.path())?,
+    );
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    armed.store(true, Ordering::SeqCst);
+    let compaction = {
+        let store = store.clone();
+        thread::spawn(move || store.compact())
+    };
+    reached.wait();
+    let (sender, receiver) = mpsc::channel();
+    {
+        let store = store.clone();
+        thread::spawn(move || sender.send(store.get("key42".to_owned())));
+    }
+    let read = receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("read held up by the compaction")?;
+    assert_eq!(read, Some("value42".to_owned()));
+    released.wait();
+    let stats = compaction.join().expect("compaction panicked")?;
+    assert!(stats.bytes_reclaimed > 0);
+
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}